@@ -0,0 +1,524 @@
+use std::net::IpAddr;
+
+use nex::packet::ethernet::EtherType;
+use nex::packet::ip::IpNextProtocol;
+
+use crate::capture::pcap::PacketCaptureOptions;
+
+/// Maximum number of instructions the kernel accepts in a classic BPF program (BPF_MAXINSNS)
+pub const MAX_INSNS: usize = 4096;
+
+// Instruction classes, sizes, modes and operations of classic BPF (linux/bpf_common.h)
+const BPF_LD: u16 = 0x00;
+const BPF_LDX: u16 = 0x01;
+const BPF_ALU: u16 = 0x04;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+const BPF_W: u16 = 0x00;
+const BPF_H: u16 = 0x08;
+const BPF_B: u16 = 0x10;
+const BPF_ABS: u16 = 0x20;
+const BPF_IND: u16 = 0x40;
+const BPF_MSH: u16 = 0xa0;
+const BPF_AND: u16 = 0x50;
+const BPF_JA: u16 = 0x00;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JGT: u16 = 0x20;
+const BPF_JGE: u16 = 0x30;
+const BPF_JSET: u16 = 0x40;
+const BPF_K: u16 = 0x00;
+
+/// Snap length returned for accepted packets (the whole packet)
+const ACCEPT: u32 = u32::MAX;
+/// Length of the Ethernet header in front of the network layer
+const ETHERNET_HEADER_LEN: u32 = 14;
+
+/// One classic BPF instruction, laid out like the kernel's `struct sock_filter`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BpfInsn {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+impl BpfInsn {
+    fn new(code: u16, jt: u8, jf: u8, k: u32) -> Self {
+        Self { code, jt, jf, k }
+    }
+}
+
+/// Jump target inside a program being assembled
+#[derive(Debug, Clone, Copy)]
+struct Label(usize);
+
+/// Classic BPF assembler. Conditional jumps only ever skip a few instructions;
+/// jumps to labels use `ja`, whose 32-bit offset reaches the whole program.
+#[derive(Default)]
+struct Assembler {
+    insns: Vec<BpfInsn>,
+    labels: Vec<Option<usize>>,
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Assembler {
+    fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.insns.len());
+    }
+
+    fn emit(&mut self, code: u16, jt: u8, jf: u8, k: u32) {
+        self.insns.push(BpfInsn::new(code, jt, jf, k));
+    }
+
+    /// Load `size` bytes at absolute offset `offset` into A
+    fn load(&mut self, size: u16, offset: u32) {
+        self.emit(BPF_LD | size | BPF_ABS, 0, 0, offset);
+    }
+
+    fn ret(&mut self, k: u32) {
+        self.emit(BPF_RET | BPF_K, 0, 0, k);
+    }
+
+    fn jump(&mut self, label: Label) {
+        self.fixups.push((self.insns.len(), label));
+        self.emit(BPF_JMP | BPF_JA, 0, 0, 0);
+    }
+
+    /// Jump to `label` if A is in one of the inclusive `ranges`, else fall through.
+    fn jump_if_in(&mut self, ranges: &[(u32, u32)], label: Label) {
+        for &(lo, hi) in ranges {
+            if lo == hi {
+                self.emit(BPF_JMP | BPF_JEQ | BPF_K, 0, 1, lo);
+            } else {
+                self.emit(BPF_JMP | BPF_JGE | BPF_K, 0, 2, lo);
+                self.emit(BPF_JMP | BPF_JGT | BPF_K, 1, 0, hi);
+            }
+            self.jump(label);
+        }
+    }
+
+    /// Jump to `label` if the 16 bytes at `offset` equal one of `addrs`, else fall through.
+    fn jump_if_ipv6_in(&mut self, offset: u32, addrs: &[[u32; 4]], label: Label) {
+        for words in addrs {
+            for (i, word) in words.iter().enumerate() {
+                self.load(BPF_W, offset + 4 * i as u32);
+                // On mismatch, skip the rest of the words and the jump
+                self.emit(BPF_JMP | BPF_JEQ | BPF_K, 0, (2 * (3 - i) + 1) as u8, *word);
+            }
+            self.jump(label);
+        }
+    }
+
+    fn finish(mut self) -> Vec<BpfInsn> {
+        for (at, label) in self.fixups {
+            let target = self.labels[label.0].expect("unbound label");
+            self.insns[at].k = (target - at - 1) as u32;
+        }
+        self.insns
+    }
+}
+
+/// Merge values into sorted inclusive ranges.
+fn ranges(values: impl IntoIterator<Item = u32>) -> Ranges {
+    let mut values: Vec<u32> = values.into_iter().collect();
+    values.sort_unstable();
+    values.dedup();
+    let mut ranges: Ranges = Vec::new();
+    for value in values {
+        match ranges.last_mut() {
+            Some((_, hi)) if *hi + 1 == value => *hi = value,
+            _ => ranges.push((value, value)),
+        }
+    }
+    ranges
+}
+
+/// Sorted inclusive ranges of values
+type Ranges = Vec<(u32, u32)>;
+
+/// IPv4 and IPv6 addresses of a host filter set
+struct HostSet {
+    ipv4: Ranges,
+    ipv6: Vec<[u32; 4]>,
+}
+
+impl HostSet {
+    fn new<'a>(addrs: impl IntoIterator<Item = &'a IpAddr>) -> Self {
+        let mut ipv4 = Vec::new();
+        let mut ipv6 = Vec::new();
+        for addr in addrs {
+            match addr {
+                IpAddr::V4(v4) => ipv4.push(u32::from(*v4)),
+                IpAddr::V6(v6) => {
+                    let octets = v6.octets();
+                    let word = |i: usize| u32::from_be_bytes([octets[i], octets[i + 1], octets[i + 2], octets[i + 3]]);
+                    ipv6.push([word(0), word(4), word(8), word(12)]);
+                }
+            }
+        }
+        ipv6.sort_unstable();
+        ipv6.dedup();
+        Self { ipv4: ranges(ipv4), ipv6 }
+    }
+}
+
+/// Filter sets of the capture options, in the form the compiler uses
+struct Filter {
+    ether_types: Ranges,
+    ip_protocols: Ranges,
+    /// Source and destination hosts, `None` if both sets are empty (any host)
+    hosts: Option<(HostSet, HostSet)>,
+    /// Source and destination ports, `None` if both sets are empty (any port)
+    ports: Option<(Ranges, Ranges)>,
+    /// Offset of the network layer
+    link_len: u32,
+}
+
+/// Compile the capture options into a classic BPF program for a Linux
+/// `AF_PACKET` socket. The program accepts at least every frame that the
+/// userspace filter of [`PacketCaptureOptions`] accepts, so received frames
+/// are still filtered in userspace.
+///
+/// Host and port sets are compiled as ranges. If the program does not fit in
+/// [`MAX_INSNS`] instructions, the host checks and then the port checks are
+/// left to userspace; `None` means no useful program fits.
+pub fn compile(capture_options: &PacketCaptureOptions) -> Option<Vec<BpfInsn>> {
+    let hosts = (!capture_options.src_ips.is_empty() || !capture_options.dst_ips.is_empty())
+        .then(|| (HostSet::new(&capture_options.src_ips), HostSet::new(&capture_options.dst_ips)));
+    let ports = (!capture_options.src_ports.is_empty() || !capture_options.dst_ports.is_empty()).then(|| {
+        (
+            ranges(capture_options.src_ports.iter().map(|port| u32::from(*port))),
+            ranges(capture_options.dst_ports.iter().map(|port| u32::from(*port))),
+        )
+    });
+    let mut filter = Filter {
+        ether_types: ranges(capture_options.ether_types.iter().map(|ether_type| u32::from(ether_type.value()))),
+        ip_protocols: ranges(capture_options.ip_protocols.iter().map(|protocol| u32::from(protocol.value()))),
+        hosts,
+        ports,
+        link_len: if capture_options.tunnel { 0 } else { ETHERNET_HEADER_LEN },
+    };
+    if filter.ether_types.is_empty() && filter.ip_protocols.is_empty() && filter.hosts.is_none() && filter.ports.is_none() {
+        return None;
+    }
+    let program = assemble(&filter);
+    if program.len() <= MAX_INSNS {
+        return Some(program);
+    }
+    filter.hosts = None;
+    let program = assemble(&filter);
+    if program.len() <= MAX_INSNS {
+        return Some(program);
+    }
+    filter.ports = None;
+    let program = assemble(&filter);
+    (program.len() <= MAX_INSNS).then_some(program)
+}
+
+fn assemble(filter: &Filter) -> Vec<BpfInsn> {
+    let mut asm = Assembler::default();
+    let ipv4 = asm.label();
+    let ipv6 = asm.label();
+    let l = filter.link_len;
+    if l == 0 {
+        // Frames start with the IP header, the version says which one
+        asm.load(BPF_B, 0);
+        asm.emit(BPF_ALU | BPF_AND | BPF_K, 0, 0, 0xf0);
+        asm.emit(BPF_JMP | BPF_JEQ | BPF_K, 0, 1, 0x40);
+        asm.jump(ipv4);
+        asm.emit(BPF_JMP | BPF_JEQ | BPF_K, 0, 1, 0x60);
+        asm.jump(ipv6);
+        asm.ret(ACCEPT);
+    } else {
+        let arp = asm.label();
+        if !filter.ether_types.is_empty() {
+            let ether_type_ok = asm.label();
+            asm.load(BPF_H, 12);
+            asm.jump_if_in(&filter.ether_types, ether_type_ok);
+            asm.ret(0);
+            asm.bind(ether_type_ok);
+        }
+        asm.load(BPF_H, 12);
+        asm.jump_if_in(&[(u32::from(EtherType::Ipv4.value()), u32::from(EtherType::Ipv4.value()))], ipv4);
+        asm.jump_if_in(&[(u32::from(EtherType::Ipv6.value()), u32::from(EtherType::Ipv6.value()))], ipv6);
+        asm.jump_if_in(&[(u32::from(EtherType::Arp.value()), u32::from(EtherType::Arp.value()))], arp);
+        // Other protocols are only filtered by ether type
+        asm.ret(ACCEPT);
+
+        asm.bind(arp);
+        if let Some((src, dst)) = &filter.hosts {
+            let host_ok = asm.label();
+            // Sender and target protocol addresses
+            asm.load(BPF_W, l + 14);
+            asm.jump_if_in(&src.ipv4, host_ok);
+            asm.load(BPF_W, l + 24);
+            asm.jump_if_in(&dst.ipv4, host_ok);
+            asm.ret(0);
+            asm.bind(host_ok);
+        }
+        asm.ret(ACCEPT);
+    }
+
+    asm.bind(ipv4);
+    if let Some((src, dst)) = &filter.hosts {
+        let host_ok = asm.label();
+        asm.load(BPF_W, l + 12);
+        asm.jump_if_in(&src.ipv4, host_ok);
+        asm.load(BPF_W, l + 16);
+        asm.jump_if_in(&dst.ipv4, host_ok);
+        asm.ret(0);
+        asm.bind(host_ok);
+    }
+    assemble_protocol_check(&mut asm, filter, l + 9);
+    if filter.ports.is_some() {
+        // Only the first fragment carries the transport header
+        asm.load(BPF_H, l + 6);
+        asm.emit(BPF_JMP | BPF_JSET | BPF_K, 0, 1, 0x1fff);
+        asm.ret(ACCEPT);
+        // X = length of the IPv4 header
+        asm.emit(BPF_LDX | BPF_B | BPF_MSH, 0, 0, l);
+        assemble_port_check(&mut asm, filter, l + 9, BPF_IND, l);
+    }
+    asm.ret(ACCEPT);
+
+    asm.bind(ipv6);
+    if let Some((src, dst)) = &filter.hosts {
+        let host_ok = asm.label();
+        asm.jump_if_ipv6_in(l + 8, &src.ipv6, host_ok);
+        asm.jump_if_ipv6_in(l + 24, &dst.ipv6, host_ok);
+        asm.ret(0);
+        asm.bind(host_ok);
+    }
+    assemble_protocol_check(&mut asm, filter, l + 6);
+    assemble_port_check(&mut asm, filter, l + 6, BPF_ABS, l + 40);
+    asm.ret(ACCEPT);
+    asm.finish()
+}
+
+/// Reject packets whose IP protocol (the byte at `offset`) is not in the filter.
+fn assemble_protocol_check(asm: &mut Assembler, filter: &Filter, offset: u32) {
+    if filter.ip_protocols.is_empty() {
+        return;
+    }
+    let protocol_ok = asm.label();
+    asm.load(BPF_B, offset);
+    asm.jump_if_in(&filter.ip_protocols, protocol_ok);
+    asm.ret(0);
+    asm.bind(protocol_ok);
+}
+
+/// Check the ports of TCP and UDP packets, whose IP protocol is the byte at `protocol_offset`.
+/// `mode` is `BPF_ABS` or `BPF_IND` (relative to X), and `offset` is the offset of the
+/// transport header. Packets of other protocols are accepted.
+fn assemble_port_check(asm: &mut Assembler, filter: &Filter, protocol_offset: u32, mode: u16, offset: u32) {
+    let Some((src_ports, dst_ports)) = &filter.ports else {
+        return;
+    };
+    let transport = asm.label();
+    let accept = asm.label();
+    asm.load(BPF_B, protocol_offset);
+    asm.jump_if_in(&[(u32::from(IpNextProtocol::Tcp.value()), u32::from(IpNextProtocol::Tcp.value()))], transport);
+    asm.jump_if_in(&[(u32::from(IpNextProtocol::Udp.value()), u32::from(IpNextProtocol::Udp.value()))], transport);
+    asm.ret(ACCEPT);
+    asm.bind(transport);
+    asm.emit(BPF_LD | BPF_H | mode, 0, 0, offset);
+    asm.jump_if_in(src_ports, accept);
+    asm.emit(BPF_LD | BPF_H | mode, 0, 0, offset + 2);
+    asm.jump_if_in(dst_ports, accept);
+    asm.ret(0);
+    asm.bind(accept);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::net::Ipv6Addr;
+    use std::time::Duration;
+
+    /// Run a program over a packet like the kernel does and return the snap length (0 = drop).
+    fn run(program: &[BpfInsn], packet: &[u8]) -> u32 {
+        let load = |offset: usize, size: usize| -> Option<u32> {
+            let bytes = packet.get(offset..offset + size)?;
+            Some(bytes.iter().fold(0, |value, byte| (value << 8) | u32::from(*byte)))
+        };
+        let (mut a, mut x, mut pc) = (0u32, 0u32, 0usize);
+        loop {
+            let insn = program[pc];
+            pc += 1;
+            let size = match insn.code & 0x18 {
+                BPF_W => 4,
+                BPF_H => 2,
+                _ => 1,
+            };
+            match insn.code & 0x07 {
+                BPF_LD => {
+                    let offset = if insn.code & 0xe0 == BPF_IND { x + insn.k } else { insn.k };
+                    match load(offset as usize, size) {
+                        Some(value) => a = value,
+                        None => return 0,
+                    }
+                }
+                BPF_LDX => match load(insn.k as usize, 1) {
+                    Some(value) => x = (value & 0x0f) * 4,
+                    None => return 0,
+                },
+                BPF_ALU => a &= insn.k,
+                BPF_JMP => {
+                    let taken = match insn.code & 0xf0 {
+                        BPF_JA => {
+                            pc += insn.k as usize;
+                            continue;
+                        }
+                        BPF_JEQ => a == insn.k,
+                        BPF_JGT => a > insn.k,
+                        BPF_JGE => a >= insn.k,
+                        BPF_JSET => a & insn.k != 0,
+                        code => panic!("unexpected jump {:#x}", code),
+                    };
+                    pc += usize::from(if taken { insn.jt } else { insn.jf });
+                }
+                BPF_RET => return insn.k,
+                class => panic!("unexpected class {:#x}", class),
+            }
+        }
+    }
+
+    fn options() -> PacketCaptureOptions {
+        PacketCaptureOptions {
+            interface_index: 1,
+            interface_name: String::from("eth0"),
+            src_ips: HashSet::new(),
+            dst_ips: HashSet::new(),
+            src_ports: HashSet::new(),
+            dst_ports: HashSet::new(),
+            ether_types: HashSet::new(),
+            ip_protocols: HashSet::new(),
+            capture_timeout: Duration::MAX,
+            read_timeout: Duration::from_millis(200),
+            promiscuous: false,
+            receive_undefined: false,
+            tunnel: false,
+            loopback: false,
+        }
+    }
+
+    fn ethernet(ether_type: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x02, 0, 0, 0, 0, 1, 0x02, 0, 0, 0, 0, 2];
+        frame.extend_from_slice(&ether_type.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// IPv4 packet with `options_len` bytes of options and a 4-byte port header
+    fn ipv4(protocol: u8, src: [u8; 4], dst: [u8; 4], options_len: usize, ports: (u16, u16)) -> Vec<u8> {
+        let mut packet = vec![0x45 + (options_len / 4) as u8, 0, 0, 0, 0, 0, 0, 0, 64, protocol, 0, 0];
+        packet.extend_from_slice(&src);
+        packet.extend_from_slice(&dst);
+        packet.resize(packet.len() + options_len, 1);
+        packet.extend_from_slice(&ports.0.to_be_bytes());
+        packet.extend_from_slice(&ports.1.to_be_bytes());
+        packet.resize(packet.len() + 16, 0);
+        packet
+    }
+
+    fn ipv6(next_header: u8, src: Ipv6Addr, dst: Ipv6Addr, ports: (u16, u16)) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0, 0, 0, next_header, 64];
+        packet.extend_from_slice(&src.octets());
+        packet.extend_from_slice(&dst.octets());
+        packet.extend_from_slice(&ports.0.to_be_bytes());
+        packet.extend_from_slice(&ports.1.to_be_bytes());
+        packet.resize(packet.len() + 16, 0);
+        packet
+    }
+
+    const US: [u8; 4] = [192, 0, 2, 2];
+    const TARGET: [u8; 4] = [192, 0, 2, 10];
+
+    #[test]
+    fn port_scan_filter_matches_replies() {
+        // Capture options of a raw TCP port scan of ports 20-30 and 80
+        let mut options = options();
+        options.dst_ips.insert(IpAddr::from(US));
+        options.dst_ips.insert(IpAddr::V6("2001:db8::2".parse().unwrap()));
+        options.src_ports.extend((20..=30).chain([80]));
+        options.ether_types.extend([EtherType::Ipv4, EtherType::Ipv6]);
+        options.ip_protocols.extend([IpNextProtocol::Tcp, IpNextProtocol::Icmp, IpNextProtocol::Icmpv6]);
+        let program = compile(&options).unwrap();
+
+        let accepted = |packet: Vec<u8>| run(&program, &packet) != 0;
+        assert!(accepted(ethernet(0x0800, &ipv4(6, TARGET, US, 0, (25, 40000)))));
+        assert!(accepted(ethernet(0x0800, &ipv4(6, TARGET, US, 0, (80, 40000)))));
+        assert!(accepted(ethernet(0x0800, &ipv4(6, TARGET, US, 8, (80, 40000)))), "IPv4 options");
+        assert!(!accepted(ethernet(0x0800, &ipv4(6, TARGET, US, 0, (31, 40000)))), "port not scanned");
+        assert!(!accepted(ethernet(0x0800, &ipv4(6, TARGET, US, 8, (81, 40000)))));
+        assert!(!accepted(ethernet(0x0800, &ipv4(6, US, TARGET, 0, (40000, 25)))), "our own probe");
+        assert!(!accepted(ethernet(0x0800, &ipv4(17, TARGET, US, 0, (25, 40000)))), "UDP");
+        assert!(accepted(ethernet(0x0800, &ipv4(1, TARGET, US, 0, (0x0303, 0)))), "ICMP has no ports");
+        assert!(!accepted(ethernet(0x0806, &[0; 28])), "ARP");
+
+        let (target6, us6) = ("2001:db8::10".parse().unwrap(), "2001:db8::2".parse().unwrap());
+        assert!(accepted(ethernet(0x86dd, &ipv6(6, target6, us6, (22, 40000)))));
+        assert!(!accepted(ethernet(0x86dd, &ipv6(6, target6, us6, (443, 40000)))));
+        assert!(!accepted(ethernet(0x86dd, &ipv6(6, target6, "2001:db8::3".parse().unwrap(), (22, 40000)))));
+        assert!(accepted(ethernet(0x86dd, &ipv6(58, target6, us6, (0x0104, 0)))));
+    }
+
+    #[test]
+    fn host_filter_checks_source_or_destination() {
+        let mut options = options();
+        options.src_ips.extend([IpAddr::from(TARGET), IpAddr::from([192, 0, 2, 11])]);
+        options.ether_types.insert(EtherType::Arp);
+        options.ether_types.insert(EtherType::Ipv4);
+        let program = compile(&options).unwrap();
+
+        let arp = |sender: [u8; 4]| {
+            let mut payload = vec![0, 1, 8, 0, 6, 4, 0, 2, 2, 0, 0, 0, 0, 9];
+            payload.extend_from_slice(&sender);
+            payload.extend_from_slice(&[2, 0, 0, 0, 0, 1]);
+            payload.extend_from_slice(&US);
+            ethernet(0x0806, &payload)
+        };
+        assert_ne!(run(&program, &arp(TARGET)), 0);
+        assert_eq!(run(&program, &arp([192, 0, 2, 12])), 0);
+        assert_ne!(run(&program, &ethernet(0x0800, &ipv4(1, [192, 0, 2, 11], US, 0, (0, 0)))), 0);
+        assert_eq!(run(&program, &ethernet(0x0800, &ipv4(1, [192, 0, 2, 9], US, 0, (0, 0)))), 0);
+    }
+
+    #[test]
+    fn tunnel_packets_start_with_the_ip_header() {
+        let mut options = options();
+        options.tunnel = true;
+        options.dst_ports.insert(40000);
+        options.ip_protocols.insert(IpNextProtocol::Udp);
+        let program = compile(&options).unwrap();
+        assert_ne!(run(&program, &ipv4(17, TARGET, US, 0, (53, 40000))), 0);
+        assert_eq!(run(&program, &ipv4(17, TARGET, US, 0, (53, 40001))), 0);
+        assert_eq!(run(&program, &ipv4(6, TARGET, US, 0, (53, 40000))), 0);
+    }
+
+    #[test]
+    fn oversized_host_sets_are_left_to_userspace() {
+        let mut options = options();
+        // IPv6 addresses do not merge into ranges
+        options.src_ips.extend((0..1000u128).map(|i| IpAddr::V6(Ipv6Addr::from((0x2001_0db8_u128 << 96) | (i * 2)))));
+        options.ip_protocols.insert(IpNextProtocol::Tcp);
+        let program = compile(&options).unwrap();
+        assert!(program.len() <= MAX_INSNS);
+        let other: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        assert_ne!(run(&program, &ethernet(0x86dd, &ipv6(6, other, other, (1, 2)))), 0);
+        assert_eq!(run(&program, &ethernet(0x86dd, &ipv6(17, other, other, (1, 2)))), 0);
+    }
+
+    #[test]
+    fn ranges_merge_adjacent_values() {
+        assert_eq!(ranges([5, 1, 2, 3, 9, 10, 2]), vec![(1, 3), (5, 5), (9, 10)]);
+        assert_eq!(compile(&options()), None);
+    }
+}
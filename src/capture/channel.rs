@@ -0,0 +1,38 @@
+use std::io;
+use std::time::Duration;
+
+use netdev::Interface;
+use nex::datalink::async_io::{async_channel, AsyncChannel, AsyncRawReceiver, AsyncRawSender};
+
+/// Size of the send and receive buffers of a channel
+const BUFFER_SIZE: usize = 4096;
+
+/// Open an asynchronous channel to send and receive raw packets on the interface.
+///
+/// On Linux, packets on Layer3 links (see [`crate::interface::is_layer3`]) are
+/// sent and received without a link-layer header.
+/// Other links exchange Ethernet frames.
+pub fn open(interface: &Interface, read_timeout: Duration) -> io::Result<(Box<dyn AsyncRawSender>, Box<dyn AsyncRawReceiver>)> {
+    #[cfg(target_os = "linux")]
+    if crate::interface::is_layer3(interface) {
+        use crate::scan::socket::{IpSocket, PacketSocket};
+        let tx = IpSocket::open(interface, BUFFER_SIZE)?.into_async()?;
+        let rx = PacketSocket::open_rx(interface, BUFFER_SIZE, read_timeout, None)?.into_async()?;
+        return Ok((Box::new(tx), Box::new(rx)));
+    }
+    let config = nex::datalink::Config {
+        write_buffer_size: BUFFER_SIZE,
+        read_buffer_size: BUFFER_SIZE,
+        read_timeout: Some(read_timeout),
+        write_timeout: None,
+        channel_type: nex::datalink::ChannelType::Layer2,
+        bpf_fd_attempts: 1000,
+        linux_fanout: None,
+        promiscuous: false,
+    };
+    let AsyncChannel::Ethernet(tx, rx) = async_channel(interface, config)?
+    else {
+        unreachable!();
+    };
+    Ok((tx, rx))
+}
@@ -1 +1,3 @@
+pub mod bpf;
+pub mod channel;
 pub mod pcap;
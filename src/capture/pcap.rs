@@ -1,5 +1,6 @@
 use std::net::IpAddr;
 use nex::datalink::async_io::AsyncRawReceiver;
+use nex::datalink::RawReceiver;
 use nex::net::interface::Interface;
 use nex::packet::frame::Frame;
 use nex::packet::frame::ParseOption;
@@ -8,8 +9,10 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::time::Duration;
 use std::time::Instant;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use futures::stream::StreamExt;
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc, oneshot};
 use crate::interface;
 
 /// Packet capture options
@@ -40,7 +43,7 @@ pub struct PacketCaptureOptions {
     pub promiscuous: bool,
     /// Receive undefined packets
     pub receive_undefined: bool,
-    /// Use TUN interface
+    /// Packets are IP packets without a link-layer header (see [`interface::is_layer3`])
     pub tunnel: bool,
     /// Loopback interface
     pub loopback: bool,
@@ -62,7 +65,7 @@ impl PacketCaptureOptions {
             read_timeout: Duration::from_millis(200),
             promiscuous: false,
             receive_undefined: true,
-            tunnel: iface.is_tun(),
+            tunnel: interface::is_layer3(&iface),
             loopback: iface.is_loopback(),
         };
         Ok(options)
@@ -82,7 +85,7 @@ impl PacketCaptureOptions {
             read_timeout: Duration::from_millis(200),
             promiscuous: false,
             receive_undefined: true,
-            tunnel: iface.is_tun(),
+            tunnel: interface::is_layer3(&iface),
             loopback: iface.is_loopback(),
         };
         Some(options)
@@ -102,7 +105,7 @@ impl PacketCaptureOptions {
             read_timeout: Duration::from_millis(200),
             promiscuous: false,
             receive_undefined: true,
-            tunnel: iface.is_tun(),
+            tunnel: interface::is_layer3(&iface),
             loopback: iface.is_loopback(),
         };
         options
@@ -121,43 +124,50 @@ impl PacketCaptureOptions {
             read_timeout: Duration::from_millis(200),
             promiscuous: false,
             receive_undefined: true,
-            tunnel: iface.is_tun(),
+            tunnel: interface::is_layer3(iface),
             loopback: iface.is_loopback(),
         };
         options
     }
 }
 
-/// Start packet capture
-pub async fn start_capture(
+/// Captured frame with its receive timestamp
+#[derive(Debug, Clone)]
+pub struct CapturedFrame {
+    pub frame: Frame,
+    pub received_at: Instant,
+}
+
+/// Start packet capture and stream the matching frames as classified events.
+/// Each matching frame is counted in `responses` and classified as soon as it is
+/// received, and the resulting event is sent to `events`. The capture runs until
+/// `stop_rx` fires, the capture timeout is reached or `events` is closed.
+pub async fn start_stream_capture<T>(
     rx: &mut Box<dyn AsyncRawReceiver>,
     capture_options: PacketCaptureOptions,
     ready_tx: oneshot::Sender<()>,
     stop_rx: &mut oneshot::Receiver<()>,
-) -> Vec<Frame> {
-    let mut frames = Vec::new();
+    responses: &AtomicU64,
+    classify: impl Fn(CapturedFrame) -> Option<T>,
+    events: mpsc::Sender<T>,
+) {
     let start_time = Instant::now();
     ready_tx.send(()).unwrap();
     loop {
         tokio::select! {
             _ = &mut *stop_rx => break,
-            // Check if capture timeout is reached
             // Read next packet
             next_read = rx.next() => {
                 match next_read {
                     Some(Ok(packet)) => {
-                        let mut parse_option: ParseOption = ParseOption::default();
-                        if capture_options.tunnel || (cfg!(any(target_os = "macos", target_os = "ios")) && capture_options.loopback) {
-                            let payload_offset = if capture_options.loopback { 14 } else { 0 };
-                            parse_option.from_ip_packet = true;
-                            parse_option.offset = payload_offset;
-                        }
-                        if let Some(frame) = Frame::from_buf(&packet, parse_option) {
-                            if filter_packet(&frame, &capture_options) {
-                                frames.push(frame);
+                        let received_at = Instant::now();
+                        if let Some(frame) = decode_frame(&packet, &capture_options) {
+                            responses.fetch_add(1, Ordering::Relaxed);
+                            if let Some(event) = classify(CapturedFrame { frame, received_at })
+                                && events.send(event).await.is_err()
+                            {
+                                break;
                             }
-                        } else {
-                            eprintln!("Error parsing packet");
                         }
                     }
                     Some(Err(e)) => {
@@ -166,13 +176,72 @@ pub async fn start_capture(
                     }
                     None => {}
                 }
+                // Check if capture timeout is reached
                 if start_time.elapsed() >= capture_options.capture_timeout {
                     break;
                 }
             }
         }
     }
-    frames
+}
+
+/// Capture packets on the current thread until `stop` is set. Each matching
+/// frame is counted in `responses` and classified, and the resulting event is
+/// sent to `events`. Waits while `events` is full and stops once it is closed.
+/// Returns the number of packets read.
+///
+/// The receiver must have a read timeout so that `stop` is checked regularly.
+pub fn start_blocking_capture<T>(
+    rx: &mut dyn RawReceiver,
+    capture_options: &PacketCaptureOptions,
+    stop: &AtomicBool,
+    classify: &dyn Fn(CapturedFrame) -> Option<T>,
+    events: &mpsc::Sender<T>,
+    responses: &AtomicU64,
+) -> u64 {
+    let mut received: u64 = 0;
+    while !stop.load(Ordering::Relaxed) {
+        match rx.next() {
+            Ok(packet) => {
+                let received_at = Instant::now();
+                received += 1;
+                if let Some(frame) = decode_frame(packet, capture_options) {
+                    responses.fetch_add(1, Ordering::Relaxed);
+                    let Some(event) = classify(CapturedFrame { frame, received_at }) else {
+                        continue;
+                    };
+                    if events.blocking_send(event).is_err() {
+                        break;
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::TimedOut => {}
+            Err(e) => {
+                eprintln!("Error reading packet: {}", e);
+                break;
+            }
+        }
+    }
+    received
+}
+
+/// Parse a received packet and apply the capture filter.
+fn decode_frame(packet: &[u8], capture_options: &PacketCaptureOptions) -> Option<Frame> {
+    let mut parse_option: ParseOption = ParseOption::default();
+    if capture_options.tunnel || (cfg!(any(target_os = "macos", target_os = "ios")) && capture_options.loopback) {
+        let payload_offset = if cfg!(any(target_os = "macos", target_os = "ios")) && capture_options.loopback { 14 } else { 0 };
+        parse_option.from_ip_packet = true;
+        parse_option.offset = payload_offset;
+    }
+    let Some(frame) = Frame::from_buf(packet, parse_option) else {
+        eprintln!("Error parsing packet");
+        return None;
+    };
+    if filter_packet(&frame, capture_options) {
+        Some(frame)
+    } else {
+        None
+    }
 }
 
 fn filter_packet(frame: &Frame, capture_options: &PacketCaptureOptions) -> bool {
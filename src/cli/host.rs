@@ -1,43 +1,39 @@
 use anyhow::{Result, Context};
-use std::{net::IpAddr, path::Path};
+use std::{net::{IpAddr, Ipv4Addr}, path::{Path, PathBuf}};
 use ipnet::IpNet;
 use std::fs;
 use crate::endpoint::Host;
+use crate::scan::target::{AddrBlock, OctetPattern, TargetSet};
 
-/// Resolve one target specification line (CIDR / IP / hostname)
-async fn expand_one_target(t: &str) -> Result<Vec<Host>> {
-    let mut out = Vec::new();
-    let resolver = crate::dns::resolver::get_resolver()?;
-
-    // CIDR
-    if let Ok(net) = t.parse::<IpNet>() {
-        for ip in net.hosts() {
-            out.push(Host::new(ip));
-        }
-        return Ok(out);
-    }
+/// Targets collected while parsing, merged into a [`TargetSet`] at the end
+#[derive(Debug, Default)]
+struct TargetSpecs {
+    blocks: Vec<AddrBlock>,
+    hosts: Vec<Host>,
+}
 
-    // IP
-    if let Ok(ip) = t.parse::<IpAddr>() {
-        out.push(Host::new(ip));
-        return Ok(out);
+/// Resolve one target specification line (CIDR / IP / range / octet pattern / hostname)
+async fn expand_one_target(t: &str, specs: &mut TargetSpecs) -> Result<()> {
+    if let Some(blocks) = parse_address_spec(t)? {
+        specs.blocks.extend(blocks);
+        return Ok(());
     }
 
     // Hostname
+    let resolver = crate::dns::resolver::get_resolver()?;
     let ips = resolver.lookup_ip(t).await.with_context(|| format!("resolve {t}"))?;
     for ip in ips {
-        out.push(Host::with_hostname(ip, t.to_string()));
+        specs.hosts.push(Host::with_hostname(ip, t.to_string()));
     }
-    Ok(out)
+    Ok(())
 }
 
 /// Expand targets from a file (each line: CIDR / IP / hostname / @file)
-async fn expand_file(path: &Path) -> Result<Vec<Host>> {
+async fn expand_file(path: &Path, specs: &mut TargetSpecs) -> Result<()> {
     let text = fs::read_to_string(path)
         .with_context(|| format!("read target list file {}", path.display()))?;
 
     // Normalize lines and prepare for recursive processing
-    let mut hosts = Vec::new();
     let mut nested_inputs = Vec::new();
 
     for line in text.lines() {
@@ -48,16 +44,16 @@ async fn expand_file(path: &Path) -> Result<Vec<Host>> {
 
     // Recursively interpret each entry in the file
     for entry in nested_inputs {
-        let nested = expand_one_target(&entry).await?;
-        hosts.extend(nested);
+        expand_one_target(&entry, specs).await?;
     }
 
-    Ok(hosts)
+    Ok(())
 }
 
-/// Parse target specifications (CIDR / IP / hostname / @file / existing file path)
-pub async fn parse_target_hosts(inputs: &[String]) -> Result<Vec<Host>> {
-    let mut out = Vec::new();
+/// Parse target specifications (CIDR / IP / hostname / @file / existing file path).
+/// Networks are not expanded; hosts are generated on demand from the returned set.
+pub async fn parse_target_set(inputs: &[String]) -> Result<TargetSet> {
+    let mut specs = TargetSpecs::default();
 
     for raw in inputs {
         let s = raw.trim();
@@ -73,18 +69,219 @@ pub async fn parse_target_hosts(inputs: &[String]) -> Result<Vec<Host>> {
         let path = Path::new(path_str);
         if is_file_hint || path.is_file() {
             // Interpret as file
-            let hosts = expand_file(path).await?;
-            out.extend(hosts);
+            expand_file(path, &mut specs).await?;
             continue;
         }
 
         // 2. Interpret as regular target
-        let hosts = expand_one_target(s).await?;
-        out.extend(hosts);
+        expand_one_target(s, &mut specs).await?;
+    }
+
+    // Duplicates and overlapping networks are merged by the set
+    Ok(TargetSet::new(specs.blocks, specs.hosts))
+}
+
+/// Parse an address specification into address blocks:
+/// IP, CIDR, IP range ("10.0.0.1-10.0.0.9", "2001:db8::1-2001:db8::ff")
+/// or IPv4 octet pattern ("192.168.1.10-50", "10.0.0-3.1-254", "10.1.*.1").
+/// Returns `None` if the spec should be resolved as a hostname.
+fn parse_address_spec(s: &str) -> Result<Option<Vec<AddrBlock>>> {
+    // CIDR: keep only the bounds of the host range
+    if let Some((addr, prefix)) = s.split_once('/') {
+        let net = parse_cidr(s, addr, prefix)?;
+        let mut hosts = net.hosts();
+        let range = hosts.next().map(|first| AddrBlock::Range(first, hosts.next_back().unwrap_or(first)));
+        return Ok(Some(range.into_iter().collect()));
+    }
+    if let Ok(ip) = s.parse::<IpAddr>() {
+        return Ok(Some(vec![AddrBlock::Range(ip, ip)]));
+    }
+    if s.contains(':') {
+        return parse_ip_range(s).map(|(start, end)| Some(vec![AddrBlock::Range(start, end)]));
+    }
+    // Anything else made of digits, dots, dashes and wildcards is a malformed address, not a hostname
+    if !s.chars().all(|c| c.is_ascii_digit() || matches!(c, '.' | '-' | '*')) {
+        return Ok(None);
+    }
+    if s.split('.').count() == 4 {
+        return parse_octet_pattern(s).map(|block| Some(vec![block]));
+    }
+    if s.contains('-') {
+        return parse_ip_range(s).map(|(start, end)| Some(vec![AddrBlock::Range(start, end)]));
+    }
+    anyhow::bail!("invalid target '{s}': expected an IP address, CIDR, range or octet pattern")
+}
+
+/// Parse a CIDR "address/prefix", reporting which of the two parts is wrong.
+fn parse_cidr(s: &str, addr: &str, prefix: &str) -> Result<IpNet> {
+    let Ok(ip) = addr.parse::<IpAddr>() else {
+        anyhow::bail!("invalid CIDR '{s}': bad network address '{addr}'");
+    };
+    let max_prefix = if ip.is_ipv4() { 32 } else { 128 };
+    let Ok(prefix_len) = prefix.parse::<u8>() else {
+        anyhow::bail!("invalid CIDR '{s}': prefix length '{prefix}' is not a number between 0 and {max_prefix}");
+    };
+    IpNet::new(ip, prefix_len).map_err(|_| anyhow::anyhow!("invalid CIDR '{s}': prefix length {prefix_len} exceeds {max_prefix}"))
+}
+
+/// Parse a full IP range: "192.168.1.10-192.168.1.20" or "2001:db8::1-2001:db8::ff"
+fn parse_ip_range(s: &str) -> Result<(IpAddr, IpAddr)> {
+    let Some((a, b)) = s.split_once('-') else {
+        anyhow::bail!("invalid target '{s}': expected an IP address, CIDR or range");
+    };
+    let Ok(start) = a.trim().parse::<IpAddr>() else {
+        anyhow::bail!("invalid IP range '{s}': bad start address '{a}'");
+    };
+    let Ok(end) = b.trim().parse::<IpAddr>() else {
+        anyhow::bail!("invalid IP range '{s}': bad end address '{b}'");
+    };
+    if start.is_ipv4() != end.is_ipv4() {
+        anyhow::bail!("invalid IP range '{s}': start and end are different address families");
+    }
+    if start > end {
+        anyhow::bail!("invalid IP range '{s}': start is greater than end");
+    }
+    Ok((start, end))
+}
+
+/// Parse one octet of an IPv4 octet pattern: "10", "1-254" or "*".
+fn parse_octet_range(s: &str) -> Result<(u8, u8)> {
+    let parse_octet = |o: &str| o.parse::<u8>().with_context(|| format!("octet '{o}' is not a number between 0 and 255"));
+    if s == "*" {
+        return Ok((0, 255));
+    }
+    let (lo, hi) = match s.split_once('-') {
+        Some((lo, hi)) => (parse_octet(lo)?, parse_octet(hi)?),
+        None => {
+            let o = parse_octet(s)?;
+            (o, o)
+        }
+    };
+    if lo > hi {
+        anyhow::bail!("octet range {lo}-{hi} is reversed");
+    }
+    Ok((lo, hi))
+}
+
+/// Parse an IPv4 octet pattern where each octet is a number, a range or "*".
+/// Patterns that cover one contiguous block become a range; others stay a lazy pattern.
+fn parse_octet_pattern(s: &str) -> Result<AddrBlock> {
+    let mut octets = [(0u8, 0u8); 4];
+    for (i, part) in s.split('.').enumerate() {
+        octets[i] = parse_octet_range(part).map_err(|e| anyhow::anyhow!("invalid target '{s}': {e}"))?;
+    }
+    // Full trailing octets make each block contiguous, so only the leading octets multiply
+    let block = 3 - (1..4).rev().take_while(|i| octets[*i] == (0, 255)).count();
+    if octets[..block].iter().all(|(lo, hi)| lo == hi) {
+        let start = Ipv4Addr::from(octets.map(|(lo, _)| lo));
+        let end = Ipv4Addr::from(octets.map(|(_, hi)| hi));
+        return Ok(AddrBlock::Range(IpAddr::V4(start), IpAddr::V4(end)));
+    }
+    Ok(AddrBlock::Pattern(OctetPattern::new(octets)))
+}
+
+/// Hosts excluded from scanning (IPs, CIDRs, ranges, octet patterns and hostnames)
+#[derive(Debug, Default)]
+pub struct TargetExclusion {
+    /// Address blocks; networks include their network and broadcast addresses
+    blocks: Vec<AddrBlock>,
+}
+
+impl TargetExclusion {
+    /// Build the exclusion list from `--exclude` specs and `--exclude-file` files.
+    pub async fn parse(specs: &[String], files: &[PathBuf]) -> Result<Self> {
+        let mut entries: Vec<String> = specs.iter().map(|s| s.trim().to_string()).collect();
+        for path in files {
+            let text = fs::read_to_string(path)
+                .with_context(|| format!("read exclude file {}", path.display()))?;
+            for line in text.lines() {
+                let s = line.trim();
+                if s.is_empty() || s.starts_with('#') { continue; } // Skip empty lines/comments
+                entries.push(s.to_string());
+            }
+        }
+
+        let mut exclusion = TargetExclusion::default();
+        for entry in entries.iter().filter(|s| !s.is_empty()) {
+            if let Ok(net) = entry.parse::<IpNet>() {
+                exclusion.blocks.push(AddrBlock::Range(net.network(), net.broadcast()));
+            } else if let Some(blocks) = parse_address_spec(entry)? {
+                exclusion.blocks.extend(blocks);
+            } else {
+                let resolver = crate::dns::resolver::get_resolver()?;
+                let ips = resolver.lookup_ip(entry.as_str()).await.with_context(|| format!("resolve {entry}"))?;
+                exclusion.blocks.extend(ips.iter().map(|ip| AddrBlock::Range(ip, ip)));
+            }
+        }
+        Ok(exclusion)
+    }
+
+    /// Get the excluded address blocks.
+    pub fn blocks(&self) -> &[AddrBlock] {
+        &self.blocks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: &str, end: &str) -> AddrBlock {
+        AddrBlock::Range(start.parse().unwrap(), end.parse().unwrap())
+    }
+
+    fn spec(s: &str) -> Vec<AddrBlock> {
+        parse_address_spec(s).unwrap().unwrap()
+    }
+
+    #[test]
+    fn single_ip_and_cidr() {
+        assert_eq!(spec("192.168.1.1"), vec![range("192.168.1.1", "192.168.1.1")]);
+        assert_eq!(spec("2001:db8::1"), vec![range("2001:db8::1", "2001:db8::1")]);
+        assert_eq!(spec("192.168.1.0/24"), vec![range("192.168.1.1", "192.168.1.254")]);
+    }
+
+    #[test]
+    fn ip_ranges() {
+        assert_eq!(spec("192.168.1.10-192.168.2.20"), vec![range("192.168.1.10", "192.168.2.20")]);
+        assert_eq!(spec("192.168.1.10-50"), vec![range("192.168.1.10", "192.168.1.50")]);
+        assert_eq!(spec("2001:db8::1-2001:db8::ff"), vec![range("2001:db8::1", "2001:db8::ff")]);
+    }
+
+    #[test]
+    fn octet_patterns() {
+        assert_eq!(spec("10.0.0-3.1-254"), vec![AddrBlock::Pattern(OctetPattern::new([(10, 10), (0, 0), (0, 3), (1, 254)]))]);
+        assert_eq!(spec("10.1.*.1"), vec![AddrBlock::Pattern(OctetPattern::new([(10, 10), (1, 1), (0, 255), (1, 1)]))]);
+        // Patterns are not expanded, however many blocks they cover
+        let AddrBlock::Pattern(all) = spec("*.*.*.1")[0] else {
+            panic!("expected an octet pattern");
+        };
+        assert_eq!(all.len(), 1 << 24);
+        // Full trailing octets collapse into one contiguous range
+        assert_eq!(spec("10.2-3.*.*"), vec![range("10.2.0.0", "10.3.255.255")]);
+        assert_eq!(spec("10.2.3.*"), vec![range("10.2.3.0", "10.2.3.255")]);
     }
 
-    // Sort by IP & remove duplicates
-    out.sort_by_key(|e| e.ip);
-    out.dedup_by_key(|e| e.ip);
-    Ok(out)
+    #[test]
+    fn hostnames_are_not_addresses() {
+        assert!(parse_address_spec("example.com").unwrap().is_none());
+        assert!(parse_address_spec("host-1.local").unwrap().is_none());
+    }
+
+    #[test]
+    fn bad_specs() {
+        let err = |s: &str| parse_address_spec(s).unwrap_err().to_string();
+        assert!(err("10.0.0.300").contains("octet '300' is not a number"));
+        assert!(err("10.0.5-3.1").contains("octet range 5-3 is reversed"));
+        assert!(err("10.0.0.1-300").contains("octet '300' is not a number"));
+        assert!(err("10.0.0.9-10.0.0.1").contains("start is greater than end"));
+        assert!(err("10.0.0.1-10.0.0").contains("bad end address"));
+        assert!(err("2001:db8::1-10.0.0.1").contains("different address families"));
+        assert!(err("2001:db8::ff-2001:db8::1").contains("start is greater than end"));
+        assert!(err("10.*.1").contains("invalid target"));
+        assert!(err("10.0.0.0/33").contains("prefix length 33 exceeds 32"));
+        assert!(err("2001:db8::/129").contains("prefix length 129 exceeds 128"));
+        assert!(err("10.0.0.0/abc").contains("prefix length 'abc' is not a number"));
+        assert!(err("10.0.0/24").contains("bad network address '10.0.0'"));
+    }
 }
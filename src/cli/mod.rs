@@ -2,11 +2,12 @@ pub mod port;
 pub mod host;
 pub mod ping;
 
+use std::net::IpAddr;
 use std::path::PathBuf;
 
 use clap::{command, value_parser, ArgAction, Args, Parser, Subcommand, ValueEnum};
 
-use crate::{config::default::{DEFAULT_BASE_TARGET_UDP_PORT, DEFAULT_PORTS_CONCURRENCY}, endpoint::TransportProtocol, protocol::Protocol};
+use crate::{config::{default::DEFAULT_BASE_TARGET_UDP_PORT, timing::TimingTemplate}, endpoint::TransportProtocol, protocol::Protocol};
 
 /// nrev - Fast Network Mapper
 #[derive(Parser, Debug)]
@@ -67,7 +68,7 @@ impl LogLevel {
 /// Subcommands
 #[derive(Subcommand, Debug)]
 pub enum Command {
-    /// Scan ports on the target(s) (TCP/QUIC)
+    /// Scan ports on the target(s) (TCP/UDP/QUIC)
     Port(PortScanArgs),
 
     /// Discover alive hosts (ICMP/UDP/TCP etc.)
@@ -91,17 +92,40 @@ pub enum Command {
 
 /// Port scan methods. Default: Connect
 #[derive(Copy, Clone, Debug, ValueEnum, Eq, PartialEq)]
-pub enum PortScanMethod { Connect, Syn }
+pub enum PortScanMethod { Connect, Syn, Ack, Window, Fin, Null, Xmas }
+
+impl PortScanMethod {
+    /// Convert to &str
+    pub fn as_str(&self) -> &str {
+        match self {
+            PortScanMethod::Connect => "connect",
+            PortScanMethod::Syn => "syn",
+            PortScanMethod::Ack => "ack",
+            PortScanMethod::Window => "window",
+            PortScanMethod::Fin => "fin",
+            PortScanMethod::Null => "null",
+            PortScanMethod::Xmas => "xmas",
+        }
+    }
+}
 
 /// Host scan protocols. Default: ICMP
 #[derive(Copy, Clone, Debug, ValueEnum, Eq, PartialEq)]
-pub enum HostScanProto { Icmp, Udp, Tcp }
+pub enum HostScanProto {
+    Icmp,
+    Udp,
+    Tcp,
+    Arp,
+    Ndp,
+    /// ICMP Echo, ICMP Timestamp, TCP SYN, TCP ACK and UDP at once
+    Combined,
+}
 
 impl HostScanProto {
     /// Convert to TransportProtocol (if applicable)
     pub fn to_transport(&self) -> Option<TransportProtocol> {
         match self {
-            HostScanProto::Icmp => None,
+            HostScanProto::Icmp | HostScanProto::Arp | HostScanProto::Ndp | HostScanProto::Combined => None,
             HostScanProto::Udp => Some(TransportProtocol::Udp),
             HostScanProto::Tcp => Some(TransportProtocol::Tcp),
         }
@@ -112,6 +136,9 @@ impl HostScanProto {
             HostScanProto::Icmp => "icmp",
             HostScanProto::Udp => "udp",
             HostScanProto::Tcp => "tcp",
+            HostScanProto::Arp => "arp",
+            HostScanProto::Ndp => "ndp",
+            HostScanProto::Combined => "combined",
         }
     }
 }
@@ -138,7 +165,7 @@ impl TraceProto {
 /// Port scan arguments
 #[derive(Args, Debug)]
 pub struct PortScanArgs {
-    /// Target IP or hostname
+    /// Targets: IP, CIDR, range (10.0.0.1-50, 10.0.0-3.1-254, 10.1.*.1), hostname or @file
     #[arg(required = true)]
     pub target: Vec<String>,
 
@@ -146,8 +173,20 @@ pub struct PortScanArgs {
     #[arg(short, long, default_value = "top-1000")]
     pub ports: String,
 
-    /// Transport to scan (now tcp only; udp/quic later)
-    #[arg(long, default_value = "tcp", value_parser = ["tcp","udp","quic"])]
+    /// Exclude targets (IP, CIDR, range, octet pattern or hostname; comma separated)
+    #[arg(long, value_delimiter = ',')]
+    pub exclude: Vec<String>,
+
+    /// Exclude targets listed in a file (one per line)
+    #[arg(long, value_name = "FILE", value_parser = value_parser!(PathBuf))]
+    pub exclude_file: Option<PathBuf>,
+
+    /// Exclude ports: "22,80" | "1-1024"
+    #[arg(long)]
+    pub exclude_ports: Option<String>,
+
+    /// Transport to scan (tcp, udp, quic or sctp)
+    #[arg(long, default_value = "tcp", value_parser = ["tcp","udp","quic","sctp"])]
     pub proto: String,
 
     /// Scanning method (default: connect)
@@ -175,15 +214,35 @@ pub struct PortScanArgs {
     #[arg(long)]
     pub interface: Option<String>,
 
-    /// Concurrency (tasks)
-    #[arg(long, default_value_t = DEFAULT_PORTS_CONCURRENCY)]
-    pub concurrency: usize,
+    /// Source IP address of probes. Must be an address of the interface
+    #[arg(long, value_name = "IP")]
+    pub source_ip: Option<IpAddr>,
+
+    /// Source port of raw TCP/UDP/SCTP probes and TCP connect scan sockets.
+    /// QUIC scans and service detection keep ephemeral ports
+    #[arg(long, value_name = "PORT")]
+    pub source_port: Option<u16>,
+
+    /// Tunnel connect scans and service probes through a proxy:
+    /// socks5://[user:pass@]host[:port] or http://[user:pass@]host[:port]
+    #[arg(long, value_name = "URL")]
+    pub proxy: Option<String>,
+
+    /// Timing template: 0-5 or paranoid|sneaky|polite|normal|aggressive|insane.
+    /// Explicit timing flags override the template
+    #[arg(short='T', long="timing", default_value = "normal")]
+    pub timing: TimingTemplate,
+
+    /// Concurrency: connections in flight per host, for this many hosts at once and
+    /// in total (connect scans and service detection). Default depends on the timing template
+    #[arg(long)]
+    pub concurrency: Option<usize>,
 
     /// Base connect timeout in ms (auto-adapted by RTT)
     #[arg(long, value_parser = value_parser!(u64).range(1..=10_000))]
     pub connect_timeout_ms: Option<u64>,
 
-    /// Read timeout in ms (auto-adapted by RTT)
+    /// Read timeout for service detection in ms
     #[arg(long, value_parser = value_parser!(u64).range(1..=10_000))]
     pub read_timeout_ms: Option<u64>,
 
@@ -192,48 +251,115 @@ pub struct PortScanArgs {
     pub wait_ms: Option<u64>,
 
     /// Task timeout in ms
-    #[arg(long, default_value_t = 30000, value_parser = value_parser!(u64).range(1..=60_000))]
-    pub task_timeout_ms: u64,
+    #[arg(long, value_parser = value_parser!(u64).range(1..=60_000))]
+    pub task_timeout_ms: Option<u64>,
+
+    /// Minimum send rate for raw scans (packets/sec). Adaptive backoff never goes below it
+    #[arg(long, value_parser = value_parser!(u32).range(1..))]
+    pub min_rate: Option<u32>,
+
+    /// Maximum send rate for raw scans (packets/sec)
+    #[arg(long, value_parser = value_parser!(u32).range(1..))]
+    pub max_rate: Option<u32>,
 
-    /// Scan ports in user-specified order (default is randomized)
+    /// Retransmissions for unanswered probes (raw TCP and SCTP scans)
+    #[arg(long, value_parser = value_parser!(u8).range(0..=10))]
+    pub retries: Option<u8>,
+
+    /// Receive threads for raw scans. More than one shares replies through PACKET_FANOUT (Linux only)
+    #[arg(long, value_parser = value_parser!(u8).range(1..=64))]
+    pub rx_threads: Option<u8>,
+
+    /// Sender threads for raw scans, each with its own socket
+    #[arg(long, value_parser = value_parser!(u8).range(1..=64))]
+    pub tx_threads: Option<u8>,
+
+    /// Datalink read buffer size for raw scans (bytes)
+    #[arg(long, value_parser = value_parser!(u32).range(2048..=16_777_216))]
+    pub read_buffer_size: Option<u32>,
+
+    /// Datalink write buffer size for raw scans (bytes)
+    #[arg(long, value_parser = value_parser!(u32).range(2048..=16_777_216))]
+    pub write_buffer_size: Option<u32>,
+
+    /// Scan hosts and ports in user-specified order (default is randomized)
     #[arg(long, action=ArgAction::SetTrue)]
     pub ordered: bool,
 
     /// Skip initial ping
     #[arg(long, action=ArgAction::SetTrue)]
     pub no_ping: bool,
+
+    /// Discover live hosts first (icmp, udp, tcp, arp, ndp or combined; default: icmp) and port scan only those.
+    /// Connect and QUIC timeouts are adapted to the RTT of each host; raw scans wait for the slowest one
+    #[arg(long, value_enum, value_name = "PROTO", num_args = 0..=1, default_missing_value = "icmp")]
+    pub discover: Option<HostScanProto>,
 }
 
 /// Host scan arguments
 #[derive(Args, Debug)]
 pub struct HostScanArgs {
-    /// Targets (CIDR, range, or list).
+    /// Targets: IP, CIDR, range (10.0.0.1-50, 10.0.0-3.1-254, 10.1.*.1), hostname or @file
     #[arg(required = true)]
     pub target: Vec<String>,
 
-    /// Protocol to use (default: ICMP)
+    /// Protocol to use (default: ICMP). ARP (IPv4) and NDP (IPv6) sweep the local segment
     #[arg(long, value_enum, default_value_t = HostScanProto::Icmp)]
     pub proto: HostScanProto,
 
-    /// Port spec: "80" | "80,443" | "22-25"
-    #[arg(short, long, default_value = "80")]
-    pub ports: String,
+    /// Port spec for TCP SYN probes: "80" | "80,443" | "22-25".
+    /// Default: 80, or 22,80,443 with combined
+    #[arg(short, long)]
+    pub ports: Option<String>,
+
+    /// Exclude targets (IP, CIDR, range, octet pattern or hostname; comma separated)
+    #[arg(long, value_delimiter = ',')]
+    pub exclude: Vec<String>,
+
+    /// Exclude targets listed in a file (one per line)
+    #[arg(long, value_name = "FILE", value_parser = value_parser!(PathBuf))]
+    pub exclude_file: Option<PathBuf>,
+
+    /// Exclude TCP probe ports: "22,80" | "1-1024" (tcp and combined only)
+    #[arg(long)]
+    pub exclude_ports: Option<String>,
 
     /// Wait time after last send (ms)
-    #[arg(short='w', long, default_value_t = 300, value_parser = value_parser!(u64).range(10..=5000))]
-    pub wait_ms: u64,
+    #[arg(short='w', long, value_parser = value_parser!(u64).range(10..=5000))]
+    pub wait_ms: Option<u64>,
 
     /// Timeout per probe (ms)
-    #[arg(long, default_value_t = 600, value_parser = value_parser!(u64).range(50..=5000))]
-    pub timeout_ms: u64,
+    #[arg(long, value_parser = value_parser!(u64).range(50..=5000))]
+    pub timeout_ms: Option<u64>,
 
     /// Network interface name to bind
     #[arg(long)]
     pub interface: Option<String>,
 
-    /// Concurrency (in-flight probes)
-    #[arg(long, default_value_t = 512)]
-    pub concurrency: usize,
+    /// Source IP address of probes. Must be an address of the interface
+    #[arg(long, value_name = "IP")]
+    pub source_ip: Option<IpAddr>,
+
+    /// Source port of TCP and UDP probes (tcp, udp and combined; ICMP, ARP and NDP have no ports)
+    #[arg(long, value_name = "PORT")]
+    pub source_port: Option<u16>,
+
+    /// Timing template: 0-5 or paranoid|sneaky|polite|normal|aggressive|insane.
+    /// Explicit timing flags override the template
+    #[arg(short='T', long="timing", default_value = "normal")]
+    pub timing: TimingTemplate,
+
+    /// Concurrency (in-flight probes). Default depends on the timing template
+    #[arg(long)]
+    pub concurrency: Option<usize>,
+
+    /// Minimum send rate (packets/sec). Adaptive backoff never goes below it
+    #[arg(long, value_parser = value_parser!(u32).range(1..))]
+    pub min_rate: Option<u32>,
+
+    /// Maximum send rate (packets/sec)
+    #[arg(long, value_parser = value_parser!(u32).range(1..))]
+    pub max_rate: Option<u32>,
 
     /// Scan hosts in user-specified order (default is randomized)
     #[arg(long, action=ArgAction::SetTrue)]
@@ -260,16 +386,29 @@ pub struct PingArgs {
     pub count: u32,
 
     /// Interval between probes (ms)
-    #[arg(short, long, default_value_t = 1000)]
-    pub interval_ms: u64,
+    #[arg(short, long)]
+    pub interval_ms: Option<u64>,
 
     /// Per-probe timeout (ms)
-    #[arg(long, default_value_t = 1000)]
-    pub timeout_ms: u64,
+    #[arg(long)]
+    pub timeout_ms: Option<u64>,
+
+    /// Timing template: 0-5 or paranoid|sneaky|polite|normal|aggressive|insane.
+    /// Explicit timing flags override the template
+    #[arg(short='T', long="timing", default_value = "normal")]
+    pub timing: TimingTemplate,
 
     /// Network interface name to bind
     #[arg(long)]
     pub interface: Option<String>,
+
+    /// Source IP address of probes. Must be an address of the interface
+    #[arg(long, value_name = "IP")]
+    pub source_ip: Option<IpAddr>,
+
+    /// Source port of TCP and UDP probes (ICMP ping has no ports)
+    #[arg(long, value_name = "PORT")]
+    pub source_port: Option<u16>,
 }
 
 
@@ -293,16 +432,29 @@ pub struct TraceArgs {
     pub max_hops: u8,
 
     /// Interval between probes (ms)
-    #[arg(short, long, default_value_t = 1000)]
-    pub interval_ms: u64,
+    #[arg(short, long)]
+    pub interval_ms: Option<u64>,
 
     /// Per-hop timeout (ms)
-    #[arg(long, default_value_t = 1000)]
-    pub timeout_ms: u64,
+    #[arg(long)]
+    pub timeout_ms: Option<u64>,
+
+    /// Timing template: 0-5 or paranoid|sneaky|polite|normal|aggressive|insane.
+    /// Explicit timing flags override the template
+    #[arg(short='T', long="timing", default_value = "normal")]
+    pub timing: TimingTemplate,
 
     /// Network interface name to bind
     #[arg(long)]
     pub interface: Option<String>,
+
+    /// Source IP address of probes. Must be an address of the interface
+    #[arg(long, value_name = "IP")]
+    pub source_ip: Option<IpAddr>,
+
+    /// Source port of the UDP probes
+    #[arg(long, value_name = "PORT")]
+    pub source_port: Option<u16>,
 }
 
 /// Neighbor discovery arguments
@@ -2,9 +2,12 @@ use anyhow::{Result, bail};
 use std::collections::BTreeSet;
 use crate::endpoint::{Port, TransportProtocol};
 
-/// Get top N ports from the default port list
-fn top_ports(n: usize) -> Vec<u16> {
-    let top_ports: Vec<u16> = crate::db::port::get_default_ports();
+/// Get top N ports from the default port list, or from the SCTP service list for SCTP
+fn top_ports(n: usize, tr: TransportProtocol) -> Vec<u16> {
+    let top_ports: Vec<u16> = match tr {
+        TransportProtocol::Sctp => crate::db::port::get_sctp_services().into_iter().map(|service| service.port).collect(),
+        _ => crate::db::port::get_default_ports(),
+    };
     top_ports.into_iter().take(n).collect()
 }
 
@@ -14,7 +17,7 @@ pub fn parse_ports(spec: &str, tr: TransportProtocol) -> Result<Vec<Port>> {
 
     if let Some(nstr) = spec.strip_prefix("top-") {
         let n: usize = nstr.parse()?;
-        for p in top_ports(n) { set.insert(Port::new(p, tr)); }
+        for p in top_ports(n, tr) { set.insert(Port::new(p, tr)); }
     } else {
         for part in spec.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
             if let Some((a,b)) = part.split_once('-') {
@@ -30,3 +33,11 @@ pub fn parse_ports(spec: &str, tr: TransportProtocol) -> Result<Vec<Port>> {
     }
     Ok(set.into_iter().collect())
 }
+
+/// Remove ports matching the exclusion spec and return how many were removed.
+pub fn exclude_ports(ports: &mut Vec<Port>, spec: &str, tr: TransportProtocol) -> Result<usize> {
+    let excluded: BTreeSet<u16> = parse_ports(spec, tr)?.into_iter().map(|p| p.number).collect();
+    let before = ports.len();
+    ports.retain(|p| !excluded.contains(&p.number));
+    Ok(before - ports.len())
+}
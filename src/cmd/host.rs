@@ -1,77 +1,116 @@
 use std::{path::PathBuf, time::Duration};
-use rand::seq::SliceRandom;
-use rand::thread_rng;
 use anyhow::Result;
-use crate::{cli::{HostScanArgs, HostScanProto}, endpoint::{Endpoint, Host, Port, TransportProtocol}, output::ScanResult, scan::HostScanner, util::json::{save_json_output, JsonStyle}};
-use crate::probe::ProbeSetting;
+use crate::{cli::{host::TargetExclusion, HostScanArgs, HostScanProto}, endpoint::{Port, TransportProtocol}, output::{host::HostScanReport, port::ReportMeta, ScanResult}, scan::HostScanner, util::json::{save_json_output, JsonStyle}};
+use crate::config::default::DEFAULT_DISCOVERY_TCP_PORTS;
+use crate::probe::{ProbeSetting, ProbeSource};
+use crate::scan::engine::IoConfig;
+use crate::scan::scheduler::{ConcurrencyLimits, Scheduler};
+use crate::scan::target::ScanTargets;
 
 /// Run host scan
 pub async fn run(args: HostScanArgs, no_stdout: bool, output: Option<PathBuf>) -> Result<()> {
-    let mut target_hosts: Vec<Host> = crate::cli::host::parse_target_hosts(&args.target).await?;
-    if target_hosts.is_empty() { anyhow::bail!("no targets resolved"); }
-
+    if matches!((args.min_rate, args.max_rate), (Some(min), Some(max)) if min > max) {
+        anyhow::bail!("--min-rate must not be greater than --max-rate");
+    }
+    let timing = args.timing.profile();
+    let mut meta = ReportMeta { timing: Some(args.timing), ..Default::default() };
+    tracing::info!("Timing template: T{} ({})", args.timing.level(), args.timing.as_str());
+    let mut target_set = crate::cli::host::parse_target_set(&args.target).await?;
+    if target_set.is_empty() { anyhow::bail!("no targets resolved"); }
+    // Apply exclusions
+    let exclusion = TargetExclusion::parse(&args.exclude, args.exclude_file.as_slice()).await?;
+    let excluded_hosts = target_set.exclude(exclusion.blocks());
+    meta.excluded_hosts = usize::try_from(excluded_hosts).unwrap_or(usize::MAX);
+    if excluded_hosts > 0 {
+        tracing::info!("Excluded {} host(s)", excluded_hosts);
+    }
+    if target_set.is_empty() { anyhow::bail!("no targets left after exclusion"); }
     let mut ports: Vec<Port> = Vec::new();
     match args.proto {
-        HostScanProto::Tcp => {
-            ports = crate::cli::port::parse_ports(&args.ports, TransportProtocol::Tcp)?;
+        HostScanProto::Tcp | HostScanProto::Combined => {
+            ports = match &args.ports {
+                Some(spec) => crate::cli::port::parse_ports(spec, TransportProtocol::Tcp)?,
+                None if args.proto == HostScanProto::Combined => DEFAULT_DISCOVERY_TCP_PORTS.iter().map(|p| Port::new(*p, TransportProtocol::Tcp)).collect(),
+                None => vec![Port::new(80, TransportProtocol::Tcp)],
+            };
+            if let Some(spec) = &args.exclude_ports {
+                meta.excluded_ports = crate::cli::port::exclude_ports(&mut ports, spec, TransportProtocol::Tcp)?;
+                tracing::info!("Excluded {} port(s)", meta.excluded_ports);
+            }
+            if ports.is_empty() { anyhow::bail!("no ports left after exclusion"); }
         }
-        _ => {}
-    }
-
-    if !args.ordered {
-        // Randomize the order of targets and ports
-        target_hosts.shuffle(&mut thread_rng());
-        ports.shuffle(&mut thread_rng());
-    }
-
-    let mut target_endpoints: Vec<Endpoint> = Vec::new();
-
-    for host in target_hosts {
-        let mut endpoint = Endpoint::new(host.ip);
-        endpoint.hostname = host.hostname;
-        for port in &ports {
-            endpoint.upsert_port(port.clone());
+        _ => {
+            // Only TCP probes have target ports to exclude, and only TCP and UDP probes a source port
+            if args.exclude_ports.is_some() {
+                anyhow::bail!("--exclude-ports requires --proto tcp or combined (got {})", args.proto.as_str());
+            }
+            if args.source_port.is_some() && args.proto != HostScanProto::Udp {
+                anyhow::bail!("--source-port requires --proto tcp, udp or combined (got {})", args.proto.as_str());
+            }
         }
-        target_endpoints.push(endpoint);
     }
 
+    // Hosts are expanded lazily; the host and port order is randomized unless --ordered
+    let port_numbers: Vec<u16> = ports.iter().map(|port| port.number).collect();
+    let Some(first_ip) = target_set.hosts().next().map(|host| host.ip) else {
+        anyhow::bail!("no targets left after exclusion");
+    };
+    let scan_targets = ScanTargets::new(target_set, port_numbers, !args.ordered)?;
+
     let interface: netdev::Interface = if let Some(if_name) = args.interface {
         match crate::interface::get_interface_by_name(if_name.to_string()) {
             Some(iface) => iface,
             None => anyhow::bail!("interface not found"),
         }
     } else {
-        match netdev::get_default_interface() {
+        match crate::interface::get_default_interface_for(first_ip) {
             Ok(iface) => iface,
             Err(_) => anyhow::bail!("failed to get default interface"),
         }
     };
 
+    let concurrency = args.concurrency.unwrap_or(timing.host_scan_concurrency);
     let probe_setting = ProbeSetting {
         if_index: interface.index,
-        target_endpoints: target_endpoints,
-        host_concurrency: args.concurrency,
-        port_concurrency: args.concurrency,
-        task_timeout: Duration::from_secs(30),
-        connect_timeout: Duration::from_millis(args.timeout_ms),
-        wait_time: Duration::from_millis(args.wait_ms),
-        send_rate: Duration::from_millis(1),
+        target_endpoints: Vec::new(),
+        scan_targets: Some(scan_targets),
+        host_concurrency: concurrency,
+        port_concurrency: concurrency,
+        scheduler: Scheduler::new(match crate::util::rlimit::max_open_sockets() {
+            Some(max_open) => ConcurrencyLimits::new(concurrency, concurrency, concurrency).capped(max_open),
+            None => ConcurrencyLimits::new(concurrency, concurrency, concurrency),
+        }),
+        neighbors: Default::default(),
+        source: ProbeSource::new(&interface, args.source_ip, args.source_port)?,
+        proxy: None,
+        task_timeout: timing.task_timeout,
+        connect_timeout: args.timeout_ms.map(Duration::from_millis).unwrap_or(timing.host_scan_timeout),
+        host_timeouts: Default::default(),
+        wait_time: args.wait_ms.map(Duration::from_millis).unwrap_or(timing.host_wait_time),
+        min_rate: args.min_rate,
+        max_rate: timing.resolve_max_rate(args.min_rate, args.max_rate),
+        retries: 0,
+        io: IoConfig::default(),
     };
 
-    let host_scanner = HostScanner::new(probe_setting.clone(), args.proto);
-    if !probe_setting.target_endpoints.is_empty() {
-        tracing::info!("Starting {} host scan. Target: {} host(s), {} port(s)", args.proto.as_str().to_uppercase(), probe_setting.target_endpoints.len(), probe_setting.target_endpoints[0].ports.len());
+    let mut host_scanner = HostScanner::new(probe_setting.clone(), args.proto);
+    meta.downgrades.extend(host_scanner.fall_back_if_unprivileged()?);
+    if let Some(targets) = &probe_setting.scan_targets {
+        tracing::info!("Starting {} host scan. Target: {} host(s), {} port(s)", args.proto.as_str().to_uppercase(), targets.hosts().len(), targets.ports().len());
     }
     let mut hostscan_result: ScanResult = host_scanner.run().await?;
     hostscan_result.sort_endpoints();
     tracing::info!("{} Host scan completed in {:?}", args.proto.as_str().to_uppercase(), hostscan_result.scan_time);
 
+    meta.finished_at = Some(std::time::SystemTime::now());
+    let report = HostScanReport::new(meta, hostscan_result);
+
     // Print result as a tree
     if !no_stdout {
-        crate::output::host::print_report_tree(&hostscan_result);
+        crate::output::host::print_report_tree(&report);
     }
     if let Some(path) = &output {
-        match save_json_output(&hostscan_result, path, JsonStyle::Pretty) {
+        match save_json_output(&report, path, JsonStyle::Pretty) {
             Ok(_) => {
                 if !no_stdout {
                     tracing::info!("JSON output saved to {}", path.display());
@@ -5,6 +5,7 @@ use anyhow::Result;
 
 /// Run neighbor discovery (ARP for IPv4, NDP for IPv6)
 pub async fn run(args: NeighborArgs, no_stdout: bool, output: Option<PathBuf>) -> Result<()> {
+    crate::util::privilege::require_raw("Neighbor discovery")?;
     let interface: netdev::Interface = if let Some(if_name) = args.interface {
         match crate::interface::get_interface_by_name(if_name.to_string()) {
             Some(iface) => iface,
@@ -1,22 +1,28 @@
 use std::{path::PathBuf, time::Duration};
 
 use crate::{cli::PingArgs, endpoint::Host, ping::{pinger::Pinger, setting::PingSetting}, protocol::Protocol, util::json::{save_json_output, JsonStyle}};
+use crate::probe::ProbeSource;
+use crate::util::privilege::{raw_required, socket_access, Downgrade};
 use anyhow::Result;
 
 /// Run ping command
 pub async fn run(args: PingArgs, no_stdout: bool, output: Option<PathBuf>) -> Result<()> {
+    if args.source_port.is_some() && args.proto == Protocol::Icmp {
+        anyhow::bail!("--source-port requires --proto tcp or udp (got {})", args.proto.as_str());
+    }
+    let dst_host: Host = crate::cli::ping::parse_target_host(&args.target).await?;
     let interface: netdev::Interface = if let Some(if_name) = args.interface {
         match crate::interface::get_interface_by_name(if_name.to_string()) {
             Some(iface) => iface,
             None => anyhow::bail!("interface not found"),
         }
     } else {
-        match netdev::get_default_interface() {
+        match crate::interface::get_default_interface_for(dst_host.ip) {
             Ok(iface) => iface,
             Err(_) => anyhow::bail!("failed to get default interface"),
         }
     };
-    let dst_host: Host = crate::cli::ping::parse_target_host(&args.target).await?;
+    let source = ProbeSource::new(&interface, args.source_ip, args.source_port)?;
     let mut ping_setting: PingSetting = match args.proto {
         Protocol::Icmp => PingSetting::icmp_ping(&interface, dst_host, args.count)?,
         Protocol::Tcp => PingSetting::tcp_ping(&interface, dst_host, args.port, args.count)?,
@@ -25,12 +31,18 @@ pub async fn run(args: PingArgs, no_stdout: bool, output: Option<PathBuf>) -> Re
             anyhow::bail!("Unsupported protocol");
         }
     };
-    ping_setting.send_rate = Duration::from_millis(args.interval_ms);
-    ping_setting.receive_timeout = Duration::from_millis(args.timeout_ms);
+    ping_setting.source = source;
+    let timing = args.timing.profile();
+    ping_setting.send_rate = args.interval_ms.map(Duration::from_millis).unwrap_or(timing.probe_interval);
+    ping_setting.receive_timeout = args.timeout_ms.map(Duration::from_millis).unwrap_or(timing.probe_timeout);
+
+    let downgrade = ping_fallback(args.proto)?;
+    ping_setting.unprivileged = downgrade.is_some();
 
     let pinger = Pinger::new(ping_setting);
     tracing::info!("Pinging {} with {}...", args.target, args.proto.as_str().to_uppercase());
-    let ping_result = pinger.run().await?;
+    let mut ping_result = pinger.run().await?;
+    ping_result.downgrade = downgrade;
     if !no_stdout {
         crate::output::ping::print_ping_tree(&ping_result);
     }
@@ -46,3 +58,17 @@ pub async fn run(args: PingArgs, no_stdout: bool, output: Option<PathBuf>) -> Re
     }
     Ok(())
 }
+
+/// Get the unprivileged fallback of the ping protocol if raw sockets are not available.
+fn ping_fallback(proto: Protocol) -> Result<Option<Downgrade>> {
+    let access = socket_access();
+    if access.raw {
+        return Ok(None);
+    }
+    match proto {
+        Protocol::Icmp if access.icmp_datagram => Ok(Some(Downgrade::new("raw icmp ping", "icmp datagram ping"))),
+        Protocol::Icmp => anyhow::bail!("ICMP ping requires raw sockets or ICMP datagram sockets (see net.ipv4.ping_group_range)"),
+        Protocol::Tcp => Ok(Some(Downgrade::new("raw tcp ping", "tcp-connect ping"))),
+        _ => Err(raw_required(&format!("{} ping", proto.as_str().to_uppercase()))),
+    }
+}
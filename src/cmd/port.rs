@@ -1,56 +1,109 @@
-use std::{path::PathBuf, time::Duration};
-use rand::seq::SliceRandom;
-use rand::thread_rng;
+use std::{collections::HashMap, net::IpAddr, path::PathBuf, sync::Arc, time::Duration};
 use anyhow::Result;
-use crate::{cli::PortScanArgs, endpoint::{Endpoint, Host, Port, PortState, TransportProtocol}, output::{port::{print_report_tree, ScanReport}, ScanResult}, probe::ProbeSetting, scan::PortScanner, service::{ServiceDetector, ServiceProbeConfig}, util::json::{save_json_output, JsonStyle}};
+use crate::nei::cache::NeighborCache;
+use crate::util::privilege::{raw_required, socket_access, Downgrade};
+use crate::{cli::{host::TargetExclusion, HostScanProto, PortScanArgs, PortScanMethod}, config::{default::DEFAULT_DISCOVERY_TCP_PORTS, timing::TimingProfile}, endpoint::{Host, Port, PortState, TransportProtocol}, output::{port::{print_report_tree, ReportMeta, ScanReport, MAX_SKIPPED_RANGES}, ScanResult}, probe::{ProbeSetting, ProbeSource}, proxy::Proxy, scan::{engine::IoConfig, scheduler::{ConcurrencyLimits, Scheduler}, target::{AddrBlock, ScanTargets, TargetSet}, HostScanner, PortScanner}, service::{ServiceDetector, ServiceProbeConfig}, util::json::{save_json_output, JsonStyle}};
 
 /// Run port scan
 pub async fn run(args: PortScanArgs, no_stdout: bool, output: Option<PathBuf>) -> Result<()> {
     let mut rep = ScanReport::new();
-    // Parse target hosts
-    let target_hosts: Vec<Host> = crate::cli::host::parse_target_hosts(&args.target).await?;
-    if target_hosts.is_empty() { anyhow::bail!("no targets resolved"); }
-    let first_host = target_hosts[0].clone();
+    rep.meta.timing = Some(args.timing);
+    let timing = args.timing.profile();
+    tracing::info!("Timing template: T{} ({})", args.timing.level(), args.timing.as_str());
+    if matches!((args.min_rate, args.max_rate), (Some(min), Some(max)) if min > max) {
+        anyhow::bail!("--min-rate must not be greater than --max-rate");
+    }
+    let scheduler = scheduler(&args, &timing);
+    // Next-hop MAC addresses resolved by host discovery are reused by the port scan
+    let neighbors = NeighborCache::default();
+    // Parse target hosts (networks are expanded lazily)
+    let mut target_set: TargetSet = crate::cli::host::parse_target_set(&args.target).await?;
+    if target_set.is_empty() { anyhow::bail!("no targets resolved"); }
+    // Apply exclusions
+    let exclusion = TargetExclusion::parse(&args.exclude, args.exclude_file.as_slice()).await?;
+    let excluded_hosts = target_set.exclude(exclusion.blocks());
+    rep.meta.excluded_hosts = usize::try_from(excluded_hosts).unwrap_or(usize::MAX);
+    if excluded_hosts > 0 {
+        tracing::info!("Excluded {} host(s)", excluded_hosts);
+    }
+    let Some(first_host) = target_set.hosts().next() else {
+        anyhow::bail!("no targets left after exclusion");
+    };
     // Parse transport protocol
     let transport: TransportProtocol = TransportProtocol::from_str(&args.proto).ok_or_else(|| anyhow::anyhow!("invalid transport"))?;
     // Parse ports
     let mut ports: Vec<Port> = crate::cli::port::parse_ports(&args.ports, transport)?;
-
-    if !args.ordered {
-        // Randomize the order of ports
-        ports.shuffle(&mut thread_rng());
+    if let Some(spec) = &args.exclude_ports {
+        rep.meta.excluded_ports = crate::cli::port::exclude_ports(&mut ports, spec, transport)?;
+        tracing::info!("Excluded {} port(s)", rep.meta.excluded_ports);
     }
-
-    // Create target endpoints from hosts and ports
-    let mut target_endpoints: Vec<Endpoint> = Vec::new();
-
-    for host in target_hosts {
-        let mut endpoint = Endpoint::new(host.ip);
-        endpoint.hostname = host.hostname;
-        for port in &ports {
-            endpoint.upsert_port(port.clone());
+    if ports.is_empty() { anyhow::bail!("no ports left after exclusion"); }
+    let method = scan_method(transport, args.method, &mut rep.meta)?;
+    if args.source_port.is_some() {
+        // Every QUIC connection needs a socket of its own
+        if transport == TransportProtocol::Quic {
+            anyhow::bail!("--source-port is not supported by QUIC scans");
+        }
+        if args.quic {
+            tracing::warn!("The QUIC scan ignores --source-port");
         }
-        target_endpoints.push(endpoint);
     }
+    let proxy = match &args.proxy {
+        Some(url) => {
+            check_proxy_support(&args, transport)?;
+            let proxy = Proxy::parse(url).await?;
+            tracing::info!("Connecting through proxy {}", proxy);
+            Some(Arc::new(proxy))
+        }
+        None => None,
+    };
 
     // Get network interface
-    let interface: netdev::Interface = if let Some(if_name) = args.interface {
+    let interface: netdev::Interface = if let Some(if_name) = &args.interface {
         match crate::interface::get_interface_by_name(if_name.to_string()) {
             Some(iface) => iface,
             None => anyhow::bail!("interface not found"),
         }
     } else {
-        match netdev::get_default_interface() {
+        match crate::interface::get_default_interface_for(first_host.ip) {
             Ok(iface) => iface,
             Err(_) => anyhow::bail!("failed to get default interface"),
         }
     };
+    let source = ProbeSource::new(&interface, args.source_ip, args.source_port)?;
 
-    // Initial ping to check reachability and measure latency
-    let initial_rtt = if args.no_ping {
+    // RTT-adapted timeouts: from host discovery per host, otherwise from an initial ping
+    let mut host_rtts: HashMap<IpAddr, Duration> = HashMap::new();
+    let initial_rtt = if let Some(proto) = args.discover {
+        let discovery = discover_hosts(&target_set, proto, &interface, &args, &timing, &scheduler, &neighbors, &mut rep.meta).await?;
+        tracing::info!("{} Host discovery completed in {:?}: {} of {} host(s) up", proto.as_str().to_uppercase(), discovery.scan_time, discovery.endpoints.len(), target_set.len());
+        // Skipped hosts are recorded as a count and the ranges left after removing the live ones
+        let live: Vec<AddrBlock> = discovery.endpoints.iter().map(|ep| AddrBlock::Range(ep.ip, ep.ip)).collect();
+        let mut skipped = target_set.clone();
+        skipped.exclude(&live);
+        rep.meta.skipped_hosts = usize::try_from(skipped.len()).unwrap_or(usize::MAX);
+        rep.meta.skipped_ranges = skipped.blocks().take(MAX_SKIPPED_RANGES).map(|block| block.to_string()).collect();
+        host_rtts = discovery
+            .endpoints
+            .iter()
+            .filter_map(|ep| ep.rtt_ms.map(|rtt| (ep.ip, Duration::from_millis(rtt as u64))))
+            .collect();
+        let live_hosts: Vec<Host> = discovery.endpoints.iter().map(|ep| Host { ip: ep.ip, hostname: ep.hostname.clone() }).collect();
+        target_set = TargetSet::new(Vec::new(), live_hosts);
+        rep.apply_host_discovery(discovery);
+        if target_set.is_empty() {
+            tracing::warn!("No live hosts found, skipping port scan");
+            rep.finish();
+            write_report(&rep, no_stdout, output.as_ref());
+            return Ok(());
+        }
+        // Hosts without a measured RTT fall back to the slowest one
+        host_rtts.values().max().copied().unwrap_or(Duration::from_millis(200))
+    } else if args.no_ping || proxy.is_some() {
+        // Targets behind the proxy are not pinged directly
         Duration::from_millis(200)
     } else {
-        match crate::ping::initial_ping(&interface, &first_host, Some(ports[0].number)).await {
+        match crate::ping::initial_ping(&interface, &first_host, Some(ports[0].number), source).await {
             Ok(rtt) => rtt,
             Err(e) => {
                 tracing::warn!("Initial ping failed: {}. Proceeding with default RTT.", e);
@@ -58,46 +111,67 @@ pub async fn run(args: PortScanArgs, no_stdout: bool, output: Option<PathBuf>) -
             }
         }
     };
-    
+
+    // (host, port) work items, generated on demand in random order unless --ordered
+    let scan_targets = ScanTargets::new(target_set, ports.iter().map(|p| p.number).collect(), !args.ordered)?;
+
     let conn_timeout = if let Some(ct) = args.connect_timeout_ms {
+        host_rtts.clear();
         Duration::from_millis(ct)
+    } else if proxy.is_some() {
+        // The RTT to targets behind the proxy is not known
+        timing.max_rtt_timeout
     } else {
         // adapt timeout based on RTT
-        let adapted = (initial_rtt.as_millis() as f64 * 1.5) as u64;
-        Duration::from_millis(adapted.clamp(50, 5000))
+        adapt_timeout(initial_rtt, 1.5, timing.min_rtt_timeout, &timing)
     };
+    let host_timeouts: HashMap<IpAddr, Duration> = host_rtts
+        .iter()
+        .map(|(ip, rtt)| (*ip, adapt_timeout(*rtt, 1.5, timing.min_rtt_timeout, &timing)))
+        .collect();
 
     let wait_time = if let Some(wt) = args.wait_ms {
         Duration::from_millis(wt)
     } else {
         // adapt wait time based on RTT
-        let adapted = (initial_rtt.as_millis() as f64 * 2.0) as u64;
-        Duration::from_millis(adapted.clamp(100, 5000))
+        adapt_timeout(initial_rtt, 2.0, timing.min_rtt_timeout * 2, &timing)
     };
 
     // Create probe setting
     let probe_setting = ProbeSetting {
         if_index: interface.index,
-        target_endpoints: target_endpoints,
-        host_concurrency: args.concurrency,
-        port_concurrency: args.concurrency,
-        task_timeout: Duration::from_millis(args.task_timeout_ms),
+        target_endpoints: Vec::new(),
+        scan_targets: Some(scan_targets),
+        host_concurrency: args.concurrency.unwrap_or(timing.host_concurrency),
+        port_concurrency: args.concurrency.unwrap_or(timing.port_concurrency),
+        scheduler: scheduler.clone(),
+        neighbors: neighbors.clone(),
+        source,
+        proxy: proxy.clone(),
+        task_timeout: args.task_timeout_ms.map(Duration::from_millis).unwrap_or(timing.task_timeout),
         connect_timeout: conn_timeout,
+        host_timeouts: Arc::new(host_timeouts),
         wait_time: wait_time,
-        send_rate: Duration::from_millis(1),
+        min_rate: args.min_rate,
+        max_rate: timing.resolve_max_rate(args.min_rate, args.max_rate),
+        retries: args.retries.map(|r| r as usize).unwrap_or(timing.retries),
+        io: io_config(&args),
     };
 
     let transport = TransportProtocol::from_str(&args.proto).unwrap();
 
-    if !probe_setting.target_endpoints.is_empty() {
-        tracing::info!("Starting {} port scan on {} host(s), {} port(s)", args.proto.to_uppercase(), probe_setting.target_endpoints.len(), probe_setting.target_endpoints[0].ports.len());
+    if let Some(targets) = &probe_setting.scan_targets {
+        tracing::info!("Starting {} port scan on {} host(s), {} port(s)", args.proto.to_uppercase(), targets.hosts().len(), targets.ports().len());
     }
     
     // Run port scan
-    let port_scanner = PortScanner::new(probe_setting.clone(), transport, args.method);
+    let port_scanner = PortScanner::new(probe_setting.clone(), transport, method);
     let portscan_result: ScanResult = port_scanner.run().await?;
     tracing::info!("{} Port scan completed in {:?}", args.proto.to_uppercase(), portscan_result.scan_time);
-    let mut endpoint_results = portscan_result.endpoints.clone();
+    if let Some(io_stats) = &portscan_result.io_stats {
+        tracing::info!("Packet I/O: {}", io_stats);
+    }
+    log_open_ports(&portscan_result);
 
     let mut active_endpoints = portscan_result.get_active_endpoints();
 
@@ -108,9 +182,9 @@ pub async fn run(args: PortScanArgs, no_stdout: bool, output: Option<PathBuf>) -
     rep.apply_port_scan(portscan_result);
 
     if transport != TransportProtocol::Quic && args.quic {
-        let port_scanner = PortScanner::new(probe_setting.clone(), TransportProtocol::Quic, args.method);
+        let port_scanner = PortScanner::new(probe_setting.clone(), TransportProtocol::Quic, method);
         let quic_portscan_result = port_scanner.run().await?;
-        endpoint_results.extend(quic_portscan_result.endpoints.clone());
+        log_open_ports(&quic_portscan_result);
         let active_quic_endpoints = quic_portscan_result.get_active_endpoints();
         // Merge active QUIC endpoints with active TCP endpoints
         active_endpoints.extend(active_quic_endpoints);
@@ -118,27 +192,28 @@ pub async fn run(args: PortScanArgs, no_stdout: bool, output: Option<PathBuf>) -
         rep.apply_port_scan(quic_portscan_result);
     }
 
-    for endpoint in &endpoint_results {
-        let mut open_ports: Vec<u16> = Vec::new();
-        for (port, port_result) in &endpoint.ports {
-            if port_result.state == PortState::Open {
-                open_ports.push(port.number);
-            }
-        }
-        tracing::info!("{}: Open ports: {:?}", endpoint.ip, open_ports);
-    }
-
-    if args.os_detect {
+    if args.os_detect && !socket_access().raw {
+        rep.meta.downgrades.push(Downgrade::new("os detection", "no os detection"));
+    } else if args.os_detect {
         // OS detection
         let os_probe_setting = ProbeSetting {
             target_endpoints: active_endpoints.clone(),
+            scan_targets: None,
             if_index: probe_setting.if_index,
             host_concurrency: probe_setting.host_concurrency,
             port_concurrency: probe_setting.port_concurrency,
+            scheduler: probe_setting.scheduler.clone(),
+            neighbors: probe_setting.neighbors.clone(),
+            source: probe_setting.source,
+            proxy: probe_setting.proxy.clone(),
             task_timeout: probe_setting.task_timeout,
             connect_timeout: probe_setting.connect_timeout,
+            host_timeouts: probe_setting.host_timeouts.clone(),
             wait_time: probe_setting.wait_time,
-            send_rate: probe_setting.send_rate,
+            min_rate: probe_setting.min_rate,
+            max_rate: probe_setting.max_rate,
+            retries: probe_setting.retries,
+            io: probe_setting.io,
         };
         tracing::info!("Starting OS detection on {} host(s)", os_probe_setting.target_endpoints.len());
         let os_detector = crate::os::OsDetector::new(os_probe_setting);
@@ -154,14 +229,25 @@ pub async fn run(args: PortScanArgs, no_stdout: bool, output: Option<PathBuf>) -
         rep.apply_os_probe(os_probe_result);
     }
     
+    if args.service_detect && transport == TransportProtocol::Sctp {
+        // Service probes connect over TCP or UDP
+        tracing::warn!("Service detection does not support SCTP, skipping SCTP ports");
+        for endpoint in &mut active_endpoints {
+            endpoint.ports.retain(|port| port.transport != TransportProtocol::Sctp);
+        }
+        active_endpoints.retain(|endpoint| !endpoint.ports.is_empty());
+    }
+
     if args.service_detect {
         // service detection 
         let service_probe_setting = ServiceProbeConfig {
-            timeout: Duration::from_secs(2),
-            max_concurrency: args.concurrency,
+            timeout: args.read_timeout_ms.map(Duration::from_millis).unwrap_or(timing.service_timeout),
+            scheduler: probe_setting.scheduler.clone(),
             max_read_size: 1024 * 1024,
             sni: true,
             skip_cert_verify: true,
+            source: probe_setting.source,
+            proxy: probe_setting.proxy.clone(),
         };
 
         let service_detector = ServiceDetector::new(service_probe_setting);
@@ -180,12 +266,134 @@ pub async fn run(args: PortScanArgs, no_stdout: bool, output: Option<PathBuf>) -
     }
 
     rep.finish();
+    write_report(&rep, no_stdout, output.as_ref());
+    Ok(())
+}
+
+/// Create the connection scheduler of a port scan. `--concurrency` sets every limit.
+/// Connections in flight are capped by the open file limit, with a warning if that
+/// reduces an explicit `--concurrency`.
+fn scheduler(args: &PortScanArgs, timing: &TimingProfile) -> Scheduler {
+    let requested = ConcurrencyLimits::new(
+        args.concurrency.unwrap_or(timing.max_in_flight),
+        args.concurrency.unwrap_or(timing.port_concurrency),
+        args.concurrency.unwrap_or(timing.host_concurrency),
+    );
+    let limits = match crate::util::rlimit::max_open_sockets() {
+        Some(max_open) => requested.capped(max_open),
+        None => requested,
+    };
+    if limits.in_flight < requested.in_flight {
+        let nofile = crate::util::rlimit::nofile_limit().unwrap_or_default();
+        if args.concurrency.is_some() {
+            tracing::warn!("Concurrency reduced from {} to {} to stay within the open file limit ({})", requested.in_flight, limits.in_flight, nofile);
+        } else {
+            tracing::debug!("Connections in flight reduced from {} to {} by the open file limit ({})", requested.in_flight, limits.in_flight, nofile);
+        }
+    }
+    Scheduler::new(limits)
+}
+
+/// Get the scan method to use. Without raw sockets, a SYN scan falls back to a connect
+/// scan and the other raw TCP and UDP scans fail. QUIC scans need no raw sockets.
+fn scan_method(transport: TransportProtocol, method: PortScanMethod, meta: &mut ReportMeta) -> Result<PortScanMethod> {
+    if socket_access().raw {
+        return Ok(method);
+    }
+    match (transport, method) {
+        (TransportProtocol::Tcp, PortScanMethod::Syn) => {
+            meta.downgrades.push(Downgrade::new("tcp-syn scan", "tcp-connect scan"));
+            Ok(PortScanMethod::Connect)
+        }
+        (TransportProtocol::Tcp, PortScanMethod::Connect) | (TransportProtocol::Quic, _) => Ok(method),
+        (TransportProtocol::Tcp, _) => Err(raw_required(&format!("TCP {} scan", method.as_str()))),
+        (TransportProtocol::Udp, _) => Err(raw_required("UDP port scan")),
+        (TransportProtocol::Sctp, _) => Err(raw_required("SCTP port scan")),
+    }
+}
+
+/// Check that every phase of the scan can go through the proxy: only TCP connections can.
+fn check_proxy_support(args: &PortScanArgs, transport: TransportProtocol) -> Result<()> {
+    if transport != TransportProtocol::Tcp || args.quic {
+        anyhow::bail!("--proxy only supports TCP port scans");
+    }
+    if args.method != PortScanMethod::Connect {
+        anyhow::bail!("--proxy only supports the connect scan method");
+    }
+    if args.discover.is_some() {
+        anyhow::bail!("--proxy cannot be combined with host discovery");
+    }
+    if args.os_detect {
+        anyhow::bail!("--proxy cannot be combined with OS detection");
+    }
+    Ok(())
+}
 
+/// Find the live hosts among the targets with a host scan.
+/// The scheduler and the neighbor cache are shared with the port scan.
+#[allow(clippy::too_many_arguments)]
+async fn discover_hosts(target_set: &TargetSet, proto: HostScanProto, interface: &netdev::Interface, args: &PortScanArgs, timing: &TimingProfile, scheduler: &Scheduler, neighbors: &NeighborCache, meta: &mut ReportMeta) -> Result<ScanResult> {
+    // The ports are only probed by TCP discovery
+    let targets = ScanTargets::new(target_set.clone(), DEFAULT_DISCOVERY_TCP_PORTS.to_vec(), !args.ordered)?;
+    let setting = ProbeSetting {
+        if_index: interface.index,
+        target_endpoints: Vec::new(),
+        scan_targets: Some(targets),
+        host_concurrency: args.concurrency.unwrap_or(timing.host_concurrency),
+        port_concurrency: args.concurrency.unwrap_or(timing.port_concurrency),
+        scheduler: scheduler.clone(),
+        neighbors: neighbors.clone(),
+        source: ProbeSource::new(interface, args.source_ip, args.source_port)?,
+        proxy: None,
+        task_timeout: args.task_timeout_ms.map(Duration::from_millis).unwrap_or(timing.task_timeout),
+        connect_timeout: timing.probe_timeout,
+        host_timeouts: Default::default(),
+        wait_time: timing.host_wait_time,
+        min_rate: args.min_rate,
+        max_rate: timing.resolve_max_rate(args.min_rate, args.max_rate),
+        retries: 0,
+        io: IoConfig::default(),
+    };
+    let mut scanner = HostScanner::new(setting, proto);
+    meta.downgrades.extend(scanner.fall_back_if_unprivileged()?);
+    tracing::info!("Starting {} host discovery on {} host(s)", proto.as_str().to_uppercase(), target_set.len());
+    scanner.run().await
+}
+
+/// Log the open ports of every host that has any.
+fn log_open_ports(result: &ScanResult) {
+    for endpoint in &result.endpoints {
+        let open_ports: Vec<u16> = endpoint.ports.iter().filter(|(_, pr)| pr.state == PortState::Open).map(|(port, _)| port.number).collect();
+        if !open_ports.is_empty() {
+            tracing::info!("{}: Open ports: {:?}", endpoint.ip, open_ports);
+        }
+    }
+}
+
+/// Get the packet I/O settings for raw port scans.
+fn io_config(args: &PortScanArgs) -> IoConfig {
+    let default = IoConfig::default();
+    IoConfig {
+        read_buffer_size: args.read_buffer_size.map(|size| size as usize).unwrap_or(default.read_buffer_size),
+        write_buffer_size: args.write_buffer_size.map(|size| size as usize).unwrap_or(default.write_buffer_size),
+        rx_threads: args.rx_threads.map(|n| n as usize).unwrap_or(default.rx_threads),
+        tx_threads: args.tx_threads.map(|n| n as usize).unwrap_or(default.tx_threads),
+    }
+}
+
+/// Scale an RTT into a timeout between `floor` and the template upper bound.
+fn adapt_timeout(rtt: Duration, factor: f64, floor: Duration, timing: &TimingProfile) -> Duration {
+    let adapted = (rtt.as_millis() as f64 * factor) as u64;
+    Duration::from_millis(adapted).clamp(floor, timing.max_rtt_timeout)
+}
+
+/// Print the report and save it as JSON if requested.
+fn write_report(rep: &ScanReport, no_stdout: bool, output: Option<&PathBuf>) {
     if !no_stdout {
-        print_report_tree(&rep);
+        print_report_tree(rep);
     }
-    if let Some(path) = &output {
-        match save_json_output(&rep, path, JsonStyle::Pretty) {
+    if let Some(path) = output {
+        match save_json_output(rep, path, JsonStyle::Pretty) {
             Ok(_) => {
                 if !no_stdout {
                     tracing::info!("JSON output saved to {}", path.display());
@@ -194,5 +402,4 @@ pub async fn run(args: PortScanArgs, no_stdout: bool, output: Option<PathBuf>) -
             Err(e) => tracing::error!("Failed to save JSON output: {}", e),
         }
     }
-    Ok(())
 }
@@ -1,22 +1,25 @@
 use std::{path::PathBuf, time::Duration};
 
 use crate::{cli::TraceArgs, endpoint::Host, protocol::Protocol, trace::{TraceSetting, Tracer}, util::json::{save_json_output, JsonStyle}};
+use crate::probe::ProbeSource;
 use anyhow::Result;
 
 /// Run traceroute
 pub async fn run(args: TraceArgs, no_stdout: bool, output: Option<PathBuf>) -> Result<()> {
+    crate::util::privilege::require_raw("Traceroute")?;
+    let dst_host: Host = crate::cli::ping::parse_target_host(&args.target).await?;
     let interface: netdev::Interface = if let Some(if_name) = args.interface {
         match crate::interface::get_interface_by_name(if_name.to_string()) {
             Some(iface) => iface,
             None => anyhow::bail!("interface not found"),
         }
     } else {
-        match netdev::get_default_interface() {
+        match crate::interface::get_default_interface_for(dst_host.ip) {
             Ok(iface) => iface,
             Err(_) => anyhow::bail!("failed to get default interface"),
         }
     };
-    let dst_host: Host = crate::cli::ping::parse_target_host(&args.target).await?;
+    let source = ProbeSource::new(&interface, args.source_ip, args.source_port)?;
     let mut trace_setting: TraceSetting = match args.proto.to_protocol() {
         Protocol::Udp => TraceSetting::udp_trace(&interface, &dst_host)?,
         _ => {
@@ -24,9 +27,11 @@ pub async fn run(args: TraceArgs, no_stdout: bool, output: Option<PathBuf>) -> R
         }
     };
     trace_setting.dst_port = Some(args.port);
+    trace_setting.source = source;
     trace_setting.hop_limit = args.max_hops;
-    trace_setting.send_rate = Duration::from_millis(args.interval_ms);
-    trace_setting.receive_timeout = Duration::from_millis(args.timeout_ms);
+    let timing = args.timing.profile();
+    trace_setting.send_rate = args.interval_ms.map(Duration::from_millis).unwrap_or(timing.probe_interval);
+    trace_setting.receive_timeout = args.timeout_ms.map(Duration::from_millis).unwrap_or(timing.probe_timeout);
 
     let tracer = Tracer::new(trace_setting);
     tracing::info!("Trace route to {} with {}...", args.target, args.proto.as_str().to_uppercase());
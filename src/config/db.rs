@@ -2,6 +2,8 @@
 pub const DEFAULT_PORTS_JSON: &str = include_str!("../../resources/nrev-default-ports.json");
 /// OS class by TTL values
 pub const OS_CLASS_TTL_JSON: &str = include_str!("../../resources/nrev-os-class-ttl.json");
+/// SCTP ports and service names, most common first
+pub const SCTP_SERVICES_JSON: &str = include_str!("../../resources/nrev-sctp-services.json");
 /// Well-known ports
 pub const WELLKNOWN_PORTS_JSON: &str = include_str!("../../resources/nrev-wellknown-ports.json");
 /// OS database and fingerprints
@@ -2,13 +2,31 @@
 pub const DEFAULT_LOCAL_TCP_PORT: u16 = 44322;
 /// Default local UDP port for sending probes
 pub const DEFAULT_LOCAL_UDP_PORT: u16 = 53445;
+/// Default local SCTP port for sending probes
+pub const DEFAULT_LOCAL_SCTP_PORT: u16 = 44323;
 /// Default base target UDP port for traceroute or ping
 pub const DEFAULT_BASE_TARGET_UDP_PORT: u16 = 33435;
+/// Target ports for TCP host discovery before a port scan
+pub const DEFAULT_DISCOVERY_TCP_PORTS: [u16; 3] = [22, 80, 443];
+/// Target port for the TCP ACK probe of combined host discovery
+pub const DEFAULT_DISCOVERY_TCP_ACK_PORT: u16 = 80;
 /// Default hop limit (TTL)
 pub const DEFAULT_HOP_LIMIT: u8 = 64;
 /// Default ping count for ping command
 pub const DEFAULT_PING_COUNT: u32 = 4;
 /// Default concurrency for host scanning
-pub const DEFAULT_HOSTS_CONCURRENCY: usize = 50;
+pub const DEFAULT_HOSTS_CONCURRENCY: usize = 512;
+/// Default per-probe timeout for host scanning (milliseconds)
+pub const DEFAULT_HOSTS_TIMEOUT_MS: u64 = 600;
 /// Default concurrency for port scanning
 pub const DEFAULT_PORTS_CONCURRENCY: usize = 100;
+/// Default limit of connections in flight across all hosts
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 1024;
+/// Default initial send rate for raw scans (packets per second)
+pub const DEFAULT_INITIAL_SEND_RATE: u32 = 1000;
+/// Rate at which slow start ends and the send rate grows additively (packets per second)
+pub const DEFAULT_SLOW_START_THRESHOLD: u32 = 16000;
+/// Default number of retransmissions for unanswered probes
+pub const DEFAULT_PROBE_RETRIES: u8 = 1;
+/// Default datalink read/write buffer size for raw scans (bytes)
+pub const DEFAULT_IO_BUFFER_SIZE: usize = 4096;
@@ -2,6 +2,7 @@ use std::path::PathBuf;
 
 pub mod db;
 pub mod default;
+pub mod timing;
 
 /// User configuration directory name
 pub const USER_CONFIG_DIR_NAME: &str = ".nrev";
@@ -0,0 +1,192 @@
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::config::default::{DEFAULT_HOSTS_CONCURRENCY, DEFAULT_HOSTS_TIMEOUT_MS, DEFAULT_MAX_IN_FLIGHT, DEFAULT_PORTS_CONCURRENCY, DEFAULT_PROBE_RETRIES};
+
+/// Timing template (-T0..-T5), from slowest to fastest
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimingTemplate {
+    Paranoid,
+    Sneaky,
+    Polite,
+    #[default]
+    Normal,
+    Aggressive,
+    Insane,
+}
+
+impl std::str::FromStr for TimingTemplate {
+    type Err = String;
+    /// Parse a template from its level (0-5) or name.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "0" | "paranoid" => Ok(TimingTemplate::Paranoid),
+            "1" | "sneaky" => Ok(TimingTemplate::Sneaky),
+            "2" | "polite" => Ok(TimingTemplate::Polite),
+            "3" | "normal" => Ok(TimingTemplate::Normal),
+            "4" | "aggressive" => Ok(TimingTemplate::Aggressive),
+            "5" | "insane" => Ok(TimingTemplate::Insane),
+            _ => Err(format!("invalid timing template: {s} (expected 0-5 or paranoid|sneaky|polite|normal|aggressive|insane)")),
+        }
+    }
+}
+
+impl TimingTemplate {
+    /// Get the string representation of the TimingTemplate.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimingTemplate::Paranoid => "paranoid",
+            TimingTemplate::Sneaky => "sneaky",
+            TimingTemplate::Polite => "polite",
+            TimingTemplate::Normal => "normal",
+            TimingTemplate::Aggressive => "aggressive",
+            TimingTemplate::Insane => "insane",
+        }
+    }
+    /// Get the numeric level (0-5).
+    pub fn level(&self) -> u8 {
+        *self as u8
+    }
+    /// Get the scan settings for this template.
+    pub fn profile(&self) -> TimingProfile {
+        match self {
+            TimingTemplate::Paranoid => TimingProfile {
+                port_concurrency: 1,
+                host_concurrency: 1,
+                max_in_flight: 1,
+                host_scan_concurrency: 1,
+                host_scan_timeout: Duration::from_millis(5000),
+                min_rtt_timeout: Duration::from_millis(1000),
+                max_rtt_timeout: Duration::from_millis(10_000),
+                probe_timeout: Duration::from_millis(5000),
+                probe_interval: Duration::from_millis(5000),
+                host_wait_time: Duration::from_millis(5000),
+                task_timeout: Duration::from_secs(600),
+                service_timeout: Duration::from_secs(10),
+                retries: 3,
+                max_rate: Some(1),
+            },
+            TimingTemplate::Sneaky => TimingProfile {
+                port_concurrency: 10,
+                host_concurrency: 5,
+                max_in_flight: 10,
+                host_scan_concurrency: 10,
+                host_scan_timeout: Duration::from_millis(3000),
+                min_rtt_timeout: Duration::from_millis(500),
+                max_rtt_timeout: Duration::from_millis(10_000),
+                probe_timeout: Duration::from_millis(3000),
+                probe_interval: Duration::from_millis(3000),
+                host_wait_time: Duration::from_millis(2000),
+                task_timeout: Duration::from_secs(300),
+                service_timeout: Duration::from_secs(5),
+                retries: 2,
+                max_rate: Some(10),
+            },
+            TimingTemplate::Polite => TimingProfile {
+                port_concurrency: 50,
+                host_concurrency: 10,
+                max_in_flight: 100,
+                host_scan_concurrency: 64,
+                host_scan_timeout: Duration::from_millis(2000),
+                min_rtt_timeout: Duration::from_millis(200),
+                max_rtt_timeout: Duration::from_millis(8000),
+                probe_timeout: Duration::from_millis(2000),
+                probe_interval: Duration::from_millis(2000),
+                host_wait_time: Duration::from_millis(1000),
+                task_timeout: Duration::from_secs(60),
+                service_timeout: Duration::from_secs(3),
+                retries: 2,
+                max_rate: Some(100),
+            },
+            TimingTemplate::Normal => TimingProfile {
+                port_concurrency: DEFAULT_PORTS_CONCURRENCY,
+                host_concurrency: DEFAULT_PORTS_CONCURRENCY,
+                max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+                host_scan_concurrency: DEFAULT_HOSTS_CONCURRENCY,
+                host_scan_timeout: Duration::from_millis(DEFAULT_HOSTS_TIMEOUT_MS),
+                min_rtt_timeout: Duration::from_millis(50),
+                max_rtt_timeout: Duration::from_millis(5000),
+                probe_timeout: Duration::from_millis(1000),
+                probe_interval: Duration::from_millis(1000),
+                host_wait_time: Duration::from_millis(300),
+                task_timeout: Duration::from_secs(30),
+                service_timeout: Duration::from_secs(2),
+                retries: DEFAULT_PROBE_RETRIES as usize,
+                max_rate: None,
+            },
+            TimingTemplate::Aggressive => TimingProfile {
+                port_concurrency: 500,
+                host_concurrency: 100,
+                max_in_flight: 2048,
+                host_scan_concurrency: 1024,
+                host_scan_timeout: Duration::from_millis(400),
+                min_rtt_timeout: Duration::from_millis(50),
+                max_rtt_timeout: Duration::from_millis(1250),
+                probe_timeout: Duration::from_millis(500),
+                probe_interval: Duration::from_millis(500),
+                host_wait_time: Duration::from_millis(200),
+                task_timeout: Duration::from_secs(20),
+                service_timeout: Duration::from_secs(1),
+                retries: 1,
+                max_rate: None,
+            },
+            TimingTemplate::Insane => TimingProfile {
+                port_concurrency: 1000,
+                host_concurrency: 200,
+                max_in_flight: 4096,
+                host_scan_concurrency: 2048,
+                host_scan_timeout: Duration::from_millis(250),
+                min_rtt_timeout: Duration::from_millis(25),
+                max_rtt_timeout: Duration::from_millis(300),
+                probe_timeout: Duration::from_millis(250),
+                probe_interval: Duration::from_millis(250),
+                host_wait_time: Duration::from_millis(100),
+                task_timeout: Duration::from_secs(10),
+                service_timeout: Duration::from_millis(500),
+                retries: 0,
+                max_rate: None,
+            },
+        }
+    }
+}
+
+/// Scan knobs set by a timing template
+#[derive(Clone, Debug)]
+pub struct TimingProfile {
+    /// Concurrent port probes per host
+    pub port_concurrency: usize,
+    /// Concurrent hosts for port scan
+    pub host_concurrency: usize,
+    /// Connections in flight across all hosts for connect scans and service detection
+    pub max_in_flight: usize,
+    /// Concurrent probes for host scan
+    pub host_scan_concurrency: usize,
+    /// Per-probe timeout for host scan
+    pub host_scan_timeout: Duration,
+    /// Lower bound for RTT-adapted connect timeouts (the wait time floor is twice this)
+    pub min_rtt_timeout: Duration,
+    /// Upper bound for RTT-adapted timeouts
+    pub max_rtt_timeout: Duration,
+    /// Per-probe timeout for ping and traceroute
+    pub probe_timeout: Duration,
+    /// Interval between ping/traceroute probes
+    pub probe_interval: Duration,
+    /// Wait time after the last host scan probe
+    pub host_wait_time: Duration,
+    /// Overall task timeout
+    pub task_timeout: Duration,
+    /// Service detection read timeout
+    pub service_timeout: Duration,
+    /// Retransmissions for unanswered probes
+    pub retries: usize,
+    /// Maximum send rate in packets per second
+    pub max_rate: Option<u32>,
+}
+
+impl TimingProfile {
+    /// Resolve the maximum send rate. An explicit limit wins over the template,
+    /// and the template limit is raised to an explicit minimum rate.
+    pub fn resolve_max_rate(&self, min_rate: Option<u32>, max_rate: Option<u32>) -> Option<u32> {
+        max_rate.or(self.max_rate.map(|m| m.max(min_rate.unwrap_or(0))))
+    }
+}
@@ -1,3 +1,7 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
 use crate::config;
 
 /// Get default port list
@@ -13,3 +17,21 @@ pub fn get_wellknown_ports() -> Vec<u16> {
         .expect("Invalid wellknown-ports.json format");
     wellknown_ports
 }
+
+/// SCTP port with its registered service name
+#[derive(Deserialize)]
+pub struct SctpService {
+    pub port: u16,
+    pub name: String,
+}
+
+/// Get SCTP service list, most common ports first
+pub fn get_sctp_services() -> Vec<SctpService> {
+    serde_json::from_str(config::db::SCTP_SERVICES_JSON)
+        .expect("Invalid sctp-services.json format")
+}
+
+/// Get SCTP service names by port
+pub fn get_sctp_service_names() -> HashMap<u16, String> {
+    get_sctp_services().into_iter().map(|service| (service.port, service.name)).collect()
+}
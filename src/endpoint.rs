@@ -34,6 +34,7 @@ pub enum TransportProtocol {
     Tcp,
     Udp,
     Quic,
+    Sctp,
 }
 
 impl TransportProtocol {
@@ -43,6 +44,7 @@ impl TransportProtocol {
             "tcp" => Some(TransportProtocol::Tcp),
             "udp" => Some(TransportProtocol::Udp),
             "quic" => Some(TransportProtocol::Quic),
+            "sctp" => Some(TransportProtocol::Sctp),
             _ => None,
         }
     }
@@ -52,6 +54,7 @@ impl TransportProtocol {
             TransportProtocol::Tcp => "tcp",
             TransportProtocol::Udp => "udp",
             TransportProtocol::Quic => "quic",
+            TransportProtocol::Sctp => "sctp",
         }
     }
 }
@@ -79,7 +82,7 @@ impl From<(u16, TransportProtocol)> for Port {
 }
 impl std::fmt::Display for Port {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}:{}", match self.transport { TransportProtocol::Tcp=>"tcp", TransportProtocol::Udp=>"udp", TransportProtocol::Quic=>"quic" }, self.number)
+        write!(f, "{}:{}", match self.transport { TransportProtocol::Tcp=>"tcp", TransportProtocol::Udp=>"udp", TransportProtocol::Quic=>"quic", TransportProtocol::Sctp=>"sctp" }, self.number)
     }
 }
 
@@ -90,6 +93,13 @@ pub enum PortState {
     Open,
     Closed,
     Filtered,
+    /// No response received (UDP, FIN/NULL/Xmas). The port may be open or filtered
+    #[serde(rename = "open|filtered")]
+    OpenFiltered,
+    /// Reachable through the firewall, but open or closed is unknown (ACK scan)
+    Unfiltered,
+    /// The probe could not be completed because of a local error
+    Unknown,
 }
 
 impl PortState {
@@ -99,6 +109,9 @@ impl PortState {
             "open" => Some(PortState::Open),
             "closed" => Some(PortState::Closed),
             "filtered" => Some(PortState::Filtered),
+            "open|filtered" => Some(PortState::OpenFiltered),
+            "unfiltered" => Some(PortState::Unfiltered),
+            "unknown" => Some(PortState::Unknown),
             _ => None,
         }
     }
@@ -108,6 +121,115 @@ impl PortState {
             PortState::Open => "open",
             PortState::Closed => "closed",
             PortState::Filtered => "filtered",
+            PortState::OpenFiltered => "open|filtered",
+            PortState::Unfiltered => "unfiltered",
+            PortState::Unknown => "unknown",
+        }
+    }
+}
+
+/// Reason a port state was decided
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum PortReason {
+    /// SYN/ACK received, or TCP connection established
+    SynAck,
+    /// RST received in reply to a raw TCP probe
+    Rst,
+    /// TCP connection refused by the target
+    ConnRefused,
+    /// UDP reply received
+    UdpResponse,
+    /// QUIC handshake answered (successfully or with a QUIC error)
+    QuicResponse,
+    /// SCTP INIT-ACK received in reply to an INIT
+    InitAck,
+    /// SCTP ABORT received in reply to an INIT
+    Abort,
+    /// No reply to any probe, including retransmissions
+    NoResponse,
+    /// Connection attempt timed out
+    Timeout,
+    /// Connection attempt failed with host unreachable
+    HostUnreach,
+    /// Connection attempt failed with network unreachable
+    NetUnreach,
+    /// ICMP/ICMPv6 port unreachable
+    IcmpPortUnreach { icmp_type: u8, icmp_code: u8 },
+    /// Other ICMP/ICMPv6 destination unreachable (e.g. administratively prohibited)
+    IcmpUnreach { icmp_type: u8, icmp_code: u8 },
+    /// The probe failed locally (e.g. out of file descriptors or buffer space)
+    LocalError,
+}
+
+impl PortReason {
+    /// Get the string representation of the PortReason.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PortReason::SynAck => "syn-ack",
+            PortReason::Rst => "rst",
+            PortReason::ConnRefused => "conn-refused",
+            PortReason::UdpResponse => "udp-response",
+            PortReason::QuicResponse => "quic-response",
+            PortReason::InitAck => "init-ack",
+            PortReason::Abort => "abort",
+            PortReason::NoResponse => "no-response",
+            PortReason::Timeout => "timeout",
+            PortReason::HostUnreach => "host-unreach",
+            PortReason::NetUnreach => "net-unreach",
+            PortReason::IcmpPortUnreach { .. } => "icmp-port-unreach",
+            PortReason::IcmpUnreach { .. } => "icmp-unreach",
+            PortReason::LocalError => "local-error",
+        }
+    }
+}
+
+impl std::fmt::Display for PortReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PortReason::IcmpPortUnreach { icmp_type, icmp_code }
+            | PortReason::IcmpUnreach { icmp_type, icmp_code } => {
+                write!(f, "{} (type={}, code={})", self.as_str(), icmp_type, icmp_code)
+            }
+            _ => f.write_str(self.as_str()),
+        }
+    }
+}
+
+/// Host discovery technique that got a response
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiscoveryTechnique {
+    /// ICMP/ICMPv6 Echo Reply
+    IcmpEcho,
+    /// ICMP Timestamp Reply (IPv4 only)
+    IcmpTimestamp,
+    /// SYN/ACK or RST in reply to a TCP SYN
+    TcpSyn,
+    /// RST in reply to a TCP ACK
+    TcpAck,
+    /// ICMP/ICMPv6 port unreachable in reply to a UDP probe
+    Udp,
+    /// Accepted or refused TCP connection (unprivileged fallback of TCP SYN)
+    TcpConnect,
+    /// ARP Reply
+    Arp,
+    /// NDP Neighbor Advertisement
+    Ndp,
+}
+
+impl DiscoveryTechnique {
+    /// Get the string representation of the DiscoveryTechnique.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiscoveryTechnique::IcmpEcho => "icmp-echo",
+            DiscoveryTechnique::IcmpTimestamp => "icmp-timestamp",
+            DiscoveryTechnique::TcpSyn => "tcp-syn",
+            DiscoveryTechnique::TcpAck => "tcp-ack",
+            DiscoveryTechnique::Udp => "udp",
+            DiscoveryTechnique::TcpConnect => "tcp-connect",
+            DiscoveryTechnique::Arp => "arp",
+            DiscoveryTechnique::Ndp => "ndp",
         }
     }
 }
@@ -188,6 +310,12 @@ pub struct PortResult {
     pub port: Port,
     pub state: PortState,
     pub rtt_ms: Option<u32>,
+    /// How the state was decided
+    #[serde(default)]
+    pub reason: Option<PortReason>,
+    /// TTL (hop limit) of the reply that decided the state
+    #[serde(default)]
+    pub reply_ttl: Option<u8>,
     #[serde(default)]
     pub service: ServiceInfo,
 }
@@ -315,6 +443,25 @@ impl Endpoint {
     }
 }
 
+/// Probed ports of an endpoint that never answered, counted instead of listed one by one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnansweredPorts {
+    pub transport: TransportProtocol,
+    pub state: PortState,
+    pub reason: PortReason,
+    pub count: usize,
+}
+
+/// Target hosts that answered none of their probes, counted instead of listed one by one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SilentHosts {
+    pub hosts: usize,
+    /// Silent hosts as target specs ("10.0.0.2-10.0.0.9"), up to `MAX_SKIPPED_RANGES` entries
+    pub ranges: Vec<String>,
+    /// Unanswered ports of each silent host
+    pub ports: UnansweredPorts,
+}
+
 /// Result of scanning an endpoint, including ports and OS guess
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EndpointResult {
@@ -322,11 +469,20 @@ pub struct EndpointResult {
     pub hostname: Option<String>,
     pub mac_addr: Option<MacAddr>,
     pub vendor_name: Option<String>,
+    /// Host round-trip time measured by host discovery (milliseconds)
+    #[serde(default)]
+    pub rtt_ms: Option<u32>,
+    /// Host discovery techniques that got a response
+    #[serde(default)]
+    pub discovered_by: Vec<DiscoveryTechnique>,
     pub os: OsGuess,
     #[serde(default)]
     pub tags: Vec<String>,
     #[serde(default, with = "ports_vec")]
     pub ports: BTreeMap<Port, PortResult>,
+    /// Probed ports without a reply, not listed in `ports`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unanswered: Vec<UnansweredPorts>,
     pub cpes: Vec<String>,
 }
 
@@ -337,9 +493,12 @@ impl Default for EndpointResult {
             hostname: None,
             mac_addr: None,
             vendor_name: None,
+            rtt_ms: None,
+            discovered_by: Vec::new(),
             os: OsGuess::default(),
             tags: Vec::new(),
             ports: BTreeMap::new(),
+            unanswered: Vec::new(),
             cpes: Vec::new(),
         }
     }
@@ -358,11 +517,28 @@ impl EndpointResult {
     pub fn upsert_port(&mut self, pr: PortResult) {
         self.ports.insert(pr.port, pr);
     }
+    /// Add unanswered ports, combining them with an entry of the same transport and state.
+    pub fn add_unanswered(&mut self, unanswered: UnansweredPorts) {
+        match self.unanswered.iter_mut().find(|u| u.transport == unanswered.transport && u.state == unanswered.state) {
+            Some(existing) => existing.count += unanswered.count,
+            None => self.unanswered.push(unanswered),
+        }
+    }
+    /// Get the number of probed ports, answered or not.
+    pub fn probed_port_count(&self) -> usize {
+        self.ports.len() + self.unanswered.iter().map(|u| u.count).sum::<usize>()
+    }
     /// Merge another EndpointResult into this one, combining tags, ports, and OS guess.
     pub fn merge(&mut self, other: EndpointResult) {
         if self.hostname.is_none() { self.hostname = other.hostname; }
         if self.mac_addr.is_none() { self.mac_addr = other.mac_addr; }
         if self.vendor_name.is_none() { self.vendor_name = other.vendor_name; }
+        if self.rtt_ms.is_none() { self.rtt_ms = other.rtt_ms; }
+        for technique in other.discovered_by {
+            if !self.discovered_by.contains(&technique) {
+                self.discovered_by.push(technique);
+            }
+        }
 
         //self.cpes = other.cpes;
         let incoming: Vec<String> = other
@@ -385,10 +561,23 @@ impl EndpointResult {
             }
         }
 
+        for unanswered in other.unanswered {
+            self.add_unanswered(unanswered);
+        }
+
         for (k, v) in other.ports {
             // check service data exists
             if let Some(existing) = self.ports.get_mut(&k) {
                 if existing.service.banner.is_none() {
+                    // Keep how the state was decided if the incoming result does not know it
+                    let mut v = v;
+                    if v.reason.is_none() {
+                        v.reason = existing.reason;
+                        v.reply_ttl = existing.reply_ttl;
+                    }
+                    if v.rtt_ms.is_none() {
+                        v.rtt_ms = existing.rtt_ms;
+                    }
                     self.ports.insert(k, v);
                 }
             } else {
@@ -455,6 +644,8 @@ mod tests {
             port: Port::new(80, TransportProtocol::Tcp),
             state: PortState::Open,
             rtt_ms: Some(23),
+            reason: Some(PortReason::SynAck),
+            reply_ttl: Some(52),
             service: ServiceInfo {
                 name: Some("http".into()),
                 ..Default::default()
@@ -464,6 +655,8 @@ mod tests {
             port: Port::new(443, TransportProtocol::Tcp),
             state: PortState::Open,
             rtt_ms: None,
+            reason: Some(PortReason::IcmpUnreach { icmp_type: 3, icmp_code: 13 }),
+            reply_ttl: None,
             service: ServiceInfo {
                 name: Some("https".into()),
                 tls_info: Some(TlsInfo {
@@ -492,5 +685,10 @@ mod tests {
         assert_eq!(back.ports.len(), 2);
         assert!(back.ports.contains_key(&Port::new(80, TransportProtocol::Tcp)));
         assert!(back.ports.contains_key(&Port::new(443, TransportProtocol::Tcp)));
+        assert_eq!(back.ports[&Port::new(80, TransportProtocol::Tcp)].reason, Some(PortReason::SynAck));
+        assert_eq!(
+            back.ports[&Port::new(443, TransportProtocol::Tcp)].reason,
+            Some(PortReason::IcmpUnreach { icmp_type: 3, icmp_code: 13 })
+        );
     }
 }
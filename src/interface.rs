@@ -1,5 +1,6 @@
 use netdev::Interface;
 use netdev::MacAddr;
+use nex::packet::frame::ParseOption;
 use std::{
     collections::{HashMap, HashSet},
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
@@ -169,4 +170,66 @@ pub fn get_gateway_macaddr(iface: &Interface) -> MacAddr {
         Some(gateway) => gateway.mac_addr.clone(),
         None => MacAddr::zero(),
     }
-}
\ No newline at end of file
+}
+
+/// Check if raw packets on the interface are IP packets without an Ethernet header.
+/// On Linux, loopback, tun and other links without a MAC address are opened as
+/// Layer3 channels. Elsewhere only tunnel interfaces carry bare IP packets.
+pub fn is_layer3(iface: &Interface) -> bool {
+    if cfg!(target_os = "linux") {
+        iface.is_tun() || iface.is_loopback() || iface.mac_addr.is_none_or(|mac_addr| mac_addr == MacAddr::zero())
+    } else {
+        iface.is_tun()
+    }
+}
+
+/// Get the options to parse the packets received on the interface.
+/// The BPF loopback device of macOS puts a 14-byte pseudo header in front of the IP packet.
+pub fn parse_option(iface: &Interface) -> ParseOption {
+    let mut parse_option = ParseOption::default();
+    let bpf_loopback = cfg!(any(target_os = "macos", target_os = "ios")) && iface.is_loopback();
+    if is_layer3(iface) || bpf_loopback {
+        parse_option.from_ip_packet = true;
+        parse_option.offset = if bpf_loopback { 14 } else { 0 };
+    }
+    parse_option
+}
+
+/// Get the interface to send to `target` from when none is given: the loopback
+/// interface for loopback addresses, otherwise the default interface.
+pub fn get_default_interface_for(target: IpAddr) -> Result<Interface, String> {
+    if target.is_loopback()
+        && let Some(iface) = netdev::interface::get_interfaces().into_iter().find(|iface| iface.is_loopback())
+    {
+        return Ok(iface);
+    }
+    netdev::get_default_interface()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn loopback_tun_and_links_without_mac_are_layer3() {
+        let mut ethernet = Interface::dummy();
+        ethernet.flags = (libc::IFF_UP | libc::IFF_BROADCAST) as u32;
+        ethernet.mac_addr = Some(MacAddr::new(0x02, 0, 0, 0, 0, 0x02));
+        assert!(!is_layer3(&ethernet));
+        assert!(!parse_option(&ethernet).from_ip_packet);
+
+        let mut loopback = ethernet.clone();
+        loopback.flags = (libc::IFF_UP | libc::IFF_LOOPBACK) as u32;
+        assert!(is_layer3(&loopback));
+        let mut tun = ethernet.clone();
+        tun.flags = (libc::IFF_UP | libc::IFF_POINTOPOINT) as u32;
+        assert!(is_layer3(&tun));
+        let mut no_mac = ethernet.clone();
+        no_mac.mac_addr = None;
+        assert!(is_layer3(&no_mac));
+        let parse_option = parse_option(&tun);
+        assert!(parse_option.from_ip_packet);
+        assert_eq!(parse_option.offset, 0);
+    }
+}
@@ -16,6 +16,7 @@ pub mod os;
 pub mod ping;
 pub mod protocol;
 pub mod probe;
+pub mod proxy;
 pub mod util;
 pub mod nei;
 pub mod trace;
@@ -34,6 +35,8 @@ async fn main() {
     // Start nrev
     let start_time = std::time::Instant::now();
     tracing::info!("nrev v{} started", env!("CARGO_PKG_VERSION"));
+    util::rlimit::init_nofile_limit();
+    util::privilege::init_socket_access();
 
     match cli.command {
         Command::Port(args) => {
@@ -4,7 +4,8 @@ use nex::packet::{
     arp::ArpOperation,
     frame::{Frame, ParseOption},
 };
-use std::{net::{IpAddr, Ipv4Addr}, time::{Duration, Instant}};
+use netdev::MacAddr;
+use std::{collections::HashMap, net::{IpAddr, Ipv4Addr}, time::{Duration, Instant}};
 use futures::stream::StreamExt;
 use futures::future::poll_fn;
 use nex::datalink::async_io::{async_channel, AsyncChannel};
@@ -86,3 +87,20 @@ pub async fn send_arp(ipv4_addr: Ipv4Addr, iface: &Interface, recv_timeout: Dura
         }
     }
 }
+
+/// Resolve the MAC addresses of on-link IPv4 neighbors with ARP.
+/// Neighbors that do not reply within `recv_timeout` are missing from the result.
+pub async fn resolve_arp(iface: &Interface, neighbors: &[Ipv4Addr], recv_timeout: Duration) -> Result<HashMap<IpAddr, MacAddr>> {
+    let requests = neighbors
+        .iter()
+        .map(|ipv4_addr| (IpAddr::V4(*ipv4_addr), crate::packet::arp::build_arp_packet(iface, IpAddr::V4(*ipv4_addr))))
+        .collect();
+    super::resolve_batch(iface, requests, recv_timeout, |frame| {
+        let arp = frame.datalink.as_ref()?.arp.as_ref()?;
+        if arp.operation != ArpOperation::Reply {
+            return None;
+        }
+        Some((IpAddr::V4(arp.sender_proto_addr), arp.sender_hw_addr))
+    })
+    .await
+}
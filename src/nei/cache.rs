@@ -0,0 +1,155 @@
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use netdev::{Interface, MacAddr};
+
+/// Maximum number of next hops resolved at once. Frames to the next hops left
+/// unresolved are addressed to the gateway.
+const MAX_RESOLVE_NEIGHBORS: usize = 65536;
+
+/// Cache of next-hop MAC addresses resolved with ARP (IPv4) and NDP (IPv6).
+/// Clones share the entries, so one cache serves every phase of a scan.
+#[derive(Clone, Debug, Default)]
+pub struct NeighborCache {
+    /// `None` marks neighbors that did not answer, so they are not asked again
+    entries: Arc<Mutex<HashMap<IpAddr, Option<MacAddr>>>>,
+}
+
+impl NeighborCache {
+    /// Get the destination MAC address of frames sent to `dst_ip`: the MAC address of its
+    /// next hop if resolved, otherwise the MAC address of the gateway.
+    pub fn next_hop_mac(&self, interface: &Interface, dst_ip: IpAddr) -> MacAddr {
+        let gateway_mac = match &interface.gateway {
+            Some(gateway) => gateway.mac_addr,
+            None => MacAddr::zero(),
+        };
+        crate::util::ip::next_hop_ip(interface, dst_ip)
+            .and_then(|next_hop| self.lookup(next_hop))
+            .unwrap_or(gateway_mac)
+    }
+
+    /// Get the MAC address of a resolved neighbor
+    pub fn lookup(&self, ip_addr: IpAddr) -> Option<MacAddr> {
+        self.entries.lock().unwrap().get(&ip_addr).copied().flatten()
+    }
+
+    /// Add the MAC address of a neighbor, e.g. learned from a reply
+    pub fn insert(&self, ip_addr: IpAddr, mac_addr: MacAddr) {
+        self.entries.lock().unwrap().insert(ip_addr, Some(mac_addr));
+    }
+
+    /// Resolve the next hops of the targets that are not cached yet.
+    /// Layer3 interfaces have no link-layer addresses to resolve.
+    pub async fn resolve(&self, interface: &Interface, targets: impl IntoIterator<Item = IpAddr>, recv_timeout: Duration) {
+        if crate::interface::is_layer3(interface) {
+            return;
+        }
+        let mut ipv4: BTreeSet<Ipv4Addr> = BTreeSet::new();
+        let mut ipv6: BTreeSet<Ipv6Addr> = BTreeSet::new();
+        {
+            let mut entries = self.entries.lock().unwrap();
+            // The gateway and the interface itself need no request
+            if let Some(gateway) = &interface.gateway
+                && gateway.mac_addr != MacAddr::zero()
+            {
+                for ip_addr in gateway.ipv4.iter().map(|ip| IpAddr::V4(*ip)).chain(gateway.ipv6.iter().map(|ip| IpAddr::V6(*ip))) {
+                    entries.entry(ip_addr).or_insert(Some(gateway.mac_addr));
+                }
+            }
+            if let Some(mac_addr) = interface.mac_addr {
+                for ip_addr in interface.ipv4.iter().map(|net| IpAddr::V4(net.addr())).chain(interface.ipv6.iter().map(|net| IpAddr::V6(net.addr()))) {
+                    entries.entry(ip_addr).or_insert(Some(mac_addr));
+                }
+            }
+            for target in targets {
+                let Some(next_hop) = crate::util::ip::next_hop_ip(interface, target) else {
+                    continue;
+                };
+                if entries.contains_key(&next_hop) {
+                    continue;
+                }
+                if ipv4.len() + ipv6.len() == MAX_RESOLVE_NEIGHBORS {
+                    tracing::warn!("Resolving only the first {} next hops; frames to the others are sent to the gateway", MAX_RESOLVE_NEIGHBORS);
+                    break;
+                }
+                match next_hop {
+                    IpAddr::V4(ip) => ipv4.insert(ip),
+                    IpAddr::V6(ip) => ipv6.insert(ip),
+                };
+            }
+        }
+        let requested = ipv4.len() + ipv6.len();
+        if requested == 0 {
+            return;
+        }
+        tracing::debug!("Resolving {} next hop(s) on {}", requested, interface.name);
+        let mut resolved: HashMap<IpAddr, MacAddr> = HashMap::new();
+        if !ipv4.is_empty() {
+            let neighbors: Vec<Ipv4Addr> = ipv4.iter().copied().collect();
+            match super::arp::resolve_arp(interface, &neighbors, recv_timeout).await {
+                Ok(macs) => resolved.extend(macs),
+                Err(e) => tracing::warn!("ARP resolution failed: {}", e),
+            }
+        }
+        if !ipv6.is_empty() {
+            let neighbors: Vec<Ipv6Addr> = ipv6.iter().copied().collect();
+            match super::ndp::resolve_ndp(interface, &neighbors, recv_timeout).await {
+                Ok(macs) => resolved.extend(macs),
+                Err(e) => tracing::warn!("NDP resolution failed: {}", e),
+            }
+        }
+        if resolved.len() < requested {
+            tracing::debug!("{} of {} next hop(s) did not answer", requested - resolved.len(), requested);
+        }
+        let mut entries = self.entries.lock().unwrap();
+        for ip_addr in ipv4.into_iter().map(IpAddr::V4).chain(ipv6.into_iter().map(IpAddr::V6)) {
+            entries.insert(ip_addr, resolved.get(&ip_addr).copied());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use netdev::NetworkDevice;
+
+    fn lan_interface() -> Interface {
+        let mut interface = Interface::dummy();
+        interface.mac_addr = Some(MacAddr::new(0x02, 0, 0, 0, 0, 0x02));
+        interface.ipv4 = vec!["192.0.2.2/24".parse().unwrap()];
+        let mut gateway = NetworkDevice::new();
+        gateway.mac_addr = MacAddr::new(0x02, 0, 0, 0, 0, 0x01);
+        gateway.ipv4 = vec![Ipv4Addr::new(192, 0, 2, 1)];
+        interface.gateway = Some(gateway);
+        interface
+    }
+
+    #[test]
+    fn next_hop_mac_uses_resolved_neighbors_on_link() {
+        let interface = lan_interface();
+        let cache = NeighborCache::default();
+        let neighbor = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 3));
+        let neighbor_mac = MacAddr::new(0x02, 0, 0, 0, 0, 0x03);
+        // Unresolved neighbors fall back to the gateway
+        assert_eq!(cache.next_hop_mac(&interface, neighbor), MacAddr::new(0x02, 0, 0, 0, 0, 0x01));
+        // Clones share the entries
+        cache.clone().insert(neighbor, neighbor_mac);
+        assert_eq!(cache.next_hop_mac(&interface, neighbor), neighbor_mac);
+        // Off-link targets are sent to the gateway
+        let remote = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+        assert_eq!(cache.next_hop_mac(&interface, remote), MacAddr::new(0x02, 0, 0, 0, 0, 0x01));
+    }
+
+    #[tokio::test]
+    async fn known_next_hops_need_no_resolution() {
+        let interface = lan_interface();
+        let cache = NeighborCache::default();
+        // The gateway and the interface itself are cached without sending requests
+        let targets = [IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)), IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1))];
+        cache.resolve(&interface, targets, Duration::from_millis(10)).await;
+        assert_eq!(cache.lookup(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))), Some(MacAddr::new(0x02, 0, 0, 0, 0, 0x01)));
+        assert_eq!(cache.lookup(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2))), Some(MacAddr::new(0x02, 0, 0, 0, 0, 0x02)));
+    }
+}
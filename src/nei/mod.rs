@@ -1,9 +1,15 @@
 pub mod arp;
+pub mod cache;
 pub mod ndp;
 
+use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use netdev::MacAddr;
-use std::{net::{IpAddr, Ipv4Addr}, time::Duration};
+use netdev::{Interface, MacAddr};
+use nex::packet::frame::{Frame, ParseOption};
+use std::{collections::{HashMap, HashSet}, net::{IpAddr, Ipv4Addr}, time::Duration};
+use futures::stream::StreamExt;
+use futures::future::poll_fn;
+use nex::datalink::async_io::{async_channel, AsyncChannel};
 
 use crate::protocol::Protocol;
 
@@ -52,3 +58,64 @@ pub fn lookup_vendor(mac: &MacAddr) -> Option<String> {
     }
     None
 }
+
+/// Send ARP requests or Neighbor Solicitations to several neighbors over one channel and
+/// collect the MAC addresses that `parse` extracts from the replies. Stops when every
+/// neighbor has answered or `recv_timeout` after the last request.
+async fn resolve_batch<F>(iface: &Interface, requests: Vec<(IpAddr, Vec<u8>)>, recv_timeout: Duration, parse: F) -> Result<HashMap<IpAddr, MacAddr>>
+where
+    F: Fn(&Frame) -> Option<(IpAddr, MacAddr)>,
+{
+    let config = nex::datalink::Config {
+        write_buffer_size: 4096,
+        read_buffer_size: 4096,
+        read_timeout: Some(recv_timeout),
+        write_timeout: None,
+        channel_type: nex::datalink::ChannelType::Layer2,
+        bpf_fd_attempts: 1000,
+        linux_fanout: None,
+        promiscuous: false,
+    };
+
+    let AsyncChannel::Ethernet(mut tx, mut rx) = async_channel(iface, config)?
+    else {
+        unreachable!();
+    };
+
+    let pending: HashSet<IpAddr> = requests.iter().map(|(ip, _)| *ip).collect();
+    let mut resolved: HashMap<IpAddr, MacAddr> = HashMap::new();
+    {
+        let send = async {
+            for (ip, packet) in &requests {
+                if let Err(e) = poll_fn(|cx| tx.poll_send(cx, packet)).await {
+                    tracing::debug!("Failed to send neighbor request for {}: {}", ip, e);
+                }
+            }
+            tokio::time::sleep(recv_timeout).await;
+        };
+        let recv = async {
+            while resolved.len() < pending.len() {
+                match rx.next().await {
+                    Some(Ok(packet)) => {
+                        if let Some(frame) = Frame::from_buf(&packet, ParseOption::default())
+                            && let Some((ip_addr, mac_addr)) = parse(&frame)
+                            && pending.contains(&ip_addr)
+                        {
+                            resolved.insert(ip_addr, mac_addr);
+                        }
+                    }
+                    Some(Err(e)) => {
+                        tracing::debug!("Failed to receive packet: {}", e);
+                        break;
+                    }
+                    None => break,
+                }
+            }
+        };
+        tokio::select! {
+            _ = send => {},
+            _ = recv => {},
+        }
+    }
+    Ok(resolved)
+}
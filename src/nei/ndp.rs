@@ -4,7 +4,8 @@ use nex::packet::{
     frame::{Frame, ParseOption},
     icmpv6::Icmpv6Type,
 };
-use std::{net::{IpAddr, Ipv6Addr}, time::{Duration, Instant}};
+use netdev::MacAddr;
+use std::{collections::HashMap, net::{IpAddr, Ipv6Addr}, time::{Duration, Instant}};
 use futures::stream::StreamExt;
 use futures::future::poll_fn;
 use nex::datalink::async_io::{async_channel, AsyncChannel};
@@ -112,3 +113,22 @@ pub async fn send_ndp(ipv6_addr: Ipv6Addr, iface: &Interface, recv_timeout: Dura
         }
     }
 }
+
+/// Resolve the MAC addresses of on-link IPv6 neighbors with Neighbor Solicitations.
+/// Neighbors that do not reply within `recv_timeout` are missing from the result.
+pub async fn resolve_ndp(iface: &Interface, neighbors: &[Ipv6Addr], recv_timeout: Duration) -> Result<HashMap<IpAddr, MacAddr>> {
+    let requests = neighbors
+        .iter()
+        .map(|ipv6_addr| (IpAddr::V6(*ipv6_addr), crate::packet::ndp::build_ndp_packet(iface, IpAddr::V6(*ipv6_addr))))
+        .collect();
+    super::resolve_batch(iface, requests, recv_timeout, |frame| {
+        let ip_layer = frame.ip.as_ref()?;
+        if ip_layer.icmpv6.as_ref()?.icmpv6_type != Icmpv6Type::NeighborAdvertisement {
+            return None;
+        }
+        // eth.source is the MAC address of the device that replied
+        let eth = frame.datalink.as_ref()?.ethernet.as_ref()?;
+        Some((IpAddr::V6(ip_layer.ipv6.as_ref()?.source), eth.source))
+    })
+    .await
+}
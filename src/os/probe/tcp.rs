@@ -1,12 +1,11 @@
 use futures::stream::StreamExt;
 use futures::future::poll_fn;
-use nex::datalink::async_io::{async_channel, AsyncChannel};
 use nex::packet::frame::{Frame, ParseOption};
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 use std::collections::BTreeMap;
 use anyhow::Result;
 use crate::config::default::DEFAULT_LOCAL_TCP_PORT;
-use crate::endpoint::{EndpointResult, OsGuess, Port, PortResult, PortState, ServiceInfo, TransportProtocol};
+use crate::endpoint::{EndpointResult, OsGuess, Port, PortReason, PortResult, PortState, ServiceInfo, TransportProtocol};
 use crate::output::port::OsProbeResult;
 use crate::probe::ProbeSetting;
 
@@ -19,29 +18,14 @@ pub async fn run_os_probe(
         Some(interface) => interface,
         None => return Err(anyhow::anyhow!("Interface not found")),
     };
+    // Resolve the MAC addresses of on-link targets
+    setting.neighbors.resolve(&interface, setting.target_ips(), setting.wait_time).await;
     // Create sender
-    let config = nex::datalink::Config {
-        write_buffer_size: 4096,
-        read_buffer_size: 4096,
-        read_timeout: Some(setting.wait_time),
-        write_timeout: None,
-        channel_type: nex::datalink::ChannelType::Layer2,
-        bpf_fd_attempts: 1000,
-        linux_fanout: None,
-        promiscuous: false,
-    };
-
-    let AsyncChannel::Ethernet(mut tx, mut rx) = async_channel(&interface, config)?
-    else {
-        unreachable!();
-    };
+    let (mut tx, mut rx) = crate::capture::channel::open(&interface, setting.wait_time)?;
 
-    let mut parse_option: ParseOption = ParseOption::default();
-    if interface.is_tun() || (cfg!(any(target_os = "macos", target_os = "ios")) && interface.is_loopback()) {
-        let payload_offset = if interface.is_loopback() { 14 } else { 0 };
-        parse_option.from_ip_packet = true;
-        parse_option.offset = payload_offset;
-    }
+    let parse_option: ParseOption = crate::interface::parse_option(&interface);
+    let ip_packet = crate::interface::is_layer3(&interface);
+    let local_port = setting.source.port_or(DEFAULT_LOCAL_TCP_PORT);
     let start_time = std::time::Instant::now();
     for target in setting.target_endpoints {
         let header_span = tracing::info_span!("os_probe");
@@ -54,7 +38,7 @@ pub async fn run_os_probe(
         let mut detected: bool = false;
         for port in &target.ports {
             let packet =
-                crate::packet::tcp::build_tcp_syn_packet(&interface, target.ip, port.number, false);
+                crate::packet::tcp::build_tcp_syn_packet(&interface, &setting.neighbors, &setting.source, target.ip, port.number, ip_packet);
 
             // Send a packet using poll_fn.
             match poll_fn(|cx| tx.poll_send(cx, &packet)).await {
@@ -93,7 +77,7 @@ pub async fn run_os_probe(
                         }
                         if let Some(transport) = &frame.transport {
                             if let Some(tcp) = &transport.tcp {
-                                if tcp.destination != DEFAULT_LOCAL_TCP_PORT {
+                                if tcp.destination != local_port {
                                     continue;
                                 }
                                 if tcp.options.len() == 0 {
@@ -113,13 +97,18 @@ pub async fn run_os_probe(
                                     state: PortState::Open,
                                     service: ServiceInfo::default(),
                                     rtt_ms: None,
+                                    reason: Some(PortReason::SynAck),
+                                    reply_ttl: Some(ttl),
                                 };
                                 let endpoint_result = EndpointResult {
                                     ip: target.ip,
                                     hostname: target.hostname.clone(),
                                     ports: BTreeMap::from([(port_result.port.clone(), port_result)]),
+                                    unanswered: Vec::new(),
                                     mac_addr: target.mac_addr,
                                     vendor_name: None,
+                                    rtt_ms: None,
+                                    discovered_by: Vec::new(),
                                     os: OsGuess {
                                         family: Some(os_match.family),
                                         confidence: Some(os_match.confidence as f32),
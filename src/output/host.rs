@@ -1,9 +1,34 @@
+use serde::{Deserialize, Serialize};
 use termtree::Tree;
-use crate::output::{tree_label, ScanResult};
+use crate::output::{port::ReportMeta, tree_label, ScanResult};
+
+/// Host scan report: the scan result plus run metadata
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HostScanReport {
+    pub meta: ReportMeta,
+    #[serde(flatten)]
+    pub result: ScanResult,
+}
+
+impl HostScanReport {
+    pub fn new(meta: ReportMeta, result: ScanResult) -> Self {
+        Self { meta, result }
+    }
+}
 
 /// Print the scan report results in a tree structure.
-pub fn print_report_tree(result: &ScanResult) {
+pub fn print_report_tree(report: &HostScanReport) {
+    let result = &report.result;
     let mut root = Tree::new(tree_label("Scan report(s)"));
+    if let Some(timing) = report.meta.timing {
+        root.push(Tree::new(tree_label(format!("timing: T{} ({})", timing.level(), timing.as_str()))));
+    }
+    if report.meta.excluded_hosts > 0 || report.meta.excluded_ports > 0 {
+        root.push(Tree::new(tree_label(format!("excluded: {} host(s), {} port(s)", report.meta.excluded_hosts, report.meta.excluded_ports))));
+    }
+    for downgrade in &report.meta.downgrades {
+        root.push(Tree::new(tree_label(format!("fallback: {}", downgrade))));
+    }
 
     // Create a tree for each endpoint
     for ep in &result.endpoints {
@@ -14,6 +39,13 @@ pub fn print_report_tree(result: &ScanResult) {
             ep.ip.to_string()
         };
         let mut ep_root = Tree::new(title);
+        if let Some(rtt) = ep.rtt_ms {
+            ep_root.push(Tree::new(tree_label(format!("RTT: {}ms", rtt))));
+        }
+        if !ep.discovered_by.is_empty() {
+            let techniques: Vec<&str> = ep.discovered_by.iter().map(|t| t.as_str()).collect();
+            ep_root.push(Tree::new(tree_label(format!("discovered by: {}", techniques.join(", ")))));
+        }
 
         // Link-layer info
         if ep.mac_addr.is_some() && !nex::net::ip::is_global_ip(&ep.ip) {
@@ -50,10 +82,16 @@ pub fn print_report_tree(result: &ScanResult) {
         }
 
         // Port information
+        for unanswered in &ep.unanswered {
+            ep_root.push(Tree::new(tree_label(format!("not shown: {} {} {} port(s) ({})", unanswered.count, unanswered.state.as_str(), unanswered.transport.as_str().to_uppercase(), unanswered.reason))));
+        }
         if !ep.ports.is_empty() {
             for (port, pr) in &ep.ports {
                 let mut pnode = Tree::new(tree_label(format!("{}/{}", port.number, port.transport.as_str().to_uppercase())));
                 pnode.push(Tree::new(tree_label(format!("state: {:?}", pr.state))));
+                if let Some(reason) = &pr.reason {
+                    pnode.push(Tree::new(tree_label(format!("reason: {}", reason))));
+                }
                 if let Some(name) = &pr.service.name {
                     pnode.push(Tree::new(tree_label(format!("service: {}", name))));
                 }
@@ -1,7 +1,8 @@
 use std::time::Duration;
 use nex::packet::frame::Frame;
 use serde::{Deserialize, Serialize};
-use crate::endpoint::{Endpoint, EndpointResult};
+use crate::endpoint::{Endpoint, EndpointResult, SilentHosts};
+use crate::scan::engine::IoStats;
 
 pub mod port;
 pub mod progress;
@@ -23,6 +24,10 @@ pub struct ScanResult {
     pub endpoints: Vec<EndpointResult>,
     pub scan_time: Duration,
     pub fingerprints: Vec<Frame>,
+    /// Packet I/O statistics (raw port scans only)
+    pub io_stats: Option<IoStats>,
+    /// Target hosts without any reply (port scans of target sets only)
+    pub silent_hosts: Vec<SilentHosts>,
 }
 
 impl ScanResult {
@@ -32,6 +37,8 @@ impl ScanResult {
             endpoints: Vec::new(),
             scan_time: Duration::new(0, 0),
             fingerprints: Vec::new(),
+            io_stats: None,
+            silent_hosts: Vec::new(),
         }
     }
 
@@ -54,6 +54,9 @@ pub fn print_ping_tree(res: &PingResult) {
         Protocol::Udp => {}
         _ => {}
     }
+    if let Some(downgrade) = &res.downgrade {
+        summary.push(Tree::new(format!("Fallback: {}", downgrade)));
+    }
     summary.push(Tree::new(format!("Received/Sent: {}/{}", s.received_count, s.transmitted_count)));
     summary.push(Tree::new(format!("Packet loss: {}", pct(loss))));
     summary.push(Tree::new(format!("Elapsed: {:?}", res.elapsed_time)));
@@ -72,17 +75,17 @@ pub fn print_ping_tree(res: &PingResult) {
         for r in &s.responses {
             match r.probe_status.kind {
                 ProbeStatusKind::Done => {
-                    let head = format!(
-                        "#{} {} bytes from {}, RTT={}, TTL={}, HOP={}",
+                    let mut head = format!(
+                        "#{} {} bytes from {}, RTT={}",
                         r.seq,
                         r.received_packet_size,
                         r.ip_addr,
                         fmt_ms(&r.rtt),
-                        r.ttl,
-                        r.hop
-                    )
-                    .trim()
-                    .to_string();
+                    );
+                    // Unprivileged sockets do not show the TTL of replies
+                    if r.ttl > 0 {
+                        head.push_str(&format!(", TTL={}, HOP={}", r.ttl, r.hop));
+                    }
 
                     let mut node = Tree::new(head);
                     if let Some(port) = &r.port_number {
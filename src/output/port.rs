@@ -3,7 +3,11 @@ use std::{collections::BTreeMap, net::IpAddr, time::{Duration, SystemTime}};
 use nex::packet::frame::Frame;
 use serde::{Deserialize, Serialize};
 use termtree::Tree;
-use crate::{endpoint::{EndpointResult, Port, PortResult, PortState, ServiceInfo, TransportProtocol}, output::{tree_label, ScanResult}, service::{probe::ServiceProbe, ServiceDetectionResult}};
+use crate::util::privilege::Downgrade;
+use crate::{config::timing::TimingTemplate, endpoint::{EndpointResult, Port, PortResult, PortState, ServiceInfo, SilentHosts, TransportProtocol}, output::{tree_label, ScanResult}, scan::engine::IoStats, service::{probe::ServiceProbe, ServiceDetectionResult}};
+
+/// Maximum number of skipped or silent host ranges listed in the report
+pub const MAX_SKIPPED_RANGES: usize = 1024;
 
 /// Results of OS probing
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,6 +35,9 @@ pub struct ScanReport {
     /// Keep IP as key for merging
     #[serde(default)]
     pub endpoints: BTreeMap<IpAddr, EndpointResult>,
+    /// Port scanned hosts that answered none of their probes, per transport
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub silent_hosts: Vec<SilentHosts>,
     #[serde(default)]
     pub stats: ReportStats,
 }
@@ -42,6 +49,24 @@ pub struct ReportMeta {
     pub version: String,         // env!("CARGO_PKG_VERSION")
     pub started_at: SystemTime,
     pub finished_at: Option<SystemTime>,
+    /// Timing template used for the scan
+    #[serde(default)]
+    pub timing: Option<TimingTemplate>,
+    /// Number of target hosts removed by `--exclude`/`--exclude-file`
+    #[serde(default)]
+    pub excluded_hosts: usize,
+    /// Number of ports removed by `--exclude-ports`
+    #[serde(default)]
+    pub excluded_ports: usize,
+    /// Number of target hosts that did not answer host discovery and were not port scanned
+    #[serde(default)]
+    pub skipped_hosts: usize,
+    /// Skipped hosts as target specs ("10.0.0.2-10.0.0.9"), up to `MAX_SKIPPED_RANGES` entries
+    #[serde(default)]
+    pub skipped_ranges: Vec<String>,
+    /// Techniques replaced by unprivileged fallbacks because raw sockets were not available
+    #[serde(default)]
+    pub downgrades: Vec<Downgrade>,
 }
 
 impl Default for ReportMeta {
@@ -51,6 +76,12 @@ impl Default for ReportMeta {
             version: env!("CARGO_PKG_VERSION").into(),
             started_at: SystemTime::now(),
             finished_at: None,
+            timing: None,
+            excluded_hosts: 0,
+            excluded_ports: 0,
+            skipped_hosts: 0,
+            skipped_ranges: Vec::new(),
+            downgrades: Vec::new(),
         }
     }
 }
@@ -61,9 +92,13 @@ pub struct ReportStats {
     pub hosts_total: usize,
     pub ports_scanned: usize,
     pub open_ports: usize,
+    pub duration_discovery: Option<Duration>, // HostScan
     pub duration_scan: Option<Duration>,      // PortScan
     pub duration_service: Option<Duration>,   // ServiceDetect
     pub duration_os: Option<Duration>,        // OS probe
+    /// Packet I/O statistics of the raw port scan
+    #[serde(default)]
+    pub io: Option<IoStats>,
 }
 
 /// An attempt to probe a service on a port
@@ -76,6 +111,20 @@ pub struct PortProbeAttempt {
 impl ScanReport {
     pub fn new() -> Self { Self::default() }
 
+    /// Apply host discovery results: add the live hosts, update stats
+    pub fn apply_host_discovery(&mut self, hs: ScanResult) {
+        for mut ep in hs.endpoints {
+            // Ports probed for discovery are not part of the port scan
+            ep.ports.clear();
+            self.endpoints
+                .entry(ep.ip)
+                .and_modify(|e| e.merge(ep.clone()))
+                .or_insert_with(|| ep);
+        }
+        self.stats.duration_discovery = Some(hs.scan_time);
+        self.recompute_stats();
+    }
+
     /// Apply port scan results: merge endpoints, update stats
     pub fn apply_port_scan(&mut self, ps: ScanResult) {
         for ep in ps.endpoints {
@@ -85,7 +134,11 @@ impl ScanReport {
                 .and_modify(|e| e.merge(ep.clone()))
                 .or_insert_with(|| ep);
         }
+        self.silent_hosts.extend(ps.silent_hosts);
         self.stats.duration_scan = Some(ps.scan_time);
+        if ps.io_stats.is_some() {
+            self.stats.io = ps.io_stats;
+        }
         self.recompute_stats();
     }
 
@@ -102,6 +155,8 @@ impl ScanReport {
                 state: PortState::Open,
                 service: r.service_info.clone(),
                 rtt_ms: None,
+                reason: None,
+                reply_ttl: None,
             });
 
             pr.state = PortState::Open;
@@ -129,6 +184,7 @@ impl ScanReport {
         self.meta.finished_at = Some(SystemTime::now());
         let tcp_svc_db = crate::db::service::tcp_service_db();
         let udp_svc_db = crate::db::service::udp_service_db();
+        let sctp_svc_names = crate::db::port::get_sctp_service_names();
         // check service name. if service name is not in result, set it.
         for ep in self.endpoints.values_mut() {
             for (port, pr) in &mut ep.ports {
@@ -140,6 +196,9 @@ impl ScanReport {
                         TransportProtocol::Udp | TransportProtocol::Quic => {
                             pr.service.name = udp_svc_db.get_name(port.number).map(|s| s.to_string());
                         }
+                        TransportProtocol::Sctp => {
+                            pr.service.name = sctp_svc_names.get(&port.number).cloned();
+                        }
                     }
                 }
             }
@@ -150,11 +209,12 @@ impl ScanReport {
     }
 
     fn recompute_stats(&mut self) {
-        self.stats.hosts_total = self.endpoints.len();
-        let mut ports_scanned = 0usize;
+        // Hosts silent on several transports are counted once
+        self.stats.hosts_total = self.endpoints.len() + self.silent_hosts.iter().map(|silent| silent.hosts).max().unwrap_or(0);
+        let mut ports_scanned: usize = self.silent_hosts.iter().map(|silent| silent.hosts.saturating_mul(silent.ports.count)).sum();
         let mut open_ports = 0usize;
         for ep in self.endpoints.values() {
-            ports_scanned += ep.ports.len();
+            ports_scanned += ep.probed_port_count();
             open_ports += ep.ports.values().filter(|p| p.state == PortState::Open).count();
         }
         self.stats.ports_scanned = ports_scanned;
@@ -188,6 +248,13 @@ fn score_service(s: &ServiceInfo) -> usize {
 /// Match and print OS detection results in a tree structure.
 pub fn print_report_tree(rep: &ScanReport) {
     let mut root = Tree::new(tree_label("Scan report(s)"));
+    for downgrade in &rep.meta.downgrades {
+        root.push(Tree::new(tree_label(format!("fallback: {}", downgrade))));
+    }
+    for silent in &rep.silent_hosts {
+        let unanswered = &silent.ports;
+        root.push(Tree::new(tree_label(format!("not shown: {} host(s) with {} {} {} port(s) each ({})", silent.hosts, unanswered.count, unanswered.state.as_str(), unanswered.transport.as_str().to_uppercase(), unanswered.reason))));
+    }
     for ep in rep.endpoints.values() {
         let title = if let Some(hn) = &ep.hostname {
             format!("{} ({})", ep.ip, hn)
@@ -195,6 +262,7 @@ pub fn print_report_tree(rep: &ScanReport) {
             format!("{}", ep.ip)
         };
         let mut ep_root = Tree::new(title);
+        if let Some(rtt) = ep.rtt_ms { ep_root.push(Tree::new(tree_label(format!("RTT: {}ms", rtt)))); }
 
         // OS
         if ep.os.family.is_some() || !ep.cpes.is_empty() {
@@ -210,12 +278,17 @@ pub fn print_report_tree(rep: &ScanReport) {
         }
 
         // Ports
+        for unanswered in &ep.unanswered {
+            ep_root.push(Tree::new(tree_label(format!("not shown: {} {} {} port(s) ({})", unanswered.count, unanswered.state.as_str(), unanswered.transport.as_str().to_uppercase(), unanswered.reason))));
+        }
         for (port, pr) in &ep.ports {
             if pr.state != PortState::Open {
                 continue;
             }
             let mut pnode = Tree::new(tree_label(format!("{}/{}", port.number, port.transport.as_str().to_uppercase())));
             pnode.push(Tree::new(tree_label(format!("state: {:?}", pr.state))));
+            if let Some(r) = &pr.reason { pnode.push(Tree::new(tree_label(format!("reason: {}", r)))); }
+            if let Some(t) = &pr.reply_ttl { pnode.push(Tree::new(tree_label(format!("TTL: {}", t)))); }
             if let Some(name) = &pr.service.name { pnode.push(Tree::new(tree_label(format!("service: {}", name)))); }
             if let Some(b) = &pr.service.banner { pnode.push(Tree::new(tree_label(format!("banner: {}", b)))); }
             if let Some(p) = &pr.service.product { pnode.push(Tree::new(tree_label(format!("product: {}", p)))); }
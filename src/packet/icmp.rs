@@ -14,50 +14,60 @@ use nex::packet::ip::IpNextProtocol;
 use nex::packet::ipv4::Ipv4Flags;
 use nex::packet::packet::Packet;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-/// Build ICMP packet. Supports both ICMPv4 and ICMPv6
-pub fn build_icmp_packet(interface: &Interface, dst_ip: IpAddr, is_ip_packet: bool) -> Vec<u8> {
-    let src_mac = interface.mac_addr.unwrap_or(MacAddr::zero());
-    let dst_mac = match &interface.gateway {
-        Some(gateway) => gateway.mac_addr,
-        None => MacAddr::zero(),
-    };
-    let src_ipv4 = crate::interface::get_interface_ipv4(interface).unwrap_or(Ipv4Addr::UNSPECIFIED);
-    let src_global_ipv6 =
-        crate::interface::get_interface_global_ipv6(interface).unwrap_or(Ipv6Addr::UNSPECIFIED);
-    let src_local_ipv6 =
-        crate::interface::get_interface_local_ipv6(interface).unwrap_or(Ipv6Addr::UNSPECIFIED);
-
-    let src_ip: IpAddr = match dst_ip {
-        IpAddr::V4(_) => {
-            IpAddr::V4(src_ipv4)
-        },
-        IpAddr::V6(_) => {
-            if nex::net::ip::is_global_ip(&dst_ip) {
-                IpAddr::V6(src_global_ipv6)
-            } else {
-                IpAddr::V6(src_local_ipv6)
-            }
-        },
-    };
+use crate::nei::cache::NeighborCache;
+use crate::probe::ProbeSource;
 
+/// Build ICMP packet. Supports both ICMPv4 and ICMPv6
+pub fn build_icmp_packet(interface: &Interface, neighbors: &NeighborCache, source: &ProbeSource, dst_ip: IpAddr, is_ip_packet: bool) -> Vec<u8> {
+    let src_ip = source.ip_for(interface, dst_ip);
     let icmp_packet: Bytes = match (src_ip, dst_ip) {
         (IpAddr::V4(src), IpAddr::V4(dst)) => IcmpPacketBuilder::new(src, dst)
             .icmp_type(IcmpType::EchoRequest)
             .icmp_code(icmp::echo_request::IcmpCodes::NoCode)
-            .echo_fields(0x1234, 0x1)
             .payload(Bytes::from_static(b"hello"))
+            .echo_fields(0x1234, 0x1)
             .build()
             .to_bytes(),
         (IpAddr::V6(src), IpAddr::V6(dst)) => Icmpv6PacketBuilder::new(src, dst)
             .icmpv6_type(Icmpv6Type::EchoRequest)
             .icmpv6_code(icmpv6::echo_request::Icmpv6Codes::NoCode)
-            .echo_fields(0x1234, 0x1)
             .payload(Bytes::from_static(b"hello"))
+            .echo_fields(0x1234, 0x1)
             .build()
             .to_bytes(),
         _ => panic!("Source and destination IP version mismatch"),
     };
+    wrap_icmp_packet(interface, neighbors, src_ip, dst_ip, icmp_packet, is_ip_packet)
+}
+
+/// Build ICMP Timestamp Request packet (ICMPv4 only)
+pub fn build_icmp_timestamp_packet(interface: &Interface, neighbors: &NeighborCache, source: &ProbeSource, dst_ip: Ipv4Addr, is_ip_packet: bool) -> Vec<u8> {
+    let src_ip = source.ip_for(interface, IpAddr::V4(dst_ip));
+    let IpAddr::V4(src) = src_ip else {
+        unreachable!();
+    };
+    // Originate timestamp in milliseconds since midnight UTC, receive and transmit left zero
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let originate = (since_epoch.as_millis() % 86_400_000) as u32;
+    let mut timestamps = Vec::with_capacity(12);
+    timestamps.extend_from_slice(&originate.to_be_bytes());
+    timestamps.extend_from_slice(&[0u8; 8]);
+    let icmp_packet = IcmpPacketBuilder::new(src, dst_ip)
+        .icmp_type(IcmpType::TimestampRequest)
+        .icmp_code(icmp::IcmpCode(0))
+        .payload(Bytes::from(timestamps))
+        .echo_fields(0x1234, 0x1)
+        .build()
+        .to_bytes();
+    wrap_icmp_packet(interface, neighbors, src_ip, IpAddr::V4(dst_ip), icmp_packet, is_ip_packet)
+}
+
+/// Wrap an ICMP/ICMPv6 message in an IP packet, and in an Ethernet frame unless `is_ip_packet`.
+fn wrap_icmp_packet(interface: &Interface, neighbors: &NeighborCache, src_ip: IpAddr, dst_ip: IpAddr, icmp_packet: Bytes, is_ip_packet: bool) -> Vec<u8> {
+    let src_mac = interface.mac_addr.unwrap_or(MacAddr::zero());
+    let dst_mac = neighbors.next_hop_mac(interface, dst_ip);
 
     let ip_packet = match (src_ip, dst_ip) {
         (IpAddr::V4(src), IpAddr::V4(dst)) => Ipv4PacketBuilder::new()
@@ -102,3 +112,58 @@ pub fn build_icmp_packet(interface: &Interface, dst_ip: IpAddr, is_ip_packet: bo
         ethernet_packet.to_bytes().to_vec()
     }
 }
+
+/// Original IP/transport header quoted in an ICMP error message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuotedPacket {
+    pub src_ip: IpAddr,
+    pub dst_ip: IpAddr,
+    pub protocol: IpNextProtocol,
+    pub src_port: u16,
+    pub dst_port: u16,
+}
+
+/// Parse the packet quoted in an ICMP/ICMPv6 error payload.
+/// `payload` is the ICMP payload following the type/code/checksum fields.
+pub fn parse_quoted_packet(payload: &[u8]) -> Option<QuotedPacket> {
+    // Skip the 4-byte unused/MTU field preceding the quoted packet
+    let quoted = payload.get(4..)?;
+    let version = quoted.first()? >> 4;
+    let (src_ip, dst_ip, protocol, transport) = match version {
+        4 => {
+            let ihl = ((quoted[0] & 0x0f) as usize) * 4;
+            if ihl < 20 || quoted.len() < ihl + 4 {
+                return None;
+            }
+            let src: [u8; 4] = quoted[12..16].try_into().ok()?;
+            let dst: [u8; 4] = quoted[16..20].try_into().ok()?;
+            (
+                IpAddr::V4(Ipv4Addr::from(src)),
+                IpAddr::V4(Ipv4Addr::from(dst)),
+                IpNextProtocol::new(quoted[9]),
+                &quoted[ihl..],
+            )
+        }
+        6 => {
+            if quoted.len() < 44 {
+                return None;
+            }
+            let src: [u8; 16] = quoted[8..24].try_into().ok()?;
+            let dst: [u8; 16] = quoted[24..40].try_into().ok()?;
+            (
+                IpAddr::V6(Ipv6Addr::from(src)),
+                IpAddr::V6(Ipv6Addr::from(dst)),
+                IpNextProtocol::new(quoted[6]),
+                &quoted[40..],
+            )
+        }
+        _ => return None,
+    };
+    Some(QuotedPacket {
+        src_ip,
+        dst_ip,
+        protocol,
+        src_port: u16::from_be_bytes([transport[0], transport[1]]),
+        dst_port: u16::from_be_bytes([transport[2], transport[3]]),
+    })
+}
@@ -1,5 +1,26 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use netdev::Interface;
+
 pub mod tcp;
 pub mod udp;
+pub mod sctp;
 pub mod icmp;
 pub mod arp;
 pub mod ndp;
+
+/// Select the interface address to send probes to `dst_ip` from.
+pub fn source_ip(interface: &Interface, dst_ip: IpAddr) -> IpAddr {
+    match dst_ip {
+        IpAddr::V4(_) => {
+            IpAddr::V4(crate::interface::get_interface_ipv4(interface).unwrap_or(Ipv4Addr::UNSPECIFIED))
+        },
+        IpAddr::V6(_) => {
+            if nex::net::ip::is_global_ip(&dst_ip) {
+                IpAddr::V6(crate::interface::get_interface_global_ipv6(interface).unwrap_or(Ipv6Addr::UNSPECIFIED))
+            } else {
+                IpAddr::V6(crate::interface::get_interface_local_ipv6(interface).unwrap_or(Ipv6Addr::UNSPECIFIED))
+            }
+        },
+    }
+}
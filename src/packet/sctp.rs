@@ -0,0 +1,194 @@
+use bytes::Bytes;
+use netdev::{Interface, MacAddr};
+use nex::packet::builder::{
+    ethernet::EthernetPacketBuilder, ipv4::Ipv4PacketBuilder, ipv6::Ipv6PacketBuilder,
+};
+use nex::packet::ethernet::EtherType;
+use nex::packet::ip::IpNextProtocol;
+use nex::packet::ipv4::Ipv4Flags;
+use nex::packet::packet::Packet;
+use std::net::IpAddr;
+
+use crate::config::default::DEFAULT_LOCAL_SCTP_PORT;
+use crate::nei::cache::NeighborCache;
+use crate::probe::ProbeSource;
+
+/// Length of the SCTP common header
+const SCTP_HEADER_LEN: usize = 12;
+/// Length of an INIT chunk without optional parameters
+const INIT_CHUNK_LEN: usize = 20;
+/// Advertised receiver window credit of INIT probes
+const INIT_A_RWND: u32 = 65535;
+/// Outbound and maximum inbound streams requested by INIT probes
+const INIT_STREAMS: u16 = 10;
+
+/// SCTP chunk types
+pub mod chunk {
+    pub const INIT: u8 = 1;
+    pub const INIT_ACK: u8 = 2;
+    pub const ABORT: u8 = 6;
+}
+
+/// Common header and first chunk type of an SCTP packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SctpHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub verification_tag: u32,
+    pub chunk_type: u8,
+}
+
+/// Parse the common header and the type of the first chunk of an SCTP packet.
+/// Packets with an invalid checksum are rejected.
+pub fn parse_sctp_packet(packet: &[u8]) -> Option<SctpHeader> {
+    if packet.len() < SCTP_HEADER_LEN + 4 {
+        return None;
+    }
+    let checksum = u32::from_le_bytes(packet[8..12].try_into().ok()?);
+    if checksum != sctp_checksum(packet) {
+        return None;
+    }
+    Some(SctpHeader {
+        src_port: u16::from_be_bytes([packet[0], packet[1]]),
+        dst_port: u16::from_be_bytes([packet[2], packet[3]]),
+        verification_tag: u32::from_be_bytes(packet[4..8].try_into().ok()?),
+        chunk_type: packet[SCTP_HEADER_LEN],
+    })
+}
+
+/// Build an SCTP packet from its common header fields and chunks, and fill in its checksum
+pub fn build_sctp_packet(src_port: u16, dst_port: u16, verification_tag: u32, chunks: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(SCTP_HEADER_LEN + chunks.len());
+    packet.extend_from_slice(&src_port.to_be_bytes());
+    packet.extend_from_slice(&dst_port.to_be_bytes());
+    packet.extend_from_slice(&verification_tag.to_be_bytes());
+    packet.extend_from_slice(&0u32.to_be_bytes());
+    packet.extend_from_slice(chunks);
+    let checksum = sctp_checksum(&packet);
+    packet[8..12].copy_from_slice(&checksum.to_le_bytes());
+    packet
+}
+
+/// Build an SCTP packet with a single INIT chunk. `init_tag` is the Initiate Tag, which the
+/// target puts in the verification tag of its INIT-ACK or ABORT.
+pub fn build_sctp_init(src_port: u16, dst_port: u16, init_tag: u32) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(INIT_CHUNK_LEN);
+    chunk.push(chunk::INIT);
+    chunk.push(0);
+    chunk.extend_from_slice(&(INIT_CHUNK_LEN as u16).to_be_bytes());
+    chunk.extend_from_slice(&init_tag.to_be_bytes());
+    chunk.extend_from_slice(&INIT_A_RWND.to_be_bytes());
+    chunk.extend_from_slice(&INIT_STREAMS.to_be_bytes());
+    chunk.extend_from_slice(&INIT_STREAMS.to_be_bytes());
+    // Initial TSN
+    chunk.extend_from_slice(&init_tag.to_be_bytes());
+    // The verification tag of a packet carrying an INIT is 0
+    build_sctp_packet(src_port, dst_port, 0, &chunk)
+}
+
+/// Build an SCTP INIT probe packet
+pub fn build_sctp_init_packet(
+    interface: &Interface,
+    neighbors: &NeighborCache,
+    source: &ProbeSource,
+    dst_ip: IpAddr,
+    dst_port: u16,
+    init_tag: u32,
+    is_ip_packet: bool,
+) -> Vec<u8> {
+    let src_mac = interface.mac_addr.unwrap_or(MacAddr::zero());
+    let dst_mac = neighbors.next_hop_mac(interface, dst_ip);
+    let src_ip = source.ip_for(interface, dst_ip);
+
+    let sctp_packet = Bytes::from(build_sctp_init(source.port_or(DEFAULT_LOCAL_SCTP_PORT), dst_port, init_tag));
+
+    let ip_packet: Bytes = match (src_ip, dst_ip) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => Ipv4PacketBuilder::new()
+            .source(src)
+            .destination(dst)
+            .protocol(IpNextProtocol::Sctp)
+            .flags(Ipv4Flags::DontFragment)
+            .payload(sctp_packet)
+            .build()
+            .to_bytes(),
+        (IpAddr::V6(src), IpAddr::V6(dst)) => Ipv6PacketBuilder::new()
+            .source(src)
+            .destination(dst)
+            .next_header(IpNextProtocol::Sctp)
+            .payload(sctp_packet)
+            .build()
+            .to_bytes(),
+        _ => panic!("Source and destination IP version mismatch"),
+    };
+
+    let ethernet_packet = EthernetPacketBuilder::new()
+        .source(if is_ip_packet {
+            MacAddr::zero()
+        } else {
+            src_mac
+        })
+        .destination(if is_ip_packet {
+            MacAddr::zero()
+        } else {
+            dst_mac
+        })
+        .ethertype(match dst_ip {
+            IpAddr::V4(_) => EtherType::Ipv4,
+            IpAddr::V6(_) => EtherType::Ipv6,
+        })
+        .payload(ip_packet)
+        .build();
+
+    let packet: Bytes = if is_ip_packet {
+        ethernet_packet.ip_packet().unwrap()
+    } else {
+        ethernet_packet.to_bytes()
+    };
+
+    packet.to_vec()
+}
+
+/// Compute the checksum of an SCTP packet, with its checksum field taken as zero
+fn sctp_checksum(packet: &[u8]) -> u32 {
+    crc32c(packet.iter().enumerate().map(|(i, byte)| if (8..12).contains(&i) { 0 } else { *byte }))
+}
+
+/// CRC32c (Castagnoli), the checksum of SCTP (RFC 9260 Appendix A)
+fn crc32c(bytes: impl IntoIterator<Item = u8>) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            // Reflected polynomial 0x1EDC6F41
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_is_crc32c() {
+        assert_eq!(crc32c(*b"123456789"), 0xe306_9283);
+        // The checksum field is taken as zero
+        let mut packet = build_sctp_init(1, 2, 3);
+        let checksum = sctp_checksum(&packet);
+        packet[8..12].copy_from_slice(&[0xff; 4]);
+        assert_eq!(sctp_checksum(&packet), checksum);
+    }
+
+    #[test]
+    fn init_chunk_is_parsed_back() {
+        let packet = build_sctp_init(44323, 3868, 0x1234_5678);
+        assert_eq!(packet.len(), SCTP_HEADER_LEN + INIT_CHUNK_LEN);
+        let header = parse_sctp_packet(&packet).unwrap();
+        assert_eq!(header, SctpHeader { src_port: 44323, dst_port: 3868, verification_tag: 0, chunk_type: chunk::INIT });
+        // Corrupted packets fail the checksum
+        let mut corrupted = packet;
+        corrupted[20] ^= 1;
+        assert_eq!(parse_sctp_packet(&corrupted), None);
+    }
+}
@@ -9,46 +9,83 @@ use nex::packet::ip::IpNextProtocol;
 use nex::packet::ipv4::Ipv4Flags;
 use nex::packet::packet::Packet;
 use nex::packet::tcp::{TcpFlags, TcpOptionPacket};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::IpAddr;
 
 use crate::config::default::DEFAULT_LOCAL_TCP_PORT;
+use crate::nei::cache::NeighborCache;
+use crate::probe::ProbeSource;
 
 /// Build TCP SYN packet with default options
 pub fn build_tcp_syn_packet(
     interface: &Interface,
+    neighbors: &NeighborCache,
+    source: &ProbeSource,
     dst_ip: IpAddr,
     dst_port: u16,
     is_ip_packet: bool
 ) -> Vec<u8> {
-    let src_mac = interface.mac_addr.unwrap_or(MacAddr::zero());
-    let dst_mac = match &interface.gateway {
-        Some(gateway) => gateway.mac_addr,
-        None => MacAddr::zero(),
-    };
-    let src_ipv4 = crate::interface::get_interface_ipv4(interface).unwrap_or(Ipv4Addr::UNSPECIFIED);
-    let src_global_ipv6 =
-        crate::interface::get_interface_global_ipv6(interface).unwrap_or(Ipv6Addr::UNSPECIFIED);
-    let src_local_ipv6 =
-        crate::interface::get_interface_local_ipv6(interface).unwrap_or(Ipv6Addr::UNSPECIFIED);
+    build_tcp_probe_packet(interface, neighbors, source, dst_ip, dst_port, TcpFlags::SYN, is_ip_packet)
+}
 
-    let src_ip: IpAddr = match dst_ip {
-        IpAddr::V4(_) => {
-            IpAddr::V4(src_ipv4)
-        },
-        IpAddr::V6(_) => {
-            if nex::net::ip::is_global_ip(&dst_ip) {
-                IpAddr::V6(src_global_ipv6)
-            } else {
-                IpAddr::V6(src_local_ipv6)
-            }
-        },
-    };
+/// Build TCP probe packet with the given flags and default options
+pub fn build_tcp_probe_packet(
+    interface: &Interface,
+    neighbors: &NeighborCache,
+    source: &ProbeSource,
+    dst_ip: IpAddr,
+    dst_port: u16,
+    flags: u8,
+    is_ip_packet: bool
+) -> Vec<u8> {
+    let fields = TcpProbeFields { src_port: source.port_or(DEFAULT_LOCAL_TCP_PORT), flags, ..Default::default() };
+    build_tcp_packet(interface, neighbors, source, dst_ip, dst_port, &fields, is_ip_packet)
+}
+
+/// Header fields of a TCP probe
+#[derive(Clone, Copy, Debug)]
+pub struct TcpProbeFields {
+    pub src_port: u16,
+    pub flags: u8,
+    pub sequence: u32,
+    pub acknowledgement: u32,
+    /// TSval of the timestamp option
+    pub timestamp: u32,
+}
+
+impl Default for TcpProbeFields {
+    fn default() -> Self {
+        Self {
+            src_port: DEFAULT_LOCAL_TCP_PORT,
+            flags: TcpFlags::SYN,
+            sequence: 0,
+            acknowledgement: 0,
+            timestamp: u32::MAX,
+        }
+    }
+}
+
+/// Build TCP packet with the given header fields and default options.
+/// The source port is taken from `fields`, the source address from `source`.
+pub fn build_tcp_packet(
+    interface: &Interface,
+    neighbors: &NeighborCache,
+    source: &ProbeSource,
+    dst_ip: IpAddr,
+    dst_port: u16,
+    fields: &TcpProbeFields,
+    is_ip_packet: bool
+) -> Vec<u8> {
+    let src_mac = interface.mac_addr.unwrap_or(MacAddr::zero());
+    let dst_mac = neighbors.next_hop_mac(interface, dst_ip);
+    let src_ip = source.ip_for(interface, dst_ip);
 
-    // Packet builder for TCP SYN
+    // Packet builder for TCP probe
     let tcp_packet = TcpPacketBuilder::new(src_ip, dst_ip)
-        .source(DEFAULT_LOCAL_TCP_PORT)
+        .source(fields.src_port)
         .destination(dst_port)
-        .flags(TcpFlags::SYN)
+        .sequence(fields.sequence)
+        .acknowledgement(fields.acknowledgement)
+        .flags(fields.flags)
         .window(65535)
         .options(vec![
             TcpOptionPacket::mss(1460),
@@ -56,7 +93,7 @@ pub fn build_tcp_syn_packet(
             TcpOptionPacket::wscale(6),
             TcpOptionPacket::nop(),
             TcpOptionPacket::nop(),
-            TcpOptionPacket::timestamp(u32::MAX, u32::MIN),
+            TcpOptionPacket::timestamp(fields.timestamp, u32::MIN),
             TcpOptionPacket::sack_perm(),
         ])
         .build();
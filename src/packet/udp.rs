@@ -8,40 +8,28 @@ use nex::packet::ethernet::EtherType;
 use nex::packet::ip::IpNextProtocol;
 use nex::packet::ipv4::Ipv4Flags;
 use nex::packet::packet::Packet;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::IpAddr;
 
 use crate::config::default::DEFAULT_LOCAL_UDP_PORT;
+use crate::nei::cache::NeighborCache;
+use crate::probe::ProbeSource;
 use crate::trace::TraceSetting;
 
 /// Build UDP packet
-pub fn build_udp_packet(interface: &Interface, dst_ip: IpAddr, dst_port: u16, is_ip_packet: bool) -> Vec<u8> {
+pub fn build_udp_packet(interface: &Interface, neighbors: &NeighborCache, source: &ProbeSource, dst_ip: IpAddr, dst_port: u16, is_ip_packet: bool) -> Vec<u8> {
+    build_udp_probe_packet(interface, neighbors, source, dst_ip, dst_port, &[], is_ip_packet)
+}
+
+/// Build UDP packet carrying the given probe payload
+pub fn build_udp_probe_packet(interface: &Interface, neighbors: &NeighborCache, source: &ProbeSource, dst_ip: IpAddr, dst_port: u16, payload: &[u8], is_ip_packet: bool) -> Vec<u8> {
     let src_mac = interface.mac_addr.unwrap_or(MacAddr::zero());
-    let dst_mac = match &interface.gateway {
-        Some(gateway) => gateway.mac_addr,
-        None => MacAddr::zero(),
-    };
-    let src_ipv4 = crate::interface::get_interface_ipv4(interface).unwrap_or(Ipv4Addr::UNSPECIFIED);
-    let src_global_ipv6 =
-        crate::interface::get_interface_global_ipv6(interface).unwrap_or(Ipv6Addr::UNSPECIFIED);
-    let src_local_ipv6 =
-        crate::interface::get_interface_local_ipv6(interface).unwrap_or(Ipv6Addr::UNSPECIFIED);
-
-    let src_ip: IpAddr = match dst_ip {
-        IpAddr::V4(_) => {
-            IpAddr::V4(src_ipv4)
-        },
-        IpAddr::V6(_) => {
-            if nex::net::ip::is_global_ip(&dst_ip) {
-                IpAddr::V6(src_global_ipv6)
-            } else {
-                IpAddr::V6(src_local_ipv6)
-            }
-        },
-    };
+    let dst_mac = neighbors.next_hop_mac(interface, dst_ip);
+    let src_ip = source.ip_for(interface, dst_ip);
 
     let udp_packet = UdpPacketBuilder::new(src_ip, dst_ip)
-        .source(DEFAULT_LOCAL_UDP_PORT)
+        .source(source.port_or(DEFAULT_LOCAL_UDP_PORT))
         .destination(dst_port)
+        .payload(Bytes::copy_from_slice(payload))
         .build();
 
     let ip_packet: Bytes = match (src_ip, dst_ip) {
@@ -91,37 +79,17 @@ pub fn build_udp_packet(interface: &Interface, dst_ip: IpAddr, dst_port: u16, is
 }
 
 /// Build UDP packet for traceroute with specific TTL
-pub fn build_udp_trace_packet(interface: &Interface, trace_setting: &TraceSetting, seq_ttl: u8) -> Vec<u8> {
+pub fn build_udp_trace_packet(interface: &Interface, neighbors: &NeighborCache, trace_setting: &TraceSetting, seq_ttl: u8) -> Vec<u8> {
     let src_mac = interface.mac_addr.unwrap_or(MacAddr::zero());
-    let dst_mac = match &interface.gateway {
-        Some(gateway) => gateway.mac_addr,
-        None => MacAddr::zero(),
-    };
-    let src_ipv4 = crate::interface::get_interface_ipv4(interface).unwrap_or(Ipv4Addr::UNSPECIFIED);
-    let src_global_ipv6 =
-        crate::interface::get_interface_global_ipv6(interface).unwrap_or(Ipv6Addr::UNSPECIFIED);
-    let src_local_ipv6 =
-        crate::interface::get_interface_local_ipv6(interface).unwrap_or(Ipv6Addr::UNSPECIFIED);
-
-    let src_ip: IpAddr = match trace_setting.dst_ip {
-        IpAddr::V4(_) => {
-            IpAddr::V4(src_ipv4)
-        },
-        IpAddr::V6(_) => {
-            if nex::net::ip::is_global_ip(&trace_setting.dst_ip) {
-                IpAddr::V6(src_global_ipv6)
-            } else {
-                IpAddr::V6(src_local_ipv6)
-            }
-        },
-    };
+    let dst_mac = neighbors.next_hop_mac(interface, trace_setting.dst_ip);
+    let src_ip = trace_setting.source.ip_for(interface, trace_setting.dst_ip);
 
     let dst_port = trace_setting.dst_port.unwrap_or(DEFAULT_LOCAL_UDP_PORT);
 
-    let is_ip_packet = interface.is_tun() || interface.is_loopback();
+    let is_ip_packet = crate::interface::is_layer3(interface) || interface.is_loopback();
 
     let udp_packet = UdpPacketBuilder::new(src_ip, trace_setting.dst_ip)
-        .source(DEFAULT_LOCAL_UDP_PORT)
+        .source(trace_setting.source.port_or(DEFAULT_LOCAL_UDP_PORT))
         .destination(dst_port)
         .build();
 
@@ -2,16 +2,17 @@ pub mod pinger;
 pub mod setting;
 pub mod result;
 pub mod probe;
+pub mod socket;
 
 use std::time::Duration;
 
 use anyhow::Result;
 use netdev::Interface;
 
-use crate::{endpoint::Host, ping::{pinger::Pinger, setting::PingSetting}};
+use crate::{endpoint::Host, ping::{pinger::Pinger, setting::PingSetting}, probe::ProbeSource, util::privilege::socket_access};
 
 // Check reachability of the target and measure latency before probing
-pub async fn initial_ping(interface: &Interface, dst_host: &Host, port: Option<u16>) -> Result<Duration> {
+pub async fn initial_ping(interface: &Interface, dst_host: &Host, port: Option<u16>, source: ProbeSource) -> Result<Duration> {
     match dst_host.hostname {
         Some(ref name) => {
             tracing::info!("Performing initial ping to {} ({})", name, dst_host.ip);
@@ -21,37 +22,50 @@ pub async fn initial_ping(interface: &Interface, dst_host: &Host, port: Option<u
         }
     }
     
+    // Without raw sockets, ICMP and TCP pings use unprivileged sockets and UDP ping is skipped
+    let access = socket_access();
+    let unprivileged = !access.raw;
+
     // 1. Try ICMP ping
-    let icmp_setting: PingSetting = PingSetting::icmp_ping(&interface, dst_host.clone(), 1)?;
-    let pinger = Pinger::new(icmp_setting);
-    match pinger.run().await {
-        Ok(r) => {
-            if let Some(first) = r.first_response() {
-                return Ok(first.rtt);
+    if access.raw || access.icmp_datagram {
+        let mut icmp_setting: PingSetting = PingSetting::icmp_ping(&interface, dst_host.clone(), 1)?;
+        icmp_setting.unprivileged = unprivileged;
+        icmp_setting.source = source;
+        let pinger = Pinger::new(icmp_setting);
+        match pinger.run().await {
+            Ok(r) => {
+                if let Some(first) = r.first_response() {
+                    return Ok(first.rtt);
+                }
+            },
+            Err(e) => {
+                tracing::warn!("Initial ICMP ping failed: {}", e);
             }
-        },
-        Err(e) => {
-            tracing::warn!("Initial ICMP ping failed: {}", e);
         }
     }
     
     // 2. Try UDP ping
-    let udp_setting: PingSetting = PingSetting::udp_ping(&interface, dst_host.clone(), 1)?;
-    let pinger = Pinger::new(udp_setting);
-    match pinger.run().await {
-        Ok(r) => {
-            if let Some(first) = r.first_response() {
-                return Ok(first.rtt);
+    if access.raw {
+        let mut udp_setting: PingSetting = PingSetting::udp_ping(&interface, dst_host.clone(), 1)?;
+        udp_setting.source = source;
+        let pinger = Pinger::new(udp_setting);
+        match pinger.run().await {
+            Ok(r) => {
+                if let Some(first) = r.first_response() {
+                    return Ok(first.rtt);
+                }
+            },
+            Err(e) => {
+                tracing::warn!("Initial UDP ping failed: {}", e);
             }
-        },
-        Err(e) => {
-            tracing::warn!("Initial UDP ping failed: {}", e);
         }
     }
 
     // 3. Try TCP ping
     let target_port = port.unwrap_or(80);
-    let tcp_setting: PingSetting = PingSetting::tcp_ping(&interface, dst_host.clone(), target_port, 1)?;
+    let mut tcp_setting: PingSetting = PingSetting::tcp_ping(&interface, dst_host.clone(), target_port, 1)?;
+    tcp_setting.unprivileged = unprivileged;
+    tcp_setting.source = source;
     let pinger = Pinger::new(tcp_setting);
     match pinger.run().await {
         Ok(r) => {
@@ -4,6 +4,7 @@ use anyhow::Result;
 /// Pinger structure.
 ///
 /// Supports ICMP Ping, TCP Ping, UDP Ping.
+/// Unprivileged settings use ICMP datagram sockets and TCP connect() instead of raw sockets.
 #[derive(Clone, Debug)]
 pub struct Pinger {
     /// Probe Setting
@@ -17,10 +18,13 @@ impl Pinger {
     }
     /// Run the ping based on the specified protocol and return the results.
     pub async fn run(&self) -> Result<PingResult> {
-        match self.ping_setting.protocol {
-            Protocol::Icmp => super::probe::icmp::run_icmp_ping(&self.ping_setting).await,
-            Protocol::Udp => super::probe::udp::run_udp_ping(&self.ping_setting).await,
-            Protocol::Tcp => super::probe::tcp::run_tcp_ping(&self.ping_setting).await,
+        match (self.ping_setting.protocol, self.ping_setting.unprivileged) {
+            (Protocol::Icmp, false) => super::probe::icmp::run_icmp_ping(&self.ping_setting).await,
+            (Protocol::Icmp, true) => super::probe::icmp::run_datagram_icmp_ping(&self.ping_setting).await,
+            (Protocol::Udp, false) => super::probe::udp::run_udp_ping(&self.ping_setting).await,
+            (Protocol::Udp, true) => Err(crate::util::privilege::raw_required("UDP ping")),
+            (Protocol::Tcp, false) => super::probe::tcp::run_tcp_ping(&self.ping_setting).await,
+            (Protocol::Tcp, true) => super::probe::tcp::run_connect_ping(&self.ping_setting).await,
             _ => {
                 Err(anyhow::anyhow!("Unsupported protocol"))
             },
@@ -8,11 +8,11 @@ use nex::packet::frame::{Frame, ParseOption};
 use nex::packet::icmp::IcmpType;
 use nex::packet::icmpv6::Icmpv6Type;
 use crate::endpoint::NodeType;
+use crate::nei::cache::NeighborCache;
 use crate::ping::result::PingStat;
 use crate::probe::{ProbeStatus, ProbeStatusKind};
 use crate::{ping::{result::PingResult, setting::PingSetting}, probe::ProbeResult, protocol::Protocol};
 use anyhow::Result;
-use nex::datalink::async_io::{async_channel, AsyncChannel};
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 
 /// Run ICMP Ping and return the results.
@@ -24,32 +24,15 @@ pub async fn run_icmp_ping(setting: &PingSetting) -> Result<PingResult> {
         Some(interface) => interface,
         None => return Err(anyhow::anyhow!("Interface not found")),
     };
+    let neighbors = NeighborCache::default();
+    neighbors.resolve(&interface, [setting.dst_ip], setting.receive_timeout).await;
     let src_ip_set: HashSet<IpAddr> = interface.ip_addrs().iter().map(|ip| ip.clone()).collect();
     // Create sender
-    let config = nex::datalink::Config {
-        write_buffer_size: 4096,
-        read_buffer_size: 4096,
-        read_timeout: Some(setting.receive_timeout),
-        write_timeout: None,
-        channel_type: nex::datalink::ChannelType::Layer2,
-        bpf_fd_attempts: 1000,
-        linux_fanout: None,
-        promiscuous: false,
-    };
-
-    let AsyncChannel::Ethernet(mut tx, mut rx) = async_channel(&interface, config)?
-    else {
-        unreachable!();
-    };
+    let (mut tx, mut rx) = crate::capture::channel::open(&interface, setting.receive_timeout)?;
 
     let mut responses: Vec<ProbeResult> = Vec::new();
 
-    let mut parse_option: ParseOption = ParseOption::default();
-    if interface.is_tun() || (cfg!(any(target_os = "macos", target_os = "ios")) && interface.is_loopback()) {
-        let payload_offset = if interface.is_loopback() { 14 } else { 0 };
-        parse_option.from_ip_packet = true;
-        parse_option.offset = payload_offset;
-    }
+    let parse_option: ParseOption = crate::interface::parse_option(&interface);
 
     let header_span = tracing::info_span!("ping");
     header_span.pb_set_style(&crate::output::progress::get_progress_style());
@@ -57,9 +40,10 @@ pub async fn run_icmp_ping(setting: &PingSetting) -> Result<PingResult> {
     header_span.pb_set_length(setting.count as u64);
     header_span.pb_set_position(0);
     header_span.pb_start();
+    let ip_packet = crate::interface::is_layer3(&interface);
     
     let start_time = Instant::now();
-    let icmp_packet = crate::packet::icmp::build_icmp_packet(&interface, setting.dst_ip, false);
+    let icmp_packet = crate::packet::icmp::build_icmp_packet(&interface, &neighbors, &setting.source, setting.dst_ip, ip_packet);
     for seq in 1..setting.count + 1 {
         let send_time = Instant::now();
         match poll_fn(|cx| tx.poll_send(cx, &icmp_packet)).await {
@@ -262,3 +246,81 @@ pub async fn run_icmp_ping(setting: &PingSetting) -> Result<PingResult> {
 
     Ok(result)
 }
+
+/// Run ICMP Ping over an unprivileged ICMP datagram socket and return the results.
+/// The kernel hides the IP header of replies, so their TTL is not known.
+pub async fn run_datagram_icmp_ping(setting: &PingSetting) -> Result<PingResult> {
+    let socket = crate::ping::socket::IcmpSocket::open(setting.dst_ip.is_ipv6(), setting.source.ip_addr)?;
+    let mut responses: Vec<ProbeResult> = Vec::new();
+
+    let header_span = tracing::info_span!("ping");
+    header_span.pb_set_style(&crate::output::progress::get_progress_style());
+    header_span.pb_set_message(&format!("ping ({})", setting.dst_ip));
+    header_span.pb_set_length(setting.count as u64);
+    header_span.pb_set_position(0);
+    header_span.pb_start();
+
+    let start_time = Instant::now();
+    for seq in 1..setting.count + 1 {
+        let send_time = Instant::now();
+        let sent_size = match socket.send_echo(setting.dst_ip, seq as u16).await {
+            Ok(size) => size,
+            Err(e) => {
+                tracing::error!("Failed to send packet: {}", e);
+                0
+            }
+        };
+        let reply = tokio::time::timeout(setting.receive_timeout, async {
+            loop {
+                match socket.recv_reply().await {
+                    Ok(reply) if reply.src == setting.dst_ip && reply.seq == seq as u16 => return Ok(reply),
+                    Ok(_) => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        })
+        .await;
+        match reply {
+            Ok(Ok(reply)) => {
+                let rtt = reply.received_at.duration_since(send_time);
+                tracing::info!("Reply from {}, bytes={} RTT={:?}", setting.dst_ip, reply.size, rtt);
+                responses.push(ProbeResult {
+                    seq,
+                    mac_addr: MacAddr::zero(),
+                    ip_addr: setting.dst_ip,
+                    host_name: setting.dst_hostname.clone(),
+                    port_number: None,
+                    port_status: None,
+                    ttl: 0,
+                    hop: 0,
+                    rtt,
+                    probe_status: ProbeStatus::new(),
+                    protocol: Protocol::Icmp,
+                    node_type: NodeType::Destination,
+                    sent_packet_size: sent_size,
+                    received_packet_size: reply.size,
+                });
+            }
+            Ok(Err(e)) => {
+                tracing::error!("Failed to receive packet: {}", e);
+            }
+            Err(_) => {
+                tracing::error!("Request timeout for seq {}", seq);
+                responses.push(ProbeResult::timeout(seq, setting.dst_ip, setting.dst_hostname.clone(), Protocol::Icmp, sent_size));
+            }
+        }
+        header_span.pb_inc(1);
+        if !setting.send_rate.is_zero() && seq < setting.count {
+            tokio::time::sleep(setting.send_rate).await;
+        }
+    }
+    drop(header_span);
+
+    let mut result = PingResult::new();
+    result.elapsed_time = start_time.elapsed();
+    result.ip_addr = setting.dst_ip;
+    result.hostname = setting.dst_hostname.clone();
+    result.protocol = Protocol::Icmp;
+    result.stat = PingStat::from_responses(responses, result.elapsed_time, setting.count as usize);
+    Ok(result)
+}
@@ -1,4 +1,5 @@
 use std::collections::HashSet;
+use std::io::ErrorKind;
 use std::net::IpAddr;
 use std::time::{Duration, Instant};
 use futures::stream::StreamExt;
@@ -7,11 +8,13 @@ use netdev::MacAddr;
 use nex::packet::frame::{Frame, ParseOption};
 use nex::packet::tcp::TcpFlags;
 use crate::endpoint::{NodeType, PortState};
+use crate::nei::cache::NeighborCache;
 use crate::ping::result::PingStat;
 use crate::probe::{ProbeStatus, ProbeStatusKind};
 use crate::{ping::{result::PingResult, setting::PingSetting}, probe::ProbeResult, protocol::Protocol};
 use anyhow::Result;
-use nex::datalink::async_io::{async_channel, AsyncChannel};
+use nex::socket::tcp::AsyncTcpSocket;
+use tokio::io::AsyncWriteExt;
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 
 /// Run TCP Ping and return the results.
@@ -24,32 +27,15 @@ pub async fn run_tcp_ping(setting: &PingSetting) -> Result<PingResult> {
         Some(interface) => interface,
         None => return Err(anyhow::anyhow!("Interface not found")),
     };
+    let neighbors = NeighborCache::default();
+    neighbors.resolve(&interface, [setting.dst_ip], setting.receive_timeout).await;
     let src_ip_set: HashSet<IpAddr> = interface.ip_addrs().iter().map(|ip| ip.clone()).collect();
     // Create sender
-    let config = nex::datalink::Config {
-        write_buffer_size: 4096,
-        read_buffer_size: 4096,
-        read_timeout: Some(setting.receive_timeout),
-        write_timeout: None,
-        channel_type: nex::datalink::ChannelType::Layer2,
-        bpf_fd_attempts: 1000,
-        linux_fanout: None,
-        promiscuous: false,
-    };
-
-    let AsyncChannel::Ethernet(mut tx, mut rx) = async_channel(&interface, config)?
-    else {
-        unreachable!();
-    };
+    let (mut tx, mut rx) = crate::capture::channel::open(&interface, setting.receive_timeout)?;
 
     let mut responses: Vec<ProbeResult> = Vec::new();
 
-    let mut parse_option: ParseOption = ParseOption::default();
-    if interface.is_tun() || (cfg!(any(target_os = "macos", target_os = "ios")) && interface.is_loopback()) {
-        let payload_offset = if interface.is_loopback() { 14 } else { 0 };
-        parse_option.from_ip_packet = true;
-        parse_option.offset = payload_offset;
-    }
+    let parse_option: ParseOption = crate::interface::parse_option(&interface);
 
     let header_span = tracing::info_span!("ping");
     header_span.pb_set_style(&crate::output::progress::get_progress_style());
@@ -57,9 +43,10 @@ pub async fn run_tcp_ping(setting: &PingSetting) -> Result<PingResult> {
     header_span.pb_set_length(setting.count as u64);
     header_span.pb_set_position(0);
     header_span.pb_start();
+    let ip_packet = crate::interface::is_layer3(&interface);
     
     let start_time = Instant::now();
-    let tcp_packet = crate::packet::tcp::build_tcp_syn_packet(&interface, setting.dst_ip, dst_port, false);
+    let tcp_packet = crate::packet::tcp::build_tcp_syn_packet(&interface, &neighbors, &setting.source, setting.dst_ip, dst_port, ip_packet);
     for seq in 1..setting.count + 1 {
         let send_time = Instant::now();
         match poll_fn(|cx| tx.poll_send(cx, &tcp_packet)).await {
@@ -93,6 +80,10 @@ pub async fn run_tcp_ping(setting: &PingSetting) -> Result<PingResult> {
                             {
                                 if let Some(transport) = &frame.transport {
                                     if let Some(tcp) = &transport.tcp {
+                                        // Replies come from the probed port, unlike the looped-back probe itself
+                                        if tcp.source != dst_port {
+                                            continue;
+                                        }
                                         let port_state: PortState = if (tcp.flags & (TcpFlags::SYN | TcpFlags::ACK)) == (TcpFlags::SYN | TcpFlags::ACK) {
                                             PortState::Open
                                         } else if (tcp.flags & TcpFlags::RST) != 0 {
@@ -132,6 +123,10 @@ pub async fn run_tcp_ping(setting: &PingSetting) -> Result<PingResult> {
                             {
                                 if let Some(transport) = &frame.transport {
                                     if let Some(tcp) = &transport.tcp {
+                                        // Replies come from the probed port, unlike the looped-back probe itself
+                                        if tcp.source != dst_port {
+                                            continue;
+                                        }
                                         let port_state: PortState = if (tcp.flags & (TcpFlags::SYN | TcpFlags::ACK)) == (TcpFlags::SYN | TcpFlags::ACK) {
                                             PortState::Open
                                         } else if (tcp.flags & TcpFlags::RST) != 0 {
@@ -274,3 +269,77 @@ pub async fn run_tcp_ping(setting: &PingSetting) -> Result<PingResult> {
 
     Ok(result)
 }
+
+/// Run TCP Ping with connect() calls and return the results. Needs no raw sockets:
+/// an established connection means the port is open, a refused one that it is closed.
+pub async fn run_connect_ping(setting: &PingSetting) -> Result<PingResult> {
+    let dst_port: u16 = setting.dst_port.unwrap_or(80);
+    let socket_addr = std::net::SocketAddr::new(setting.dst_ip, dst_port);
+    let cfg = setting.source.tcp_config(setting.dst_ip);
+    let mut responses: Vec<ProbeResult> = Vec::new();
+
+    let header_span = tracing::info_span!("ping");
+    header_span.pb_set_style(&crate::output::progress::get_progress_style());
+    header_span.pb_set_message(&format!("ping ({})", setting.dst_ip));
+    header_span.pb_set_length(setting.count as u64);
+    header_span.pb_set_position(0);
+    header_span.pb_start();
+
+    let start_time = Instant::now();
+    for seq in 1..setting.count + 1 {
+        let socket = AsyncTcpSocket::from_config(&cfg)?;
+        let send_time = Instant::now();
+        let port_state = match socket.connect_timeout(socket_addr, setting.receive_timeout).await {
+            Ok(mut stream) => {
+                let _ = stream.shutdown().await;
+                Some(PortState::Open)
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset) => Some(PortState::Closed),
+            Err(e) if e.kind() == ErrorKind::TimedOut => None,
+            Err(e) => {
+                tracing::error!("Failed to connect to {}: {}", socket_addr, e);
+                None
+            }
+        };
+        let rtt = send_time.elapsed();
+        match port_state {
+            Some(port_state) => {
+                tracing::info!("Reply from {}:{}, State={} RTT={:?}", setting.dst_ip, dst_port, port_state.as_str(), rtt);
+                responses.push(ProbeResult {
+                    seq,
+                    mac_addr: MacAddr::zero(),
+                    ip_addr: setting.dst_ip,
+                    host_name: setting.dst_hostname.clone(),
+                    port_number: Some(dst_port),
+                    port_status: Some(port_state),
+                    ttl: 0,
+                    hop: 0,
+                    rtt,
+                    probe_status: ProbeStatus::new(),
+                    protocol: Protocol::Tcp,
+                    node_type: NodeType::Destination,
+                    sent_packet_size: 0,
+                    received_packet_size: 0,
+                });
+            }
+            None => {
+                tracing::error!("Request timeout for seq {}", seq);
+                responses.push(ProbeResult::timeout(seq, setting.dst_ip, setting.dst_hostname.clone(), Protocol::Tcp, 0));
+            }
+        }
+        header_span.pb_inc(1);
+        if !setting.send_rate.is_zero() && seq < setting.count {
+            tokio::time::sleep(setting.send_rate).await;
+        }
+    }
+    drop(header_span);
+
+    let mut result = PingResult::new();
+    result.elapsed_time = start_time.elapsed();
+    result.ip_addr = setting.dst_ip;
+    result.hostname = setting.dst_hostname.clone();
+    result.port_number = Some(dst_port);
+    result.protocol = Protocol::Tcp;
+    result.stat = PingStat::from_responses(responses, result.elapsed_time, setting.count as usize);
+    Ok(result)
+}
@@ -9,11 +9,11 @@ use nex::packet::icmp::IcmpType;
 use nex::packet::icmpv6::Icmpv6Type;
 use crate::config::default::DEFAULT_BASE_TARGET_UDP_PORT;
 use crate::endpoint::NodeType;
+use crate::nei::cache::NeighborCache;
 use crate::ping::result::PingStat;
 use crate::probe::{ProbeStatus, ProbeStatusKind};
 use crate::{ping::{result::PingResult, setting::PingSetting}, probe::ProbeResult, protocol::Protocol};
 use anyhow::Result;
-use nex::datalink::async_io::{async_channel, AsyncChannel};
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 
 /// Run UDP Ping and return the results.
@@ -25,32 +25,15 @@ pub async fn run_udp_ping(setting: &PingSetting) -> Result<PingResult> {
         Some(interface) => interface,
         None => return Err(anyhow::anyhow!("Interface not found")),
     };
+    let neighbors = NeighborCache::default();
+    neighbors.resolve(&interface, [setting.dst_ip], setting.receive_timeout).await;
     let src_ip_set: HashSet<IpAddr> = interface.ip_addrs().iter().map(|ip| ip.clone()).collect();
     // Create sender
-    let config = nex::datalink::Config {
-        write_buffer_size: 4096,
-        read_buffer_size: 4096,
-        read_timeout: Some(setting.receive_timeout),
-        write_timeout: None,
-        channel_type: nex::datalink::ChannelType::Layer2,
-        bpf_fd_attempts: 1000,
-        linux_fanout: None,
-        promiscuous: false,
-    };
-
-    let AsyncChannel::Ethernet(mut tx, mut rx) = async_channel(&interface, config)?
-    else {
-        unreachable!();
-    };
+    let (mut tx, mut rx) = crate::capture::channel::open(&interface, setting.receive_timeout)?;
 
     let mut responses: Vec<ProbeResult> = Vec::new();
 
-    let mut parse_option: ParseOption = ParseOption::default();
-    if interface.is_tun() || (cfg!(any(target_os = "macos", target_os = "ios")) && interface.is_loopback()) {
-        let payload_offset = if interface.is_loopback() { 14 } else { 0 };
-        parse_option.from_ip_packet = true;
-        parse_option.offset = payload_offset;
-    }
+    let parse_option: ParseOption = crate::interface::parse_option(&interface);
 
     let header_span = tracing::info_span!("ping");
     header_span.pb_set_style(&crate::output::progress::get_progress_style());
@@ -58,9 +41,10 @@ pub async fn run_udp_ping(setting: &PingSetting) -> Result<PingResult> {
     header_span.pb_set_length(setting.count as u64);
     header_span.pb_set_position(0);
     header_span.pb_start();
+    let ip_packet = crate::interface::is_layer3(&interface);
     
     let start_time = Instant::now();
-    let udp_packet = crate::packet::udp::build_udp_packet(&interface, setting.dst_ip, DEFAULT_BASE_TARGET_UDP_PORT, false);
+    let udp_packet = crate::packet::udp::build_udp_packet(&interface, &neighbors, &setting.source, setting.dst_ip, DEFAULT_BASE_TARGET_UDP_PORT, ip_packet);
     for seq in 1..setting.count + 1 {
         let send_time = Instant::now();
         match poll_fn(|cx| tx.poll_send(cx, &udp_packet)).await {
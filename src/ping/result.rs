@@ -1,4 +1,4 @@
-use crate::{probe::{ProbeResult, ProbeStatus, ProbeStatusKind}, protocol::Protocol};
+use crate::{probe::{ProbeResult, ProbeStatus, ProbeStatusKind}, protocol::Protocol, util::privilege::Downgrade};
 use serde::{Deserialize, Serialize};
 use std::{net::{IpAddr, Ipv4Addr}, time::Duration};
 
@@ -33,6 +33,23 @@ impl PingStat {
             max: None,
         }
     }
+    /// Summarize the responses of a ping. RTTs are taken from answered probes only.
+    pub fn from_responses(responses: Vec<ProbeResult>, probe_time: Duration, transmitted_count: usize) -> PingStat {
+        let rtts: Vec<Duration> = responses
+            .iter()
+            .filter(|r| r.probe_status.kind == ProbeStatusKind::Done)
+            .map(|r| r.rtt)
+            .collect();
+        PingStat {
+            probe_time,
+            transmitted_count,
+            received_count: rtts.len(),
+            min: rtts.iter().min().copied(),
+            avg: if rtts.is_empty() { None } else { Some(rtts.iter().sum::<Duration>() / rtts.len() as u32) },
+            max: rtts.iter().max().copied(),
+            responses,
+        }
+    }
 }
 
 /// Result of a ping operation
@@ -45,6 +62,9 @@ pub struct PingResult {
     pub hostname: Option<String>,
     pub port_number: Option<u16>,
     pub protocol: Protocol,
+    /// Set when the ping fell back to an unprivileged technique
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downgrade: Option<Downgrade>,
 }
 
 impl PingResult {
@@ -57,6 +77,7 @@ impl PingResult {
             hostname: None,
             port_number: None,
             protocol: Protocol::Icmp,
+            downgrade: None,
         }
     }
     /// Return first successful response
@@ -5,6 +5,7 @@ use netdev::Interface;
 use serde::{Deserialize, Serialize};
 use crate::config::default::{DEFAULT_BASE_TARGET_UDP_PORT, DEFAULT_HOP_LIMIT, DEFAULT_PING_COUNT};
 use crate::endpoint::Host;
+use crate::probe::ProbeSource;
 use crate::protocol::Protocol;
 
 /// Settings for a ping operation
@@ -22,6 +23,12 @@ pub struct PingSetting {
     pub send_rate: Duration,
     pub tunnel: bool,
     pub loopback: bool,
+    /// Use unprivileged sockets instead of raw ones
+    #[serde(default)]
+    pub unprivileged: bool,
+    /// Source address and port of probes
+    #[serde(default)]
+    pub source: ProbeSource,
 }
 
 impl Default for PingSetting {
@@ -39,6 +46,8 @@ impl Default for PingSetting {
             send_rate: Duration::from_secs(1),
             tunnel: false,
             loopback: false,
+            unprivileged: false,
+            source: ProbeSource::default(),
         }
     }
 }
@@ -66,6 +75,8 @@ impl PingSetting {
             send_rate: Duration::from_secs(1),
             tunnel: use_tun,
             loopback: loopback,
+            unprivileged: false,
+            source: ProbeSource::default(),
         };
         Ok(setting)
     }
@@ -92,6 +103,8 @@ impl PingSetting {
             send_rate: Duration::from_secs(1),
             tunnel: use_tun,
             loopback: loopback,
+            unprivileged: false,
+            source: ProbeSource::default(),
         };
         Ok(setting)
     }
@@ -117,6 +130,8 @@ impl PingSetting {
             send_rate: Duration::from_secs(1),
             tunnel: use_tun,
             loopback: loopback,
+            unprivileged: false,
+            source: ProbeSource::default(),
         };
         Ok(setting)
     }
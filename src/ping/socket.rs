@@ -0,0 +1,171 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+
+/// ICMP type of Echo Request
+const ICMP_ECHO_REQUEST: u8 = 8;
+/// ICMP type of Echo Reply
+const ICMP_ECHO_REPLY: u8 = 0;
+/// ICMPv6 type of Echo Request
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+/// ICMPv6 type of Echo Reply
+const ICMPV6_ECHO_REPLY: u8 = 129;
+/// Payload bytes of an Echo Request
+const ECHO_PAYLOAD_LEN: usize = 32;
+
+/// Echo Reply received on an [`IcmpSocket`]
+#[derive(Debug, Clone, Copy)]
+pub struct EchoReply {
+    pub src: IpAddr,
+    pub seq: u16,
+    /// Size of the ICMP message
+    pub size: usize,
+    pub received_at: Instant,
+}
+
+/// Unprivileged ICMP Echo socket (`SOCK_DGRAM` with `IPPROTO_ICMP` or `IPPROTO_ICMPV6`).
+///
+/// Linux replaces the identifier of sent requests with the local port of the socket
+/// and only delivers the matching replies to it. macOS delivers every Echo Reply, so
+/// replies are matched against the identifier of the socket there.
+#[cfg(unix)]
+pub struct IcmpSocket {
+    socket: tokio::io::unix::AsyncFd<socket2::Socket>,
+    ipv6: bool,
+    ident: u16,
+}
+
+#[cfg(unix)]
+impl IcmpSocket {
+    /// Open an IPv4 or IPv6 Echo socket, bound to `source_ip` if it is of the same IP version.
+    pub fn open(ipv6: bool, source_ip: Option<IpAddr>) -> io::Result<Self> {
+        use socket2::{Domain, Protocol, Socket, Type};
+        let (domain, protocol) = if ipv6 { (Domain::IPV6, Protocol::ICMPV6) } else { (Domain::IPV4, Protocol::ICMPV4) };
+        let socket = Socket::new(domain, Type::DGRAM, Some(protocol))?;
+        if let Some(source_ip) = source_ip.filter(|ip| ip.is_ipv6() == ipv6) {
+            socket.bind(&SocketAddr::new(source_ip, 0).into())?;
+        }
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket: tokio::io::unix::AsyncFd::new(socket)?,
+            ipv6,
+            ident: fastrand::u16(..),
+        })
+    }
+
+    /// Send an Echo Request and return the size of the ICMP message.
+    pub async fn send_echo(&self, dst: IpAddr, seq: u16) -> io::Result<usize> {
+        let packet = echo_request(self.ipv6, self.ident, seq);
+        let addr = socket2::SockAddr::from(SocketAddr::new(dst, 0));
+        loop {
+            let mut guard = self.socket.writable().await?;
+            match guard.try_io(|socket| socket.get_ref().send_to(&packet, &addr)) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Wait for the next Echo Reply. Other ICMP messages are skipped.
+    pub async fn recv_reply(&self) -> io::Result<EchoReply> {
+        let mut buf = [std::mem::MaybeUninit::<u8>::uninit(); 1500];
+        loop {
+            let mut guard = self.socket.readable().await?;
+            let (len, addr) = match guard.try_io(|socket| socket.get_ref().recv_from(&mut buf)) {
+                Ok(result) => result?,
+                Err(_would_block) => continue,
+            };
+            let received_at = Instant::now();
+            // recv_from initialized the first `len` bytes
+            let data = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, len) };
+            let Some(src) = addr.as_socket().map(|addr| addr.ip()) else {
+                continue;
+            };
+            if let Some((ident, seq, size)) = parse_echo_reply(data, self.ipv6)
+                && (cfg!(target_os = "linux") || ident == self.ident)
+            {
+                return Ok(EchoReply { src, seq, size, received_at });
+            }
+        }
+    }
+}
+
+/// ICMP datagram sockets are not available on this platform
+#[cfg(not(unix))]
+pub struct IcmpSocket;
+
+#[cfg(not(unix))]
+impl IcmpSocket {
+    pub fn open(_ipv6: bool, _source_ip: Option<IpAddr>) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "ICMP datagram sockets are not supported"))
+    }
+
+    pub async fn send_echo(&self, _dst: IpAddr, _seq: u16) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "ICMP datagram sockets are not supported"))
+    }
+
+    pub async fn recv_reply(&self) -> io::Result<EchoReply> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "ICMP datagram sockets are not supported"))
+    }
+}
+
+/// Build an Echo Request message. The kernel computes the ICMPv6 checksum.
+fn echo_request(ipv6: bool, ident: u16, seq: u16) -> Vec<u8> {
+    let mut packet = vec![0u8; 8 + ECHO_PAYLOAD_LEN];
+    packet[0] = if ipv6 { ICMPV6_ECHO_REQUEST } else { ICMP_ECHO_REQUEST };
+    packet[4..6].copy_from_slice(&ident.to_be_bytes());
+    packet[6..8].copy_from_slice(&seq.to_be_bytes());
+    for (i, byte) in packet[8..].iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    if !ipv6 {
+        let checksum = nex::packet::util::checksum(&packet, 1);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+    packet
+}
+
+/// Get the identifier, sequence number and ICMP message size of an Echo Reply.
+/// IPv4 messages may start with the IP header (macOS).
+fn parse_echo_reply(data: &[u8], ipv6: bool) -> Option<(u16, u16, usize)> {
+    let message = match data.first() {
+        Some(first) if !ipv6 && first >> 4 == 4 => data.get(usize::from(first & 0x0f) * 4..)?,
+        _ => data,
+    };
+    if message.len() < 8 {
+        return None;
+    }
+    let echo_reply = if ipv6 { ICMPV6_ECHO_REPLY } else { ICMP_ECHO_REPLY };
+    if message[0] != echo_reply || message[1] != 0 {
+        return None;
+    }
+    let ident = u16::from_be_bytes([message[4], message[5]]);
+    let seq = u16::from_be_bytes([message[6], message[7]]);
+    Some((ident, seq, message.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn echo_reply_is_parsed_with_and_without_ip_header() {
+        let mut reply = echo_request(false, 0x1234, 7);
+        reply[0] = ICMP_ECHO_REPLY;
+        assert_eq!(parse_echo_reply(&reply, false), Some((0x1234, 7, reply.len())));
+        let mut with_header = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, 1, 0, 0, 192, 0, 2, 1, 192, 0, 2, 2];
+        with_header.extend_from_slice(&reply);
+        assert_eq!(parse_echo_reply(&with_header, false), Some((0x1234, 7, reply.len())));
+        // Echo Requests are not replies
+        assert_eq!(parse_echo_reply(&echo_request(false, 0x1234, 7), false), None);
+        let mut reply_v6 = echo_request(true, 1, 2);
+        reply_v6[0] = ICMPV6_ECHO_REPLY;
+        assert_eq!(parse_echo_reply(&reply_v6, true), Some((1, 2, reply_v6.len())));
+    }
+
+    #[test]
+    fn echo_request_checksum_is_valid() {
+        let packet = echo_request(false, 0xbeef, 1);
+        assert_eq!(nex::packet::util::checksum(&packet, usize::MAX), 0);
+    }
+}
@@ -1,22 +1,54 @@
-use crate::endpoint::{Endpoint, NodeType, PortState};
+use crate::endpoint::{Endpoint, NodeType, PortState, TransportProtocol};
+use crate::nei::cache::NeighborCache;
 use crate::protocol::Protocol;
+use crate::proxy::Proxy;
+use crate::scan::engine::IoConfig;
+use crate::scan::scheduler::Scheduler;
+use crate::scan::target::ScanTargets;
+use netdev::Interface;
 use nex::net::mac::MacAddr;
+use nex::socket::tcp::TcpConfig;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::net::IpAddr;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
 use std::time::Duration;
 
 /// Settings for probe
 #[derive(Debug, Clone)]
 pub struct ProbeSetting {
     pub target_endpoints: Vec<Endpoint>,
+    /// Lazily expanded port scan targets. When set, port scans probe these
+    /// instead of the ports of `target_endpoints`.
+    pub scan_targets: Option<ScanTargets>,
     pub if_index: u32,
     pub host_concurrency: usize,
     pub port_concurrency: usize,
+    /// Connection scheduler enforcing the concurrency limits of connect scans.
+    /// Clones of the setting share it with service detection.
+    pub scheduler: Scheduler,
+    /// Next-hop MAC addresses of raw probes. Clones of the setting share the resolved
+    /// neighbors, so host discovery, port scan and OS detection resolve each once.
+    pub neighbors: NeighborCache,
+    /// Source address and port of raw probes and connect sockets
+    pub source: ProbeSource,
+    /// Proxy that connect scans are tunneled through
+    pub proxy: Option<Arc<Proxy>>,
     pub task_timeout: Duration,
     pub connect_timeout: Duration,
+    /// Per-host connect timeouts adapted to the RTT measured by host discovery.
+    /// Hosts without an entry use `connect_timeout`. Only connect and QUIC scans use them:
+    /// raw scans wait once for every host, with `wait_time` adapted to the slowest one.
+    pub host_timeouts: Arc<HashMap<IpAddr, Duration>>,
     pub wait_time: Duration,
-    pub send_rate: Duration,
+    /// Minimum send rate in packets per second (floor for adaptive backoff)
+    pub min_rate: Option<u32>,
+    /// Maximum send rate in packets per second
+    pub max_rate: Option<u32>,
+    /// Number of retransmissions for unanswered probes
+    pub retries: usize,
+    /// Packet I/O settings for raw port scans
+    pub io: IoConfig,
 }
 
 impl ProbeSetting {
@@ -28,8 +60,149 @@ impl ProbeSetting {
                 map.insert(ep.ip, hostname.clone());
             }
         }
+        if let Some(targets) = &self.scan_targets {
+            map.extend(targets.hosts().hostnames().iter().map(|(ip, hostname)| (*ip, hostname.clone())));
+        }
         map
     }
+    /// Get the connect timeout for the host
+    pub fn connect_timeout_for(&self, ip: &IpAddr) -> Duration {
+        self.host_timeouts.get(ip).copied().unwrap_or(self.connect_timeout)
+    }
+    /// Iterate over the target hosts
+    pub fn target_ips(&self) -> Box<dyn Iterator<Item = IpAddr> + Send + '_> {
+        match &self.scan_targets {
+            Some(targets) => Box::new(targets.host_iter()),
+            None => Box::new(self.target_endpoints.iter().map(|ep| ep.ip)),
+        }
+    }
+    /// Get the number of target hosts
+    pub fn target_host_count(&self) -> u64 {
+        match &self.scan_targets {
+            Some(targets) => targets.hosts().len() as u64,
+            None => self.target_endpoints.len() as u64,
+        }
+    }
+    /// Iterate over the target hosts with the number of ports probed on each
+    pub fn target_port_counts(&self, transport: TransportProtocol) -> Box<dyn Iterator<Item = (IpAddr, usize)> + Send + '_> {
+        match &self.scan_targets {
+            Some(targets) => Box::new(targets.hosts().hosts().map(|host| (host.ip, targets.ports().len()))),
+            None => Box::new(self.target_endpoints.iter().map(move |ep| (ep.ip, ep.ports.iter().filter(|p| p.transport == transport).count()))),
+        }
+    }
+    /// Iterate over the socket addresses to probe for port scanning, in scan order
+    pub fn target_addrs(&self, transport: TransportProtocol) -> Box<dyn ExactSizeIterator<Item = SocketAddr> + Send> {
+        match &self.scan_targets {
+            Some(targets) => Box::new(targets.iter()),
+            None => {
+                let addrs: Vec<SocketAddr> = self.target_endpoints.iter().flat_map(|ep| ep.socket_addrs(transport)).collect();
+                Box::new(addrs.into_iter())
+            }
+        }
+    }
+    /// Get the set of target ports for port scanning
+    pub fn target_ports(&self, transport: TransportProtocol) -> HashSet<u16> {
+        match &self.scan_targets {
+            Some(targets) => targets.ports().iter().copied().collect(),
+            None => self
+                .target_endpoints
+                .iter()
+                .flat_map(|ep| ep.ports.iter().filter(|p| p.transport == transport).map(|p| p.number))
+                .collect(),
+        }
+    }
+    /// Get the port scan targets to match replies against. Build it once per scan:
+    /// without `scan_targets`, the socket addresses of the endpoints are collected into a set.
+    pub fn target_addr_set(&self, transport: TransportProtocol) -> TargetAddrs<'_> {
+        match &self.scan_targets {
+            Some(targets) => TargetAddrs::Targets(targets),
+            None => TargetAddrs::Addrs(self.target_endpoints.iter().flat_map(|ep| ep.socket_addrs(transport)).collect()),
+        }
+    }
+}
+
+/// Port scan targets for matching replies
+#[derive(Debug)]
+pub enum TargetAddrs<'a> {
+    Targets(&'a ScanTargets),
+    Addrs(HashSet<SocketAddr>),
+}
+
+impl TargetAddrs<'_> {
+    /// Check if the socket address is one of the port scan targets
+    pub fn contains(&self, socket_addr: &SocketAddr) -> bool {
+        match self {
+            TargetAddrs::Targets(targets) => targets.contains(socket_addr),
+            TargetAddrs::Addrs(addrs) => addrs.contains(socket_addr),
+        }
+    }
+}
+
+/// Source address and port of probes, for hosts with several addresses.
+/// Unset fields are chosen per probe: the address from the interface, the port by each scan.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct ProbeSource {
+    pub ip_addr: Option<IpAddr>,
+    pub port: Option<u16>,
+}
+
+impl ProbeSource {
+    /// Create the source of probes sent on the interface. The address must be one of its own.
+    pub fn new(interface: &Interface, ip_addr: Option<IpAddr>, port: Option<u16>) -> anyhow::Result<Self> {
+        if let Some(ip_addr) = ip_addr
+            && !interface.ip_addrs().contains(&ip_addr)
+        {
+            anyhow::bail!("source address {} is not an address of interface {}", ip_addr, interface.name);
+        }
+        Ok(Self { ip_addr, port })
+    }
+    /// Get the source address of probes to `dst_ip`. Destinations of the other IP version
+    /// get an address selected from the interface.
+    pub fn ip_for(&self, interface: &Interface, dst_ip: IpAddr) -> IpAddr {
+        match self.ip_addr {
+            Some(ip_addr) if ip_addr.is_ipv4() == dst_ip.is_ipv4() => ip_addr,
+            _ => crate::packet::source_ip(interface, dst_ip),
+        }
+    }
+    /// Get the source port, or `default` if none was chosen
+    pub fn port_or(&self, default: u16) -> u16 {
+        self.port.unwrap_or(default)
+    }
+    /// Get the config of connect sockets to `dst_ip`, bound to the chosen address and port.
+    /// Connections share a chosen port with SO_REUSEADDR. Without a choice the socket is
+    /// left unbound, so the kernel picks the port at connect time.
+    pub fn tcp_config(&self, dst_ip: IpAddr) -> TcpConfig {
+        let mut cfg = if dst_ip.is_ipv4() { TcpConfig::v4_stream() } else { TcpConfig::v6_stream() };
+        let ip_addr = self.ip_addr.filter(|ip_addr| ip_addr.is_ipv4() == dst_ip.is_ipv4());
+        if ip_addr.is_some() || self.port.is_some() {
+            cfg.bind_addr = Some(SocketAddr::new(ip_addr.unwrap_or(unspecified(dst_ip)), self.port.unwrap_or(0)));
+            cfg.reuseaddr = self.port.map(|_| true);
+        }
+        cfg
+    }
+    /// Get the local address of sockets to `dst_ip` that cannot share a source port
+    /// (QUIC endpoints and service detection): the chosen address with port 0.
+    pub fn local_addr(&self, dst_ip: IpAddr) -> SocketAddr {
+        let ip_addr = self.ip_addr.filter(|ip_addr| ip_addr.is_ipv4() == dst_ip.is_ipv4());
+        SocketAddr::new(ip_addr.unwrap_or(unspecified(dst_ip)), 0)
+    }
+    /// Open a TCP connection from the chosen address, if any.
+    /// The source port is left to the OS, as the port scan may still hold it.
+    pub async fn connect(&self, addr: SocketAddr) -> std::io::Result<tokio::net::TcpStream> {
+        let socket = if addr.is_ipv4() { tokio::net::TcpSocket::new_v4()? } else { tokio::net::TcpSocket::new_v6()? };
+        if self.ip_addr.is_some() {
+            socket.bind(self.local_addr(addr.ip()))?;
+        }
+        socket.connect(addr).await
+    }
+}
+
+/// Get the unspecified address of the IP version of `ip_addr`
+fn unspecified(ip_addr: IpAddr) -> IpAddr {
+    match ip_addr {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    }
 }
 
 /// Status of probe
@@ -194,3 +367,40 @@ impl ProbeResult {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lan_interface() -> Interface {
+        let mut interface = Interface::dummy();
+        interface.name = "eth0".to_string();
+        interface.ipv4 = vec!["192.0.2.2/24".parse().unwrap()];
+        interface.ipv6 = vec!["2001:db8::2/64".parse().unwrap()];
+        interface
+    }
+
+    #[test]
+    fn source_address_must_belong_to_the_interface() {
+        let interface = lan_interface();
+        let own = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        assert_eq!(ProbeSource::new(&interface, Some(own), Some(40000)).unwrap().ip_addr, Some(own));
+        assert!(ProbeSource::new(&interface, Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 99))), None).is_err());
+    }
+
+    #[test]
+    fn source_address_applies_to_its_ip_version_only() {
+        let interface = lan_interface();
+        let own = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let source = ProbeSource { ip_addr: Some(own), port: Some(40000) };
+        let dst_v4 = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let dst_v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(source.ip_for(&interface, dst_v4), own);
+        assert_eq!(source.ip_for(&interface, dst_v6), "2001:db8::2".parse::<IpAddr>().unwrap());
+        assert_eq!(source.tcp_config(dst_v4).bind_addr, Some(SocketAddr::new(own, 40000)));
+        assert_eq!(source.tcp_config(dst_v6).bind_addr, Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 40000)));
+        assert_eq!(source.local_addr(dst_v4), SocketAddr::new(own, 0));
+        // Without a choice connect sockets are left unbound
+        assert_eq!(ProbeSource::default().tcp_config(dst_v4).bind_addr, None);
+    }
+}
@@ -0,0 +1,326 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+
+use netdev::{Interface, MacAddr};
+use nex::packet::frame::Frame;
+
+use crate::endpoint::{DiscoveryTechnique, EndpointResult, OsGuess, Port, PortReason, PortResult, PortState, ServiceInfo, TransportProtocol, UnansweredPorts};
+use crate::output::ScanResult;
+use crate::probe::{ProbeSetting, TargetAddrs};
+use crate::scan::send::reply_rtt_ms;
+
+/// Number of reply events queued between the capture and the scan. The capture
+/// waits while the queue is full, and frames pile up in the socket buffers instead.
+pub const REPLY_QUEUE_LEN: usize = 4096;
+
+/// Addresses of the scanning interface, for checking where reply frames come from
+#[derive(Debug, Clone)]
+pub struct LocalAddrs {
+    mac_addr: MacAddr,
+    ips: HashSet<IpAddr>,
+}
+
+impl LocalAddrs {
+    pub fn new(iface: &Interface) -> Self {
+        Self {
+            mac_addr: iface.mac_addr.unwrap_or(MacAddr::zero()),
+            ips: iface.ip_addrs().into_iter().collect(),
+        }
+    }
+
+    /// Get the source address, TTL and source MAC address of an IP reply frame.
+    /// Frames sent to another MAC address are rejected. Replies from the interface
+    /// itself carry its MAC address and their initial TTL.
+    pub fn reply_source(&self, frame: &Frame) -> Option<(IpAddr, u8, MacAddr)> {
+        let mac_addr = match frame.datalink.as_ref().and_then(|datalink| datalink.ethernet.as_ref()) {
+            Some(ethernet_frame) if ethernet_frame.destination != self.mac_addr => return None,
+            Some(ethernet_frame) => ethernet_frame.source,
+            None => MacAddr::zero(),
+        };
+        let ip = frame.ip.as_ref()?;
+        let (src_ip, ttl) = match (&ip.ipv4, &ip.ipv6) {
+            (Some(ipv4_packet), _) => (IpAddr::V4(ipv4_packet.source), ipv4_packet.ttl),
+            (None, Some(ipv6_packet)) => (IpAddr::V6(ipv6_packet.source), ipv6_packet.hop_limit),
+            _ => return None,
+        };
+        if self.ips.contains(&src_ip) {
+            Some((src_ip, crate::util::ip::initial_ttl(ttl), self.mac_addr))
+        } else {
+            Some((src_ip, ttl, mac_addr))
+        }
+    }
+}
+
+/// One host discovery probe: the target host, the technique and the target port (0 if none).
+/// Send times are keyed by probe so that each reply is timed against its own probe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ProbeKey {
+    pub ip: IpAddr,
+    pub technique: DiscoveryTechnique,
+    pub port: u16,
+}
+
+impl ProbeKey {
+    pub fn new(ip: IpAddr, technique: DiscoveryTechnique, port: u16) -> Self {
+        Self { ip, technique, port }
+    }
+}
+
+/// Send times of host discovery probes, shared by the sender and the reply collector
+/// of one scan. Both run on the same task, so the map is never borrowed across an await.
+#[derive(Debug, Default)]
+pub struct SentProbes(RefCell<HashMap<ProbeKey, Vec<Instant>>>);
+
+impl SentProbes {
+    /// Record that the probe was just sent.
+    pub fn record(&self, key: ProbeKey) {
+        self.0.borrow_mut().entry(key).or_default().push(Instant::now());
+    }
+
+    /// Get the RTT of a reply to the probe, or `None` if the probe was never sent.
+    fn reply_rtt_ms(&self, key: &ProbeKey, received_at: Instant) -> Option<Option<u32>> {
+        self.0.borrow().get(key).map(|sent_times| reply_rtt_ms(sent_times, received_at))
+    }
+}
+
+/// Reply to a host discovery probe, classified as soon as it is received
+#[derive(Debug, Clone)]
+pub struct HostReply {
+    /// Probe the reply answers
+    pub probe: ProbeKey,
+    /// Whether the reply is a SYN/ACK from an open port
+    pub open: bool,
+    /// TTL or hop limit of IP replies
+    pub ttl: Option<u8>,
+    pub mac_addr: MacAddr,
+    pub os: OsGuess,
+    pub cpes: Vec<String>,
+    pub received_at: Instant,
+    pub frame: Frame,
+}
+
+/// Host scan results, built up from the reply events while the scan runs.
+/// Live hosts are logged as soon as they answer, and only the first reply frame
+/// to each probe is kept.
+pub struct HostCollector {
+    dns_map: HashMap<IpAddr, String>,
+    endpoints: HashMap<IpAddr, EndpointResult>,
+    replied: HashSet<ProbeKey>,
+    fingerprints: Vec<Frame>,
+}
+
+impl HostCollector {
+    pub fn new(setting: &ProbeSetting) -> Self {
+        Self {
+            dns_map: setting.get_dns_map(),
+            endpoints: HashMap::new(),
+            replied: HashSet::new(),
+            fingerprints: Vec::new(),
+        }
+    }
+
+    /// Record a reply. Replies to probes that were never sent are ignored, the others
+    /// are timed against their own probe.
+    pub fn record(&mut self, reply: HostReply, sent: &SentProbes) {
+        let Some(rtt_ms) = sent.reply_rtt_ms(&reply.probe, reply.received_at) else {
+            return;
+        };
+        let ip = reply.probe.ip;
+        if !self.endpoints.contains_key(&ip) {
+            let endpoint = self.endpoint(reply.probe);
+            endpoint.mac_addr = Some(reply.mac_addr);
+            endpoint.vendor_name = crate::nei::lookup_vendor(&reply.mac_addr);
+            endpoint.os = reply.os.clone();
+            endpoint.cpes = reply.cpes.clone();
+        }
+        self.update(reply.probe, reply.open, rtt_ms, reply.ttl);
+        if self.replied.insert(reply.probe) {
+            self.fingerprints.push(reply.frame);
+        }
+    }
+
+    /// Record a reply received on an unprivileged socket, which has no frame to keep.
+    pub fn record_socket_reply(&mut self, probe: ProbeKey, open: bool, rtt_ms: Option<u32>) {
+        self.endpoint(probe);
+        self.update(probe, open, rtt_ms, None);
+    }
+
+    /// Get the endpoint of the probed host, adding it when the host first answers.
+    fn endpoint(&mut self, probe: ProbeKey) -> &mut EndpointResult {
+        let ip = probe.ip;
+        self.endpoints.entry(ip).or_insert_with(|| {
+            tracing::info!("Host {} is up ({})", ip, probe.technique.as_str());
+            let mut endpoint = EndpointResult::new(ip);
+            endpoint.hostname = self.dns_map.get(&ip).cloned();
+            endpoint
+        })
+    }
+
+    fn update(&mut self, probe: ProbeKey, open: bool, rtt_ms: Option<u32>, ttl: Option<u8>) {
+        let Some(endpoint) = self.endpoints.get_mut(&probe.ip) else {
+            return;
+        };
+        // The fastest reply gives the host RTT
+        endpoint.rtt_ms = match (endpoint.rtt_ms, rtt_ms) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        if !endpoint.discovered_by.contains(&probe.technique) {
+            endpoint.discovered_by.push(probe.technique);
+        }
+        if open {
+            endpoint.upsert_port(PortResult {
+                port: Port::new(probe.port, TransportProtocol::Tcp),
+                state: PortState::Open,
+                service: ServiceInfo::default(),
+                rtt_ms,
+                reason: Some(PortReason::SynAck),
+                reply_ttl: ttl,
+            });
+        }
+    }
+
+    /// Build the scan result.
+    pub fn finish(self) -> ScanResult {
+        let mut result = ScanResult::new();
+        result.endpoints = self
+            .endpoints
+            .into_values()
+            .map(|mut endpoint| {
+                endpoint.discovered_by.sort();
+                endpoint
+            })
+            .collect();
+        result.fingerprints = self.fingerprints;
+        result
+    }
+}
+
+/// Reply to a raw port scan probe, classified as soon as it is received
+#[derive(Debug, Clone)]
+pub struct PortReply {
+    /// Probed socket address
+    pub addr: SocketAddr,
+    pub state: PortState,
+    pub reason: PortReason,
+    pub rtt_ms: Option<u32>,
+    /// TTL or hop limit of the reply
+    pub ttl: u8,
+    /// Link-layer source of the reply, if it came from the target itself rather than a router
+    pub target_mac: Option<MacAddr>,
+    /// Reply frame of an open port, kept as a fingerprint
+    pub frame: Option<Frame>,
+}
+
+/// Raw port scan results, built up from the reply events while the scan runs.
+/// Open ports are logged as soon as they are found, and frames are only kept for open ports.
+pub struct PortCollector<'a> {
+    setting: &'a ProbeSetting,
+    transport: TransportProtocol,
+    targets: TargetAddrs<'a>,
+    dns_map: HashMap<IpAddr, String>,
+    endpoints: HashMap<IpAddr, EndpointResult>,
+    answered: HashSet<SocketAddr>,
+    fingerprints: Vec<Frame>,
+}
+
+impl<'a> PortCollector<'a> {
+    pub fn new(setting: &'a ProbeSetting, transport: TransportProtocol) -> Self {
+        Self {
+            setting,
+            transport,
+            targets: setting.target_addr_set(transport),
+            dns_map: setting.get_dns_map(),
+            endpoints: HashMap::new(),
+            answered: HashSet::new(),
+            fingerprints: Vec::new(),
+        }
+    }
+
+    /// Record a reply. Replies for addresses that were not probed are ignored.
+    pub fn record(&mut self, reply: PortReply) {
+        if !self.targets.contains(&reply.addr) {
+            return;
+        }
+        self.answered.insert(reply.addr);
+        let ip = reply.addr.ip();
+        let endpoint = self.endpoints.entry(ip).or_insert_with(|| {
+            let mut endpoint = EndpointResult::new(ip);
+            endpoint.hostname = self.dns_map.get(&ip).cloned();
+            endpoint
+        });
+        if let Some(mac_addr) = reply.target_mac {
+            endpoint.mac_addr = Some(mac_addr);
+            endpoint.os = OsGuess::default().with_ttl_observed(reply.ttl);
+        }
+        let port = Port::new(reply.addr.port(), self.transport);
+        // A reply from the target itself is conclusive, so it takes precedence over ICMP errors
+        let replace = match endpoint.ports.get(&port) {
+            Some(existing) => existing.reason.is_some_and(is_icmp) && !is_icmp(reply.reason),
+            None => true,
+        };
+        if !replace {
+            return;
+        }
+        if reply.state == PortState::Open {
+            tracing::info!("Discovered open port {}/{} on {}", port.number, self.transport.as_str(), ip);
+            self.fingerprints.extend(reply.frame);
+        }
+        endpoint.upsert_port(PortResult {
+            port,
+            state: reply.state,
+            service: ServiceInfo::default(),
+            rtt_ms: reply.rtt_ms,
+            reason: Some(reply.reason),
+            reply_ttl: Some(reply.ttl),
+        });
+    }
+
+    /// Check if the socket address has answered.
+    pub fn is_answered(&self, addr: &SocketAddr) -> bool {
+        self.answered.contains(addr)
+    }
+
+    /// Get the number of socket addresses that have answered.
+    pub fn answered_count(&self) -> usize {
+        self.answered.len()
+    }
+
+    /// Build the scan result. The ports that never answered are counted per host as `unanswered`.
+    pub fn finish(self, unanswered: UnansweredPorts) -> ScanResult {
+        let mut result = ScanResult::new();
+        result.endpoints = self.endpoints.into_values().collect();
+        result.fingerprints = self.fingerprints;
+        crate::scan::summarize_unanswered(&mut result, self.setting, self.transport, unanswered);
+        result
+    }
+}
+
+fn is_icmp(reason: PortReason) -> bool {
+    matches!(reason, PortReason::IcmpPortUnreach { .. } | PortReason::IcmpUnreach { .. })
+}
+
+/// Targets that have not been answered yet, for retransmission rounds
+pub struct Unanswered<'a, 'b, I> {
+    pub targets: I,
+    pub collector: &'a RefCell<PortCollector<'b>>,
+    pub remaining: usize,
+}
+
+impl<I: Iterator<Item = SocketAddr>> Iterator for Unanswered<'_, '_, I> {
+    type Item = SocketAddr;
+
+    fn next(&mut self) -> Option<SocketAddr> {
+        let target = self.targets.find(|target| !self.collector.borrow().is_answered(target))?;
+        self.remaining = self.remaining.saturating_sub(1);
+        Some(target)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<I: Iterator<Item = SocketAddr>> ExactSizeIterator for Unanswered<'_, '_, I> {}
@@ -0,0 +1,471 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use netdev::Interface;
+use nex::datalink::{RawReceiver, RawSender};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::capture::pcap::{CapturedFrame, PacketCaptureOptions};
+use crate::config::default::DEFAULT_IO_BUFFER_SIZE;
+use crate::scan::collect::REPLY_QUEUE_LEN;
+use crate::scan::send::SendFeedback;
+#[cfg(target_os = "linux")]
+use crate::scan::socket::PacketSocket;
+
+/// Interval at which receive threads check for the stop signal
+const RX_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Number of packets queued per sender thread
+const TX_QUEUE_LEN: usize = 1024;
+
+/// Packet I/O settings for raw port scans
+#[derive(Clone, Copy, Debug)]
+pub struct IoConfig {
+    /// Size of the datalink read buffer in bytes
+    pub read_buffer_size: usize,
+    /// Size of the datalink write buffer in bytes
+    pub write_buffer_size: usize,
+    /// Number of receive threads. More than one joins a PACKET_FANOUT group (Linux only)
+    pub rx_threads: usize,
+    /// Number of sender threads
+    pub tx_threads: usize,
+}
+
+impl Default for IoConfig {
+    fn default() -> Self {
+        Self {
+            read_buffer_size: DEFAULT_IO_BUFFER_SIZE,
+            write_buffer_size: DEFAULT_IO_BUFFER_SIZE,
+            rx_threads: 1,
+            tx_threads: 1,
+        }
+    }
+}
+
+/// Packet I/O statistics of a raw scan
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct IoStats {
+    /// Packets handed to the kernel
+    pub packets_sent: u64,
+    /// Packets that could not be sent (e.g. full transmit queue)
+    pub send_errors: u64,
+    /// Average send rate achieved while sending (packets per second)
+    pub send_pps: f64,
+    /// Packets read by the receive threads, before filtering
+    pub packets_received: u64,
+    /// Packets dropped by the receive sockets of the scan because their buffers were full (Linux only)
+    #[serde(default)]
+    pub socket_drops: Option<u64>,
+    /// Receive drops counted by the interface during the scan, by any process (Linux only)
+    pub rx_dropped: Option<u64>,
+    /// Transmit drops counted by the interface during the scan (Linux only)
+    pub tx_dropped: Option<u64>,
+    pub rx_threads: usize,
+    pub tx_threads: usize,
+}
+
+impl std::fmt::Display for IoStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "sent {} packet(s) at {:.0} pps ({} send error(s)), received {} packet(s)",
+            self.packets_sent, self.send_pps, self.send_errors, self.packets_received
+        )?;
+        if let Some(drops) = self.socket_drops {
+            write!(f, ", socket drops {}", drops)?;
+        }
+        if let (Some(rx), Some(tx)) = (self.rx_dropped, self.tx_dropped) {
+            write!(f, ", interface drops rx {} / tx {}", rx, tx)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct IoCounters {
+    sent: AtomicU64,
+    received: AtomicU64,
+    socket_drops: AtomicU64,
+    /// Shared with the rate controller
+    feedback: SendFeedback,
+}
+
+/// Transmit/receive engine for high-rate raw scans.
+///
+/// Packets are sent by a pool of sender threads, each with its own datalink
+/// socket, fed round-robin from bounded queues. Replies are read by dedicated
+/// receive threads; on Linux, several of them share the traffic through a
+/// PACKET_FANOUT group (hashed by flow). Receive threads run from
+/// [`PacketEngine::open`] until [`PacketEngine::finish`], so replies arriving
+/// between send rounds are not lost. The receive threads classify the frames
+/// into reply events, which the scan consumes from a bounded queue while it
+/// is still sending; frames are never buffered.
+///
+/// On Linux, sender sockets are send-only and receive nothing. Elsewhere the
+/// sender threads use full datalink channels, sharing the first one with the
+/// receive thread.
+pub struct PacketEngine {
+    queues: Vec<mpsc::Sender<Vec<u8>>>,
+    next_queue: usize,
+    dispatched: u64,
+    tx_handles: Vec<JoinHandle<()>>,
+    rx_handles: Vec<JoinHandle<()>>,
+    stop: Arc<AtomicBool>,
+    counters: Arc<IoCounters>,
+    round_start: Option<Instant>,
+    send_time: Duration,
+    if_name: String,
+    link_drops: Option<(u64, u64)>,
+    rx_threads: usize,
+    tx_threads: usize,
+}
+
+impl PacketEngine {
+    /// Open the sockets on the interface and start the sender and receive threads.
+    /// Received frames are filtered with `capture_options` and turned into events
+    /// by `classify`; matching frames and send errors are counted in `feedback`.
+    /// Returns the engine and the queue of events, which closes after [`PacketEngine::finish`].
+    pub fn open<T, F>(
+        interface: &Interface,
+        io: &IoConfig,
+        capture_options: PacketCaptureOptions,
+        feedback: SendFeedback,
+        classify: F,
+    ) -> Result<(Self, mpsc::Receiver<T>)>
+    where
+        T: Send + 'static,
+        F: Fn(CapturedFrame) -> Option<T> + Send + Sync + 'static,
+    {
+        let tx_threads = io.tx_threads.max(1);
+        let mut rx_threads = io.rx_threads.max(1);
+        if rx_threads > 1 && !cfg!(target_os = "linux") {
+            // Without fanout every receiver would see every packet
+            tracing::warn!("Multiple receive threads require PACKET_FANOUT (Linux only), using one");
+            rx_threads = 1;
+        }
+        let (senders, receivers) = match open_sockets(interface, io, rx_threads, tx_threads) {
+            Ok(sockets) => sockets,
+            Err(e) if rx_threads > 1 => {
+                tracing::warn!("Failed to join a PACKET_FANOUT group ({}), using one receive thread", e);
+                rx_threads = 1;
+                open_sockets(interface, io, rx_threads, tx_threads)?
+            }
+            Err(e) => return Err(e.into()),
+        };
+        attach_filter(&receivers, &capture_options);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let counters = Arc::new(IoCounters { feedback, ..Default::default() });
+        let (event_tx, events) = mpsc::channel(REPLY_QUEUE_LEN);
+        let capture_options = Arc::new(capture_options);
+        let classify = Arc::new(classify);
+        let mut queues = Vec::with_capacity(tx_threads);
+        let mut tx_handles = Vec::with_capacity(tx_threads);
+        let mut rx_handles = Vec::with_capacity(rx_threads);
+        for (i, tx) in senders.into_iter().enumerate() {
+            let (queue_tx, queue_rx) = mpsc::channel(TX_QUEUE_LEN);
+            let counters = counters.clone();
+            queues.push(queue_tx);
+            tx_handles.push(
+                std::thread::Builder::new()
+                    .name(format!("nrev-tx-{}", i))
+                    .spawn(move || send_loop(tx, queue_rx, &counters))?,
+            );
+        }
+        for (i, mut rx) in receivers.into_iter().enumerate() {
+            let (stop, counters, event_tx) = (stop.clone(), counters.clone(), event_tx.clone());
+            let (capture_options, classify) = (capture_options.clone(), classify.clone());
+            rx_handles.push(std::thread::Builder::new().name(format!("nrev-rx-{}", i)).spawn(move || {
+                let received = crate::capture::pcap::start_blocking_capture(
+                    receiver(&mut rx),
+                    &capture_options,
+                    &stop,
+                    classify.as_ref(),
+                    &event_tx,
+                    &counters.feedback.responses,
+                );
+                counters.received.fetch_add(received, Ordering::Relaxed);
+                counters.socket_drops.fetch_add(socket_drops(&rx).unwrap_or(0), Ordering::Relaxed);
+            })?);
+        }
+        tracing::debug!("Packet engine on {}: {} sender(s), {} receiver(s)", interface.name, tx_threads, rx_threads);
+
+        let engine = Self {
+            queues,
+            next_queue: 0,
+            dispatched: 0,
+            tx_handles,
+            rx_handles,
+            stop,
+            counters,
+            round_start: None,
+            send_time: Duration::ZERO,
+            if_name: interface.name.clone(),
+            link_drops: link_drops(&interface.name),
+            rx_threads,
+            tx_threads,
+        };
+        Ok((engine, events))
+    }
+
+    /// Queue a packet on the next sender thread.
+    /// Waits while that thread's queue is full.
+    pub async fn send(&mut self, packet: Vec<u8>) -> io::Result<()> {
+        self.round_start.get_or_insert_with(Instant::now);
+        let queue = &self.queues[self.next_queue];
+        self.next_queue = (self.next_queue + 1) % self.queues.len();
+        queue
+            .send(packet)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "sender thread stopped"))?;
+        self.dispatched += 1;
+        Ok(())
+    }
+
+    /// Wait until every queued packet has been sent.
+    pub async fn flush(&mut self) {
+        while self.counters.sent.load(Ordering::Relaxed) + self.counters.feedback.send_errors.load(Ordering::Relaxed) < self.dispatched {
+            if self.tx_handles.iter().all(|handle| handle.is_finished()) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        if let Some(start) = self.round_start.take() {
+            self.send_time += start.elapsed();
+        }
+    }
+
+    /// Stop all threads and return the I/O statistics.
+    /// The event queue closes once the receive threads are done.
+    pub async fn finish(mut self) -> IoStats {
+        self.flush().await;
+        self.stop.store(true, Ordering::Relaxed);
+        self.queues.clear();
+        let handles: Vec<JoinHandle<()>> = self.tx_handles.drain(..).chain(self.rx_handles.drain(..)).collect();
+        let _ = tokio::task::spawn_blocking(move || {
+            for handle in handles {
+                let _ = handle.join();
+            }
+        })
+        .await;
+
+        let packets_sent = self.counters.sent.load(Ordering::Relaxed);
+        let send_secs = self.send_time.as_secs_f64();
+        let drops = self.link_drops.zip(link_drops(&self.if_name)).map(|((rx_before, tx_before), (rx, tx))| {
+            (rx.saturating_sub(rx_before), tx.saturating_sub(tx_before))
+        });
+        IoStats {
+            packets_sent,
+            send_errors: self.counters.feedback.send_errors.load(Ordering::Relaxed),
+            send_pps: if send_secs > 0.0 { packets_sent as f64 / send_secs } else { 0.0 },
+            packets_received: self.counters.received.load(Ordering::Relaxed),
+            socket_drops: cfg!(target_os = "linux").then(|| self.counters.socket_drops.load(Ordering::Relaxed)),
+            rx_dropped: drops.map(|(rx, _)| rx),
+            tx_dropped: drops.map(|(_, tx)| tx),
+            rx_threads: self.rx_threads,
+            tx_threads: self.tx_threads,
+        }
+    }
+}
+
+impl Drop for PacketEngine {
+    fn drop(&mut self) {
+        // Receive threads left running (e.g. on early return) stop at their next poll
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Receive socket of a receive thread
+#[cfg(target_os = "linux")]
+type RxSocket = PacketSocket;
+#[cfg(not(target_os = "linux"))]
+type RxSocket = Box<dyn RawReceiver>;
+
+/// Sender and receive sockets of the engine
+type Sockets = (Vec<Box<dyn RawSender>>, Vec<RxSocket>);
+
+/// Filter the frames of the receive sockets in the kernel. Frames queued before the
+/// program is attached, and frames on other platforms, are filtered in userspace only.
+#[cfg(target_os = "linux")]
+fn attach_filter(receivers: &[RxSocket], capture_options: &PacketCaptureOptions) {
+    let Some(program) = crate::capture::bpf::compile(capture_options) else {
+        return;
+    };
+    for rx in receivers {
+        if let Err(e) = rx.attach_filter(&program) {
+            tracing::warn!("Failed to attach the kernel packet filter ({}), filtering in userspace", e);
+            return;
+        }
+    }
+    tracing::debug!("Attached a {}-instruction kernel packet filter", program.len());
+}
+
+#[cfg(not(target_os = "linux"))]
+fn attach_filter(_receivers: &[RxSocket], _capture_options: &PacketCaptureOptions) {}
+
+/// Open the sender and receive sockets. The receive sockets join the same
+/// fanout group if there are several of them.
+#[cfg(target_os = "linux")]
+fn open_sockets(
+    interface: &Interface,
+    io: &IoConfig,
+    rx_threads: usize,
+    tx_threads: usize,
+) -> io::Result<Sockets> {
+    // Fanout groups are global, so keep ours apart from other processes
+    let fanout_group = (rx_threads > 1).then(|| std::process::id() as u16);
+    let receivers = (0..rx_threads)
+        .map(|_| PacketSocket::open_rx(interface, io.read_buffer_size, RX_POLL_INTERVAL, fanout_group))
+        .collect::<io::Result<Vec<_>>>()?;
+    let senders = (0..tx_threads)
+        .map(|_| crate::scan::socket::open_sender(interface, io.write_buffer_size))
+        .collect::<io::Result<Vec<_>>>()?;
+    Ok((senders, receivers))
+}
+
+/// Open one datalink channel per thread. The first `rx_threads` channels are
+/// read from; the receive halves of the others are closed.
+#[cfg(not(target_os = "linux"))]
+fn open_sockets(
+    interface: &Interface,
+    io: &IoConfig,
+    rx_threads: usize,
+    tx_threads: usize,
+) -> io::Result<Sockets> {
+    let mut senders = Vec::with_capacity(tx_threads);
+    let mut receivers = Vec::with_capacity(rx_threads);
+    for i in 0..rx_threads.max(tx_threads) {
+        let config = nex::datalink::Config {
+            write_buffer_size: io.write_buffer_size,
+            read_buffer_size: io.read_buffer_size,
+            read_timeout: Some(RX_POLL_INTERVAL),
+            write_timeout: None,
+            channel_type: nex::datalink::ChannelType::Layer2,
+            bpf_fd_attempts: 1000,
+            linux_fanout: None,
+            promiscuous: false,
+        };
+        let nex::datalink::Channel::Ethernet(tx, rx) = nex::datalink::channel(interface, config)? else {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "unsupported datalink channel"));
+        };
+        if i < tx_threads {
+            senders.push(tx);
+        }
+        if i < rx_threads {
+            receivers.push(rx);
+        }
+    }
+    Ok((senders, receivers))
+}
+
+#[cfg(target_os = "linux")]
+fn receiver(rx: &mut RxSocket) -> &mut dyn RawReceiver {
+    rx
+}
+
+#[cfg(not(target_os = "linux"))]
+fn receiver(rx: &mut RxSocket) -> &mut dyn RawReceiver {
+    rx.as_mut()
+}
+
+/// Get the frames the kernel dropped on the receive socket.
+#[cfg(target_os = "linux")]
+fn socket_drops(rx: &RxSocket) -> Option<u64> {
+    rx.take_drops()
+        .map_err(|e| tracing::debug!("Failed to read PACKET_STATISTICS: {}", e))
+        .ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn socket_drops(_rx: &RxSocket) -> Option<u64> {
+    None
+}
+
+/// Send queued packets until the queue is closed.
+fn send_loop(mut tx: Box<dyn RawSender>, mut queue: mpsc::Receiver<Vec<u8>>, counters: &IoCounters) {
+    while let Some(packet) = queue.blocking_recv() {
+        match tx.send(&packet) {
+            Some(Ok(())) => {
+                counters.sent.fetch_add(1, Ordering::Relaxed);
+            }
+            Some(Err(e)) => {
+                tracing::trace!("Failed to send packet: {}", e);
+                counters.feedback.send_errors.fetch_add(1, Ordering::Relaxed);
+            }
+            None => {
+                counters.feedback.send_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Read the (rx_dropped, tx_dropped) counters of the interface.
+#[cfg(target_os = "linux")]
+fn link_drops(if_name: &str) -> Option<(u64, u64)> {
+    let read = |counter: &str| -> Option<u64> {
+        std::fs::read_to_string(format!("/sys/class/net/{}/statistics/{}", if_name, counter))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    };
+    Some((read("rx_dropped")?, read("tx_dropped")?))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn link_drops(_if_name: &str) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats() -> IoStats {
+        IoStats {
+            packets_sent: 1000,
+            send_errors: 2,
+            send_pps: 499.6,
+            packets_received: 640,
+            rx_threads: 2,
+            tx_threads: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn io_config_default() {
+        let io = IoConfig::default();
+        assert_eq!((io.read_buffer_size, io.write_buffer_size), (DEFAULT_IO_BUFFER_SIZE, DEFAULT_IO_BUFFER_SIZE));
+        assert_eq!((io.rx_threads, io.tx_threads), (1, 1));
+    }
+
+    #[test]
+    fn io_stats_display() {
+        let mut stats = stats();
+        assert_eq!(stats.to_string(), "sent 1000 packet(s) at 500 pps (2 send error(s)), received 640 packet(s)");
+        stats.socket_drops = Some(3);
+        stats.rx_dropped = Some(7);
+        assert_eq!(stats.to_string(), "sent 1000 packet(s) at 500 pps (2 send error(s)), received 640 packet(s), socket drops 3");
+        stats.tx_dropped = Some(0);
+        assert!(stats.to_string().ends_with(", socket drops 3, interface drops rx 7 / tx 0"));
+    }
+
+    #[test]
+    fn io_stats_serde() {
+        let mut stats = stats();
+        stats.socket_drops = Some(3);
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["socket_drops"], 3);
+        let parsed: IoStats = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!((parsed.packets_sent, parsed.socket_drops, parsed.rx_threads), (1000, Some(3), 2));
+        // Reports saved before socket drops were recorded still load
+        let mut json = json;
+        json.as_object_mut().unwrap().remove("socket_drops");
+        assert_eq!(serde_json::from_value::<IoStats>(json).unwrap().socket_drops, None);
+    }
+}
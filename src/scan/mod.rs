@@ -1,8 +1,21 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
 use anyhow::Result;
 
-use crate::{cli::{HostScanProto, PortScanMethod}, endpoint::TransportProtocol, output::ScanResult, probe::ProbeSetting};
+use crate::{cli::{HostScanProto, PortScanMethod}, endpoint::{EndpointResult, SilentHosts, TransportProtocol, UnansweredPorts}, output::{port::MAX_SKIPPED_RANGES, ScanResult}, probe::ProbeSetting, protocol::Protocol};
+use crate::scan::target::{AddrBlock, ScanTargets};
+use crate::util::privilege::{raw_required, socket_access, Downgrade};
 
+pub mod collect;
+pub mod engine;
 pub mod probe;
+pub mod scheduler;
+pub mod send;
+#[cfg(target_os = "linux")]
+pub mod socket;
+pub mod stateless;
+pub mod target;
 
 /// A port scanner that can perform scans using different methods and transport protocols.
 pub struct PortScanner {
@@ -24,8 +37,9 @@ impl PortScanner {
     pub async fn run(&self) -> Result<ScanResult> {
         match self.transport {
             TransportProtocol::Tcp => probe::tcp::run_port_scan(self.settings.clone(), self.scan_method).await,
+            TransportProtocol::Udp => probe::udp::run_port_scan(self.settings.clone(), self.scan_method).await,
             TransportProtocol::Quic => probe::quic::run_port_scan(self.settings.clone(), self.scan_method).await,
-            _ => anyhow::bail!("Unsupported transport protocol: {:?}", self.transport),
+            TransportProtocol::Sctp => probe::sctp::run_port_scan(self.settings.clone(), self.scan_method).await,
         }
     }
 }
@@ -34,19 +48,179 @@ impl PortScanner {
 pub struct HostScanner {
     pub settings: ProbeSetting,
     pub protocol: HostScanProto,
+    /// Use unprivileged sockets instead of raw ones
+    pub unprivileged: bool,
 }
 
 impl HostScanner {
     /// Create a new HostScanner instance.
     pub fn new(settings: ProbeSetting, protocol: HostScanProto) -> Self {
-        Self { settings, protocol }
+        Self { settings, protocol, unprivileged: false }
+    }
+    /// Fall back to unprivileged techniques if raw sockets are not available.
+    /// Returns the downgrade, or an error if the protocol has no fallback.
+    pub fn fall_back_if_unprivileged(&mut self) -> Result<Option<Downgrade>> {
+        let access = socket_access();
+        if access.raw {
+            return Ok(None);
+        }
+        let used = match self.protocol {
+            HostScanProto::Icmp if access.icmp_datagram => "icmp datagram echo",
+            HostScanProto::Tcp => "tcp-connect ping",
+            HostScanProto::Combined if access.icmp_datagram => "icmp datagram echo and tcp-connect ping",
+            HostScanProto::Combined => "tcp-connect ping",
+            HostScanProto::Icmp => {
+                anyhow::bail!("ICMP host scan requires raw sockets or ICMP datagram sockets (see net.ipv4.ping_group_range)")
+            }
+            HostScanProto::Udp | HostScanProto::Arp | HostScanProto::Ndp => {
+                return Err(raw_required(&format!("{} host scan", self.protocol.as_str().to_uppercase())));
+            }
+        };
+        self.unprivileged = true;
+        Ok(Some(Downgrade::new(format!("raw {} host scan", self.protocol.as_str()), used)))
     }
     /// Run the host scan based on the specified protocol.
     pub async fn run(&self) -> Result<ScanResult> {
+        if self.unprivileged {
+            return match self.protocol {
+                HostScanProto::Icmp => probe::icmp::run_datagram_host_scan(self.settings.clone()).await,
+                HostScanProto::Tcp => probe::tcp::run_connect_host_scan(self.settings.clone()).await,
+                HostScanProto::Combined => probe::discovery::run_unprivileged_host_scan(self.settings.clone()).await,
+                _ => Err(raw_required(&format!("{} host scan", self.protocol.as_str().to_uppercase()))),
+            };
+        }
         match self.protocol {
             HostScanProto::Icmp => probe::icmp::run_host_scan(self.settings.clone()).await,
             HostScanProto::Udp => probe::udp::run_host_scan(self.settings.clone()).await,
             HostScanProto::Tcp => probe::tcp::run_host_scan(self.settings.clone()).await,
+            HostScanProto::Arp => probe::nei::run_host_scan(self.settings.clone(), Protocol::Arp).await,
+            HostScanProto::Ndp => probe::nei::run_host_scan(self.settings.clone(), Protocol::Ndp).await,
+            HostScanProto::Combined => probe::discovery::run_host_scan(self.settings.clone()).await,
+        }
+    }
+}
+
+/// Count the probed ports that never answered instead of listing them one by one.
+/// Only `unanswered.transport` ports count as answered. With a target set, the hosts without
+/// any reply are summarized as [`SilentHosts`]; otherwise they are listed with all of their
+/// ports unanswered, as the endpoints are already in memory.
+pub fn summarize_unanswered(result: &mut ScanResult, setting: &ProbeSetting, transport: TransportProtocol, unanswered: UnansweredPorts) {
+    if let Some(targets) = &setting.scan_targets {
+        summarize_target_set(result, targets, unanswered);
+        return;
+    }
+    let index: HashMap<IpAddr, usize> = result.endpoints.iter().enumerate().map(|(i, ep)| (ep.ip, i)).collect();
+    let dns_map = setting.get_dns_map();
+    for (ip, probed) in setting.target_port_counts(transport) {
+        match index.get(&ip) {
+            Some(&i) => {
+                let endpoint = &mut result.endpoints[i];
+                let answered = endpoint.ports.keys().filter(|port| port.transport == unanswered.transport).count();
+                let count = probed.saturating_sub(answered);
+                if count > 0 {
+                    endpoint.add_unanswered(UnansweredPorts { count, ..unanswered });
+                }
+            }
+            None if probed > 0 => {
+                let mut endpoint = EndpointResult::new(ip);
+                endpoint.hostname = dns_map.get(&ip).cloned();
+                endpoint.add_unanswered(UnansweredPorts { count: probed, ..unanswered });
+                result.endpoints.push(endpoint);
+            }
+            None => {}
+        }
+    }
+}
+
+/// Count the unanswered ports of a target set scan without walking its hosts, so that
+/// memory use does not grow with the number of targets. Silent hosts given by name are
+/// still listed to keep their hostname.
+fn summarize_target_set(result: &mut ScanResult, targets: &ScanTargets, unanswered: UnansweredPorts) {
+    let probed = targets.ports().len();
+    if probed == 0 {
+        return;
+    }
+    let mut answered: Vec<AddrBlock> = Vec::with_capacity(result.endpoints.len());
+    for endpoint in &mut result.endpoints {
+        let count = probed.saturating_sub(endpoint.ports.keys().filter(|port| port.transport == unanswered.transport).count());
+        if count > 0 {
+            endpoint.add_unanswered(UnansweredPorts { count, ..unanswered });
         }
+        answered.push(AddrBlock::Range(endpoint.ip, endpoint.ip));
+    }
+    let mut silent = targets.hosts().clone();
+    silent.exclude(&answered);
+    let mut named: Vec<(IpAddr, String)> = silent.hostnames().iter().map(|(ip, name)| (*ip, name.clone())).collect();
+    named.sort();
+    for (ip, hostname) in &named {
+        let mut endpoint = EndpointResult::new(*ip);
+        endpoint.hostname = Some(hostname.clone());
+        endpoint.add_unanswered(UnansweredPorts { count: probed, ..unanswered });
+        result.endpoints.push(endpoint);
+    }
+    let named: Vec<AddrBlock> = named.into_iter().map(|(ip, _)| AddrBlock::Range(ip, ip)).collect();
+    silent.exclude(&named);
+    if silent.is_empty() {
+        return;
+    }
+    result.silent_hosts.push(SilentHosts {
+        hosts: usize::try_from(silent.len()).unwrap_or(usize::MAX),
+        ranges: silent.blocks().take(MAX_SKIPPED_RANGES).map(|block| block.to_string()).collect(),
+        ports: UnansweredPorts { count: probed, ..unanswered },
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::endpoint::{Port, PortReason, PortResult, PortState, ServiceInfo};
+    use crate::endpoint::Host;
+    use crate::scan::target::TargetSet;
+
+    fn filtered() -> UnansweredPorts {
+        UnansweredPorts { transport: TransportProtocol::Tcp, state: PortState::Filtered, reason: PortReason::NoResponse, count: 0 }
+    }
+
+    #[test]
+    fn silent_hosts_of_large_target_sets_are_summarized() {
+        // A /8 with no replies yields a single summary instead of 16M endpoints
+        let hosts = TargetSet::new(vec![AddrBlock::Range("10.0.0.0".parse().unwrap(), "10.255.255.255".parse().unwrap())], Vec::new());
+        let targets = ScanTargets::new(hosts, vec![22, 80, 443], true).unwrap();
+        let mut result = ScanResult::new();
+        summarize_target_set(&mut result, &targets, filtered());
+        assert!(result.endpoints.is_empty());
+        assert_eq!(
+            result.silent_hosts,
+            vec![SilentHosts { hosts: 1 << 24, ranges: vec!["10.0.0.0-10.255.255.255".into()], ports: UnansweredPorts { count: 3, ..filtered() } }]
+        );
+    }
+
+    #[test]
+    fn answered_and_named_hosts_are_listed() {
+        let named: IpAddr = "192.0.2.20".parse().unwrap();
+        let hosts = TargetSet::new(
+            vec![AddrBlock::Range("192.0.2.1".parse().unwrap(), "192.0.2.10".parse().unwrap())],
+            vec![Host { ip: named, hostname: Some("gw.example.com".into()) }],
+        );
+        let targets = ScanTargets::new(hosts, vec![22, 80], false).unwrap();
+        let mut result = ScanResult::new();
+        let mut answered = EndpointResult::new("192.0.2.5".parse().unwrap());
+        answered.upsert_port(PortResult {
+            port: Port::new(22, TransportProtocol::Tcp),
+            state: PortState::Open,
+            service: ServiceInfo::default(),
+            rtt_ms: None,
+            reason: Some(PortReason::SynAck),
+            reply_ttl: None,
+        });
+        result.endpoints.push(answered);
+        summarize_target_set(&mut result, &targets, filtered());
+        assert_eq!(result.endpoints.len(), 2);
+        assert_eq!(result.endpoints[0].unanswered, vec![UnansweredPorts { count: 1, ..filtered() }]);
+        assert_eq!(result.endpoints[1].hostname.as_deref(), Some("gw.example.com"));
+        assert_eq!(result.endpoints[1].unanswered, vec![UnansweredPorts { count: 2, ..filtered() }]);
+        let silent = &result.silent_hosts[0];
+        assert_eq!(silent.hosts, 9);
+        assert_eq!(silent.ranges, vec!["192.0.2.1-192.0.2.4".to_string(), "192.0.2.6-192.0.2.10".to_string()]);
     }
 }
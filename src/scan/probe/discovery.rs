@@ -0,0 +1,286 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+use netdev::Interface;
+use nex::datalink::async_io::AsyncRawSender;
+use nex::packet::frame::Frame;
+use nex::packet::icmp::IcmpType;
+use nex::packet::icmpv6::Icmpv6Type;
+use nex::packet::ip::IpNextProtocol;
+use nex::packet::tcp::TcpFlags;
+use tokio::sync::mpsc;
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+use anyhow::Result;
+use crate::{output::ScanResult, scan::ProbeSetting};
+use crate::capture::pcap::{CapturedFrame, PacketCaptureOptions};
+use crate::config::default::{DEFAULT_BASE_TARGET_UDP_PORT, DEFAULT_DISCOVERY_TCP_ACK_PORT, DEFAULT_LOCAL_TCP_PORT};
+use crate::scan::collect::{HostCollector, HostReply, LocalAddrs, ProbeKey, SentProbes, REPLY_QUEUE_LEN};
+use crate::scan::send::RateController;
+use crate::endpoint::{DiscoveryTechnique, OsGuess};
+
+/// Iterate over the target hosts with the ports to send TCP SYN probes to.
+fn target_host_ports(setting: &ProbeSetting) -> Box<dyn Iterator<Item = (IpAddr, Vec<u16>)> + Send + '_> {
+    match &setting.scan_targets {
+        Some(targets) => Box::new(targets.hosts().hosts().map(move |host| (host.ip, targets.ports().to_vec()))),
+        None => Box::new(setting.target_endpoints.iter().map(|ep| (ep.ip, ep.ports.iter().map(|port| port.number).collect()))),
+    }
+}
+
+/// Send every discovery probe to each target host: ICMP Echo, ICMP Timestamp (IPv4),
+/// TCP SYN to the target ports, TCP ACK and UDP.
+/// The send time of every probe is recorded in `sent` for RTT measurement.
+pub async fn send_hostscan_packets(
+    tx: &mut Box<dyn AsyncRawSender>,
+    interface: &Interface,
+    scan_setting: &ProbeSetting,
+    rate: &mut RateController,
+    sent: &SentProbes,
+) {
+    let header_span = tracing::info_span!("combined_host_scan");
+    header_span.pb_set_style(&crate::output::progress::get_progress_style());
+    header_span.pb_set_message("HostScan");
+    header_span.pb_set_length(scan_setting.target_host_count());
+    header_span.pb_set_position(0);
+    header_span.pb_start();
+    let ip_packet = crate::interface::is_layer3(interface);
+
+    for (target_ip, ports) in target_host_ports(scan_setting) {
+        let mut packets: Vec<(ProbeKey, Vec<u8>)> = Vec::with_capacity(ports.len() + 4);
+        packets.push((
+            ProbeKey::new(target_ip, DiscoveryTechnique::IcmpEcho, 0),
+            crate::packet::icmp::build_icmp_packet(interface, &scan_setting.neighbors, &scan_setting.source, target_ip, ip_packet),
+        ));
+        if let IpAddr::V4(ipv4) = target_ip {
+            packets.push((
+                ProbeKey::new(target_ip, DiscoveryTechnique::IcmpTimestamp, 0),
+                crate::packet::icmp::build_icmp_timestamp_packet(interface, &scan_setting.neighbors, &scan_setting.source, ipv4, ip_packet),
+            ));
+        }
+        for port in ports {
+            packets.push((
+                ProbeKey::new(target_ip, DiscoveryTechnique::TcpSyn, port),
+                crate::packet::tcp::build_tcp_syn_packet(interface, &scan_setting.neighbors, &scan_setting.source, target_ip, port, ip_packet),
+            ));
+        }
+        packets.push((
+            ProbeKey::new(target_ip, DiscoveryTechnique::TcpAck, DEFAULT_DISCOVERY_TCP_ACK_PORT),
+            crate::packet::tcp::build_tcp_probe_packet(interface, &scan_setting.neighbors, &scan_setting.source, target_ip, DEFAULT_DISCOVERY_TCP_ACK_PORT, TcpFlags::ACK, ip_packet),
+        ));
+        packets.push((
+            ProbeKey::new(target_ip, DiscoveryTechnique::Udp, DEFAULT_BASE_TARGET_UDP_PORT),
+            crate::packet::udp::build_udp_packet(interface, &scan_setting.neighbors, &scan_setting.source, target_ip, DEFAULT_BASE_TARGET_UDP_PORT, ip_packet),
+        ));
+        for (key, packet) in packets {
+            match rate.send(tx, &packet).await {
+                Ok(_) => sent.record(key),
+                Err(e) => eprintln!("Failed to send packet: {}", e),
+            }
+        }
+        header_span.pb_inc(1);
+    }
+    drop(header_span);
+}
+
+/// Run host scan with all discovery techniques at once and return the results.
+/// Replies are merged per host, recording which techniques got a response.
+pub async fn run_host_scan(setting: ProbeSetting) -> Result<ScanResult> {
+    let interface = match crate::interface::get_interface_by_index(setting.if_index) {
+        Some(interface) => interface,
+        None => return Err(anyhow::anyhow!("Interface not found")),
+    };
+    // Resolve the MAC addresses of on-link targets
+    setting.neighbors.resolve(&interface, setting.target_ips(), setting.wait_time).await;
+    // Create sender
+    let (mut tx, mut rx) = crate::capture::channel::open(&interface, setting.wait_time)?;
+
+    let mut capture_options: PacketCaptureOptions = PacketCaptureOptions {
+        interface_index: interface.index,
+        interface_name: interface.name.clone(),
+        src_ips: HashSet::new(),
+        dst_ips: HashSet::new(),
+        src_ports: HashSet::new(),
+        dst_ports: HashSet::new(),
+        ether_types: HashSet::new(),
+        ip_protocols: HashSet::new(),
+        capture_timeout: setting.task_timeout,
+        read_timeout: setting.wait_time,
+        promiscuous: false,
+        receive_undefined: false,
+        tunnel: crate::interface::is_layer3(&interface),
+        loopback: interface.is_loopback(),
+    };
+    for endpoint in &setting.target_endpoints {
+        capture_options.src_ips.insert(endpoint.ip);
+    }
+    capture_options.ip_protocols.insert(IpNextProtocol::Icmp);
+    capture_options.ip_protocols.insert(IpNextProtocol::Icmpv6);
+    capture_options.ip_protocols.insert(IpNextProtocol::Tcp);
+
+    let mut rate = RateController::new(&setting);
+    let responses = rate.feedback().responses;
+    let (ready_tx, ready_rx) = tokio::sync::oneshot::channel();
+    let (stop_tx, mut stop_rx) = tokio::sync::oneshot::channel();
+    let (reply_tx, mut replies) = mpsc::channel(REPLY_QUEUE_LEN);
+    let local = LocalAddrs::new(&interface);
+    let local_port = setting.source.port_or(DEFAULT_LOCAL_TCP_PORT);
+
+    let capture_handle: tokio::task::JoinHandle<_> = tokio::spawn(async move {
+        crate::capture::pcap::start_stream_capture(
+            &mut rx,
+            capture_options,
+            ready_tx,
+            &mut stop_rx,
+            &responses,
+            |captured| classify_host_reply(captured, &local, local_port),
+            reply_tx,
+        )
+        .await
+    });
+
+    // Wait for listener to start
+    let _ = ready_rx.await;
+    let start_time = std::time::Instant::now();
+    let sent = SentProbes::default();
+    let collector = RefCell::new(HostCollector::new(&setting));
+    // Record replies while the probes are still being sent, merged per host
+    let collect = async {
+        while let Some(reply) = replies.recv().await {
+            collector.borrow_mut().record(reply, &sent);
+        }
+    };
+    let scan = async {
+        // Send probe packets
+        send_hostscan_packets(&mut tx, &interface, &setting, &mut rate, &sent).await;
+        tokio::time::sleep(setting.wait_time).await;
+        // Stop pcap
+        let _ = stop_tx.send(());
+        capture_handle.await.unwrap();
+    };
+    tokio::join!(collect, scan);
+    let mut result = collector.into_inner().finish();
+    result.scan_time = start_time.elapsed();
+    Ok(result)
+}
+
+/// Run host scan without raw sockets: ICMP Echo on datagram sockets, if they are allowed,
+/// and TCP connect() to the target ports at once. Replies are merged per host.
+pub async fn run_unprivileged_host_scan(setting: ProbeSetting) -> Result<ScanResult> {
+    let start_time = std::time::Instant::now();
+    let collector = RefCell::new(HostCollector::new(&setting));
+    let echo = async {
+        if crate::util::privilege::socket_access().icmp_datagram {
+            crate::scan::probe::icmp::probe_datagram_echo(&setting, &collector).await
+        } else {
+            Ok(())
+        }
+    };
+    let connect = crate::scan::probe::tcp::probe_connect(&setting, &collector);
+    let (echo, connect) = tokio::join!(echo, connect);
+    echo?;
+    connect?;
+    let mut result = collector.into_inner().finish();
+    result.scan_time = start_time.elapsed();
+    Ok(result)
+}
+
+/// Classify a captured frame as a reply to one of the discovery probes.
+/// Returns the probe it answers and whether it is a SYN/ACK from an open port.
+/// TCP replies must be addressed to `local_port`, the source port of the probes.
+fn classify_reply(frame: &Frame, local_port: u16) -> Option<(ProbeKey, bool)> {
+    let ip = frame.ip.as_ref()?;
+    let src_ip = match (&ip.ipv4, &ip.ipv6) {
+        (Some(ipv4_packet), _) => IpAddr::V4(ipv4_packet.source),
+        (None, Some(ipv6_packet)) => IpAddr::V6(ipv6_packet.source),
+        _ => return None,
+    };
+    if let Some(tcp_packet) = frame.transport.as_ref().and_then(|transport| transport.tcp.as_ref()) {
+        if tcp_packet.destination != local_port {
+            return None;
+        }
+        let f = tcp_packet.flags;
+        let port = tcp_packet.source;
+        return if (f & (TcpFlags::SYN | TcpFlags::ACK)) == (TcpFlags::SYN | TcpFlags::ACK) {
+            Some((ProbeKey::new(src_ip, DiscoveryTechnique::TcpSyn, port), true))
+        } else if f & TcpFlags::RST == 0 {
+            None
+        } else if f & TcpFlags::ACK == 0 {
+            // A RST answering a segment that carried an ACK has no ACK flag itself (RFC 9293)
+            Some((ProbeKey::new(src_ip, DiscoveryTechnique::TcpAck, port), false))
+        } else {
+            Some((ProbeKey::new(src_ip, DiscoveryTechnique::TcpSyn, port), false))
+        };
+    }
+    // Only unreachables sent by the probed host itself prove it is up
+    let udp_unreachable = || {
+        crate::packet::icmp::parse_quoted_packet(&frame.payload)
+            .filter(|quoted| quoted.dst_ip == src_ip && quoted.protocol == IpNextProtocol::Udp && quoted.dst_port == DEFAULT_BASE_TARGET_UDP_PORT)
+            .map(|quoted| (ProbeKey::new(src_ip, DiscoveryTechnique::Udp, quoted.dst_port), false))
+    };
+    if let Some(icmp) = &ip.icmp {
+        return match icmp.icmp_type {
+            IcmpType::EchoReply => Some((ProbeKey::new(src_ip, DiscoveryTechnique::IcmpEcho, 0), false)),
+            IcmpType::TimestampReply => Some((ProbeKey::new(src_ip, DiscoveryTechnique::IcmpTimestamp, 0), false)),
+            IcmpType::DestinationUnreachable => udp_unreachable(),
+            _ => None,
+        };
+    }
+    if let Some(icmpv6) = &ip.icmpv6 {
+        return match icmpv6.icmpv6_type {
+            Icmpv6Type::EchoReply => Some((ProbeKey::new(src_ip, DiscoveryTechnique::IcmpEcho, 0), false)),
+            Icmpv6Type::DestinationUnreachable => udp_unreachable(),
+            _ => None,
+        };
+    }
+    None
+}
+
+/// Classify a captured frame as a reply to one of the discovery probes.
+fn classify_host_reply(captured: CapturedFrame, local: &LocalAddrs, local_port: u16) -> Option<HostReply> {
+    let (_, ttl, mac_addr) = local.reply_source(&captured.frame)?;
+    let (probe, open) = classify_reply(&captured.frame, local_port)?;
+    Some(HostReply {
+        probe,
+        open,
+        ttl: Some(ttl),
+        mac_addr,
+        os: OsGuess::default().with_ttl_observed(ttl),
+        cpes: Vec::new(),
+        received_at: captured.received_at,
+        frame: captured.frame,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nex::packet::frame::ParseOption;
+
+    fn tcp_reply(src_port: u16, dst_port: u16, flags: u8) -> Frame {
+        let mut packet = vec![0x45, 0, 0, 40, 0, 0, 0, 0, 64, 6, 0, 0, 192, 0, 2, 10, 192, 0, 2, 2];
+        packet.extend_from_slice(&src_port.to_be_bytes());
+        packet.extend_from_slice(&dst_port.to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        Frame::from_buf(&packet, ParseOption { from_ip_packet: true, offset: 0 }).unwrap()
+    }
+
+    #[test]
+    fn tcp_replies_are_keyed_by_probe() {
+        let ip: IpAddr = "192.0.2.10".parse().unwrap();
+        let syn_ack = TcpFlags::SYN | TcpFlags::ACK;
+        assert_eq!(
+            classify_reply(&tcp_reply(8080, DEFAULT_LOCAL_TCP_PORT, syn_ack), DEFAULT_LOCAL_TCP_PORT),
+            Some((ProbeKey::new(ip, DiscoveryTechnique::TcpSyn, 8080), true))
+        );
+        assert_eq!(
+            classify_reply(&tcp_reply(22, DEFAULT_LOCAL_TCP_PORT, TcpFlags::RST | TcpFlags::ACK), DEFAULT_LOCAL_TCP_PORT),
+            Some((ProbeKey::new(ip, DiscoveryTechnique::TcpSyn, 22), false))
+        );
+        assert_eq!(
+            classify_reply(&tcp_reply(DEFAULT_DISCOVERY_TCP_ACK_PORT, DEFAULT_LOCAL_TCP_PORT, TcpFlags::RST), DEFAULT_LOCAL_TCP_PORT),
+            Some((ProbeKey::new(ip, DiscoveryTechnique::TcpAck, DEFAULT_DISCOVERY_TCP_ACK_PORT), false))
+        );
+        assert_eq!(classify_reply(&tcp_reply(80, DEFAULT_LOCAL_TCP_PORT + 1, syn_ack), DEFAULT_LOCAL_TCP_PORT), None);
+        assert_eq!(classify_reply(&tcp_reply(80, DEFAULT_LOCAL_TCP_PORT, TcpFlags::ACK), DEFAULT_LOCAL_TCP_PORT), None);
+    }
+}
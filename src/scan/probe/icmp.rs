@@ -1,39 +1,43 @@
-use std::collections::{BTreeMap, HashMap, HashSet};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
 
-use futures::future::poll_fn;
-use netdev::{Interface, MacAddr};
-use nex::datalink::async_io::{async_channel, AsyncChannel, AsyncRawSender};
-use nex::packet::frame::Frame;
+use netdev::Interface;
+use nex::datalink::async_io::AsyncRawSender;
 use nex::packet::ip::IpNextProtocol;
+use tokio::sync::mpsc;
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 use anyhow::Result;
 use crate::{output::ScanResult, scan::ProbeSetting};
-use crate::capture::pcap::PacketCaptureOptions;
-use crate::endpoint::{EndpointResult, OsGuess};
+use crate::capture::pcap::{CapturedFrame, PacketCaptureOptions};
+use crate::ping::socket::{EchoReply, IcmpSocket};
+use crate::scan::collect::{HostCollector, HostReply, LocalAddrs, ProbeKey, SentProbes, REPLY_QUEUE_LEN};
+use crate::scan::send::RateController;
+use crate::endpoint::{DiscoveryTechnique, OsGuess};
 
-/// Send ICMP Echo Request packets to the specified target endpoints
+/// Send ICMP Echo Request packets to the specified target hosts.
+/// The send time of every probe is recorded in `sent` for RTT measurement.
 pub async fn send_hostscan_packets(
     tx: &mut Box<dyn AsyncRawSender>,
     interface: &Interface,
     scan_setting: &ProbeSetting,
+    rate: &mut RateController,
+    sent: &SentProbes,
 ) {
     let header_span = tracing::info_span!("icmp_host_scan");
     header_span.pb_set_style(&crate::output::progress::get_progress_style());
     header_span.pb_set_message("HostScan");
-    header_span.pb_set_length(scan_setting.target_endpoints.len() as u64);
+    header_span.pb_set_length(scan_setting.target_host_count());
     header_span.pb_set_position(0);
     header_span.pb_start();
+    let ip_packet = crate::interface::is_layer3(interface);
 
-    for target in &scan_setting.target_endpoints {
-        let packet = crate::packet::icmp::build_icmp_packet(&interface, target.ip, false);
-        // Send a packet using poll_fn.
-        match poll_fn(|cx| tx.poll_send(cx, &packet)).await {
-            Ok(_) => {
-                if !scan_setting.send_rate.is_zero() {
-                    tokio::time::sleep(scan_setting.send_rate).await;
-                }
-            },
+    for target_ip in scan_setting.target_ips() {
+        let packet = crate::packet::icmp::build_icmp_packet(&interface, &scan_setting.neighbors, &scan_setting.source, target_ip, ip_packet);
+        match rate.send(tx, &packet).await {
+            Ok(_) => sent.record(ProbeKey::new(target_ip, DiscoveryTechnique::IcmpEcho, 0)),
             Err(e) => eprintln!("Failed to send packet: {}", e),
         }
         header_span.pb_inc(1);
@@ -47,22 +51,10 @@ pub async fn run_host_scan(setting: ProbeSetting) -> Result<ScanResult> {
         Some(interface) => interface,
         None => return Err(anyhow::anyhow!("Interface not found")),
     };
+    // Resolve the MAC addresses of on-link targets
+    setting.neighbors.resolve(&interface, setting.target_ips(), setting.wait_time).await;
     // Create sender
-    let config = nex::datalink::Config {
-        write_buffer_size: 4096,
-        read_buffer_size: 4096,
-        read_timeout: Some(setting.wait_time),
-        write_timeout: None,
-        channel_type: nex::datalink::ChannelType::Layer2,
-        bpf_fd_attempts: 1000,
-        linux_fanout: None,
-        promiscuous: false,
-    };
-
-    let AsyncChannel::Ethernet(mut tx, mut rx) = async_channel(&interface, config)?
-    else {
-        unreachable!();
-    };
+    let (mut tx, mut rx) = crate::capture::channel::open(&interface, setting.wait_time)?;
 
     let mut capture_options: PacketCaptureOptions = PacketCaptureOptions {
         interface_index: interface.index,
@@ -77,7 +69,7 @@ pub async fn run_host_scan(setting: ProbeSetting) -> Result<ScanResult> {
         read_timeout: setting.wait_time,
         promiscuous: false,
         receive_undefined: false,
-        tunnel: interface.is_tun(),
+        tunnel: crate::interface::is_layer3(&interface),
         loopback: interface.is_loopback(),
     };
     for endpoint in &setting.target_endpoints {
@@ -87,121 +79,149 @@ pub async fn run_host_scan(setting: ProbeSetting) -> Result<ScanResult> {
     capture_options.ip_protocols.insert(IpNextProtocol::Icmpv6);
 
 
+    let mut rate = RateController::new(&setting);
+    let responses = rate.feedback().responses;
     let (ready_tx, ready_rx) = tokio::sync::oneshot::channel();
     let (stop_tx, mut stop_rx) = tokio::sync::oneshot::channel();
+    let (reply_tx, mut replies) = mpsc::channel(REPLY_QUEUE_LEN);
+    let local = LocalAddrs::new(&interface);
 
     let capture_handle: tokio::task::JoinHandle<_> = tokio::spawn(async move {
-        crate::capture::pcap::start_capture(
+        crate::capture::pcap::start_stream_capture(
             &mut rx,
             capture_options,
             ready_tx,
             &mut stop_rx,
+            &responses,
+            |captured| classify_host_reply(captured, &local),
+            reply_tx,
         )
         .await
     });
 
     // Wait for listener to start
-    let _ = ready_rx;
+    let _ = ready_rx.await;
     let start_time = std::time::Instant::now();
-    // Send probe packets
-    send_hostscan_packets(&mut tx, &interface, &setting).await;
-    tokio::time::sleep(setting.wait_time).await;
-    // Stop pcap
-    let _ = stop_tx.send(());
-    let frames = capture_handle.await.unwrap();
-    let dns_map = setting.get_dns_map();
-    let mut result = parse_hostscan_result(frames, &interface, &dns_map);
+    let sent = SentProbes::default();
+    let collector = RefCell::new(HostCollector::new(&setting));
+    // Record replies while the probes are still being sent
+    let collect = async {
+        while let Some(reply) = replies.recv().await {
+            collector.borrow_mut().record(reply, &sent);
+        }
+    };
+    let scan = async {
+        // Send probe packets
+        send_hostscan_packets(&mut tx, &interface, &setting, &mut rate, &sent).await;
+        tokio::time::sleep(setting.wait_time).await;
+        // Stop pcap
+        let _ = stop_tx.send(());
+        capture_handle.await.unwrap();
+    };
+    tokio::join!(collect, scan);
+    let mut result = collector.into_inner().finish();
     result.scan_time = start_time.elapsed();
     Ok(result)
 }
 
-/// Parse captured packets and extract scan results.
-fn parse_hostscan_result(
-    packets: Vec<Frame>,
-    iface: &Interface,
-    dns_map: &HashMap<IpAddr, String>,
-) -> ScanResult {
-    let oui_db = crate::db::oui::oui_db();
-    let if_ipv4_set: HashSet<Ipv4Addr> = iface.ipv4_addrs().into_iter().collect();
-    let if_ipv6_set: HashSet<Ipv6Addr> = iface.ipv6_addrs().into_iter().collect();
-    let mut result: ScanResult = ScanResult::new();
-    let mut endpoint_map: HashMap<IpAddr, EndpointResult> = HashMap::new();
-    for p in packets {
-        if p.ip.is_none() {
-            continue;
-        }
-        let mut mac_addr: MacAddr;
-        if let Some(datalink) = &p.datalink {
-            if let Some(ethernet_frame) = &datalink.ethernet {
-                if ethernet_frame.destination != iface.mac_addr.unwrap_or(MacAddr::zero()) {
-                    continue;
-                }
-                mac_addr = ethernet_frame.source;
-            } else {
-                mac_addr = MacAddr::zero();
-            }
-        } else {
-            mac_addr = MacAddr::zero();
+/// Classify a captured frame as an ICMP reply from a probed host.
+/// Any ICMP message from the host proves that it is up.
+fn classify_host_reply(captured: CapturedFrame, local: &LocalAddrs) -> Option<HostReply> {
+    let (ip_addr, ttl, mac_addr) = local.reply_source(&captured.frame)?;
+    let ip = captured.frame.ip.as_ref()?;
+    if ip.icmp.is_none() && ip.icmpv6.is_none() {
+        return None;
+    }
+    Some(HostReply {
+        probe: ProbeKey::new(ip_addr, DiscoveryTechnique::IcmpEcho, 0),
+        open: false,
+        ttl: Some(ttl),
+        mac_addr,
+        os: OsGuess::default().with_ttl_observed(ttl),
+        cpes: Vec::new(),
+        received_at: captured.received_at,
+        frame: captured.frame,
+    })
+}
+
+/// Run host scan using ICMP Echo Requests on unprivileged ICMP datagram sockets.
+pub async fn run_datagram_host_scan(setting: ProbeSetting) -> Result<ScanResult> {
+    let start_time = std::time::Instant::now();
+    let collector = RefCell::new(HostCollector::new(&setting));
+    probe_datagram_echo(&setting, &collector).await?;
+    let mut result = collector.into_inner().finish();
+    result.scan_time = start_time.elapsed();
+    Ok(result)
+}
+
+/// Send ICMP Echo Requests on datagram sockets and record the replies in `collector`.
+/// IPv6 targets are skipped if ICMPv6 datagram sockets are not allowed.
+pub async fn probe_datagram_echo(setting: &ProbeSetting, collector: &RefCell<HostCollector>) -> Result<()> {
+    let socket_v4 = IcmpSocket::open(false, setting.source.ip_addr)?;
+    let socket_v6 = match IcmpSocket::open(true, setting.source.ip_addr) {
+        Ok(socket) => Some(socket),
+        Err(e) => {
+            tracing::debug!("ICMPv6 datagram socket not available: {}", e);
+            None
         }
-        let ip_addr: IpAddr;
-        let ttl: u8;
-        if let Some(ip) = &p.ip {
-            // Expect ICMP or ICMPv6 Port Unreachable
-            if ip.icmp.is_none() && ip.icmpv6.is_none() {
-                continue;
-            }
-            if let Some(ipv4_packet) = &ip.ipv4 {
-                if if_ipv4_set.contains(&ipv4_packet.source) {
-                    mac_addr = iface.mac_addr.unwrap_or(MacAddr::zero());
-                    ttl = crate::util::ip::initial_ttl(ipv4_packet.ttl);
-                }else{
-                    ttl = ipv4_packet.ttl;
-                }
-                ip_addr = IpAddr::V4(ipv4_packet.source);
-            } else if let Some(ipv6_packet) = &ip.ipv6 {
-                if if_ipv6_set.contains(&ipv6_packet.source) {
-                    mac_addr = iface.mac_addr.unwrap_or(MacAddr::zero());
-                    ttl = crate::util::ip::initial_ttl(ipv6_packet.hop_limit);
-                }else {
-                    ttl = ipv6_packet.hop_limit;
+    };
+    let mut rate = RateController::new(setting);
+    let responses = rate.feedback().responses;
+    let (stop_tx, mut stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let sent: RefCell<HashMap<IpAddr, Instant>> = RefCell::new(HashMap::new());
+    // Record replies while the probes are still being sent
+    let collect = async {
+        loop {
+            let reply = tokio::select! {
+                _ = &mut stop_rx => break,
+                reply = socket_v4.recv_reply() => reply,
+                reply = recv_reply(socket_v6.as_ref()) => reply,
+            };
+            let reply = match reply {
+                Ok(reply) => reply,
+                Err(e) => {
+                    tracing::error!("Failed to receive ICMP reply: {}", e);
+                    break;
                 }
-                ip_addr = IpAddr::V6(ipv6_packet.source);
-            } else {
+            };
+            let Some(sent_at) = sent.borrow().get(&reply.src).copied() else {
                 continue;
-            }
-        } else {
-            continue;
+            };
+            responses.fetch_add(1, Ordering::Relaxed);
+            let rtt_ms = reply.received_at.duration_since(sent_at).as_millis() as u32;
+            collector.borrow_mut().record_socket_reply(ProbeKey::new(reply.src, DiscoveryTechnique::IcmpEcho, 0), false, Some(rtt_ms));
         }
-
-        let vendor_name_opt: Option<String>;
-        if let Some(oui) = oui_db.lookup_mac(&mac_addr) {
-            if let Some(vendor_detail) = &oui.vendor_detail {
-                vendor_name_opt = Some(vendor_detail.clone());
-            } else {
-                vendor_name_opt = Some(oui.vendor.clone());
+    };
+    let scan = async {
+        let header_span = tracing::info_span!("icmp_host_scan");
+        header_span.pb_set_style(&crate::output::progress::get_progress_style());
+        header_span.pb_set_message("HostScan");
+        header_span.pb_set_length(setting.target_host_count());
+        header_span.pb_set_position(0);
+        header_span.pb_start();
+        for target_ip in setting.target_ips() {
+            let socket = if target_ip.is_ipv4() { Some(&socket_v4) } else { socket_v6.as_ref() };
+            if let Some(socket) = socket {
+                match rate.send_on(socket.send_echo(target_ip, 0)).await {
+                    Ok(_) => {
+                        sent.borrow_mut().insert(target_ip, Instant::now());
+                    }
+                    Err(e) => tracing::debug!("Failed to send ICMP Echo to {}: {}", target_ip, e),
+                }
             }
-        } else {
-            vendor_name_opt = None;
+            header_span.pb_inc(1);
         }
+        drop(header_span);
+        tokio::time::sleep(setting.wait_time).await;
+        let _ = stop_tx.send(());
+    };
+    tokio::join!(collect, scan);
+    Ok(())
+}
 
-        endpoint_map
-            .entry(ip_addr)
-            .or_insert(EndpointResult {
-                ip: ip_addr,
-                hostname: dns_map.get(&ip_addr).cloned(),
-                ports: BTreeMap::new(),
-                mac_addr: Some(mac_addr),
-                vendor_name: vendor_name_opt,
-                os: OsGuess::default().with_ttl_observed(ttl),
-                tags: Vec::new(),
-                cpes: Vec::new(),
-            });
-
-        result.fingerprints.push(p.clone());
-
-    }
-    for (_ip, endpoint) in endpoint_map {
-        result.endpoints.push(endpoint);
+async fn recv_reply(socket: Option<&IcmpSocket>) -> std::io::Result<EchoReply> {
+    match socket {
+        Some(socket) => socket.recv_reply().await,
+        None => std::future::pending().await,
     }
-    result
 }
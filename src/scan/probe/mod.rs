@@ -1,4 +1,7 @@
 pub mod tcp;
 pub mod udp;
 pub mod quic;
+pub mod sctp;
 pub mod icmp;
+pub mod discovery;
+pub mod nei;
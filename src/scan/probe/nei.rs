@@ -0,0 +1,199 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+use netdev::Interface;
+use nex::datalink::async_io::{async_channel, AsyncChannel, AsyncRawSender};
+use nex::packet::arp::ArpOperation;
+use nex::packet::ethernet::EtherType;
+use nex::packet::icmpv6::Icmpv6Type;
+use nex::packet::ip::IpNextProtocol;
+use tokio::sync::mpsc;
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+use anyhow::Result;
+use crate::{output::ScanResult, protocol::Protocol, scan::ProbeSetting};
+use crate::capture::pcap::{CapturedFrame, PacketCaptureOptions};
+use crate::scan::collect::{HostCollector, HostReply, ProbeKey, SentProbes, REPLY_QUEUE_LEN};
+use crate::scan::send::RateController;
+use crate::endpoint::{DiscoveryTechnique, OsGuess};
+
+/// Send ARP requests (IPv4 targets) or NDP Neighbor Solicitations (IPv6 targets).
+/// Targets of the other address family are skipped.
+/// The send time of every request is recorded in `sent` for RTT measurement.
+pub async fn send_hostscan_packets(
+    tx: &mut Box<dyn AsyncRawSender>,
+    interface: &Interface,
+    scan_setting: &ProbeSetting,
+    protocol: Protocol,
+    rate: &mut RateController,
+    sent: &SentProbes,
+) {
+    let header_span = tracing::info_span!("nei_host_scan");
+    header_span.pb_set_style(&crate::output::progress::get_progress_style());
+    header_span.pb_set_message("HostScan");
+    header_span.pb_set_length(scan_setting.target_host_count());
+    header_span.pb_set_position(0);
+    header_span.pb_start();
+
+    let mut skipped: usize = 0;
+    for target_ip in scan_setting.target_ips() {
+        header_span.pb_inc(1);
+        let packet = match (protocol, target_ip) {
+            (Protocol::Arp, IpAddr::V4(_)) => crate::packet::arp::build_arp_packet(interface, target_ip),
+            (Protocol::Ndp, IpAddr::V6(_)) => crate::packet::ndp::build_ndp_packet(interface, target_ip),
+            _ => {
+                skipped += 1;
+                continue;
+            }
+        };
+        match rate.send(tx, &packet).await {
+            Ok(_) => sent.record(ProbeKey::new(target_ip, technique(target_ip), 0)),
+            Err(e) => eprintln!("Failed to send packet: {}", e),
+        }
+    }
+    drop(header_span);
+    if skipped > 0 {
+        tracing::warn!("Skipped {} target(s): {} requires {} addresses", skipped, protocol.as_str().to_uppercase(), if protocol == Protocol::Arp { "IPv4" } else { "IPv6" });
+    }
+}
+
+/// Run host scan by sweeping the targets with ARP (IPv4) or NDP (IPv6) and return the results.
+/// Only hosts on the local segment of the interface can answer.
+pub async fn run_host_scan(setting: ProbeSetting, protocol: Protocol) -> Result<ScanResult> {
+    let interface = match crate::interface::get_interface_by_index(setting.if_index) {
+        Some(interface) => interface,
+        None => return Err(anyhow::anyhow!("Interface not found")),
+    };
+    if crate::interface::is_layer3(&interface) {
+        anyhow::bail!("{} scan requires an Ethernet interface ({} has no link layer)", protocol.as_str().to_uppercase(), interface.name);
+    }
+    // Create sender
+    let config = nex::datalink::Config {
+        write_buffer_size: 4096,
+        read_buffer_size: 4096,
+        read_timeout: Some(setting.wait_time),
+        write_timeout: None,
+        channel_type: nex::datalink::ChannelType::Layer2,
+        bpf_fd_attempts: 1000,
+        linux_fanout: None,
+        promiscuous: false,
+    };
+
+    let AsyncChannel::Ethernet(mut tx, mut rx) = async_channel(&interface, config)?
+    else {
+        unreachable!();
+    };
+
+    let mut capture_options: PacketCaptureOptions = PacketCaptureOptions {
+        interface_index: interface.index,
+        interface_name: interface.name.clone(),
+        src_ips: HashSet::new(),
+        dst_ips: HashSet::new(),
+        src_ports: HashSet::new(),
+        dst_ports: HashSet::new(),
+        ether_types: HashSet::new(),
+        ip_protocols: HashSet::new(),
+        capture_timeout: setting.task_timeout,
+        read_timeout: setting.wait_time,
+        promiscuous: false,
+        receive_undefined: false,
+        tunnel: false,
+        loopback: false,
+    };
+    for endpoint in &setting.target_endpoints {
+        capture_options.src_ips.insert(endpoint.ip);
+    }
+    match protocol {
+        Protocol::Arp => {
+            capture_options.ether_types.insert(EtherType::Arp);
+        }
+        Protocol::Ndp => {
+            capture_options.ether_types.insert(EtherType::Ipv6);
+            capture_options.ip_protocols.insert(IpNextProtocol::Icmpv6);
+        }
+        _ => anyhow::bail!("{} is not a neighbor discovery protocol", protocol.as_str()),
+    }
+
+    let mut rate = RateController::new(&setting);
+    let responses = rate.feedback().responses;
+    let (ready_tx, ready_rx) = tokio::sync::oneshot::channel();
+    let (stop_tx, mut stop_rx) = tokio::sync::oneshot::channel();
+    let (reply_tx, mut replies) = mpsc::channel(REPLY_QUEUE_LEN);
+
+    let capture_handle: tokio::task::JoinHandle<_> = tokio::spawn(async move {
+        crate::capture::pcap::start_stream_capture(
+            &mut rx,
+            capture_options,
+            ready_tx,
+            &mut stop_rx,
+            &responses,
+            classify_host_reply,
+            reply_tx,
+        )
+        .await
+    });
+
+    // Wait for listener to start
+    let _ = ready_rx.await;
+    let start_time = std::time::Instant::now();
+    let sent = SentProbes::default();
+    let collector = RefCell::new(HostCollector::new(&setting));
+    // Record replies while the requests are still being sent
+    let collect = async {
+        while let Some(reply) = replies.recv().await {
+            // Later phases of the scan send to the discovered neighbors directly
+            setting.neighbors.insert(reply.probe.ip, reply.mac_addr);
+            collector.borrow_mut().record(reply, &sent);
+        }
+    };
+    let scan = async {
+        // Send requests
+        send_hostscan_packets(&mut tx, &interface, &setting, protocol, &mut rate, &sent).await;
+        tokio::time::sleep(setting.wait_time).await;
+        // Stop pcap
+        let _ = stop_tx.send(());
+        capture_handle.await.unwrap();
+    };
+    tokio::join!(collect, scan);
+    let mut result = collector.into_inner().finish();
+    result.scan_time = start_time.elapsed();
+    Ok(result)
+}
+
+/// Get the neighbor discovery technique for a target address.
+fn technique(ip_addr: IpAddr) -> DiscoveryTechnique {
+    if ip_addr.is_ipv4() { DiscoveryTechnique::Arp } else { DiscoveryTechnique::Ndp }
+}
+
+/// Classify a captured frame as an ARP Reply or Neighbor Advertisement.
+/// Unsolicited announcements and replies for other hosts are ignored by the collector.
+fn classify_host_reply(captured: CapturedFrame) -> Option<HostReply> {
+    let p = &captured.frame;
+    let datalink = p.datalink.as_ref()?;
+    let (ip_addr, mac_addr) = if let Some(arp) = &datalink.arp {
+        if arp.operation != ArpOperation::Reply {
+            return None;
+        }
+        (IpAddr::V4(arp.sender_proto_addr), arp.sender_hw_addr)
+    } else if let (Some(ethernet_frame), Some(ip)) = (&datalink.ethernet, &p.ip) {
+        let (Some(ipv6_packet), Some(icmpv6)) = (&ip.ipv6, &ip.icmpv6) else {
+            return None;
+        };
+        if icmpv6.icmpv6_type != Icmpv6Type::NeighborAdvertisement {
+            return None;
+        }
+        (IpAddr::V6(ipv6_packet.source), ethernet_frame.source)
+    } else {
+        return None;
+    };
+    Some(HostReply {
+        probe: ProbeKey::new(ip_addr, technique(ip_addr), 0),
+        open: false,
+        ttl: None,
+        mac_addr,
+        os: OsGuess::default(),
+        cpes: Vec::new(),
+        received_at: captured.received_at,
+        frame: captured.frame,
+    })
+}
@@ -1,50 +1,62 @@
-use std::{collections::BTreeMap, time::Duration};
+use std::{collections::{BTreeMap, HashMap}, net::{IpAddr, SocketAddr}, sync::Arc, time::Duration};
 
 use anyhow::Result;
 use futures::StreamExt;
 use tokio::sync::mpsc;
 use tracing_indicatif::span_ext::IndicatifSpanExt;
-use crate::{cli::PortScanMethod, endpoint::{EndpointResult, OsGuess, Port, PortResult, PortState, ServiceInfo, TransportProtocol}, output::ScanResult, scan::ProbeSetting, service::probe::quic::quic_client_config};
+use crate::{cli::PortScanMethod, endpoint::{EndpointResult, OsGuess, UnansweredPorts, Port, PortReason, PortResult, PortState, ServiceInfo, TransportProtocol}, output::ScanResult, probe::ProbeSource, scan::ProbeSetting, scan::scheduler::Scheduler, service::probe::quic::quic_client_config};
 
-/// Try to connect to the given ports on the target endpoint using QUIC protocol.
-/// Concurrency specifies the number of concurrent connection attempts.
+/// Try to connect to the given socket addresses using QUIC protocol.
+/// Every connection attempt holds a permit of `scheduler`.
+/// Targets are consumed lazily. Hosts listed in `host_timeouts` use their own timeout.
+/// Endpoints are bound to the source address, if one was chosen.
+/// Only ports that got an answer or failed locally are returned; timed out ports are left out.
 pub async fn try_connect_ports(
-    target: crate::endpoint::Endpoint,
-    concurrency: usize,
+    targets: Box<dyn ExactSizeIterator<Item = SocketAddr> + Send>,
+    source: ProbeSource,
+    dns_map: Arc<HashMap<IpAddr, String>>,
+    scheduler: &Scheduler,
     timeout: Duration,
-) -> Result<EndpointResult> {
+    host_timeouts: Arc<HashMap<IpAddr, Duration>>,
+) -> Result<HashMap<IpAddr, BTreeMap<Port, PortResult>>> {
     let alpn: [&[u8]; 8] = [
             b"h3".as_slice(),
             b"h3-34".as_slice(), b"h3-33".as_slice(), b"h3-32".as_slice(), b"h3-31".as_slice(), b"h3-30".as_slice(), b"h3-29".as_slice(),
             b"hq-29".as_slice(),
         ];
-    let (ch_tx, mut ch_rx) = mpsc::unbounded_channel::<PortResult>();
+    let (ch_tx, mut ch_rx) = mpsc::unbounded_channel::<(IpAddr, PortResult)>();
     let header_span = tracing::info_span!("quic_connect_scan");
     header_span.pb_set_style(&crate::output::progress::get_progress_style());
-    header_span.pb_set_message(&format!("QUIC PortScan ({})", target.ip));
-    header_span.pb_set_length(target.ports.len() as u64);
+    header_span.pb_set_message("QUIC PortScan");
+    header_span.pb_set_length(targets.len() as u64);
     header_span.pb_set_position(0);
     header_span.pb_start();
 
     let span_rx = header_span.clone();
     let recv_task = tokio::spawn(async move {
-        let mut open_ports: BTreeMap<Port, PortResult> = BTreeMap::new();
-        while let Some(port_result) = ch_rx.recv().await {
-            open_ports.insert(port_result.port.clone(), port_result);
+        let mut port_map: HashMap<IpAddr, BTreeMap<Port, PortResult>> = HashMap::new();
+        while let Some((ip, port_result)) = ch_rx.recv().await {
+            // Timed out ports are only counted by the caller
+            if port_result.reason != Some(PortReason::Timeout) {
+                port_map.entry(ip).or_default().insert(port_result.port, port_result);
+            }
             // Update progress bar
             span_rx.pb_inc(1);
         }
-        open_ports
+        port_map
     });
 
-    let hostname = target.hostname.clone().unwrap_or_else(|| target.ip.to_string());
-    let prod = futures::stream::iter(target.socket_addrs(TransportProtocol::Quic)).for_each_concurrent(concurrency, move |socket_addr| {
+    let scheduler = scheduler.clone();
+    let prod = futures::stream::iter(targets).for_each_concurrent(scheduler.limits().in_flight, move |socket_addr| {
         let ch_tx = ch_tx.clone();
-        let hostname = hostname.clone();
+        let scheduler = scheduler.clone();
+        let hostname = dns_map.get(&socket_addr.ip()).cloned().unwrap_or_else(|| socket_addr.ip().to_string());
         let client_cfg = quic_client_config(true, &alpn).unwrap();
+        let timeout = host_timeouts.get(&socket_addr.ip()).copied().unwrap_or(timeout);
 
         async move {
-            let mut endpoint = match quinn::Endpoint::client((if target.ip.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" }).parse().unwrap()) {
+            let _permit = scheduler.acquire(socket_addr.ip()).await;
+            let mut endpoint = match quinn::Endpoint::client(source.local_addr(socket_addr.ip())) {
                 Ok(ep) => ep,
                 Err(_) => return,
             };
@@ -63,6 +75,8 @@ pub async fn try_connect_ports(
                 state: PortState::Closed,
                 service: ServiceInfo::default(),
                 rtt_ms: None,
+                reason: None,
+                reply_ttl: None,
             };
             match tokio::time::timeout(timeout, connect_fut).await {
                 Ok(quinn_conn) => {
@@ -70,6 +84,7 @@ pub async fn try_connect_ports(
                         Ok(conn) => {
                             // Connection succeeded
                             port_result.state = PortState::Open;
+                            port_result.reason = Some(PortReason::QuicResponse);
                             conn.close(0u32.into(), b"Connection closed by client");
                         }
                         Err(e) => {
@@ -82,38 +97,39 @@ pub async fn try_connect_ports(
                                     // Error, but QUIC service is still running
                                     // So we classify it as open
                                     port_result.state = PortState::Open;
+                                    port_result.reason = Some(PortReason::QuicResponse);
+                                },
+                                // No answer from the peer within the QUIC idle timeout
+                                quinn::ConnectionError::TimedOut => {
+                                    port_result.state = PortState::OpenFiltered;
+                                    port_result.reason = Some(PortReason::Timeout);
+                                },
+                                _ => {
+                                    port_result.state = PortState::Unknown;
+                                    port_result.reason = Some(PortReason::LocalError);
+                                    tracing::debug!("{}: {}", socket_addr, e);
                                 },
-                                _ => {},
                             }
                         }
                     }
                 }
                 Err(e) => {
-                    // Timeout
-                    tracing::error!("Failed to connect to {}: {}", socket_addr, e);
+                    // Timeout: UDP gives no negative answer, so the port may be open or filtered
+                    port_result.state = PortState::OpenFiltered;
+                    port_result.reason = Some(PortReason::Timeout);
+                    tracing::debug!("Failed to connect to {}: {}", socket_addr, e);
                 }
             }
-            let _ = ch_tx.send(port_result);
+            let _ = ch_tx.send((socket_addr.ip(), port_result));
         }
     });
 
     let prod_task = tokio::spawn(prod);
     let (results_res, _prod_res) = tokio::join!(recv_task, prod_task);
-    let open_ports = results_res?;
+    let port_map = results_res?;
     // Finish header span
     drop(header_span);
-
-    let ep = EndpointResult {
-        ip: target.ip,
-        hostname: target.hostname,
-        ports: open_ports,
-        mac_addr: target.mac_addr,
-        vendor_name: None,
-        os: OsGuess::default(),
-        tags: target.tags,
-        cpes: Vec::new(),
-    };
-    Ok(ep)
+    Ok(port_map)
 }
 
 /// Run a QUIC connect scan based on the provided probe settings.
@@ -121,36 +137,41 @@ pub async fn run_connect_scan(
     setting: ProbeSetting,
 ) -> Result<ScanResult> {
     let start_time = std::time::Instant::now();
-    let mut tasks = vec![];
-    for target in setting.target_endpoints {
-        tasks.push(tokio::spawn(async move {
-            let host = try_connect_ports(
-                target,
-                setting.port_concurrency,
-                setting.connect_timeout,
-            )
-            .await;
-            host
-        }));
-    }
-    let mut endpoints: Vec<EndpointResult> = vec![];
-    for task in tasks {
-        match task.await {
-            Ok(endpoint) => {
-                match endpoint {
-                    Ok(ep) => endpoints.push(ep),
-                    Err(e) => {
-                        tracing::error!("Failed to connect to endpoint: {}", e);
-                    }
-                }
-            }
-            Err(e) => {
-                tracing::error!("Failed to join task: {}", e);
-            }
-        }
-    }
+    let dns_map = Arc::new(setting.get_dns_map());
+    let port_map = try_connect_ports(
+        setting.target_addrs(TransportProtocol::Quic),
+        setting.source,
+        dns_map.clone(),
+        &setting.scheduler,
+        setting.connect_timeout,
+        setting.host_timeouts.clone(),
+    )
+    .await?;
     let mut result = ScanResult::new();
-    result.endpoints = endpoints;
+    result.endpoints = port_map
+        .into_iter()
+        .map(|(ip, ports)| EndpointResult {
+            ip,
+            hostname: dns_map.get(&ip).cloned(),
+            ports,
+            unanswered: Vec::new(),
+            mac_addr: None,
+            vendor_name: None,
+            rtt_ms: None,
+            discovered_by: Vec::new(),
+            os: OsGuess::default(),
+            tags: Vec::new(),
+            cpes: Vec::new(),
+        })
+        .collect();
+    // QUIC ports are reported as UDP ports
+    let unanswered = UnansweredPorts {
+        transport: TransportProtocol::Udp,
+        state: PortState::OpenFiltered,
+        reason: PortReason::Timeout,
+        count: 0,
+    };
+    crate::scan::summarize_unanswered(&mut result, &setting, TransportProtocol::Quic, unanswered);
     result.scan_time = start_time.elapsed();
     result.fingerprints = Vec::new();
     Ok(result)
@@ -0,0 +1,308 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use netdev::{Interface, MacAddr};
+use nex::packet::frame::Frame;
+use nex::packet::icmp::IcmpType;
+use nex::packet::icmpv6::Icmpv6Type;
+use nex::packet::ip::IpNextProtocol;
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+use anyhow::Result;
+use crate::{config::default::DEFAULT_LOCAL_SCTP_PORT, output::ScanResult, probe::ProbeSetting};
+use crate::capture::pcap::{CapturedFrame, PacketCaptureOptions};
+use crate::scan::collect::{PortCollector, PortReply, Unanswered};
+use crate::scan::engine::PacketEngine;
+use crate::scan::send::{reply_rtt_ms, RateController};
+use crate::cli::PortScanMethod;
+use crate::endpoint::{PortReason, PortState, TransportProtocol, UnansweredPorts};
+use crate::packet::sctp::chunk;
+
+/// ICMP Destination Unreachable code for Port Unreachable
+const ICMP_PORT_UNREACHABLE: u8 = 3;
+/// ICMPv6 Destination Unreachable code for Port Unreachable
+const ICMPV6_PORT_UNREACHABLE: u8 = 4;
+
+/// Send SCTP INIT packets for port scanning.
+pub async fn send_portscan_packets(
+    engine: &mut PacketEngine,
+    interface: &Interface,
+    scan_setting: &ProbeSetting,
+    init_tag: u32,
+    rate: &mut RateController,
+    targets: impl ExactSizeIterator<Item = SocketAddr>,
+    message: &str,
+) {
+    rate.begin_round();
+    let header_span = tracing::info_span!("sctp_port_scan");
+    header_span.pb_set_style(&crate::output::progress::get_progress_style());
+    header_span.pb_set_message(message);
+    header_span.pb_set_length(targets.len() as u64);
+    header_span.pb_set_position(0);
+    header_span.pb_start();
+    let ip_packet = crate::interface::is_layer3(interface);
+
+    for target in targets {
+        let packet = crate::packet::sctp::build_sctp_init_packet(interface, &scan_setting.neighbors, &scan_setting.source, target.ip(), target.port(), init_tag, ip_packet);
+        if let Err(e) = rate.dispatch(engine, packet).await {
+            eprintln!("Failed to send packet: {}", e);
+        }
+        header_span.pb_inc(1);
+    }
+    drop(header_span);
+}
+
+/// Run an SCTP INIT port scan based on the provided probe settings.
+pub async fn run_init_scan(setting: ProbeSetting) -> Result<ScanResult> {
+    let interface = match crate::interface::get_interface_by_index(setting.if_index) {
+        Some(interface) => interface,
+        None => return Err(anyhow::anyhow!("Interface not found")),
+    };
+    // Resolve the MAC addresses of on-link targets
+    setting.neighbors.resolve(&interface, setting.target_ips(), setting.wait_time).await;
+    let mut capture_options: PacketCaptureOptions = PacketCaptureOptions {
+        interface_index: interface.index,
+        interface_name: interface.name.clone(),
+        src_ips: HashSet::new(),
+        dst_ips: HashSet::new(),
+        src_ports: HashSet::new(),
+        dst_ports: HashSet::new(),
+        ether_types: HashSet::new(),
+        ip_protocols: HashSet::new(),
+        capture_timeout: setting.task_timeout,
+        read_timeout: setting.wait_time,
+        promiscuous: false,
+        receive_undefined: false,
+        tunnel: crate::interface::is_layer3(&interface),
+        loopback: interface.is_loopback(),
+    };
+    // ICMP errors may be sent by intermediate routers, so accept anything addressed to us.
+    // SCTP replies are matched against the local port and the Initiate Tag by the classifier.
+    capture_options.dst_ips.extend(interface.ip_addrs());
+    capture_options.ip_protocols.insert(IpNextProtocol::Sctp);
+    capture_options.ip_protocols.insert(IpNextProtocol::Icmp);
+    capture_options.ip_protocols.insert(IpNextProtocol::Icmpv6);
+    let local_port = setting.source.port_or(DEFAULT_LOCAL_SCTP_PORT);
+    // Replies to an INIT carry its Initiate Tag, which must not be 0
+    let init_tag = fastrand::u32(1..);
+
+    let mut rate = RateController::new(&setting);
+    let iface_mac = interface.mac_addr.unwrap_or(MacAddr::zero());
+    // Start times of the send rounds, replies are timed against the latest round before them
+    let rounds: Arc<Mutex<Vec<Instant>>> = Arc::new(Mutex::new(Vec::new()));
+    let round_starts = Arc::clone(&rounds);
+    let (mut engine, mut replies) = PacketEngine::open(&interface, &setting.io, capture_options, rate.feedback(), move |captured| {
+        classify_port_reply(captured, iface_mac, local_port, init_tag, &round_starts.lock().unwrap())
+    })?;
+    // Replies are recorded as they arrive, while probes are still being sent
+    let collector = RefCell::new(PortCollector::new(&setting, TransportProtocol::Sctp));
+    let collect = async {
+        while let Some(reply) = replies.recv().await {
+            collector.borrow_mut().record(reply);
+        }
+    };
+    let start_time = std::time::Instant::now();
+    let target_count = setting.target_addrs(TransportProtocol::Sctp).len();
+    let scan = async {
+        for round in 0..=setting.retries {
+            let answered_before = collector.borrow().answered_count();
+            let retransmitted = target_count.saturating_sub(answered_before);
+            if round > 0 && retransmitted == 0 {
+                break;
+            }
+            let targets = Unanswered {
+                targets: setting.target_addrs(TransportProtocol::Sctp),
+                collector: &collector,
+                remaining: retransmitted,
+            };
+            let message = if round == 0 {
+                "SCTP PortScan".to_string()
+            } else {
+                format!("SCTP PortScan (retry {})", round)
+            };
+            rounds.lock().unwrap().push(Instant::now());
+            send_portscan_packets(&mut engine, &interface, &setting, init_tag, &mut rate, targets, &message).await;
+            engine.flush().await;
+            tokio::time::sleep(setting.wait_time).await;
+            if round > 0 {
+                rate.record_retransmit(retransmitted, collector.borrow().answered_count() - answered_before);
+            }
+        }
+        engine.finish().await
+    };
+    let ((), io_stats) = tokio::join!(collect, scan);
+    // INITs to closed ports are answered with ABORT, so silence means filtered
+    let unanswered = UnansweredPorts {
+        transport: TransportProtocol::Sctp,
+        state: PortState::Filtered,
+        reason: PortReason::NoResponse,
+        count: 0,
+    };
+    let mut result = collector.into_inner().finish(unanswered);
+    result.scan_time = start_time.elapsed();
+    result.io_stats = Some(io_stats);
+    Ok(result)
+}
+
+/// Run an SCTP port scan using the specified probe settings and method.
+pub async fn run_port_scan(
+    setting: ProbeSetting,
+    _method: PortScanMethod,
+) -> Result<ScanResult> {
+    run_init_scan(setting).await
+}
+
+/// Classify a captured frame as a reply to one of our INIT probes:
+/// INIT-ACK means open, ABORT or ICMP port unreachable closed and other unreachables filtered.
+/// Returns the probed socket address, the port state implied by the reply and the reason.
+fn classify_reply(frame: &Frame, local_port: u16, init_tag: u32) -> Option<(SocketAddr, PortState, PortReason)> {
+    let ip = frame.ip.as_ref()?;
+    let (src_ip, protocol) = if let Some(ipv4_packet) = &ip.ipv4 {
+        (IpAddr::V4(ipv4_packet.source), ipv4_packet.next_level_protocol)
+    } else if let Some(ipv6_packet) = &ip.ipv6 {
+        (IpAddr::V6(ipv6_packet.source), ipv6_packet.next_header)
+    } else {
+        return None;
+    };
+    if protocol == IpNextProtocol::Sctp {
+        let header = crate::packet::sctp::parse_sctp_packet(&frame.payload)?;
+        if header.dst_port != local_port || header.verification_tag != init_tag {
+            return None;
+        }
+        let (state, reason) = match header.chunk_type {
+            chunk::INIT_ACK => (PortState::Open, PortReason::InitAck),
+            chunk::ABORT => (PortState::Closed, PortReason::Abort),
+            _ => return None,
+        };
+        return Some((SocketAddr::new(src_ip, header.src_port), state, reason));
+    }
+    let (icmp_type, icmp_code, port_unreachable) = if let Some(icmp) = &ip.icmp {
+        if icmp.icmp_type != IcmpType::DestinationUnreachable {
+            return None;
+        }
+        (icmp.icmp_type.value(), icmp.icmp_code.0, icmp.icmp_code.0 == ICMP_PORT_UNREACHABLE)
+    } else if let Some(icmpv6) = &ip.icmpv6 {
+        if icmpv6.icmpv6_type != Icmpv6Type::DestinationUnreachable {
+            return None;
+        }
+        (icmpv6.icmpv6_type.value(), icmpv6.icmpv6_code.0, icmpv6.icmpv6_code.0 == ICMPV6_PORT_UNREACHABLE)
+    } else {
+        return None;
+    };
+    let quoted = crate::packet::icmp::parse_quoted_packet(&frame.payload)?;
+    if quoted.protocol != IpNextProtocol::Sctp || quoted.src_port != local_port {
+        return None;
+    }
+    let addr = SocketAddr::new(quoted.dst_ip, quoted.dst_port);
+    if port_unreachable {
+        Some((addr, PortState::Closed, PortReason::IcmpPortUnreach { icmp_type, icmp_code }))
+    } else {
+        Some((addr, PortState::Filtered, PortReason::IcmpUnreach { icmp_type, icmp_code }))
+    }
+}
+
+/// Classify a captured frame into a port scan reply.
+/// Frames not addressed to the interface and replies to other INITs are discarded.
+/// The round trip time is measured from the start of the latest send round before the reply.
+fn classify_port_reply(captured: CapturedFrame, iface_mac: MacAddr, local_port: u16, init_tag: u32, rounds: &[Instant]) -> Option<PortReply> {
+    let p = captured.frame;
+    let ip = p.ip.as_ref()?;
+    let mac_addr = match p.datalink.as_ref().and_then(|datalink| datalink.ethernet.as_ref()) {
+        Some(ethernet_frame) if ethernet_frame.destination != iface_mac => return None,
+        Some(ethernet_frame) => ethernet_frame.source,
+        None => MacAddr::zero(),
+    };
+    let (src_ip, ttl) = if let Some(ipv4_packet) = &ip.ipv4 {
+        (IpAddr::V4(ipv4_packet.source), ipv4_packet.ttl)
+    } else if let Some(ipv6_packet) = &ip.ipv6 {
+        (IpAddr::V6(ipv6_packet.source), ipv6_packet.hop_limit)
+    } else {
+        return None;
+    };
+    let (addr, state, reason) = classify_reply(&p, local_port, init_tag)?;
+    Some(PortReply {
+        addr,
+        state,
+        reason,
+        rtt_ms: reply_rtt_ms(rounds, captured.received_at),
+        ttl,
+        // Link-layer and TTL information is only meaningful for replies from the target itself
+        target_mac: (src_ip == addr.ip()).then_some(mac_addr),
+        frame: (state == PortState::Open).then_some(p),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nex::packet::frame::ParseOption;
+
+    /// IPv4 packet from 192.0.2.10 to 192.0.2.2 carrying `payload`
+    fn ipv4_packet(protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, protocol, 0, 0, 192, 0, 2, 10, 192, 0, 2, 2];
+        packet[2..4].copy_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    /// SCTP reply from port `src_port` with a single empty chunk of type `chunk_type`
+    fn sctp_reply(src_port: u16, dst_port: u16, verification_tag: u32, chunk_type: u8) -> Frame {
+        let sctp = crate::packet::sctp::build_sctp_packet(src_port, dst_port, verification_tag, &[chunk_type, 0, 0, 4]);
+        Frame::from_buf(&ipv4_packet(132, &sctp), ParseOption { from_ip_packet: true, offset: 0 }).unwrap()
+    }
+
+    /// ICMP Destination Unreachable with `code` quoting our INIT to port `dst_port`
+    fn icmp_unreachable(code: u8, dst_port: u16) -> Frame {
+        let init = crate::packet::sctp::build_sctp_init(DEFAULT_LOCAL_SCTP_PORT, dst_port, 1);
+        let mut quoted = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, 132, 0, 0, 192, 0, 2, 2, 192, 0, 2, 10];
+        quoted[2..4].copy_from_slice(&((20 + init.len()) as u16).to_be_bytes());
+        quoted.extend_from_slice(&init[..8]);
+        let mut icmp = vec![3, code, 0, 0, 0, 0, 0, 0];
+        icmp.extend_from_slice(&quoted);
+        let checksum = nex::packet::util::checksum(&icmp, 1);
+        icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+        Frame::from_buf(&ipv4_packet(1, &icmp), ParseOption { from_ip_packet: true, offset: 0 }).unwrap()
+    }
+
+    #[test]
+    fn init_replies_classify_ports() {
+        let (local_port, init_tag) = (DEFAULT_LOCAL_SCTP_PORT, 0x1234_5678);
+        let target = |port| SocketAddr::new("192.0.2.10".parse().unwrap(), port);
+        assert_eq!(
+            classify_reply(&sctp_reply(3868, local_port, init_tag, chunk::INIT_ACK), local_port, init_tag),
+            Some((target(3868), PortState::Open, PortReason::InitAck))
+        );
+        assert_eq!(
+            classify_reply(&sctp_reply(2905, local_port, init_tag, chunk::ABORT), local_port, init_tag),
+            Some((target(2905), PortState::Closed, PortReason::Abort))
+        );
+        // Replies to other INITs are discarded
+        assert_eq!(classify_reply(&sctp_reply(3868, local_port, init_tag + 1, chunk::INIT_ACK), local_port, init_tag), None);
+        assert_eq!(classify_reply(&sctp_reply(3868, local_port + 1, init_tag, chunk::INIT_ACK), local_port, init_tag), None);
+        assert_eq!(
+            classify_reply(&icmp_unreachable(ICMP_PORT_UNREACHABLE, 2904), local_port, init_tag),
+            Some((target(2904), PortState::Closed, PortReason::IcmpPortUnreach { icmp_type: 3, icmp_code: 3 }))
+        );
+        assert_eq!(
+            classify_reply(&icmp_unreachable(13, 2904), local_port, init_tag),
+            Some((target(2904), PortState::Filtered, PortReason::IcmpUnreach { icmp_type: 3, icmp_code: 13 }))
+        );
+    }
+
+    #[test]
+    fn replies_are_timed_against_the_latest_round() {
+        let (local_port, init_tag) = (DEFAULT_LOCAL_SCTP_PORT, 0x1234_5678);
+        let start = Instant::now();
+        let rounds = [start, start + std::time::Duration::from_millis(500)];
+        let reply = |received_at| {
+            let frame = sctp_reply(3868, local_port, init_tag, chunk::ABORT);
+            classify_port_reply(CapturedFrame { frame, received_at }, MacAddr::zero(), local_port, init_tag, &rounds).unwrap()
+        };
+        assert_eq!(reply(start + std::time::Duration::from_millis(30)).rtt_ms, Some(30));
+        assert_eq!(reply(start + std::time::Duration::from_millis(520)).rtt_ms, Some(20));
+        let frame = sctp_reply(3868, local_port, init_tag, chunk::ABORT);
+        assert_eq!(classify_port_reply(CapturedFrame { frame, received_at: start }, MacAddr::zero(), local_port, init_tag, &[]).unwrap().rtt_ms, None);
+    }
+}
@@ -1,94 +1,165 @@
 use futures::stream::{self, StreamExt};
-use futures::future::poll_fn;
 use netdev::{Interface, MacAddr};
-use nex::datalink::async_io::{async_channel, AsyncChannel, AsyncRawSender};
+use nex::datalink::async_io::AsyncRawSender;
+use nex::packet::ethernet::EtherType;
 use nex::packet::frame::Frame;
+use nex::packet::icmp::IcmpType;
+use nex::packet::icmpv6::Icmpv6Type;
 use nex::packet::ip::IpNextProtocol;
 use nex::packet::tcp::TcpFlags;
-use nex::socket::tcp::{AsyncTcpSocket, TcpConfig};
+use nex::socket::tcp::AsyncTcpSocket;
 use tracing_indicatif::span_ext::IndicatifSpanExt;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
-use std::time::Duration;
+use std::net::{IpAddr, SocketAddr};
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
 use tokio::io::AsyncWriteExt;
+use std::cell::RefCell;
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
 use anyhow::Result;
 use tokio::sync::mpsc;
 
-use crate::capture::pcap::PacketCaptureOptions;
+use crate::capture::pcap::{CapturedFrame, PacketCaptureOptions};
 use crate::cli::{PortScanMethod};
-use crate::endpoint::{Endpoint, EndpointResult, OsGuess, Port, PortResult, PortState, ServiceInfo, TransportProtocol};
+use crate::endpoint::{DiscoveryTechnique, EndpointResult, OsGuess, Port, PortReason, PortResult, PortState, ServiceInfo, TransportProtocol, UnansweredPorts};
 use crate::output::ScanResult;
-use crate::probe::ProbeSetting;
-
-/// Try to connect to the given ports on the target endpoint using TCP protocol.
-/// Concurrency specifies the number of concurrent connection attempts.
+use crate::probe::{ProbeSetting, ProbeSource};
+use crate::proxy::Proxy;
+use crate::scan::collect::{HostCollector, HostReply, LocalAddrs, PortCollector, PortReply, ProbeKey, SentProbes, Unanswered, REPLY_QUEUE_LEN};
+use crate::scan::engine::PacketEngine;
+use crate::scan::scheduler::Scheduler;
+use crate::scan::send::RateController;
+use crate::scan::stateless::StatelessProbe;
+
+/// ICMP Destination Unreachable codes that mean a probe was filtered:
+/// net/host unreachable and the administratively prohibited codes
+const ICMP_FILTERED_CODES: [u8; 5] = [0, 1, 9, 10, 13];
+/// ICMPv6 Destination Unreachable codes that mean a probe was filtered:
+/// no route, administratively prohibited and address unreachable
+const ICMPV6_FILTERED_CODES: [u8; 3] = [0, 1, 3];
+
+/// Retries of a connection attempt that failed locally
+const LOCAL_ERROR_RETRIES: usize = 2;
+/// Base delay before retrying a connection attempt that failed locally
+const LOCAL_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Try to connect to the given socket addresses using TCP protocol.
+/// Every connection attempt holds a permit of `scheduler`.
+/// Targets are consumed lazily. Hosts listed in `host_timeouts` use their own timeout.
+/// Sockets are bound to `source` if an address or port was chosen. With a `proxy`,
+/// connections are tunneled through it and its replies classify the ports.
+/// Only ports that got an answer or failed locally are returned; timed out ports are left out.
 pub async fn try_connect_ports(
-    target: Endpoint,
-    concurrency: usize,
+    targets: Box<dyn ExactSizeIterator<Item = SocketAddr> + Send>,
+    source: ProbeSource,
+    proxy: Option<Arc<Proxy>>,
+    scheduler: &Scheduler,
     timeout: Duration,
-) -> Result<EndpointResult> {
-    let (ch_tx, mut ch_rx) = mpsc::unbounded_channel::<PortResult>();
+    host_timeouts: Arc<HashMap<IpAddr, Duration>>,
+) -> Result<HashMap<IpAddr, BTreeMap<Port, PortResult>>> {
+    let (ch_tx, mut ch_rx) = mpsc::unbounded_channel::<(IpAddr, PortResult)>();
     let header_span = tracing::info_span!("tcp_connect_scan");
     header_span.pb_set_style(&crate::output::progress::get_progress_style());
-    header_span.pb_set_message(&format!("TCP PortScan ({})", target.ip));
-    header_span.pb_set_length(target.ports.len() as u64);
+    header_span.pb_set_message("TCP PortScan");
+    header_span.pb_set_length(targets.len() as u64);
     header_span.pb_set_position(0);
     header_span.pb_start();
 
     let span_rx = header_span.clone();
     let recv_task = tokio::spawn(async move {
-        let mut open_ports: BTreeMap<Port, PortResult> = BTreeMap::new();
-        while let Some(port_result) = ch_rx.recv().await {
-            open_ports.insert(port_result.port.clone(), port_result);
+        let mut port_map: HashMap<IpAddr, BTreeMap<Port, PortResult>> = HashMap::new();
+        while let Some((ip, port_result)) = ch_rx.recv().await {
+            // Timed out ports are only counted by the caller
+            if port_result.reason != Some(PortReason::Timeout) {
+                port_map.entry(ip).or_default().insert(port_result.port, port_result);
+            }
             // Update progress bar
             span_rx.pb_inc(1);
         }
-        open_ports
+        port_map
     });
 
-    let prod = stream::iter(target.socket_addrs(TransportProtocol::Tcp)).for_each_concurrent(concurrency, move |socket_addr| {
+    let scheduler = scheduler.clone();
+    let prod = stream::iter(targets).for_each_concurrent(scheduler.limits().in_flight, move |socket_addr| {
         let ch_tx = ch_tx.clone();
+        let timeout = host_timeouts.get(&socket_addr.ip()).copied().unwrap_or(timeout);
+        let scheduler = scheduler.clone();
+        let proxy = proxy.clone();
         async move {
-            let cfg = if socket_addr.is_ipv4() {
-                TcpConfig::v4_stream()
-            } else {
-                TcpConfig::v6_stream()
-            };
-            let socket = AsyncTcpSocket::from_config(&cfg).unwrap();
+            let _permit = scheduler.acquire(socket_addr.ip()).await;
             let mut port_result = PortResult {
                 port: Port::new(socket_addr.port(), TransportProtocol::Tcp),
-                state: PortState::Closed,
+                state: PortState::Unknown,
                 service: ServiceInfo::default(),
                 rtt_ms: None,
+                reason: Some(PortReason::LocalError),
+                reply_ttl: None,
             };
-            match socket.connect_timeout(socket_addr, timeout).await {
-                Ok(mut stream) => {
-                    port_result.state = PortState::Open;
-                    match stream.shutdown().await {
-                        Ok(_) => {}
-                        Err(_) => {}
+            for attempt in 0..=LOCAL_ERROR_RETRIES {
+                if attempt > 0 {
+                    // Give other connections time to release their sockets
+                    tokio::time::sleep(LOCAL_ERROR_BACKOFF * attempt as u32).await;
+                }
+                let start_time = Instant::now();
+                match connect_port(socket_addr, source, proxy.as_deref(), timeout).await {
+                    Ok(()) => {
+                        port_result.state = PortState::Open;
+                        port_result.reason = Some(PortReason::SynAck);
+                        port_result.rtt_ms = Some(start_time.elapsed().as_millis() as u32);
                     }
+                    Err(e) => match e.kind() {
+                        // The target answered with RST
+                        ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset => {
+                            port_result.state = PortState::Closed;
+                            port_result.reason = Some(PortReason::ConnRefused);
+                            port_result.rtt_ms = Some(start_time.elapsed().as_millis() as u32);
+                        }
+                        // No answer at all, the probe was most likely dropped
+                        ErrorKind::TimedOut => {
+                            port_result.state = PortState::Filtered;
+                            port_result.reason = Some(PortReason::Timeout);
+                        }
+                        ErrorKind::HostUnreachable => {
+                            port_result.state = PortState::Filtered;
+                            port_result.reason = Some(PortReason::HostUnreach);
+                        }
+                        ErrorKind::NetworkUnreachable => {
+                            port_result.state = PortState::Filtered;
+                            port_result.reason = Some(PortReason::NetUnreach);
+                        }
+                        // Local failure (e.g. EMFILE, ENOBUFS, EADDRNOTAVAIL): nothing is known about the port
+                        _ => {
+                            tracing::debug!("{}: {}", socket_addr, e);
+                            continue;
+                        }
+                    },
                 }
-                Err(_) => {}
+                break;
             }
-            let _ = ch_tx.send(port_result);
+            let _ = ch_tx.send((socket_addr.ip(), port_result));
         }
     });
     let prod_task = tokio::spawn(prod);
     let (results_res, _prod_res) = tokio::join!(recv_task, prod_task);
-    let open_ports = results_res?;
+    let port_map = results_res?;
     // Finish header span
     drop(header_span);
-    Ok(EndpointResult {
-        ip: target.ip,
-        hostname: target.hostname,
-        ports: open_ports,
-        mac_addr: target.mac_addr,
-        vendor_name: None,
-        os: OsGuess::default(),
-        tags: target.tags,
-        cpes: Vec::new(),
-    })
+    Ok(port_map)
+}
+
+/// Connect to the port, directly or through the proxy, and close the connection again.
+async fn connect_port(socket_addr: SocketAddr, source: ProbeSource, proxy: Option<&Proxy>, timeout: Duration) -> std::io::Result<()> {
+    if let Some(proxy) = proxy {
+        let mut stream = tokio::time::timeout(timeout, proxy.connect(socket_addr, source))
+            .await
+            .map_err(|_| std::io::Error::from(ErrorKind::TimedOut))??;
+        let _ = stream.shutdown().await;
+        return Ok(());
+    }
+    let socket = AsyncTcpSocket::from_config(&source.tcp_config(socket_addr.ip()))?;
+    let mut stream = socket.connect_timeout(socket_addr, timeout).await?;
+    let _ = stream.shutdown().await;
+    Ok(())
 }
 
 /// Run a TCP connect scan based on the provided probe settings.
@@ -96,136 +167,152 @@ pub async fn run_connect_scan(
     setting: ProbeSetting,
 ) -> Result<ScanResult> {
     let start_time = std::time::Instant::now();
-    let mut tasks = vec![];
-    for target in setting.target_endpoints {
-        tasks.push(tokio::spawn(async move {
-            let host = try_connect_ports(
-                target,
-                setting.port_concurrency,
-                setting.connect_timeout,
-            )
-            .await;
-            host
-        }));
-    }
-    let mut endpoints: Vec<EndpointResult> = vec![];
-    for task in tasks {
-        match task.await {
-            Ok(endpoint_result) => {
-                match endpoint_result {
-                    Ok(endpoint) => {
-                        endpoints.push(endpoint);
-                    }
-                    Err(e) => {
-                        tracing::error!("error: {}", e);
-                    }
-                }
-            }
-            Err(e) => {
-                tracing::error!("error: {}", e);
-            }
-        }
+    let port_map = try_connect_ports(
+        setting.target_addrs(TransportProtocol::Tcp),
+        setting.source,
+        setting.proxy.clone(),
+        &setting.scheduler,
+        setting.connect_timeout,
+        setting.host_timeouts.clone(),
+    )
+    .await?;
+    let failed = port_map.values().flat_map(|ports| ports.values()).filter(|p| p.state == PortState::Unknown).count();
+    if failed > 0 {
+        tracing::warn!("{} port(s) could not be probed because of local errors; try a lower --concurrency", failed);
     }
+    let dns_map = setting.get_dns_map();
     let mut result = ScanResult::new();
-    result.endpoints = endpoints;
+    result.endpoints = port_map
+        .into_iter()
+        .map(|(ip, ports)| EndpointResult {
+            ip,
+            hostname: dns_map.get(&ip).cloned(),
+            ports,
+            unanswered: Vec::new(),
+            mac_addr: None,
+            vendor_name: None,
+            rtt_ms: None,
+            discovered_by: Vec::new(),
+            os: OsGuess::default(),
+            tags: Vec::new(),
+            cpes: Vec::new(),
+        })
+        .collect();
+    let unanswered = UnansweredPorts {
+        transport: TransportProtocol::Tcp,
+        state: PortState::Filtered,
+        reason: PortReason::Timeout,
+        count: 0,
+    };
+    crate::scan::summarize_unanswered(&mut result, &setting, TransportProtocol::Tcp, unanswered);
     result.scan_time = start_time.elapsed();
     result.fingerprints = Vec::new();
     Ok(result)
 }
 
-/// Send TCP SYN packets for port scanning.
+/// Get the TCP flags sent by the given raw scan method.
+fn probe_flags(method: PortScanMethod) -> u8 {
+    match method {
+        PortScanMethod::Ack | PortScanMethod::Window => TcpFlags::ACK,
+        PortScanMethod::Fin => TcpFlags::FIN,
+        PortScanMethod::Null => 0,
+        PortScanMethod::Xmas => TcpFlags::FIN | TcpFlags::PSH | TcpFlags::URG,
+        PortScanMethod::Connect | PortScanMethod::Syn => TcpFlags::SYN,
+    }
+}
+
+/// Get the state of a port that never answered the given raw scan method.
+fn no_response_state(method: PortScanMethod) -> PortState {
+    match method {
+        // Open ports silently drop FIN/NULL/Xmas probes
+        PortScanMethod::Fin | PortScanMethod::Null | PortScanMethod::Xmas => PortState::OpenFiltered,
+        _ => PortState::Filtered,
+    }
+}
+
+/// Send raw TCP probe packets for port scanning.
+/// Probes carry no per-probe state; replies are matched with `probe`.
+/// `message` labels the progress bar.
 pub async fn send_portscan_packets(
-    tx: &mut Box<dyn AsyncRawSender>,
+    engine: &mut PacketEngine,
     interface: &Interface,
-    scan_setting: &ProbeSetting,
+    setting: &ProbeSetting,
+    rate: &mut RateController,
+    probe: &StatelessProbe,
+    targets: impl ExactSizeIterator<Item = SocketAddr>,
+    message: &str,
 ) {
-    let mut sent: usize = 0;
-    for target in &scan_setting.target_endpoints {
-        let header_span = tracing::info_span!("tcp_syn_scan");
-        header_span.pb_set_style(&crate::output::progress::get_progress_style());
-        header_span.pb_set_message(&format!("PortScan ({})", target.ip));
-        header_span.pb_set_length(target.ports.len() as u64);
-        header_span.pb_set_position(0);
-        header_span.pb_start();
-        
-        for port in &target.ports {
-            let packet =
-                crate::packet::tcp::build_tcp_syn_packet(&interface, target.ip, port.number, false);
-
-            // Send a packet using poll_fn.
-            match poll_fn(|cx| tx.poll_send(cx, &packet)).await {
-                Ok(_) => {
-                    if !scan_setting.send_rate.is_zero() && sent < 64 {
-                        tokio::time::sleep(scan_setting.send_rate).await;
-                    }
-                    sent += 1;
-                }
-                Err(e) => eprintln!("Failed to send packet: {}", e),
-            }
-            header_span.pb_inc(1);
+    let header_span = tracing::info_span!("tcp_raw_scan");
+    header_span.pb_set_style(&crate::output::progress::get_progress_style());
+    header_span.pb_set_message(message);
+    header_span.pb_set_length(targets.len() as u64);
+    header_span.pb_set_position(0);
+    header_span.pb_start();
+    let ip_packet = crate::interface::is_layer3(interface);
+
+    rate.begin_round();
+    for target in targets {
+        let fields = probe.fields(setting.source.ip_for(interface, target.ip()), target);
+        let packet = crate::packet::tcp::build_tcp_packet(
+            interface,
+            &setting.neighbors,
+            &setting.source,
+            target.ip(),
+            target.port(),
+            &fields,
+            ip_packet,
+        );
+
+        if let Err(e) = rate.dispatch(engine, packet).await {
+            eprintln!("Failed to send packet: {}", e);
         }
-        drop(header_span);
+        header_span.pb_inc(1);
     }
+    drop(header_span);
 }
 
 /// Send TCP SYN packets for host scanning.
+/// The send time of every probe is recorded in `sent_map` for RTT measurement.
 pub async fn send_hostscan_packets(
     tx: &mut Box<dyn AsyncRawSender>,
     interface: &Interface,
     scan_setting: &ProbeSetting,
+    rate: &mut RateController,
+    sent: &SentProbes,
 ) {
+    let targets = scan_setting.target_addrs(TransportProtocol::Tcp);
     let header_span = tracing::info_span!("tcp_syn_host_scan");
     header_span.pb_set_style(&crate::output::progress::get_progress_style());
     header_span.pb_set_message("HostScan");
-    header_span.pb_set_length(scan_setting.target_endpoints.len() as u64);
+    header_span.pb_set_length(targets.len() as u64);
     header_span.pb_set_position(0);
     header_span.pb_start();
-    
-    for target in &scan_setting.target_endpoints {
-        for port in &target.ports {
-            let packet =
-                crate::packet::tcp::build_tcp_syn_packet(&interface, target.ip, port.number, false);
-
-            // Send a packet using poll_fn.
-            match poll_fn(|cx| tx.poll_send(cx, &packet)).await {
-                Ok(_) => {
-                    if !scan_setting.send_rate.is_zero() {
-                        tokio::time::sleep(scan_setting.send_rate).await;
-                    }
-                }
-                Err(e) => eprintln!("Failed to send packet: {}", e),
-            }
+    let ip_packet = crate::interface::is_layer3(interface);
+
+    for target in targets {
+        let packet =
+            crate::packet::tcp::build_tcp_syn_packet(interface, &scan_setting.neighbors, &scan_setting.source, target.ip(), target.port(), ip_packet);
+        match rate.send(tx, &packet).await {
+            Ok(_) => sent.record(ProbeKey::new(target.ip(), DiscoveryTechnique::TcpSyn, target.port())),
+            Err(e) => eprintln!("Failed to send packet: {}", e),
         }
         header_span.pb_inc(1);
     }
     drop(header_span);
 }
 
-/// Run a TCP SYN scan based on the provided probe settings.
-pub async fn run_syn_scan(
+/// Run a raw TCP scan (SYN, ACK, Window, FIN, NULL or Xmas) based on the provided probe settings.
+/// Unanswered probes are retransmitted up to `setting.retries` times.
+pub async fn run_raw_scan(
     setting: ProbeSetting,
+    method: PortScanMethod,
 ) -> Result<ScanResult> {
     let interface = match crate::interface::get_interface_by_index(setting.if_index) {
         Some(interface) => interface,
         None => return Err(anyhow::anyhow!("Interface not found")),
     };
-    // Create sender
-    let config = nex::datalink::Config {
-        write_buffer_size: 4096,
-        read_buffer_size: 4096,
-        read_timeout: Some(setting.wait_time),
-        write_timeout: None,
-        channel_type: nex::datalink::ChannelType::Layer2,
-        bpf_fd_attempts: 1000,
-        linux_fanout: None,
-        promiscuous: false,
-    };
-
-    let AsyncChannel::Ethernet(mut tx, mut rx) = async_channel(&interface, config)?
-    else {
-        unreachable!();
-    };
-
+    // Resolve the MAC addresses of on-link targets
+    setting.neighbors.resolve(&interface, setting.target_ips(), setting.wait_time).await;
     let mut capture_options: PacketCaptureOptions = PacketCaptureOptions {
         interface_index: interface.index,
         interface_name: interface.name.clone(),
@@ -239,40 +326,79 @@ pub async fn run_syn_scan(
         read_timeout: setting.wait_time,
         promiscuous: false,
         receive_undefined: false,
-        tunnel: interface.is_tun(),
+        tunnel: crate::interface::is_layer3(&interface),
         loopback: interface.is_loopback(),
     };
-    for endpoint in setting.target_endpoints.clone() {
-        capture_options.src_ips.insert(endpoint.ip);
-        capture_options.src_ports.extend(endpoint.ports.iter().map(|p| p.number));
-    }
+    // ICMP errors may come from intermediate routers, so accept anything addressed to us,
+    // but only TCP segments sent from a scanned port
+    capture_options.dst_ips.extend(interface.ip_addrs());
+    capture_options.src_ports.extend(setting.target_ports(TransportProtocol::Tcp));
+    capture_options.ether_types.insert(EtherType::Ipv4);
+    capture_options.ether_types.insert(EtherType::Ipv6);
     capture_options.ip_protocols.insert(IpNextProtocol::Tcp);
-
-    let (ready_tx, ready_rx) = tokio::sync::oneshot::channel();
-    let (stop_tx, mut stop_rx) = tokio::sync::oneshot::channel();
-
-    let capture_handle: tokio::task::JoinHandle<_> = tokio::spawn(async move {
-        crate::capture::pcap::start_capture(
-            &mut rx,
-            capture_options,
-            ready_tx,
-            &mut stop_rx,
-        )
-        .await
-    });
-
-    // Wait for listener to start
-    let _ = ready_rx;
-    let start_time = std::time::Instant::now();
-    // Send probe packets
-    send_portscan_packets(&mut tx, &interface, &setting).await;
-    tokio::time::sleep(setting.wait_time).await;
-    // Stop pcap
-    let _ = stop_tx.send(());
-    let frames = capture_handle.await.unwrap();
-    let dns_map = setting.get_dns_map();
-    let mut result = parse_portscan_result(frames, &interface, &dns_map);
+    capture_options.ip_protocols.insert(IpNextProtocol::Icmp);
+    capture_options.ip_protocols.insert(IpNextProtocol::Icmpv6);
+
+    let mut rate = RateController::new(&setting);
+    let probe = StatelessProbe::new(probe_flags(method)).with_src_port(setting.source.port);
+    let iface_mac = interface.mac_addr.unwrap_or(MacAddr::zero());
+    let classify = {
+        let probe = probe.clone();
+        move |captured| classify_port_reply(captured, iface_mac, method, &probe)
+    };
+    // Receive threads run across all rounds; retransmission rounds stream the targets again
+    let (mut engine, mut replies) = PacketEngine::open(&interface, &setting.io, capture_options, rate.feedback(), classify)?;
+    // Replies are recorded as they arrive, while probes are still being sent.
+    // Only the answered targets are remembered
+    let collector = RefCell::new(PortCollector::new(&setting, TransportProtocol::Tcp));
+    let collect = async {
+        while let Some(reply) = replies.recv().await {
+            collector.borrow_mut().record(reply);
+        }
+    };
+    let target_count = setting.target_addrs(TransportProtocol::Tcp).len();
+
+    let start_time = Instant::now();
+    let scan = async {
+        for round in 0..=setting.retries {
+            let answered_before = collector.borrow().answered_count();
+            let retransmitted = target_count.saturating_sub(answered_before);
+            if round > 0 && retransmitted == 0 {
+                break;
+            }
+            // Send probe packets
+            let targets = Unanswered {
+                targets: setting.target_addrs(TransportProtocol::Tcp),
+                collector: &collector,
+                remaining: retransmitted,
+            };
+            let message = if round == 0 {
+                format!("{} PortScan", method.as_str().to_uppercase())
+            } else {
+                format!("{} PortScan (retry {})", method.as_str().to_uppercase(), round)
+            };
+            send_portscan_packets(&mut engine, &interface, &setting, &mut rate, &probe, targets, &message).await;
+            engine.flush().await;
+            tokio::time::sleep(setting.wait_time).await;
+            if round > 0 {
+                // Replies to retransmissions mean earlier probes or replies were dropped
+                rate.record_retransmit(retransmitted, collector.borrow().answered_count() - answered_before);
+            }
+        }
+        engine.finish().await
+    };
+    let ((), io_stats) = tokio::join!(collect, scan);
+    tracing::debug!("Sent {} probes, final send rate {:.0} pps", rate.total_sent(), rate.rate());
+    // Ports that never answered, as Filtered or Open|Filtered depending on the method
+    let unanswered = UnansweredPorts {
+        transport: TransportProtocol::Tcp,
+        state: no_response_state(method),
+        reason: PortReason::NoResponse,
+        count: 0,
+    };
+    let mut result = collector.into_inner().finish(unanswered);
     result.scan_time = start_time.elapsed();
+    result.io_stats = Some(io_stats);
     Ok(result)
 }
 
@@ -285,8 +411,8 @@ pub async fn run_port_scan(
         PortScanMethod::Connect => {
             return run_connect_scan(setting).await;
         }
-        PortScanMethod::Syn => {
-            return run_syn_scan(setting).await;
+        _ => {
+            return run_raw_scan(setting, method).await;
         }
     }
 }
@@ -297,22 +423,10 @@ pub async fn run_host_scan(setting: ProbeSetting) -> Result<ScanResult> {
         Some(interface) => interface,
         None => return Err(anyhow::anyhow!("Interface not found")),
     };
+    // Resolve the MAC addresses of on-link targets
+    setting.neighbors.resolve(&interface, setting.target_ips(), setting.wait_time).await;
     // Create sender
-    let config = nex::datalink::Config {
-        write_buffer_size: 4096,
-        read_buffer_size: 4096,
-        read_timeout: Some(setting.wait_time),
-        write_timeout: None,
-        channel_type: nex::datalink::ChannelType::Layer2,
-        bpf_fd_attempts: 1000,
-        linux_fanout: None,
-        promiscuous: false,
-    };
-
-    let AsyncChannel::Ethernet(mut tx, mut rx) = async_channel(&interface, config)?
-    else {
-        unreachable!();
-    };
+    let (mut tx, mut rx) = crate::capture::channel::open(&interface, setting.wait_time)?;
 
     let mut capture_options: PacketCaptureOptions = PacketCaptureOptions {
         interface_index: interface.index,
@@ -327,283 +441,249 @@ pub async fn run_host_scan(setting: ProbeSetting) -> Result<ScanResult> {
         read_timeout: setting.wait_time,
         promiscuous: false,
         receive_undefined: false,
-        tunnel: interface.is_tun(),
+        tunnel: crate::interface::is_layer3(&interface),
         loopback: interface.is_loopback(),
     };
     for endpoint in &setting.target_endpoints {
         capture_options.src_ips.insert(endpoint.ip);
         capture_options.src_ports.extend(endpoint.ports.iter().map(|p| p.number));
     }
+    if let Some(targets) = &setting.scan_targets {
+        capture_options.src_ports.extend(targets.ports().iter().copied());
+    }
     capture_options.ip_protocols.insert(IpNextProtocol::Tcp);
 
+    let mut rate = RateController::new(&setting);
+    let responses = rate.feedback().responses;
     let (ready_tx, ready_rx) = tokio::sync::oneshot::channel();
     let (stop_tx, mut stop_rx) = tokio::sync::oneshot::channel();
+    let (reply_tx, mut replies) = mpsc::channel(REPLY_QUEUE_LEN);
+    let local = LocalAddrs::new(&interface);
 
     let capture_handle: tokio::task::JoinHandle<_> = tokio::spawn(async move {
-        crate::capture::pcap::start_capture(
+        crate::capture::pcap::start_stream_capture(
             &mut rx,
             capture_options,
             ready_tx,
             &mut stop_rx,
+            &responses,
+            |captured| classify_host_reply(captured, &local),
+            reply_tx,
         )
         .await
     });
 
     // Wait for listener to start
-    let _ = ready_rx;
+    let _ = ready_rx.await;
     let start_time = std::time::Instant::now();
-    // Send probe packets
-    send_hostscan_packets(&mut tx, &interface, &setting).await;
-    tokio::time::sleep(setting.wait_time).await;
-    // Stop pcap
-    let _ = stop_tx.send(());
-    let frames = capture_handle.await.unwrap();
-    let dns_map = setting.get_dns_map();
-    let mut result = parse_hostscan_result(frames, &interface, &dns_map);
+    let sent = SentProbes::default();
+    let collector = RefCell::new(HostCollector::new(&setting));
+    // Record replies while the probes are still being sent
+    let collect = async {
+        while let Some(reply) = replies.recv().await {
+            collector.borrow_mut().record(reply, &sent);
+        }
+    };
+    let scan = async {
+        // Send probe packets
+        send_hostscan_packets(&mut tx, &interface, &setting, &mut rate, &sent).await;
+        tokio::time::sleep(setting.wait_time).await;
+        // Stop pcap
+        let _ = stop_tx.send(());
+        capture_handle.await.unwrap();
+    };
+    tokio::join!(collect, scan);
+    let mut result = collector.into_inner().finish();
     result.scan_time = start_time.elapsed();
     Ok(result)
 }
 
-/// Parse port scan results from captured packets.
-fn parse_portscan_result(
-    packets: Vec<Frame>,
-    iface: &Interface,
-    dns_map: &HashMap<IpAddr, String>,
-) -> ScanResult {
-    let mut result: ScanResult = ScanResult::new();
-    let mut socket_set: HashSet<SocketAddr> = HashSet::new();
-    let mut endpoint_map: HashMap<IpAddr, EndpointResult> = HashMap::new();
-    for p in packets {
-        if p.ip.is_none() || p.transport.is_none() {
-            continue;
-        }
-        let mac_addr: MacAddr;
-        if let Some(datalink) = &p.datalink {
-            if let Some(ethernet_frame) = &datalink.ethernet {
-                if ethernet_frame.destination != iface.mac_addr.unwrap_or(MacAddr::zero()) {
-                    continue;
-                }
-                mac_addr = ethernet_frame.source;
-            } else {
-                mac_addr = MacAddr::zero();
+/// Run a TCP host scan with connect() calls, which needs no raw sockets.
+pub async fn run_connect_host_scan(setting: ProbeSetting) -> Result<ScanResult> {
+    let start_time = std::time::Instant::now();
+    let collector = RefCell::new(HostCollector::new(&setting));
+    probe_connect(&setting, &collector).await?;
+    let mut result = collector.into_inner().finish();
+    result.scan_time = start_time.elapsed();
+    Ok(result)
+}
+
+/// Connect to the target ports and record the hosts that accept or refuse a connection in `collector`.
+pub async fn probe_connect(setting: &ProbeSetting, collector: &RefCell<HostCollector>) -> Result<()> {
+    let port_map = try_connect_ports(
+        setting.target_addrs(TransportProtocol::Tcp),
+        setting.source,
+        setting.proxy.clone(),
+        &setting.scheduler,
+        setting.connect_timeout,
+        setting.host_timeouts.clone(),
+    )
+    .await?;
+    let mut collector = collector.borrow_mut();
+    for (ip, ports) in port_map {
+        for (port, port_result) in ports {
+            if matches!(port_result.state, PortState::Open | PortState::Closed) {
+                let probe = ProbeKey::new(ip, DiscoveryTechnique::TcpConnect, port.number);
+                collector.record_socket_reply(probe, port_result.state == PortState::Open, port_result.rtt_ms);
             }
-        } else {
-            mac_addr = MacAddr::zero();
         }
-        let ip_addr: IpAddr;
-        let ttl: u8;
-        let port: PortResult;
-        if let Some(ip) = &p.ip {
-            if let Some(ipv4_packet) = &ip.ipv4 {
-                ip_addr = IpAddr::V4(ipv4_packet.source);
-                ttl = ipv4_packet.ttl;
-            } else if let Some(ipv6_packet) = &ip.ipv6 {
-                ip_addr = IpAddr::V6(ipv6_packet.source);
-                ttl = ipv6_packet.hop_limit;
-            } else {
-                continue;
-            }
+    }
+    Ok(())
+}
+
+/// Classify a captured frame as a reply to one of our raw TCP probes.
+/// Returns the probed socket address, the port state implied by the reply and the reason.
+/// Replies that do not match the keyed hash of `probe` are discarded.
+fn classify_reply(frame: &Frame, method: PortScanMethod, probe: &StatelessProbe) -> Option<(SocketAddr, PortState, PortReason)> {
+    let ip = frame.ip.as_ref()?;
+    if let Some(tcp_packet) = frame.transport.as_ref().and_then(|t| t.tcp.as_ref()) {
+        let (src_ip, dst_ip) = if let Some(ipv4_packet) = &ip.ipv4 {
+            (IpAddr::V4(ipv4_packet.source), IpAddr::V4(ipv4_packet.destination))
+        } else if let Some(ipv6_packet) = &ip.ipv6 {
+            (IpAddr::V6(ipv6_packet.source), IpAddr::V6(ipv6_packet.destination))
         } else {
-            continue;
+            return None;
+        };
+        if !probe.check_reply(dst_ip, SocketAddr::new(src_ip, tcp_packet.source), tcp_packet) {
+            return None;
         }
-        if let Some(transport) = &p.transport {
-            if let Some(tcp_packet) = &transport.tcp {
-                if socket_set.contains(&SocketAddr::new(ip_addr,tcp_packet.source)) {
-                    continue;
-                }
-                let f = tcp_packet.flags;
+        let f = tcp_packet.flags;
+        let reason = if (f & TcpFlags::RST) != 0 { PortReason::Rst } else { PortReason::SynAck };
+        let state = match method {
+            PortScanMethod::Connect | PortScanMethod::Syn => {
                 if (f & TcpFlags::RST) != 0 {
-                    port = PortResult {
-                        port: Port::new(tcp_packet.source, TransportProtocol::Tcp),
-                        state: PortState::Closed,
-                        service: ServiceInfo::default(),
-                        rtt_ms: None,
-                    };
+                    PortState::Closed
                 } else if (f & (TcpFlags::SYN | TcpFlags::ACK)) == (TcpFlags::SYN | TcpFlags::ACK) {
-                    port = PortResult {
-                        port: Port::new(tcp_packet.source, TransportProtocol::Tcp),
-                        state: PortState::Open,
-                        service: ServiceInfo::default(),
-                        rtt_ms: None,
-                    };
+                    PortState::Open
                 } else {
-                    continue;
+                    return None;
                 }
-            } else {
-                continue;
             }
-        } else {
-            continue;
-        }
-        endpoint_map
-            .entry(ip_addr)
-            .or_insert(EndpointResult {
-                ip: ip_addr,
-                hostname: dns_map.get(&ip_addr).cloned(),
-                ports: BTreeMap::new(),
-                mac_addr: Some(mac_addr),
-                vendor_name: None,
-                os: OsGuess::default().with_ttl_observed(ttl),
-                tags: Vec::new(),
-                cpes: Vec::new(),
-            })
-            .ports
-            .insert(port.port.clone(), port.clone());
-
-        result.fingerprints.push(p.clone());
-        socket_set.insert(SocketAddr::new(ip_addr, port.port.number));
-
+            // Any RST means the probe got through the firewall
+            PortScanMethod::Ack if (f & TcpFlags::RST) != 0 => PortState::Unfiltered,
+            // Some stacks answer with a non-zero window for open ports
+            PortScanMethod::Window if (f & TcpFlags::RST) != 0 => {
+                if tcp_packet.window > 0 { PortState::Open } else { PortState::Closed }
+            }
+            // Closed ports answer FIN/NULL/Xmas probes with RST
+            PortScanMethod::Fin | PortScanMethod::Null | PortScanMethod::Xmas if (f & TcpFlags::RST) != 0 => PortState::Closed,
+            _ => return None,
+        };
+        return Some((SocketAddr::new(src_ip, tcp_packet.source), state, reason));
     }
-    for (ip, endpoint) in endpoint_map {
-        let mut ep = EndpointResult::new(ip);
-        ep.hostname = endpoint.hostname;
-        ep.mac_addr = endpoint.mac_addr;
-        ep.vendor_name = endpoint.vendor_name;
-        ep.os = endpoint.os;
-        ep.tags = endpoint.tags;
-        for (_port, port_result) in endpoint.ports {
-            ep.upsert_port(port_result);
-        }
-        result.endpoints.push(ep);
+    // ICMP unreachable (host/net unreachable or administratively prohibited) quoting our probe
+    let reason = if let Some(icmp) = ip.icmp.as_ref().filter(|icmp| {
+        icmp.icmp_type == IcmpType::DestinationUnreachable && ICMP_FILTERED_CODES.contains(&icmp.icmp_code.0)
+    }) {
+        PortReason::IcmpUnreach { icmp_type: icmp.icmp_type.value(), icmp_code: icmp.icmp_code.0 }
+    } else if let Some(icmpv6) = ip.icmpv6.as_ref().filter(|icmpv6| {
+        icmpv6.icmpv6_type == Icmpv6Type::DestinationUnreachable && ICMPV6_FILTERED_CODES.contains(&icmpv6.icmpv6_code.0)
+    }) {
+        PortReason::IcmpUnreach { icmp_type: icmpv6.icmpv6_type.value(), icmp_code: icmpv6.icmpv6_code.0 }
+    } else {
+        return None;
+    };
+    let quoted = crate::packet::icmp::parse_quoted_packet(&frame.payload)?;
+    if quoted.protocol != IpNextProtocol::Tcp || !probe.check_quoted(&quoted) {
+        return None;
     }
-    result
+    Some((SocketAddr::new(quoted.dst_ip, quoted.dst_port), PortState::Filtered, reason))
 }
 
-/// Parse host scan results from captured packets.
-fn parse_hostscan_result(
-    packets: Vec<Frame>,
-    iface: &Interface,
-    dns_map: &HashMap<IpAddr, String>,
-) -> ScanResult {
-    let oui_db = crate::db::oui::oui_db();
-    let if_ipv4_set: HashSet<Ipv4Addr> = iface.ipv4_addrs().into_iter().collect();
-    let if_ipv6_set: HashSet<Ipv6Addr> = iface.ipv6_addrs().into_iter().collect();
-    let mut result: ScanResult = ScanResult::new();
-    let mut socket_set: HashSet<SocketAddr> = HashSet::new();
-    let mut endpoint_map: HashMap<IpAddr, EndpointResult> = HashMap::new();
-    for p in packets {
-        if p.ip.is_none() || p.transport.is_none() {
-            continue;
-        }
-        let mut mac_addr: MacAddr;
-        if let Some(datalink) = &p.datalink {
-            if let Some(ethernet_frame) = &datalink.ethernet {
-                if ethernet_frame.destination != iface.mac_addr.unwrap_or(MacAddr::zero()) {
-                    continue;
-                }
-                mac_addr = ethernet_frame.source;
-            } else {
-                mac_addr = MacAddr::zero();
-            }
-        } else {
-            mac_addr = MacAddr::zero();
-        }
-        let ip_addr: IpAddr;
-        let ttl: u8;
-        let port: PortResult;
-        if let Some(ip) = &p.ip {
-            if let Some(ipv4_packet) = &ip.ipv4 {
-                if if_ipv4_set.contains(&ipv4_packet.source) {
-                    mac_addr = iface.mac_addr.unwrap_or(MacAddr::zero());
-                    ttl = crate::util::ip::initial_ttl(ipv4_packet.ttl);
-                }else{
-                    ttl = ipv4_packet.ttl;
-                }
-                ip_addr = IpAddr::V4(ipv4_packet.source);
-            } else if let Some(ipv6_packet) = &ip.ipv6 {
-                if if_ipv6_set.contains(&ipv6_packet.source) {
-                    mac_addr = iface.mac_addr.unwrap_or(MacAddr::zero());
-                    ttl = crate::util::ip::initial_ttl(ipv6_packet.hop_limit);
-                }else {
-                    ttl = ipv6_packet.hop_limit;
-                }
-                ip_addr = IpAddr::V6(ipv6_packet.source);
-            } else {
-                continue;
-            }
-        } else {
-            continue;
-        }
-        if let Some(transport) = &p.transport {
-            if let Some(tcp_packet) = &transport.tcp {
-                if socket_set.contains(&SocketAddr::new(ip_addr,tcp_packet.source)) {
-                    continue;
-                }
-                let f = tcp_packet.flags;
-                if (f & (TcpFlags::SYN | TcpFlags::ACK)) == (TcpFlags::SYN | TcpFlags::ACK) {
-                    port = PortResult {
-                        port: Port::new(tcp_packet.source, TransportProtocol::Tcp),
-                        state: PortState::Open,
-                        service: ServiceInfo::default(),
-                        rtt_ms: None,
-                    };
-                } else {
-                    continue;
-                }
-            } else {
-                continue;
-            }
-        } else {
-            continue;
-        }
+/// Classify a captured frame into a port scan reply.
+/// Frames not addressed to the interface and replies that do not match `probe` are discarded.
+fn classify_port_reply(captured: CapturedFrame, iface_mac: MacAddr, method: PortScanMethod, probe: &StatelessProbe) -> Option<PortReply> {
+    let p = captured.frame;
+    let ip = p.ip.as_ref()?;
+    let mac_addr = match p.datalink.as_ref().and_then(|datalink| datalink.ethernet.as_ref()) {
+        Some(ethernet_frame) if ethernet_frame.destination != iface_mac => return None,
+        Some(ethernet_frame) => ethernet_frame.source,
+        None => MacAddr::zero(),
+    };
+    let (src_ip, ttl) = if let Some(ipv4_packet) = &ip.ipv4 {
+        (IpAddr::V4(ipv4_packet.source), ipv4_packet.ttl)
+    } else if let Some(ipv6_packet) = &ip.ipv6 {
+        (IpAddr::V6(ipv6_packet.source), ipv6_packet.hop_limit)
+    } else {
+        return None;
+    };
+    let (addr, state, reason) = classify_reply(&p, method, probe)?;
+    let rtt_ms = p
+        .transport
+        .as_ref()
+        .and_then(|t| t.tcp.as_ref())
+        .and_then(|tcp_packet| probe.reply_rtt_ms(tcp_packet, captured.received_at));
+    Some(PortReply {
+        addr,
+        state,
+        reason,
+        rtt_ms,
+        ttl,
+        // Link-layer and TTL information is only meaningful for replies from the target itself
+        target_mac: (src_ip == addr.ip()).then_some(mac_addr),
+        frame: (state == PortState::Open).then_some(p),
+    })
+}
 
-        let vendor_name_opt: Option<String>;
-        if let Some(oui) = oui_db.lookup_mac(&mac_addr) {
-            if let Some(vendor_detail) = &oui.vendor_detail {
-                vendor_name_opt = Some(vendor_detail.clone());
-            } else {
-                vendor_name_opt = Some(oui.vendor.clone());
-            }
-        } else {
-            vendor_name_opt = None;
+/// Classify a captured frame as a SYN/ACK from a probed host port.
+/// The OS of the host is guessed from the TCP/IP signature of the reply.
+fn classify_host_reply(captured: CapturedFrame, local: &LocalAddrs) -> Option<HostReply> {
+    let (ip_addr, ttl, mac_addr) = local.reply_source(&captured.frame)?;
+    let tcp_packet = captured.frame.transport.as_ref()?.tcp.as_ref()?;
+    if (tcp_packet.flags & (TcpFlags::SYN | TcpFlags::ACK)) != (TcpFlags::SYN | TcpFlags::ACK) {
+        return None;
+    }
+    let probe = ProbeKey::new(ip_addr, DiscoveryTechnique::TcpSyn, tcp_packet.source);
+    let mut os_guess = OsGuess::default().with_ttl_observed(ttl);
+    let mut cpes: Vec<String> = Vec::new();
+    match crate::os::match_tcpip_signatures(&captured.frame) {
+        Some(os_match) => {
+            os_guess.family = Some(os_match.family);
+            os_guess.confidence = Some(os_match.confidence as f32);
+            cpes = os_match.cpes;
         }
-
-        let mut os_guess = OsGuess::default().with_ttl_observed(ttl);
-        let mut cpes: Vec<String> = Vec::new();
-
-        match crate::os::match_tcpip_signatures(&p) {
-            Some(os_match) => {
-                os_guess.family = Some(os_match.family);
-                os_guess.confidence = Some(os_match.confidence as f32);
-                os_guess.ttl_observed = Some(ttl);
-                cpes = os_match.cpes;
-            }
-            None => {
-                tracing::debug!("No matching OS found");
-            }
+        None => {
+            tracing::debug!("No matching OS found");
         }
-
-        endpoint_map
-            .entry(ip_addr)
-            .or_insert(EndpointResult {
-                ip: ip_addr,
-                hostname: dns_map.get(&ip_addr).cloned(),
-                ports: BTreeMap::new(),
-                mac_addr: Some(mac_addr),
-                vendor_name: vendor_name_opt,
-                os: os_guess,
-                tags: Vec::new(),
-                cpes: cpes,
-            })
-            .ports
-            .insert(port.port.clone(), port.clone());
-
-        result.fingerprints.push(p.clone());
-        socket_set.insert(SocketAddr::new(ip_addr, port.port.number));
-
     }
-    for (ip, endpoint) in endpoint_map {
-        let mut ep = EndpointResult::new(ip);
-        ep.hostname = endpoint.hostname;
-        ep.mac_addr = endpoint.mac_addr;
-        ep.vendor_name = endpoint.vendor_name;
-        ep.os = endpoint.os;
-        ep.cpes = endpoint.cpes;
-        ep.tags = endpoint.tags;
-        for (_port, port_result) in endpoint.ports {
-            ep.upsert_port(port_result);
-        }
-        result.endpoints.push(ep);
+    Some(HostReply {
+        probe,
+        open: true,
+        ttl: Some(ttl),
+        mac_addr,
+        os: os_guess,
+        cpes,
+        received_at: captured.received_at,
+        frame: captured.frame,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::scheduler::ConcurrencyLimits;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn connect_scan_through_socks5_proxy_classifies_ports() {
+        let open = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open_addr = open.local_addr().unwrap();
+        let closed_addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let server = crate::proxy::testing::spawn_socks5_server(None).await;
+        let proxy = Proxy::parse(&format!("socks5://{}", server)).await.unwrap();
+        let targets: Vec<SocketAddr> = vec![open_addr, closed_addr];
+        let port_map = try_connect_ports(
+            Box::new(targets.into_iter()),
+            ProbeSource::default(),
+            Some(Arc::new(proxy)),
+            &Scheduler::new(ConcurrencyLimits::new(4, 4, 1)),
+            Duration::from_secs(2),
+            Arc::new(HashMap::new()),
+        )
+        .await
+        .unwrap();
+        let ports = &port_map[&open_addr.ip()];
+        assert_eq!(ports[&Port::new(open_addr.port(), TransportProtocol::Tcp)].state, PortState::Open);
+        assert_eq!(ports[&Port::new(closed_addr.port(), TransportProtocol::Tcp)].state, PortState::Closed);
     }
-    result
 }
@@ -1,39 +1,112 @@
-use std::collections::{BTreeMap, HashMap, HashSet};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 
-use futures::future::poll_fn;
 use netdev::{Interface, MacAddr};
-use nex::datalink::async_io::{async_channel, AsyncChannel, AsyncRawSender};
-use nex::packet::frame::Frame;
+use nex::datalink::async_io::AsyncRawSender;
+use nex::packet::icmp::IcmpType;
+use nex::packet::icmpv6::Icmpv6Type;
 use nex::packet::ip::IpNextProtocol;
+use tokio::sync::mpsc;
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 use anyhow::Result;
-use crate::{config::default::DEFAULT_BASE_TARGET_UDP_PORT, output::ScanResult, probe::ProbeSetting};
-use crate::capture::pcap::PacketCaptureOptions;
-use crate::endpoint::{EndpointResult, OsGuess};
+use crate::{config::default::{DEFAULT_BASE_TARGET_UDP_PORT, DEFAULT_LOCAL_UDP_PORT}, output::ScanResult, probe::ProbeSetting};
+use crate::capture::pcap::{CapturedFrame, PacketCaptureOptions};
+use crate::scan::collect::{HostCollector, HostReply, LocalAddrs, PortCollector, PortReply, ProbeKey, SentProbes, REPLY_QUEUE_LEN};
+use crate::scan::engine::PacketEngine;
+use crate::scan::send::RateController;
+use crate::cli::PortScanMethod;
+use crate::endpoint::{DiscoveryTechnique, OsGuess, Port, PortReason, PortState, TransportProtocol, UnansweredPorts};
+use crate::service::payload::{PayloadBuilder, PayloadContext};
+use crate::service::probe::PortProbe;
+
+/// ICMP Destination Unreachable code for Port Unreachable
+const ICMP_PORT_UNREACHABLE: u8 = 3;
+/// ICMPv6 Destination Unreachable code for Port Unreachable
+const ICMPV6_PORT_UNREACHABLE: u8 = 4;
+
+/// Get the protocol-specific probe payload for a UDP port.
+/// Returns an empty payload if no UDP probe is registered for the port.
+fn get_probe_payload(port: u16) -> Vec<u8> {
+    let (Some(port_probe_db), Some(service_probe_db)) = (
+        crate::db::service::PORT_PROBE_DB.get(),
+        crate::db::service::SERVICE_PROBE_DB.get(),
+    ) else {
+        return Vec::new();
+    };
+    let Some(probes) = port_probe_db.get(&Port::new(port, TransportProtocol::Udp)) else {
+        return Vec::new();
+    };
+    for probe in probes {
+        let Some(probe_payload) = service_probe_db.get(probe) else {
+            continue;
+        };
+        let port_probe = PortProbe {
+            probe_id: probe.clone(),
+            probe_name: probe_payload.id.clone(),
+            port,
+            transport: TransportProtocol::Udp,
+            payload: probe_payload.payload.clone(),
+            payload_encoding: probe_payload.payload_encoding,
+        };
+        match PayloadBuilder::new(port_probe).payload(PayloadContext::default()) {
+            Ok(payload) => return payload,
+            Err(e) => tracing::debug!("Failed to build UDP payload for port {}: {}", port, e),
+        }
+    }
+    Vec::new()
+}
+
+/// Send UDP probe packets for port scanning.
+pub async fn send_portscan_packets(
+    engine: &mut PacketEngine,
+    interface: &Interface,
+    scan_setting: &ProbeSetting,
+    rate: &mut RateController,
+) {
+    let header_span = tracing::info_span!("udp_port_scan");
+    header_span.pb_set_style(&crate::output::progress::get_progress_style());
+    header_span.pb_set_message("UDP PortScan");
+    let targets = scan_setting.target_addrs(TransportProtocol::Udp);
+    header_span.pb_set_length(targets.len() as u64);
+    header_span.pb_set_position(0);
+    header_span.pb_start();
+    let ip_packet = crate::interface::is_layer3(interface);
+
+    // Payloads are built once per port
+    let mut payload_map: HashMap<u16, Vec<u8>> = HashMap::new();
+    for target in targets {
+        let payload = payload_map.entry(target.port()).or_insert_with(|| get_probe_payload(target.port()));
+        let packet = crate::packet::udp::build_udp_probe_packet(interface, &scan_setting.neighbors, &scan_setting.source, target.ip(), target.port(), payload, ip_packet);
+        if let Err(e) = rate.dispatch(engine, packet).await {
+            eprintln!("Failed to send packet: {}", e);
+        }
+        header_span.pb_inc(1);
+    }
+    drop(header_span);
+}
 
 /// Send UDP packets for host scanning.
+/// The send time of every probe is recorded in `sent_map` for RTT measurement.
 pub async fn send_hostscan_packets(
     tx: &mut Box<dyn AsyncRawSender>,
     interface: &Interface,
     scan_setting: &ProbeSetting,
+    rate: &mut RateController,
+    sent: &SentProbes,
 ) {
     let header_span = tracing::info_span!("udp_host_scan");
     header_span.pb_set_style(&crate::output::progress::get_progress_style());
     header_span.pb_set_message("HostScan");
-    header_span.pb_set_length(scan_setting.target_endpoints.len() as u64);
+    header_span.pb_set_length(scan_setting.target_host_count());
     header_span.pb_set_position(0);
     header_span.pb_start();
+    let ip_packet = crate::interface::is_layer3(interface);
 
-    for target in &scan_setting.target_endpoints {
-        let packet = crate::packet::udp::build_udp_packet(&interface, target.ip, DEFAULT_BASE_TARGET_UDP_PORT, false);
-        // Send a packet using poll_fn.
-        match poll_fn(|cx| tx.poll_send(cx, &packet)).await {
-            Ok(_) => {
-                if !scan_setting.send_rate.is_zero() {
-                    tokio::time::sleep(scan_setting.send_rate).await;
-                }
-            },
+    for target_ip in scan_setting.target_ips() {
+        let packet = crate::packet::udp::build_udp_packet(&interface, &scan_setting.neighbors, &scan_setting.source, target_ip, DEFAULT_BASE_TARGET_UDP_PORT, ip_packet);
+        match rate.send(tx, &packet).await {
+            Ok(_) => sent.record(ProbeKey::new(target_ip, DiscoveryTechnique::Udp, DEFAULT_BASE_TARGET_UDP_PORT)),
             Err(e) => eprintln!("Failed to send packet: {}", e),
         }
         header_span.pb_inc(1);
@@ -47,22 +120,10 @@ pub async fn run_host_scan(setting: ProbeSetting) -> Result<ScanResult> {
         Some(interface) => interface,
         None => return Err(anyhow::anyhow!("Interface not found")),
     };
+    // Resolve the MAC addresses of on-link targets
+    setting.neighbors.resolve(&interface, setting.target_ips(), setting.wait_time).await;
     // Create sender
-    let config = nex::datalink::Config {
-        write_buffer_size: 4096,
-        read_buffer_size: 4096,
-        read_timeout: Some(setting.wait_time),
-        write_timeout: None,
-        channel_type: nex::datalink::ChannelType::Layer2,
-        bpf_fd_attempts: 1000,
-        linux_fanout: None,
-        promiscuous: false,
-    };
-
-    let AsyncChannel::Ethernet(mut tx, mut rx) = async_channel(&interface, config)?
-    else {
-        unreachable!();
-    };
+    let (mut tx, mut rx) = crate::capture::channel::open(&interface, setting.wait_time)?;
 
     let mut capture_options: PacketCaptureOptions = PacketCaptureOptions {
         interface_index: interface.index,
@@ -77,7 +138,7 @@ pub async fn run_host_scan(setting: ProbeSetting) -> Result<ScanResult> {
         read_timeout: setting.wait_time,
         promiscuous: false,
         receive_undefined: false,
-        tunnel: interface.is_tun(),
+        tunnel: crate::interface::is_layer3(&interface),
         loopback: interface.is_loopback(),
     };
     for endpoint in &setting.target_endpoints {
@@ -87,121 +148,206 @@ pub async fn run_host_scan(setting: ProbeSetting) -> Result<ScanResult> {
     capture_options.ip_protocols.insert(IpNextProtocol::Icmp);
     capture_options.ip_protocols.insert(IpNextProtocol::Icmpv6);
 
+    let mut rate = RateController::new(&setting);
+    let responses = rate.feedback().responses;
     let (ready_tx, ready_rx) = tokio::sync::oneshot::channel();
     let (stop_tx, mut stop_rx) = tokio::sync::oneshot::channel();
+    let (reply_tx, mut replies) = mpsc::channel(REPLY_QUEUE_LEN);
+    let local = LocalAddrs::new(&interface);
 
     let capture_handle: tokio::task::JoinHandle<_> = tokio::spawn(async move {
-        crate::capture::pcap::start_capture(
+        crate::capture::pcap::start_stream_capture(
             &mut rx,
             capture_options,
             ready_tx,
             &mut stop_rx,
+            &responses,
+            |captured| classify_host_reply(captured, &local),
+            reply_tx,
         )
         .await
     });
 
     // Wait for listener to start
-    let _ = ready_rx;
+    let _ = ready_rx.await;
     let start_time = std::time::Instant::now();
-    // Send probe packets
-    send_hostscan_packets(&mut tx, &interface, &setting).await;
-    tokio::time::sleep(setting.wait_time).await;
-    // Stop pcap
-    let _ = stop_tx.send(());
-    let frames = capture_handle.await.unwrap();
-    let dns_map = setting.get_dns_map();
-    let mut result = parse_hostscan_result(frames, &interface, &dns_map);
+    let sent = SentProbes::default();
+    let collector = RefCell::new(HostCollector::new(&setting));
+    // Record replies while the probes are still being sent
+    let collect = async {
+        while let Some(reply) = replies.recv().await {
+            collector.borrow_mut().record(reply, &sent);
+        }
+    };
+    let scan = async {
+        // Send probe packets
+        send_hostscan_packets(&mut tx, &interface, &setting, &mut rate, &sent).await;
+        tokio::time::sleep(setting.wait_time).await;
+        // Stop pcap
+        let _ = stop_tx.send(());
+        capture_handle.await.unwrap();
+    };
+    tokio::join!(collect, scan);
+    let mut result = collector.into_inner().finish();
     result.scan_time = start_time.elapsed();
     Ok(result)
 }
 
-/// Parse host scan results from captured packets.
-fn parse_hostscan_result(
-    packets: Vec<Frame>,
-    iface: &Interface,
-    dns_map: &HashMap<IpAddr, String>,
-) -> ScanResult {
-    let oui_db = crate::db::oui::oui_db();
-    let if_ipv4_set: HashSet<Ipv4Addr> = iface.ipv4_addrs().into_iter().collect();
-    let if_ipv6_set: HashSet<Ipv6Addr> = iface.ipv6_addrs().into_iter().collect();
-    let mut result: ScanResult = ScanResult::new();
-    let mut endpoint_map: HashMap<IpAddr, EndpointResult> = HashMap::new();
-    for p in packets {
-        if p.ip.is_none() {
-            continue;
+/// Run a UDP port scan based on the provided probe settings.
+pub async fn run_udp_scan(setting: ProbeSetting) -> Result<ScanResult> {
+    let interface = match crate::interface::get_interface_by_index(setting.if_index) {
+        Some(interface) => interface,
+        None => return Err(anyhow::anyhow!("Interface not found")),
+    };
+    // Resolve the MAC addresses of on-link targets
+    setting.neighbors.resolve(&interface, setting.target_ips(), setting.wait_time).await;
+    let mut capture_options: PacketCaptureOptions = PacketCaptureOptions {
+        interface_index: interface.index,
+        interface_name: interface.name.clone(),
+        src_ips: HashSet::new(),
+        dst_ips: HashSet::new(),
+        src_ports: HashSet::new(),
+        dst_ports: HashSet::new(),
+        ether_types: HashSet::new(),
+        ip_protocols: HashSet::new(),
+        capture_timeout: setting.task_timeout,
+        read_timeout: setting.wait_time,
+        promiscuous: false,
+        receive_undefined: false,
+        tunnel: crate::interface::is_layer3(&interface),
+        loopback: interface.is_loopback(),
+    };
+    // ICMP errors may be sent by intermediate routers, so accept anything addressed to us
+    capture_options.dst_ips.extend(interface.ip_addrs());
+    let local_port = setting.source.port_or(DEFAULT_LOCAL_UDP_PORT);
+    capture_options.dst_ports.insert(local_port);
+    capture_options.ip_protocols.insert(IpNextProtocol::Udp);
+    capture_options.ip_protocols.insert(IpNextProtocol::Icmp);
+    capture_options.ip_protocols.insert(IpNextProtocol::Icmpv6);
+
+    let mut rate = RateController::new(&setting);
+    let iface_mac = interface.mac_addr.unwrap_or(MacAddr::zero());
+    let (mut engine, mut replies) = PacketEngine::open(&interface, &setting.io, capture_options, rate.feedback(), move |captured| {
+        classify_port_reply(captured, iface_mac, local_port)
+    })?;
+    // Replies are recorded as they arrive, while probes are still being sent
+    let collector = RefCell::new(PortCollector::new(&setting, TransportProtocol::Udp));
+    let collect = async {
+        while let Some(reply) = replies.recv().await {
+            collector.borrow_mut().record(reply);
         }
-        let mut mac_addr: MacAddr;
-        if let Some(datalink) = &p.datalink {
-            if let Some(ethernet_frame) = &datalink.ethernet {
-                if ethernet_frame.destination != iface.mac_addr.unwrap_or(MacAddr::zero()) {
-                    continue;
-                }
-                mac_addr = ethernet_frame.source;
-            } else {
-                mac_addr = MacAddr::zero();
-            }
-        } else {
-            mac_addr = MacAddr::zero();
+    };
+    let start_time = std::time::Instant::now();
+    let scan = async {
+        send_portscan_packets(&mut engine, &interface, &setting, &mut rate).await;
+        engine.flush().await;
+        tokio::time::sleep(setting.wait_time).await;
+        engine.finish().await
+    };
+    let ((), io_stats) = tokio::join!(collect, scan);
+    // Ports that never answered are open|filtered
+    let unanswered = UnansweredPorts {
+        transport: TransportProtocol::Udp,
+        state: PortState::OpenFiltered,
+        reason: PortReason::NoResponse,
+        count: 0,
+    };
+    let mut result = collector.into_inner().finish(unanswered);
+    result.scan_time = start_time.elapsed();
+    result.io_stats = Some(io_stats);
+    Ok(result)
+}
+
+/// Run a UDP port scan using the specified probe settings and method.
+pub async fn run_port_scan(
+    setting: ProbeSetting,
+    _method: PortScanMethod,
+) -> Result<ScanResult> {
+    run_udp_scan(setting).await
+}
+
+/// Classify a captured frame as a reply to one of our UDP probes:
+/// a UDP reply means open, ICMP port unreachable closed and other unreachables filtered.
+fn classify_port_reply(captured: CapturedFrame, iface_mac: MacAddr, local_port: u16) -> Option<PortReply> {
+    let p = captured.frame;
+    let ip = p.ip.as_ref()?;
+    let mac_addr = match p.datalink.as_ref().and_then(|datalink| datalink.ethernet.as_ref()) {
+        Some(ethernet_frame) if ethernet_frame.destination != iface_mac => return None,
+        Some(ethernet_frame) => ethernet_frame.source,
+        None => MacAddr::zero(),
+    };
+    let (src_ip, ttl) = if let Some(ipv4_packet) = &ip.ipv4 {
+        (IpAddr::V4(ipv4_packet.source), ipv4_packet.ttl)
+    } else if let Some(ipv6_packet) = &ip.ipv6 {
+        (IpAddr::V6(ipv6_packet.source), ipv6_packet.hop_limit)
+    } else {
+        return None;
+    };
+
+    // Determine target IP, port, state and reason from the response
+    let (target_ip, port_number, state, reason) = if let Some(udp_packet) = p.transport.as_ref().and_then(|t| t.udp.as_ref()) {
+        if udp_packet.destination != local_port {
+            return None;
+        }
+        (src_ip, udp_packet.source, PortState::Open, PortReason::UdpResponse)
+    } else if let Some(icmp) = &ip.icmp {
+        if icmp.icmp_type != IcmpType::DestinationUnreachable {
+            return None;
         }
-        let ip_addr: IpAddr;
-        let ttl: u8;
-        if let Some(ip) = &p.ip {
-            // Expect ICMP or ICMPv6 Port Unreachable
-            if ip.icmp.is_none() && ip.icmpv6.is_none() {
-                continue;
-            }
-            if let Some(ipv4_packet) = &ip.ipv4 {
-                if if_ipv4_set.contains(&ipv4_packet.source) {
-                    mac_addr = iface.mac_addr.unwrap_or(MacAddr::zero());
-                    ttl = crate::util::ip::initial_ttl(ipv4_packet.ttl);
-                }else{
-                    ttl = ipv4_packet.ttl;
-                }
-                ip_addr = IpAddr::V4(ipv4_packet.source);
-            } else if let Some(ipv6_packet) = &ip.ipv6 {
-                if if_ipv6_set.contains(&ipv6_packet.source) {
-                    mac_addr = iface.mac_addr.unwrap_or(MacAddr::zero());
-                    ttl = crate::util::ip::initial_ttl(ipv6_packet.hop_limit);
-                }else {
-                    ttl = ipv6_packet.hop_limit;
-                }
-                ip_addr = IpAddr::V6(ipv6_packet.source);
-            } else {
-                continue;
-            }
+        let quoted = crate::packet::icmp::parse_quoted_packet(&p.payload)?;
+        if quoted.protocol != IpNextProtocol::Udp || quoted.src_port != local_port {
+            return None;
+        }
+        let (icmp_type, icmp_code) = (icmp.icmp_type.value(), icmp.icmp_code.0);
+        if icmp_code == ICMP_PORT_UNREACHABLE {
+            (quoted.dst_ip, quoted.dst_port, PortState::Closed, PortReason::IcmpPortUnreach { icmp_type, icmp_code })
         } else {
-            continue;
+            (quoted.dst_ip, quoted.dst_port, PortState::Filtered, PortReason::IcmpUnreach { icmp_type, icmp_code })
         }
-
-        let vendor_name_opt: Option<String>;
-        if let Some(oui) = oui_db.lookup_mac(&mac_addr) {
-            if let Some(vendor_detail) = &oui.vendor_detail {
-                vendor_name_opt = Some(vendor_detail.clone());
-            } else {
-                vendor_name_opt = Some(oui.vendor.clone());
-            }
+    } else if let Some(icmpv6) = &ip.icmpv6 {
+        if icmpv6.icmpv6_type != Icmpv6Type::DestinationUnreachable {
+            return None;
+        }
+        let quoted = crate::packet::icmp::parse_quoted_packet(&p.payload)?;
+        if quoted.protocol != IpNextProtocol::Udp || quoted.src_port != local_port {
+            return None;
+        }
+        let (icmp_type, icmp_code) = (icmpv6.icmpv6_type.value(), icmpv6.icmpv6_code.0);
+        if icmp_code == ICMPV6_PORT_UNREACHABLE {
+            (quoted.dst_ip, quoted.dst_port, PortState::Closed, PortReason::IcmpPortUnreach { icmp_type, icmp_code })
         } else {
-            vendor_name_opt = None;
+            (quoted.dst_ip, quoted.dst_port, PortState::Filtered, PortReason::IcmpUnreach { icmp_type, icmp_code })
         }
+    } else {
+        return None;
+    };
+    Some(PortReply {
+        addr: SocketAddr::new(target_ip, port_number),
+        state,
+        reason,
+        rtt_ms: None,
+        ttl,
+        // Link-layer and TTL information is only meaningful for replies from the target itself
+        target_mac: (src_ip == target_ip).then_some(mac_addr),
+        frame: (state == PortState::Open).then_some(p),
+    })
+}
 
-        endpoint_map
-            .entry(ip_addr)
-            .or_insert(EndpointResult {
-                ip: ip_addr,
-                hostname: dns_map.get(&ip_addr).cloned(),
-                ports: BTreeMap::new(),
-                mac_addr: Some(mac_addr),
-                vendor_name: vendor_name_opt,
-                os: OsGuess::default().with_ttl_observed(ttl),
-                tags: Vec::new(),
-                cpes: Vec::new(),
-            });
-
-        result.fingerprints.push(p.clone());
-
-    }
-    for (_ip, endpoint) in endpoint_map {
-        result.endpoints.push(endpoint);
+/// Classify a captured frame as an ICMP reply (expected Port Unreachable) from a probed host.
+fn classify_host_reply(captured: CapturedFrame, local: &LocalAddrs) -> Option<HostReply> {
+    let (ip_addr, ttl, mac_addr) = local.reply_source(&captured.frame)?;
+    let ip = captured.frame.ip.as_ref()?;
+    if ip.icmp.is_none() && ip.icmpv6.is_none() {
+        return None;
     }
-    result
+    Some(HostReply {
+        probe: ProbeKey::new(ip_addr, DiscoveryTechnique::Udp, DEFAULT_BASE_TARGET_UDP_PORT),
+        open: false,
+        ttl: Some(ttl),
+        mac_addr,
+        os: OsGuess::default().with_ttl_observed(ttl),
+        cpes: Vec::new(),
+        received_at: captured.received_at,
+        frame: captured.frame,
+    })
 }
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Concurrency limits of connection-based scans
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConcurrencyLimits {
+    /// Connections in flight across all hosts
+    pub in_flight: usize,
+    /// Connections in flight to one host
+    pub per_host: usize,
+    /// Hosts with connections in flight
+    pub hosts: usize,
+}
+
+impl ConcurrencyLimits {
+    /// Create limits, raising any zero limit to 1.
+    pub fn new(in_flight: usize, per_host: usize, hosts: usize) -> Self {
+        Self {
+            in_flight: in_flight.max(1),
+            per_host: per_host.max(1),
+            hosts: hosts.max(1),
+        }
+    }
+
+    /// Cap the limits so that at most `max_in_flight` connections are in flight at once.
+    pub fn capped(self, max_in_flight: usize) -> Self {
+        let in_flight = self.in_flight.min(max_in_flight.max(1));
+        Self {
+            in_flight,
+            per_host: self.per_host.min(in_flight),
+            hosts: self.hosts,
+        }
+    }
+}
+
+/// Connection scheduler shared by the connect scan, the QUIC scan and service detection.
+/// Every connection holds a [`ScanPermit`] of the scheduler, which enforces the global,
+/// per-host and host-parallelism limits at once. Clones share the same limits.
+#[derive(Clone)]
+pub struct Scheduler {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    limits: ConcurrencyLimits,
+    in_flight: Arc<Semaphore>,
+    hosts: Arc<Semaphore>,
+    active: Mutex<HashMap<IpAddr, ActiveHost>>,
+}
+
+/// Host with connections in flight or waiting
+struct ActiveHost {
+    in_flight: Arc<Semaphore>,
+    /// Tasks holding or waiting for a permit of the host
+    users: usize,
+    /// Released when the last user is done
+    _slot: OwnedSemaphorePermit,
+}
+
+impl fmt::Debug for Scheduler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Scheduler").field("limits", &self.inner.limits).finish()
+    }
+}
+
+impl Scheduler {
+    pub fn new(limits: ConcurrencyLimits) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                limits,
+                in_flight: Arc::new(Semaphore::new(limits.in_flight)),
+                hosts: Arc::new(Semaphore::new(limits.hosts)),
+                active: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Get the limits of the scheduler.
+    pub fn limits(&self) -> ConcurrencyLimits {
+        self.inner.limits
+    }
+
+    /// Wait until a connection to `ip` may start. The connection may run until the permit is dropped.
+    pub async fn acquire(&self, ip: IpAddr) -> ScanPermit {
+        let host = self.join_host(ip).await;
+        let per_host = host.in_flight.clone().acquire_owned().await.expect("scheduler semaphore closed");
+        let in_flight = self.inner.in_flight.clone().acquire_owned().await.expect("scheduler semaphore closed");
+        ScanPermit {
+            _in_flight: in_flight,
+            _per_host: per_host,
+            _host: host.guard,
+        }
+    }
+
+    /// Register a user of the host, waiting for a host slot if the host is not active yet.
+    async fn join_host(&self, ip: IpAddr) -> JoinedHost {
+        if let Some(host) = self.join_active(ip) {
+            return host;
+        }
+        let slot = self.inner.hosts.clone().acquire_owned().await.expect("scheduler semaphore closed");
+        let mut active = self.inner.active.lock().unwrap();
+        // Another task may have activated the host meanwhile; then the slot is released again
+        let host = active.entry(ip).or_insert_with(|| ActiveHost {
+            in_flight: Arc::new(Semaphore::new(self.inner.limits.per_host)),
+            users: 0,
+            _slot: slot,
+        });
+        host.users += 1;
+        JoinedHost {
+            in_flight: host.in_flight.clone(),
+            guard: HostGuard { inner: self.inner.clone(), ip },
+        }
+    }
+
+    fn join_active(&self, ip: IpAddr) -> Option<JoinedHost> {
+        let mut active = self.inner.active.lock().unwrap();
+        let host = active.get_mut(&ip)?;
+        host.users += 1;
+        Some(JoinedHost {
+            in_flight: host.in_flight.clone(),
+            guard: HostGuard { inner: self.inner.clone(), ip },
+        })
+    }
+}
+
+struct JoinedHost {
+    in_flight: Arc<Semaphore>,
+    guard: HostGuard,
+}
+
+/// Deregisters a user of an active host, releasing the host slot after the last one
+struct HostGuard {
+    inner: Arc<Inner>,
+    ip: IpAddr,
+}
+
+impl Drop for HostGuard {
+    fn drop(&mut self) {
+        let mut active = self.inner.active.lock().unwrap();
+        if let Some(host) = active.get_mut(&self.ip) {
+            host.users -= 1;
+            if host.users == 0 {
+                active.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Permission to run one connection, released on drop
+pub struct ScanPermit {
+    _in_flight: OwnedSemaphorePermit,
+    _per_host: OwnedSemaphorePermit,
+    _host: HostGuard,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Current and maximum number of tasks in a section
+    #[derive(Default)]
+    struct Gauge {
+        current: AtomicUsize,
+        max: AtomicUsize,
+    }
+
+    impl Gauge {
+        fn enter(&self) {
+            let current = self.current.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max.fetch_max(current, Ordering::SeqCst);
+        }
+        fn leave(&self) {
+            self.current.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn permits_respect_all_limits() {
+        let scheduler = Scheduler::new(ConcurrencyLimits::new(6, 2, 3));
+        let total = Arc::new(Gauge::default());
+        let hosts = Arc::new(Gauge::default());
+        let per_host: Arc<Vec<Gauge>> = Arc::new((0..8).map(|_| Gauge::default()).collect());
+        let host_users: Arc<Vec<AtomicUsize>> = Arc::new((0..8).map(|_| AtomicUsize::new(0)).collect());
+        let mut tasks = Vec::new();
+        for i in 0..8 * 5 {
+            let host = i % 8;
+            let (scheduler, total, hosts, per_host, host_users) =
+                (scheduler.clone(), total.clone(), hosts.clone(), per_host.clone(), host_users.clone());
+            tasks.push(tokio::spawn(async move {
+                let _permit = scheduler.acquire(IpAddr::from([192, 0, 2, host as u8])).await;
+                if host_users[host].fetch_add(1, Ordering::SeqCst) == 0 {
+                    hosts.enter();
+                }
+                total.enter();
+                per_host[host].enter();
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                per_host[host].leave();
+                total.leave();
+                if host_users[host].fetch_sub(1, Ordering::SeqCst) == 1 {
+                    hosts.leave();
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert!(total.max.load(Ordering::SeqCst) <= 6);
+        assert!(hosts.max.load(Ordering::SeqCst) <= 3);
+        assert!(per_host.iter().all(|gauge| gauge.max.load(Ordering::SeqCst) <= 2));
+        // Every host slot is released at the end
+        assert!(scheduler.inner.active.lock().unwrap().is_empty());
+        assert_eq!(scheduler.inner.hosts.available_permits(), 3);
+    }
+
+    #[test]
+    fn capped_limits_keep_the_per_host_limit_within_the_total() {
+        let limits = ConcurrencyLimits::new(4096, 1000, 200).capped(960);
+        assert_eq!(limits, ConcurrencyLimits::new(960, 960, 200));
+        assert_eq!(ConcurrencyLimits::new(100, 10, 5).capped(960), ConcurrencyLimits::new(100, 10, 5));
+    }
+
+    #[tokio::test]
+    async fn host_slot_is_kept_while_the_host_has_users() {
+        let scheduler = Scheduler::new(ConcurrencyLimits::new(10, 10, 1));
+        let a = IpAddr::from([192, 0, 2, 1]);
+        let first = scheduler.acquire(a).await;
+        // The same host does not need another slot
+        let second = scheduler.acquire(a).await;
+        drop(first);
+        let other = tokio::time::timeout(Duration::from_millis(20), scheduler.acquire(IpAddr::from([192, 0, 2, 2]))).await;
+        assert!(other.is_err(), "the only host slot is still taken");
+        drop(second);
+        let other = tokio::time::timeout(Duration::from_millis(20), scheduler.acquire(IpAddr::from([192, 0, 2, 2]))).await;
+        assert!(other.is_ok());
+    }
+}
@@ -0,0 +1,374 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::future::poll_fn;
+use nex::datalink::async_io::AsyncRawSender;
+
+use crate::config::default::{DEFAULT_INITIAL_SEND_RATE, DEFAULT_SLOW_START_THRESHOLD};
+use crate::probe::ProbeSetting;
+use crate::scan::engine::PacketEngine;
+
+/// Number of packets sent between rate increases
+const RATE_WINDOW: usize = 64;
+/// Loss ratio above which the send rate is cut
+const LOSS_THRESHOLD: f64 = 0.05;
+/// Lowest rate the controller backs off to when no minimum is set (pps)
+const FLOOR_RATE: f64 = 10.0;
+/// Sleeps shorter than this are accumulated instead of slept
+const MIN_SLEEP: Duration = Duration::from_millis(1);
+/// How far the pacer may fall behind before its schedule is reset
+const MAX_LAG: Duration = Duration::from_millis(10);
+/// Smoothing factor of the long-term reply ratio
+const BASELINE_ALPHA: f64 = 0.1;
+/// Smoothing factor of the recent reply ratio
+const RECENT_ALPHA: f64 = 0.5;
+/// The rate is cut when the recent reply ratio falls below this share of the long-term one
+const RESPONSE_DROP: f64 = 0.5;
+/// Replies per window the long-term ratio must predict before a drop is trusted
+const MIN_EXPECTED_REPLIES: f64 = 2.0;
+/// Windows without any further cut after the rate has been cut
+const COOLDOWN_WINDOWS: usize = 4;
+
+/// Counters the capture and send paths feed back into a [`RateController`] while sending
+#[derive(Clone, Debug, Default)]
+pub struct SendFeedback {
+    /// Replies that matched the scan
+    pub responses: Arc<AtomicU64>,
+    /// Packets the kernel refused (e.g. ENOBUFS)
+    pub send_errors: Arc<AtomicU64>,
+}
+
+/// Paced packet sender shared by the raw port and host scans.
+///
+/// The rate follows TCP-style congestion control: it doubles every window
+/// until the slow start threshold, then grows additively. It is halved when
+/// send errors occur, when the reply ratio of recent windows falls well below
+/// the ratio seen so far in the scan, or when retransmissions recover replies
+/// ([`RateController::record_retransmit`]). The rate always stays within
+/// `--min-rate`/`--max-rate`.
+#[derive(Debug)]
+pub struct RateController {
+    /// Current rate in packets per second
+    rate: f64,
+    min_rate: f64,
+    max_rate: f64,
+    /// Slow start threshold
+    ssthresh: f64,
+    next_send: Instant,
+    window_sent: usize,
+    round_start: Instant,
+    last_send: Instant,
+    round_sent: usize,
+    total_sent: usize,
+    feedback: SendFeedback,
+    last_responses: u64,
+    last_send_errors: u64,
+    /// Long-term reply ratio (replies per packet)
+    baseline_ratio: Option<f64>,
+    /// Recent reply ratio
+    recent_ratio: f64,
+    cooldown: usize,
+}
+
+impl RateController {
+    /// Create a new RateController from the rate limits in the probe settings.
+    pub fn new(setting: &ProbeSetting) -> Self {
+        Self::with_limits(setting.min_rate, setting.max_rate)
+    }
+
+    fn with_limits(min_rate: Option<u32>, max_rate: Option<u32>) -> Self {
+        let max_rate = max_rate.map(|r| r as f64).unwrap_or(f64::INFINITY);
+        let min_rate = min_rate.map(|r| r as f64).unwrap_or(FLOOR_RATE).min(max_rate);
+        let rate = (DEFAULT_INITIAL_SEND_RATE as f64).clamp(min_rate, max_rate);
+        let now = Instant::now();
+        Self {
+            rate,
+            min_rate,
+            max_rate,
+            // Without a limit, slow start would double the rate forever
+            ssthresh: (DEFAULT_SLOW_START_THRESHOLD as f64).clamp(min_rate, max_rate),
+            next_send: now,
+            window_sent: 0,
+            round_start: now,
+            last_send: now,
+            round_sent: 0,
+            total_sent: 0,
+            feedback: SendFeedback::default(),
+            last_responses: 0,
+            last_send_errors: 0,
+            baseline_ratio: None,
+            recent_ratio: 0.0,
+            cooldown: 0,
+        }
+    }
+
+    /// Get the counters to feed replies and send errors back into the controller.
+    pub fn feedback(&self) -> SendFeedback {
+        self.feedback.clone()
+    }
+
+    /// Current rate in packets per second.
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Total number of packets sent.
+    pub fn total_sent(&self) -> usize {
+        self.total_sent
+    }
+
+    /// Mark the start of a send round (e.g. a retransmission pass).
+    pub fn begin_round(&mut self) {
+        self.round_start = Instant::now();
+        self.last_send = self.round_start;
+        self.round_sent = 0;
+        self.next_send = self.round_start;
+    }
+
+    /// Wait until the next packet may be sent.
+    pub async fn pace(&mut self) {
+        let now = Instant::now();
+        if self.next_send + MAX_LAG < now {
+            // Do not burst to catch up after a stall
+            self.next_send = now;
+        }
+        if self.next_send > now + MIN_SLEEP {
+            tokio::time::sleep_until(self.next_send.into()).await;
+        }
+        self.next_send += Duration::from_secs_f64(1.0 / self.rate);
+    }
+
+    /// Pace and send a packet.
+    pub async fn send(&mut self, tx: &mut Box<dyn AsyncRawSender>, packet: &[u8]) -> std::io::Result<()> {
+        self.pace().await;
+        if let Err(e) = poll_fn(|cx| tx.poll_send(cx, packet)).await {
+            self.feedback.send_errors.fetch_add(1, Ordering::Relaxed);
+            return Err(e);
+        }
+        self.on_sent();
+        Ok(())
+    }
+
+    /// Pace and send a packet on a socket of its own, e.g. an ICMP datagram socket.
+    pub async fn send_on<T>(&mut self, send: impl Future<Output = std::io::Result<T>>) -> std::io::Result<T> {
+        self.pace().await;
+        match send.await {
+            Ok(sent) => {
+                self.on_sent();
+                Ok(sent)
+            }
+            Err(e) => {
+                self.feedback.send_errors.fetch_add(1, Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+
+    /// Pace a packet and queue it on the sender threads of the packet engine.
+    pub async fn dispatch(&mut self, engine: &mut PacketEngine, packet: Vec<u8>) -> std::io::Result<()> {
+        self.pace().await;
+        engine.send(packet).await?;
+        self.on_sent();
+        Ok(())
+    }
+
+    fn on_sent(&mut self) {
+        self.last_send = Instant::now();
+        self.total_sent += 1;
+        self.round_sent += 1;
+        self.window_sent += 1;
+        if self.window_sent < RATE_WINDOW {
+            return;
+        }
+        self.window_sent = 0;
+        let responses = self.feedback.responses.load(Ordering::Relaxed);
+        let send_errors = self.feedback.send_errors.load(Ordering::Relaxed);
+        self.end_window(responses - self.last_responses, send_errors - self.last_send_errors);
+        self.last_responses = responses;
+        self.last_send_errors = send_errors;
+    }
+
+    /// Adjust the rate at the end of a window of `RATE_WINDOW` packets,
+    /// given the replies and send errors seen during the window.
+    fn end_window(&mut self, responses: u64, send_errors: u64) {
+        let ratio = responses as f64 / RATE_WINDOW as f64;
+        self.recent_ratio = RECENT_ALPHA * ratio + (1.0 - RECENT_ALPHA) * self.recent_ratio;
+        if self.cooldown > 0 {
+            self.cooldown -= 1;
+        } else if send_errors > 0 || self.replies_dropped() {
+            self.back_off();
+            return;
+        }
+        if self.recent_ratio > 0.0 {
+            self.baseline_ratio = Some(match self.baseline_ratio {
+                Some(baseline) => BASELINE_ALPHA * self.recent_ratio + (1.0 - BASELINE_ALPHA) * baseline,
+                None => self.recent_ratio,
+            });
+        }
+        if self.rate < self.ssthresh {
+            self.rate *= 2.0;
+        } else {
+            self.rate += RATE_WINDOW as f64;
+        }
+        self.rate = self.rate.clamp(self.min_rate, self.max_rate);
+    }
+
+    /// Check if the recent reply ratio fell well below the long-term one.
+    fn replies_dropped(&self) -> bool {
+        match self.baseline_ratio {
+            Some(baseline) if baseline * RATE_WINDOW as f64 >= MIN_EXPECTED_REPLIES => {
+                self.recent_ratio < baseline * RESPONSE_DROP
+            }
+            _ => false,
+        }
+    }
+
+    /// Report the reply ratio of a retransmission round.
+    /// `recovered` is the number of `retransmitted` probes that got a reply this time,
+    /// i.e. probes (or replies) that were dropped in the previous round.
+    pub fn record_retransmit(&mut self, retransmitted: usize, recovered: usize) {
+        if retransmitted == 0 {
+            return;
+        }
+        let loss = recovered as f64 / retransmitted as f64;
+        if loss > LOSS_THRESHOLD {
+            self.back_off();
+        }
+    }
+
+    /// Halve the rate, based on what was actually achieved in the last round.
+    fn back_off(&mut self) {
+        let elapsed = self.last_send.duration_since(self.round_start).as_secs_f64();
+        let achieved = if elapsed > 0.0 && self.round_sent > 0 {
+            self.round_sent as f64 / elapsed
+        } else {
+            self.rate
+        };
+        self.ssthresh = (self.rate.min(achieved) / 2.0).clamp(self.min_rate, self.max_rate);
+        self.rate = self.ssthresh;
+        self.cooldown = COOLDOWN_WINDOWS;
+        tracing::debug!("Drops detected, send rate reduced to {:.0} pps", self.rate);
+    }
+}
+
+/// Measure the RTT of a reply against the latest probe sent before it.
+pub fn reply_rtt_ms(sent_times: &[Instant], received_at: Instant) -> Option<u32> {
+    sent_times
+        .iter()
+        .rev()
+        .find(|sent_at| **sent_at <= received_at)
+        .map(|sent_at| received_at.duration_since(*sent_at).as_millis() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send one full window with the given feedback.
+    fn send_window(rate: &mut RateController, responses: u64, send_errors: u64) {
+        rate.feedback.responses.fetch_add(responses, Ordering::Relaxed);
+        rate.feedback.send_errors.fetch_add(send_errors, Ordering::Relaxed);
+        for _ in 0..RATE_WINDOW {
+            rate.on_sent();
+        }
+    }
+
+    #[test]
+    fn slow_start_doubles_up_to_threshold_then_grows_additively() {
+        let mut rate = RateController::with_limits(None, None);
+        let initial = DEFAULT_INITIAL_SEND_RATE as f64;
+        let threshold = DEFAULT_SLOW_START_THRESHOLD as f64;
+        assert_eq!(rate.rate(), initial);
+        assert_eq!(rate.ssthresh, threshold);
+
+        send_window(&mut rate, 0, 0);
+        assert_eq!(rate.rate(), initial * 2.0);
+        while rate.rate() < threshold {
+            send_window(&mut rate, 0, 0);
+        }
+        assert_eq!(rate.rate(), threshold);
+        send_window(&mut rate, 0, 0);
+        assert_eq!(rate.rate(), threshold + RATE_WINDOW as f64);
+    }
+
+    #[test]
+    fn rate_stays_within_limits() {
+        let mut rate = RateController::with_limits(Some(500), Some(3000));
+        for _ in 0..10 {
+            send_window(&mut rate, 0, 0);
+        }
+        assert_eq!(rate.rate(), 3000.0);
+        for _ in 0..10 {
+            rate.back_off();
+        }
+        assert_eq!(rate.rate(), 500.0);
+
+        // A maximum below the initial rate caps it from the start
+        let rate = RateController::with_limits(None, Some(100));
+        assert_eq!(rate.rate(), 100.0);
+        assert_eq!(rate.ssthresh, 100.0);
+    }
+
+    #[test]
+    fn send_errors_halve_the_rate() {
+        let mut rate = RateController::with_limits(None, None);
+        send_window(&mut rate, 0, 0);
+        let before = rate.rate();
+        send_window(&mut rate, 0, 3);
+        assert_eq!(rate.rate(), before / 2.0);
+        assert_eq!(rate.ssthresh, before / 2.0);
+    }
+
+    #[test]
+    fn reply_ratio_drop_halves_the_rate() {
+        let mut rate = RateController::with_limits(None, None);
+        // Half of the probes are answered
+        for _ in 0..3 {
+            send_window(&mut rate, RATE_WINDOW as u64 / 2, 0);
+        }
+        // Replies stop: one silent window is tolerated, the second one
+        // brings the recent ratio below half of the baseline
+        send_window(&mut rate, 0, 0);
+        let before = rate.rate();
+        send_window(&mut rate, 0, 0);
+        assert_eq!(rate.rate(), before / 2.0);
+        let reduced = rate.rate();
+        // No further cut during the cooldown
+        send_window(&mut rate, 0, 0);
+        assert!(rate.rate() >= reduced);
+    }
+
+    #[test]
+    fn sparse_replies_do_not_trigger_backoff() {
+        let mut rate = RateController::with_limits(None, None);
+        // One reply every few windows is too little to judge drops by
+        for i in 0..20 {
+            let before = rate.rate();
+            send_window(&mut rate, (i % 4 == 0) as u64, 0);
+            assert!(rate.rate() >= before);
+        }
+    }
+
+    #[test]
+    fn retransmit_recovery_backs_off() {
+        let mut rate = RateController::with_limits(None, None);
+        send_window(&mut rate, 0, 0);
+        let before = rate.rate();
+        // Replies to 1 of 100 retransmissions is within tolerance
+        rate.record_retransmit(100, 1);
+        assert_eq!(rate.rate(), before);
+        rate.record_retransmit(100, 20);
+        assert_eq!(rate.rate(), before / 2.0);
+        rate.record_retransmit(0, 0);
+        assert_eq!(rate.rate(), before / 2.0);
+    }
+
+    #[test]
+    fn rtt_is_measured_from_the_latest_earlier_probe() {
+        let start = Instant::now();
+        let sent = [start, start + Duration::from_millis(100), start + Duration::from_millis(300)];
+        assert_eq!(reply_rtt_ms(&sent, start + Duration::from_millis(150)), Some(50));
+        assert_eq!(reply_rtt_ms(&sent[1..], start), None);
+    }
+}
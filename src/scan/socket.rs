@@ -0,0 +1,384 @@
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::stream::Stream;
+use netdev::Interface;
+use nex::datalink::async_io::AsyncRawSender;
+use nex::datalink::{RawReceiver, RawSender};
+use tokio::io::unix::AsyncFd;
+
+use crate::capture::bpf::BpfInsn;
+
+/// Raw `AF_PACKET` socket bound to one interface (Linux only).
+///
+/// Receive sockets see every frame on the interface and keep kernel counters
+/// that tell how many frames were dropped because the socket buffer was full.
+/// Frames sent by this host are skipped, so loopback traffic is seen once.
+/// Send-only sockets are opened with protocol 0, so the kernel never queues
+/// received frames on them.
+///
+/// On Layer3 links (see [`crate::interface::is_layer3`]) the socket is a
+/// `SOCK_DGRAM` socket that receives IP packets without a link-layer header.
+/// Packets are sent there with an [`IpSocket`].
+pub struct PacketSocket {
+    fd: OwnedFd,
+    buffer: Vec<u8>,
+    read_timeout: Duration,
+}
+
+impl PacketSocket {
+    /// Open a receive socket. Sockets that join the same `fanout_group` share
+    /// the traffic of the interface, hashed by flow.
+    pub fn open_rx(interface: &Interface, read_buffer_size: usize, read_timeout: Duration, fanout_group: Option<u16>) -> io::Result<Self> {
+        let socket = Self::open(interface, libc::ETH_P_ALL as u16, read_buffer_size, read_timeout)?;
+        if let Some(group) = fanout_group {
+            let arg: u32 = u32::from(group) | ((libc::PACKET_FANOUT_HASH | libc::PACKET_FANOUT_FLAG_DEFRAG) << 16);
+            setsockopt(socket.fd.as_raw_fd(), libc::SOL_PACKET, libc::PACKET_FANOUT, &arg)?;
+        }
+        Ok(socket)
+    }
+
+    /// Open a send-only socket. Use [`open_sender`] for Layer3 links.
+    pub fn open_tx(interface: &Interface, write_buffer_size: usize) -> io::Result<Self> {
+        Self::open(interface, 0, write_buffer_size, Duration::ZERO)
+    }
+
+    fn open(interface: &Interface, protocol: u16, buffer_size: usize, read_timeout: Duration) -> io::Result<Self> {
+        let socket_type = if crate::interface::is_layer3(interface) { libc::SOCK_DGRAM } else { libc::SOCK_RAW };
+        let fd = unsafe { libc::socket(libc::AF_PACKET, socket_type | libc::SOCK_CLOEXEC, i32::from(protocol.to_be())) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as libc::sa_family_t;
+        addr.sll_protocol = protocol.to_be();
+        addr.sll_ifindex = interface.index as i32;
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd,
+            buffer: vec![0; buffer_size],
+            read_timeout,
+        })
+    }
+
+    /// Switch the socket to non-blocking mode for use from async tasks.
+    pub fn into_async(self) -> io::Result<AsyncPacketSocket> {
+        set_nonblocking(self.fd.as_raw_fd())?;
+        Ok(AsyncPacketSocket { socket: AsyncFd::new(self)? })
+    }
+
+    /// Attach a classic BPF program, so that the kernel only queues the frames it accepts.
+    pub fn attach_filter(&self, program: &[BpfInsn]) -> io::Result<()> {
+        let fprog = libc::sock_fprog {
+            len: program.len() as libc::c_ushort,
+            // BpfInsn has the layout of sock_filter, and the kernel copies the program
+            filter: program.as_ptr() as *mut libc::sock_filter,
+        };
+        setsockopt(self.fd.as_raw_fd(), libc::SOL_SOCKET, libc::SO_ATTACH_FILTER, &fprog)
+    }
+
+    /// Get the number of frames the kernel dropped since the last call because
+    /// the receive buffer of the socket was full (`tp_drops` of PACKET_STATISTICS).
+    pub fn take_drops(&self) -> io::Result<u64> {
+        let mut stats: libc::tpacket_stats = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::tpacket_stats>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                self.fd.as_raw_fd(),
+                libc::SOL_PACKET,
+                libc::PACKET_STATISTICS,
+                &mut stats as *mut libc::tpacket_stats as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(u64::from(stats.tp_drops))
+    }
+}
+
+/// Open a send-only socket for the interface: an [`IpSocket`] on Layer3 links,
+/// otherwise a [`PacketSocket`].
+pub fn open_sender(interface: &Interface, write_buffer_size: usize) -> io::Result<Box<dyn RawSender>> {
+    if crate::interface::is_layer3(interface) {
+        Ok(Box::new(IpSocket::open(interface, write_buffer_size)?))
+    } else {
+        Ok(Box::new(PacketSocket::open_tx(interface, write_buffer_size)?))
+    }
+}
+
+/// Send-only raw IP sockets (`IPPROTO_RAW`) bound to one interface, for Layer3 links.
+///
+/// Packets are routed like locally generated ones. Packets injected below IP on
+/// loopback would be dropped as martians, as they carry 127/8 addresses but no route.
+pub struct IpSocket {
+    ipv4: OwnedFd,
+    /// `None` if IPv6 is disabled
+    ipv6: Option<OwnedFd>,
+}
+
+impl IpSocket {
+    pub fn open(interface: &Interface, write_buffer_size: usize) -> io::Result<Self> {
+        let open = |domain: libc::c_int| -> io::Result<OwnedFd> {
+            let fd = unsafe { libc::socket(domain, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::IPPROTO_RAW) };
+            if fd == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            let ret = unsafe {
+                libc::setsockopt(
+                    fd.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_BINDTODEVICE,
+                    interface.name.as_ptr() as *const libc::c_void,
+                    interface.name.len() as libc::socklen_t,
+                )
+            };
+            if ret == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            let size = libc::c_int::try_from(write_buffer_size).unwrap_or(libc::c_int::MAX);
+            setsockopt(fd.as_raw_fd(), libc::SOL_SOCKET, libc::SO_SNDBUF, &size)?;
+            Ok(fd)
+        };
+        Ok(Self {
+            ipv4: open(libc::AF_INET)?,
+            ipv6: open(libc::AF_INET6).ok(),
+        })
+    }
+
+    /// Switch the sockets to non-blocking mode for use from async tasks.
+    pub fn into_async(self) -> io::Result<AsyncIpSocket> {
+        set_nonblocking(self.ipv4.as_raw_fd())?;
+        if let Some(ipv6) = &self.ipv6 {
+            set_nonblocking(ipv6.as_raw_fd())?;
+        }
+        Ok(AsyncIpSocket {
+            ipv4: AsyncFd::new(self.ipv4)?,
+            ipv6: self.ipv6.map(AsyncFd::new).transpose()?,
+        })
+    }
+
+    fn send(&self, packet: &[u8]) -> io::Result<()> {
+        match packet.first().map(|b| b >> 4) {
+            Some(6) => match &self.ipv6 {
+                Some(fd) => send_ip(fd.as_raw_fd(), packet),
+                None => Err(io::Error::new(io::ErrorKind::Unsupported, "IPv6 is not available")),
+            },
+            _ => send_ip(self.ipv4.as_raw_fd(), packet),
+        }
+    }
+}
+
+impl RawSender for IpSocket {
+    fn build_and_send(&mut self, num_packets: usize, packet_size: usize, func: &mut dyn FnMut(&mut [u8])) -> Option<io::Result<()>> {
+        if packet_size == 0 {
+            return None;
+        }
+        let mut packet = vec![0u8; packet_size];
+        for _ in 0..num_packets {
+            func(&mut packet);
+            if let Err(e) = IpSocket::send(self, &packet) {
+                return Some(Err(e));
+            }
+        }
+        Some(Ok(()))
+    }
+
+    fn send(&mut self, packet: &[u8]) -> Option<io::Result<()>> {
+        Some(IpSocket::send(self, packet))
+    }
+}
+
+/// Non-blocking [`IpSocket`] driven by the tokio reactor.
+pub struct AsyncIpSocket {
+    ipv4: AsyncFd<OwnedFd>,
+    ipv6: Option<AsyncFd<OwnedFd>>,
+}
+
+impl AsyncRawSender for AsyncIpSocket {
+    fn poll_send(&mut self, cx: &mut Context<'_>, packet: &[u8]) -> Poll<io::Result<()>> {
+        let socket = match packet.first().map(|b| b >> 4) {
+            Some(6) => match &self.ipv6 {
+                Some(socket) => socket,
+                None => return Poll::Ready(Err(io::Error::new(io::ErrorKind::Unsupported, "IPv6 is not available"))),
+            },
+            _ => &self.ipv4,
+        };
+        loop {
+            let mut guard = match socket.poll_write_ready(cx) {
+                Poll::Ready(guard) => guard?,
+                Poll::Pending => return Poll::Pending,
+            };
+            match guard.try_io(|fd| send_ip(fd.as_raw_fd(), packet)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+/// Send an IP packet to the destination address in its header.
+fn send_ip(fd: RawFd, packet: &[u8]) -> io::Result<()> {
+    let dst: std::net::IpAddr = match packet.first().map(|b| b >> 4) {
+        Some(4) if packet.len() >= 20 => <[u8; 4]>::try_from(&packet[16..20]).unwrap().into(),
+        Some(6) if packet.len() >= 40 => <[u8; 16]>::try_from(&packet[24..40]).unwrap().into(),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "not an IP packet")),
+    };
+    let addr = socket2::SockAddr::from(std::net::SocketAddr::new(dst, 0));
+    let ret = unsafe { libc::sendto(fd, packet.as_ptr() as *const libc::c_void, packet.len(), 0, addr.as_ptr() as *const libc::sockaddr, addr.len()) };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn setsockopt<T>(fd: RawFd, level: libc::c_int, name: libc::c_int, value: &T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(fd, level, name, value as *const T as *const libc::c_void, mem::size_of::<T>() as libc::socklen_t)
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn send(fd: RawFd, packet: &[u8]) -> io::Result<()> {
+    let ret = unsafe { libc::send(fd, packet.as_ptr() as *const libc::c_void, packet.len(), 0) };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receive the next frame into `buffer` and return its length,
+/// or `None` for a frame sent by this host.
+fn recv(fd: RawFd, buffer: &mut [u8]) -> io::Result<Option<usize>> {
+    let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+    let mut addr_len = mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+    let len = unsafe {
+        libc::recvfrom(
+            fd,
+            buffer.as_mut_ptr() as *mut libc::c_void,
+            buffer.len(),
+            libc::MSG_TRUNC,
+            &mut addr as *mut libc::sockaddr_ll as *mut libc::sockaddr,
+            &mut addr_len,
+        )
+    };
+    if len == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    if addr.sll_pkttype == libc::PACKET_OUTGOING {
+        return Ok(None);
+    }
+    // Frames larger than the buffer are returned truncated
+    Ok(Some((len as usize).min(buffer.len())))
+}
+
+impl RawReceiver for PacketSocket {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        loop {
+            let mut pollfd = libc::pollfd {
+                fd: self.fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let ret = unsafe { libc::poll(&mut pollfd, 1, self.read_timeout.as_millis() as libc::c_int) };
+            if ret == 0 {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out"));
+            }
+            if ret == -1 {
+                let err = io::Error::last_os_error();
+                // A signal is handled like an empty poll interval
+                return Err(match err.kind() {
+                    io::ErrorKind::Interrupted => io::Error::new(io::ErrorKind::TimedOut, err),
+                    _ => err,
+                });
+            }
+            if let Some(len) = recv(self.fd.as_raw_fd(), &mut self.buffer)? {
+                return Ok(&self.buffer[..len]);
+            }
+        }
+    }
+}
+
+impl RawSender for PacketSocket {
+    fn build_and_send(&mut self, num_packets: usize, packet_size: usize, func: &mut dyn FnMut(&mut [u8])) -> Option<io::Result<()>> {
+        if packet_size == 0 || num_packets * packet_size > self.buffer.len() {
+            return None;
+        }
+        let fd = self.fd.as_raw_fd();
+        for chunk in self.buffer[..num_packets * packet_size].chunks_mut(packet_size) {
+            func(chunk);
+            if let Err(e) = send(fd, chunk) {
+                return Some(Err(e));
+            }
+        }
+        Some(Ok(()))
+    }
+
+    fn send(&mut self, packet: &[u8]) -> Option<io::Result<()>> {
+        Some(send(self.fd.as_raw_fd(), packet))
+    }
+}
+
+impl AsRawFd for PacketSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// Non-blocking receive [`PacketSocket`] driven by the tokio reactor, for the
+/// asynchronous channels of [`crate::capture::channel`].
+pub struct AsyncPacketSocket {
+    socket: AsyncFd<PacketSocket>,
+}
+
+impl Stream for AsyncPacketSocket {
+    type Item = io::Result<Vec<u8>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let me = self.get_mut();
+        loop {
+            let mut guard = match me.socket.poll_read_ready_mut(cx) {
+                Poll::Ready(guard) => guard?,
+                Poll::Pending => return Poll::Pending,
+            };
+            let received = guard.try_io(|socket| {
+                let socket = socket.get_mut();
+                recv(socket.fd.as_raw_fd(), &mut socket.buffer).map(|len| len.map(|len| socket.buffer[..len].to_vec()))
+            });
+            match received {
+                Ok(Ok(Some(packet))) => return Poll::Ready(Some(Ok(packet))),
+                // Frames sent by this host
+                Ok(Ok(None)) => continue,
+                Ok(Err(e)) => return Poll::Ready(Some(Err(e))),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+
+use nex::packet::tcp::{TcpFlags, TcpHeader, TcpOptionKind};
+
+use crate::packet::icmp::QuotedPacket;
+use crate::packet::tcp::TcpProbeFields;
+
+/// First source port used for probes
+const SRC_PORT_BASE: u16 = 32768;
+/// Number of source ports probes are spread over
+const SRC_PORT_RANGE: u32 = 65536 - SRC_PORT_BASE as u32;
+/// First bit of the send tick in the sequence number of probes
+const TICK_SHIFT: u32 = 16;
+/// Mask of the send tick, in milliseconds: RTTs are measured modulo 8.192 seconds
+const TICK_MASK: u32 = (1 << 13) - 1;
+
+/// Stateless raw TCP probing in the style of masscan.
+///
+/// The source port, sequence and acknowledgement numbers of every probe are
+/// derived from a keyed hash of (source IP, target IP, target port) with a key
+/// that is random per scan. A reply is accepted only if it hits the expected
+/// source port and acknowledges the expected sequence number, so stray traffic
+/// and other scans running at the same time are ignored, and nothing has to be
+/// remembered per probe. The send time is carried in the TSval of the timestamp
+/// option, and as a millisecond tick in bits 16-28 of the sequence number, which are
+/// left out of the check. Hosts that echo the TSval back give the exact RTT; other
+/// replies, such as RSTs without options, are timed from the tick.
+#[derive(Clone, Debug)]
+pub struct StatelessProbe {
+    key: RandomState,
+    flags: u8,
+    start: Instant,
+    /// Source port of every probe, instead of one derived from the cookie
+    src_port: Option<u16>,
+}
+
+impl StatelessProbe {
+    /// Create a new probe generator for the given TCP flags with a fresh key.
+    pub fn new(flags: u8) -> Self {
+        Self {
+            key: RandomState::new(),
+            flags,
+            start: Instant::now(),
+            src_port: None,
+        }
+    }
+
+    /// Send every probe from `src_port`. Replies are still matched by the sequence numbers.
+    pub fn with_src_port(mut self, src_port: Option<u16>) -> Self {
+        self.src_port = src_port;
+        self
+    }
+
+    fn cookie(&self, src_ip: IpAddr, dst: SocketAddr) -> u64 {
+        self.key.hash_one((src_ip, dst))
+    }
+
+    fn src_port(&self, cookie: u64) -> u16 {
+        self.src_port.unwrap_or(SRC_PORT_BASE + ((cookie >> 32) as u32 % SRC_PORT_RANGE) as u16)
+    }
+
+    /// Get the header fields of the probe from `src_ip` to `dst`.
+    pub fn fields(&self, src_ip: IpAddr, dst: SocketAddr) -> TcpProbeFields {
+        let cookie = self.cookie(src_ip, dst);
+        let elapsed_ms = self.start.elapsed().as_millis() as u32;
+        let sequence = (cookie as u32 & !(TICK_MASK << TICK_SHIFT)) | ((elapsed_ms & TICK_MASK) << TICK_SHIFT);
+        TcpProbeFields {
+            src_port: self.src_port(cookie),
+            flags: self.flags,
+            sequence,
+            // Only looked at by the target when the ACK flag is set; echoed as the sequence number of its RST
+            acknowledgement: sequence,
+            // Never zero, so an echoed zero is known to be no echo
+            timestamp: elapsed_ms.wrapping_add(1),
+        }
+    }
+
+    /// Get the sequence number of our probe that a reply echoes back.
+    fn echoed_sequence(&self, tcp: &TcpHeader) -> u32 {
+        if self.flags & TcpFlags::ACK != 0 {
+            // A RST answering a segment with ACK takes its sequence number from our acknowledgement
+            tcp.sequence
+        } else {
+            // SYN and FIN each take up one sequence number
+            let consumed = (self.flags & TcpFlags::SYN != 0) as u32 + (self.flags & TcpFlags::FIN != 0) as u32;
+            tcp.acknowledgement.wrapping_sub(consumed)
+        }
+    }
+
+    /// Check that a TCP segment from `remote` to `local_ip` answers one of our probes.
+    pub fn check_reply(&self, local_ip: IpAddr, remote: SocketAddr, tcp: &TcpHeader) -> bool {
+        let cookie = self.cookie(local_ip, remote);
+        if tcp.destination != self.src_port(cookie) {
+            return false;
+        }
+        let flags_match = if self.flags & TcpFlags::ACK != 0 {
+            tcp.flags & TcpFlags::RST != 0
+        } else {
+            tcp.flags & TcpFlags::ACK != 0
+        };
+        let checked = !(TICK_MASK << TICK_SHIFT);
+        flags_match && self.echoed_sequence(tcp) & checked == cookie as u32 & checked
+    }
+
+    /// Check that the packet quoted in an ICMP error is one of our probes.
+    pub fn check_quoted(&self, quoted: &QuotedPacket) -> bool {
+        let cookie = self.cookie(quoted.src_ip, SocketAddr::new(quoted.dst_ip, quoted.dst_port));
+        quoted.src_port == self.src_port(cookie)
+    }
+
+    /// Measure the RTT of a reply that passed [`Self::check_reply`]: from the TSval echoed in
+    /// its timestamp option, otherwise from the send tick in the echoed sequence number.
+    pub fn reply_rtt_ms(&self, tcp: &TcpHeader, received_at: Instant) -> Option<u32> {
+        let received_ms = received_at.checked_duration_since(self.start)?.as_millis() as u32;
+        let echoed = tcp
+            .options
+            .iter()
+            .find(|option| option.kind() == TcpOptionKind::TIMESTAMPS)
+            .map(|option| option.get_timestamp().1)
+            .filter(|echoed| *echoed != 0);
+        match echoed {
+            Some(echoed) => received_ms.wrapping_add(1).checked_sub(echoed),
+            None => {
+                let sent_tick = (self.echoed_sequence(tcp) >> TICK_SHIFT) & TICK_MASK;
+                Some(received_ms.wrapping_sub(sent_tick) & TICK_MASK)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nex::packet::ip::IpNextProtocol;
+    use nex::packet::tcp::TcpOptionPacket;
+    use std::time::Duration;
+
+    const LOCAL: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+    fn remote() -> SocketAddr {
+        "192.0.2.10:443".parse().unwrap()
+    }
+
+    fn reply(destination: u16, flags: u8, sequence: u32, acknowledgement: u32) -> TcpHeader {
+        TcpHeader {
+            source: remote().port(),
+            destination,
+            sequence,
+            acknowledgement,
+            data_offset: 5,
+            reserved: 0,
+            flags,
+            window: 65535,
+            checksum: 0,
+            urgent_ptr: 0,
+            options: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn fixed_source_port_is_used_for_every_probe() {
+        let probe = StatelessProbe::new(TcpFlags::SYN).with_src_port(Some(53));
+        let fields = probe.fields(LOCAL, remote());
+        assert_eq!(fields.src_port, 53);
+        assert_eq!(probe.fields(LOCAL, "192.0.2.11:80".parse().unwrap()).src_port, 53);
+        assert!(probe.check_reply(LOCAL, remote(), &reply(53, TcpFlags::SYN | TcpFlags::ACK, 7, fields.sequence.wrapping_add(1))));
+        // The sequence number still tells the targets apart
+        assert!(!probe.check_reply(LOCAL, remote(), &reply(53, TcpFlags::SYN | TcpFlags::ACK, 7, fields.sequence)));
+    }
+
+    #[test]
+    fn syn_reply_acknowledges_one_past_the_sequence() {
+        let probe = StatelessProbe::new(TcpFlags::SYN);
+        let fields = probe.fields(LOCAL, remote());
+        assert!(fields.src_port >= SRC_PORT_BASE);
+        let seq = fields.sequence;
+        assert!(probe.check_reply(LOCAL, remote(), &reply(fields.src_port, TcpFlags::SYN | TcpFlags::ACK, 7, seq.wrapping_add(1))));
+        assert!(probe.check_reply(LOCAL, remote(), &reply(fields.src_port, TcpFlags::RST | TcpFlags::ACK, 0, seq.wrapping_add(1))));
+        // Wrong acknowledgement, missing ACK flag, wrong port or another target
+        assert!(!probe.check_reply(LOCAL, remote(), &reply(fields.src_port, TcpFlags::SYN | TcpFlags::ACK, 7, seq)));
+        assert!(!probe.check_reply(LOCAL, remote(), &reply(fields.src_port, TcpFlags::RST, 0, seq.wrapping_add(1))));
+        assert!(!probe.check_reply(LOCAL, remote(), &reply(fields.src_port.wrapping_add(1), TcpFlags::SYN | TcpFlags::ACK, 7, seq.wrapping_add(1))));
+        let other: SocketAddr = "192.0.2.11:443".parse().unwrap();
+        let other_port = probe.fields(LOCAL, other).src_port;
+        assert!(!probe.check_reply(LOCAL, other, &reply(other_port, TcpFlags::SYN | TcpFlags::ACK, 7, seq.wrapping_add(1))));
+        // Another scan has another key
+        assert!(!StatelessProbe::new(TcpFlags::SYN).check_reply(LOCAL, remote(), &reply(fields.src_port, TcpFlags::SYN | TcpFlags::ACK, 7, seq.wrapping_add(1))));
+    }
+
+    #[test]
+    fn fin_and_null_replies_consume_sequence_numbers() {
+        let fin = StatelessProbe::new(TcpFlags::FIN);
+        let fields = fin.fields(LOCAL, remote());
+        assert!(fin.check_reply(LOCAL, remote(), &reply(fields.src_port, TcpFlags::RST | TcpFlags::ACK, 0, fields.sequence.wrapping_add(1))));
+        assert!(!fin.check_reply(LOCAL, remote(), &reply(fields.src_port, TcpFlags::RST | TcpFlags::ACK, 0, fields.sequence)));
+        let xmas = StatelessProbe::new(TcpFlags::FIN | TcpFlags::PSH | TcpFlags::URG);
+        let fields = xmas.fields(LOCAL, remote());
+        assert!(xmas.check_reply(LOCAL, remote(), &reply(fields.src_port, TcpFlags::RST | TcpFlags::ACK, 0, fields.sequence.wrapping_add(1))));
+        let null = StatelessProbe::new(0);
+        let fields = null.fields(LOCAL, remote());
+        assert!(null.check_reply(LOCAL, remote(), &reply(fields.src_port, TcpFlags::RST | TcpFlags::ACK, 0, fields.sequence)));
+        assert!(!null.check_reply(LOCAL, remote(), &reply(fields.src_port, TcpFlags::RST | TcpFlags::ACK, 0, fields.sequence.wrapping_add(1))));
+    }
+
+    #[test]
+    fn ack_reply_is_a_rst_with_our_acknowledgement_as_sequence() {
+        for flags in [TcpFlags::ACK, TcpFlags::ACK | TcpFlags::FIN] {
+            let probe = StatelessProbe::new(flags);
+            let fields = probe.fields(LOCAL, remote());
+            assert!(probe.check_reply(LOCAL, remote(), &reply(fields.src_port, TcpFlags::RST, fields.acknowledgement, 0)));
+            assert!(!probe.check_reply(LOCAL, remote(), &reply(fields.src_port, TcpFlags::RST, fields.acknowledgement.wrapping_add(1), 0)));
+            assert!(!probe.check_reply(LOCAL, remote(), &reply(fields.src_port, TcpFlags::ACK, fields.acknowledgement, 0)));
+        }
+    }
+
+    #[test]
+    fn quoted_probe_is_checked_by_source_port() {
+        let probe = StatelessProbe::new(TcpFlags::SYN);
+        let fields = probe.fields(LOCAL, remote());
+        let mut quoted = QuotedPacket {
+            src_ip: LOCAL,
+            dst_ip: remote().ip(),
+            protocol: IpNextProtocol::Tcp,
+            src_port: fields.src_port,
+            dst_port: remote().port(),
+        };
+        assert!(probe.check_quoted(&quoted));
+        quoted.dst_port += 1;
+        assert!(!probe.check_quoted(&quoted));
+    }
+
+    #[test]
+    fn rtt_from_echoed_timestamp() {
+        let probe = StatelessProbe::new(TcpFlags::SYN);
+        let tsval = probe.fields(LOCAL, remote()).timestamp;
+        let mut tcp = reply(0, TcpFlags::SYN | TcpFlags::ACK, 0, 0);
+        tcp.options = vec![TcpOptionPacket::nop(), TcpOptionPacket::timestamp(99, tsval)];
+        let received_at = probe.start + Duration::from_millis(tsval as u64 - 1 + 25);
+        assert_eq!(probe.reply_rtt_ms(&tcp, received_at), Some(25));
+        // A zero TSecr is no echo, and an echo from the future is rejected
+        tcp.options = vec![TcpOptionPacket::timestamp(99, u32::MAX)];
+        assert_eq!(probe.reply_rtt_ms(&tcp, received_at), None);
+    }
+
+    #[test]
+    fn rtt_from_send_tick_without_timestamp_echo() {
+        let probe = StatelessProbe::new(TcpFlags::SYN);
+        std::thread::sleep(Duration::from_millis(5));
+        let fields = probe.fields(LOCAL, remote());
+        let sent_ms = (fields.sequence >> TICK_SHIFT) & TICK_MASK;
+        assert!(sent_ms >= 5);
+        // A RST from a closed port carries no options
+        let rst = reply(fields.src_port, TcpFlags::RST | TcpFlags::ACK, 0, fields.sequence.wrapping_add(1));
+        assert!(probe.check_reply(LOCAL, remote(), &rst));
+        let received_at = probe.start + Duration::from_millis(sent_ms as u64 + 40);
+        assert_eq!(probe.reply_rtt_ms(&rst, received_at), Some(40));
+        // A zero TSecr is no echo either
+        let mut syn_ack = reply(fields.src_port, TcpFlags::SYN | TcpFlags::ACK, 7, fields.sequence.wrapping_add(1));
+        syn_ack.options = vec![TcpOptionPacket::timestamp(99, 0)];
+        assert_eq!(probe.reply_rtt_ms(&syn_ack, received_at), Some(40));
+        // The tick wraps around after TICK_MASK + 1 milliseconds
+        let wrapped = probe.start + Duration::from_millis(sent_ms as u64 + TICK_MASK as u64 + 1 + 3);
+        assert_eq!(probe.reply_rtt_ms(&rst, wrapped), Some(3));
+        // The tick bits are not checked, the others are
+        let mut late = rst.clone();
+        late.acknowledgement ^= 1 << TICK_SHIFT;
+        assert!(probe.check_reply(LOCAL, remote(), &late));
+        late.acknowledgement ^= 1 << 30;
+        assert!(!probe.check_reply(LOCAL, remote(), &late));
+    }
+
+    #[test]
+    fn ack_probe_rst_is_timed_from_its_sequence() {
+        let probe = StatelessProbe::new(TcpFlags::ACK);
+        let fields = probe.fields(LOCAL, remote());
+        let sent_ms = (fields.acknowledgement >> TICK_SHIFT) & TICK_MASK;
+        let rst = reply(fields.src_port, TcpFlags::RST, fields.acknowledgement, 0);
+        let received_at = probe.start + Duration::from_millis(sent_ms as u64 + 12);
+        assert_eq!(probe.reply_rtt_ms(&rst, received_at), Some(12));
+    }
+}
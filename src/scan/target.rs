@@ -0,0 +1,711 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::endpoint::Host;
+use crate::util::permutation::CyclicPermutation;
+
+/// Upper bound on the number of (host, port) work items in one scan
+const MAX_SCAN_TARGETS: u128 = 1 << 62;
+
+/// Inclusive address range within one address family
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct AddrRange {
+    v6: bool,
+    start: u128,
+    end: u128,
+}
+
+impl AddrRange {
+    fn new(start: IpAddr, end: IpAddr) -> Self {
+        Self {
+            v6: start.is_ipv6(),
+            start: addr_to_u128(start),
+            end: addr_to_u128(end),
+        }
+    }
+    fn len(&self) -> u128 {
+        (self.end - self.start).saturating_add(1)
+    }
+    fn addr(&self, offset: u128) -> IpAddr {
+        u128_to_addr(self.start + offset, self.v6)
+    }
+}
+
+fn addr_to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(v4) => u32::from(v4) as u128,
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+fn u128_to_addr(value: u128, v6: bool) -> IpAddr {
+    if v6 {
+        IpAddr::V6(Ipv6Addr::from(value))
+    } else {
+        IpAddr::V4(Ipv4Addr::from(value as u32))
+    }
+}
+
+/// Sort ranges and merge the overlapping or adjacent ones.
+fn merge_ranges(mut ranges: Vec<AddrRange>) -> Vec<AddrRange> {
+    ranges.sort();
+    let mut merged: Vec<AddrRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if last.v6 == range.v6 && range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end);
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Remove `excluded` (sorted and merged) from `ranges`.
+fn subtract_ranges(ranges: &[AddrRange], excluded: &[AddrRange]) -> Vec<AddrRange> {
+    let mut out = Vec::new();
+    for range in ranges {
+        let mut start = range.start;
+        let mut covered = false;
+        for ex in excluded {
+            if ex.v6 != range.v6 || ex.end < start || ex.start > range.end {
+                continue;
+            }
+            if ex.start > start {
+                out.push(AddrRange { v6: range.v6, start, end: ex.start - 1 });
+            }
+            if ex.end >= range.end {
+                covered = true;
+                break;
+            }
+            start = ex.end + 1;
+        }
+        if !covered {
+            out.push(AddrRange { v6: range.v6, start, end: range.end });
+        }
+    }
+    out
+}
+
+/// IPv4 octet pattern such as "10.1-3.*.1": the addresses whose every octet lies in
+/// the inclusive range of that octet. Patterns are never expanded into address lists.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OctetPattern {
+    octets: [(u8, u8); 4],
+}
+
+impl OctetPattern {
+    /// Create a pattern from the inclusive range of each octet. Every range must not be reversed.
+    pub fn new(octets: [(u8, u8); 4]) -> Self {
+        debug_assert!(octets.iter().all(|(lo, hi)| lo <= hi));
+        Self { octets }
+    }
+
+    /// Number of addresses matching the pattern. A pattern is never empty.
+    pub(crate) fn len(&self) -> u64 {
+        self.octets.iter().map(|(lo, hi)| (hi - lo) as u64 + 1).product()
+    }
+
+    /// Get the address at the given index, in address order.
+    fn get(&self, mut index: u64) -> Ipv4Addr {
+        let mut bytes = [0u8; 4];
+        for i in (0..4).rev() {
+            let (lo, hi) = self.octets[i];
+            let width = (hi - lo) as u64 + 1;
+            bytes[i] = lo + (index % width) as u8;
+            index /= width;
+        }
+        Ipv4Addr::from(bytes)
+    }
+
+    /// Check if the address matches the pattern.
+    pub fn contains(&self, ip: &Ipv4Addr) -> bool {
+        ip.octets().iter().zip(&self.octets).all(|(o, (lo, hi))| lo <= o && o <= hi)
+    }
+
+    /// Get the first and last matching addresses.
+    fn bounds(&self) -> (u32, u32) {
+        let lo = self.octets.map(|(lo, _)| lo);
+        let hi = self.octets.map(|(_, hi)| hi);
+        (u32::from_be_bytes(lo), u32::from_be_bytes(hi))
+    }
+
+    fn intersect(&self, other: &Self) -> Option<Self> {
+        let mut octets = self.octets;
+        for (i, (lo, hi)) in octets.iter_mut().enumerate() {
+            *lo = (*lo).max(other.octets[i].0);
+            *hi = (*hi).min(other.octets[i].1);
+            if lo > hi {
+                return None;
+            }
+        }
+        Some(Self { octets })
+    }
+
+    /// Remove `other` from the pattern. The rest is split into at most 8 disjoint patterns.
+    fn subtract(&self, other: &Self) -> Vec<Self> {
+        let Some(common) = self.intersect(other) else {
+            return vec![*self];
+        };
+        let mut rest = Vec::new();
+        let mut remaining = *self;
+        for i in 0..4 {
+            let (lo, hi) = remaining.octets[i];
+            let (common_lo, common_hi) = common.octets[i];
+            if lo < common_lo {
+                let mut below = remaining;
+                below.octets[i] = (lo, common_lo - 1);
+                rest.push(below);
+            }
+            if hi > common_hi {
+                let mut above = remaining;
+                above.octets[i] = (common_hi + 1, hi);
+                rest.push(above);
+            }
+            remaining.octets[i] = common.octets[i];
+        }
+        rest
+    }
+
+    /// Split an inclusive IPv4 range into at most 7 disjoint patterns.
+    fn from_range(start: u32, end: u32) -> Vec<Self> {
+        let mut patterns = Vec::new();
+        let (mut start, end) = (start as u64, end as u64);
+        while start <= end {
+            // Largest octet-aligned block size that starts here and fits in the range
+            let mut level = 0;
+            while level < 4 && start % (1u64 << (8 * (level + 1))) == 0 && start + (1u64 << (8 * (level + 1))) - 1 <= end {
+                level += 1;
+            }
+            let block = 1u64 << (8 * level);
+            let octet = 3 - level.min(3);
+            // Consecutive blocks up to the end of the range or of the enclosing block
+            let first = ((start / block) % 256) as u8;
+            let blocks = ((end + 1 - start) / block).min(256 - first as u64);
+            let bytes = (start as u32).to_be_bytes();
+            let mut octets = [(0u8, 255u8); 4];
+            for i in 0..4 {
+                if i < octet {
+                    octets[i] = (bytes[i], bytes[i]);
+                } else if i == octet && level < 4 {
+                    octets[i] = (first, first + (blocks - 1) as u8);
+                }
+            }
+            patterns.push(Self { octets });
+            start += if level < 4 { blocks * block } else { block };
+        }
+        patterns
+    }
+
+    /// Remove the IPv4 parts of the sorted `ranges` from the patterns.
+    fn subtract_ranges(patterns: Vec<Self>, ranges: &[AddrRange]) -> Vec<Self> {
+        let mut out = Vec::with_capacity(patterns.len());
+        for pattern in patterns {
+            let (lo, hi) = pattern.bounds();
+            let first = ranges.partition_point(|r| (r.v6, r.end) < (false, lo as u128));
+            let mut pieces = vec![pattern];
+            for range in ranges[first..].iter().take_while(|r| !r.v6 && r.start <= hi as u128) {
+                let cut = Self::from_range(range.start.max(lo as u128) as u32, range.end.min(hi as u128) as u32);
+                for c in &cut {
+                    pieces = pieces.iter().flat_map(|p| p.subtract(c)).collect();
+                }
+            }
+            out.extend(pieces);
+        }
+        out
+    }
+}
+
+/// Block of addresses in a target or exclusion specification
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddrBlock {
+    /// Inclusive address range
+    Range(IpAddr, IpAddr),
+    /// IPv4 octet pattern
+    Pattern(OctetPattern),
+}
+
+impl std::fmt::Display for AddrBlock {
+    /// Format the block as a target spec: "10.0.0.1", "10.0.0.1-10.0.0.9" or "10.*.0-3.1".
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AddrBlock::Range(start, end) if start == end => write!(f, "{}", start),
+            AddrBlock::Range(start, end) => write!(f, "{}-{}", start, end),
+            AddrBlock::Pattern(pattern) => {
+                let octets: Vec<String> = pattern
+                    .octets
+                    .iter()
+                    .map(|(lo, hi)| match (lo, hi) {
+                        (0, 255) => "*".to_string(),
+                        (lo, hi) if lo == hi => lo.to_string(),
+                        (lo, hi) => format!("{}-{}", lo, hi),
+                    })
+                    .collect();
+                write!(f, "{}", octets.join("."))
+            }
+        }
+    }
+}
+
+/// Set of target hosts, kept as address ranges and octet patterns and expanded on demand
+#[derive(Clone, Debug, Default)]
+pub struct TargetSet {
+    /// Sorted, non-overlapping ranges
+    ranges: Vec<AddrRange>,
+    /// Octet patterns, disjoint from each other and from the ranges
+    patterns: Vec<OctetPattern>,
+    /// Number of hosts before each range, then before each pattern
+    offsets: Vec<u128>,
+    len: u128,
+    hostnames: HashMap<IpAddr, String>,
+}
+
+impl TargetSet {
+    /// Create a target set from address blocks and single hosts.
+    /// Overlapping entries are merged, so every address appears once.
+    pub fn new(blocks: Vec<AddrBlock>, hosts: Vec<Host>) -> Self {
+        let mut addr_ranges: Vec<AddrRange> = Vec::new();
+        let mut patterns: Vec<OctetPattern> = Vec::new();
+        for block in blocks {
+            match block {
+                AddrBlock::Range(start, end) => addr_ranges.push(AddrRange::new(start, end)),
+                AddrBlock::Pattern(pattern) => {
+                    // Keep only the part not covered by an earlier pattern
+                    let mut pieces = vec![pattern];
+                    for earlier in &patterns {
+                        pieces = pieces.iter().flat_map(|p| p.subtract(earlier)).collect();
+                    }
+                    patterns.extend(pieces);
+                }
+            }
+        }
+        let mut hostnames = HashMap::new();
+        for host in hosts {
+            addr_ranges.push(AddrRange::new(host.ip, host.ip));
+            if let Some(hostname) = host.hostname {
+                hostnames.entry(host.ip).or_insert(hostname);
+            }
+        }
+        let ranges = merge_ranges(addr_ranges);
+        let patterns = OctetPattern::subtract_ranges(patterns, &ranges);
+        Self::from_parts(ranges, patterns, hostnames)
+    }
+
+    fn from_parts(ranges: Vec<AddrRange>, patterns: Vec<OctetPattern>, hostnames: HashMap<IpAddr, String>) -> Self {
+        let mut offsets = Vec::with_capacity(ranges.len() + patterns.len());
+        let mut len: u128 = 0;
+        for size in ranges.iter().map(|r| r.len()).chain(patterns.iter().map(|p| p.len() as u128)) {
+            offsets.push(len);
+            len = len.saturating_add(size);
+        }
+        Self { ranges, patterns, offsets, len, hostnames }
+    }
+
+    /// Number of hosts in the set.
+    pub fn len(&self) -> u128 {
+        self.len
+    }
+
+    /// Check if the set has no hosts.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get the host address at the given index (ranges in address order, then the patterns).
+    pub fn get(&self, index: u128) -> Option<IpAddr> {
+        if index >= self.len {
+            return None;
+        }
+        let i = self.offsets.partition_point(|offset| *offset <= index) - 1;
+        let offset = index - self.offsets[i];
+        match self.ranges.get(i) {
+            Some(range) => Some(range.addr(offset)),
+            None => Some(IpAddr::V4(self.patterns[i - self.ranges.len()].get(offset as u64))),
+        }
+    }
+
+    /// Check if the IP address is in the set.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let (v6, value) = (ip.is_ipv6(), addr_to_u128(*ip));
+        let i = self.ranges.partition_point(|r| (r.v6, r.end) < (v6, value));
+        if self.ranges.get(i).is_some_and(|r| r.v6 == v6 && r.start <= value) {
+            return true;
+        }
+        match ip {
+            IpAddr::V4(v4) => self.patterns.iter().any(|p| p.contains(v4)),
+            IpAddr::V6(_) => false,
+        }
+    }
+
+    /// Iterate over the address blocks of the set: ranges in address order, then the patterns.
+    pub fn blocks(&self) -> impl Iterator<Item = AddrBlock> + '_ {
+        let ranges = self.ranges.iter().map(|r| AddrBlock::Range(r.addr(0), r.addr(r.end - r.start)));
+        ranges.chain(self.patterns.iter().map(|p| AddrBlock::Pattern(*p)))
+    }
+
+    /// Get the hostnames the targets were resolved from.
+    pub fn hostnames(&self) -> &HashMap<IpAddr, String> {
+        &self.hostnames
+    }
+
+    /// Iterate over the hosts: ranges in address order, then the patterns.
+    pub fn hosts(&self) -> impl Iterator<Item = Host> + '_ {
+        let ranges = self.ranges.iter().flat_map(|range| (0..range.len()).map(move |offset| range.addr(offset)));
+        let patterns = self.patterns.iter().flat_map(|pattern| (0..pattern.len()).map(move |offset| IpAddr::V4(pattern.get(offset))));
+        ranges
+            .chain(patterns)
+            .map(|ip| Host { ip, hostname: self.hostnames.get(&ip).cloned() })
+    }
+
+    /// Remove the given address blocks and return the number of hosts removed.
+    pub fn exclude(&mut self, excluded: &[AddrBlock]) -> u128 {
+        if excluded.is_empty() {
+            return 0;
+        }
+        let mut excluded_ranges = Vec::new();
+        let mut excluded_patterns = Vec::new();
+        for block in excluded {
+            match block {
+                AddrBlock::Range(start, end) => excluded_ranges.push(AddrRange::new(*start, *end)),
+                AddrBlock::Pattern(pattern) => excluded_patterns.push(*pattern),
+            }
+        }
+        let excluded_ranges = merge_ranges(excluded_ranges);
+        let mut ranges = subtract_ranges(&self.ranges, &excluded_ranges);
+        let mut patterns = OctetPattern::subtract_ranges(std::mem::take(&mut self.patterns), &excluded_ranges);
+        for excluded in &excluded_patterns {
+            patterns = patterns.iter().flat_map(|p| p.subtract(excluded)).collect();
+            // Ranges with a hole are split into patterns
+            let (lo, hi) = excluded.bounds();
+            let mut kept = Vec::with_capacity(ranges.len());
+            for range in ranges {
+                let overlaps = !range.v6
+                    && range.start <= hi as u128
+                    && range.end >= lo as u128
+                    && OctetPattern::from_range(range.start as u32, range.end as u32).iter().any(|p| p.intersect(excluded).is_some());
+                if overlaps {
+                    let pieces = OctetPattern::from_range(range.start as u32, range.end as u32);
+                    patterns.extend(pieces.iter().flat_map(|p| p.subtract(excluded)));
+                } else {
+                    kept.push(range);
+                }
+            }
+            ranges = kept;
+        }
+        let before = self.len;
+        let mut hostnames = std::mem::take(&mut self.hostnames);
+        *self = Self::from_parts(ranges, patterns, HashMap::new());
+        hostnames.retain(|ip, _| self.contains(ip));
+        self.hostnames = hostnames;
+        before - self.len
+    }
+}
+
+/// Work items of a port scan: every host of a [`TargetSet`] paired with every port.
+///
+/// Items are generated on demand, so memory use does not grow with the size of the
+/// address space. In random order, the host × port index space is walked with a
+/// [`CyclicPermutation`] instead of being shuffled.
+#[derive(Clone, Debug)]
+pub struct ScanTargets {
+    hosts: Arc<TargetSet>,
+    ports: Arc<Vec<u16>>,
+    port_set: Arc<HashSet<u16>>,
+    randomize: bool,
+}
+
+impl ScanTargets {
+    /// Create the work items for the given hosts and ports.
+    /// Fails if the space does not fit in a single scan.
+    pub fn new(hosts: TargetSet, ports: Vec<u16>, randomize: bool) -> Result<Self> {
+        // Host scans without ports still walk the hosts by index
+        if hosts.len().saturating_mul(ports.len().max(1) as u128) > MAX_SCAN_TARGETS {
+            anyhow::bail!("target space too large: {} host(s) x {} port(s)", hosts.len(), ports.len());
+        }
+        Ok(Self {
+            hosts: Arc::new(hosts),
+            port_set: Arc::new(ports.iter().copied().collect()),
+            ports: Arc::new(ports),
+            randomize,
+        })
+    }
+
+    /// Number of (host, port) work items.
+    pub fn len(&self) -> u64 {
+        (self.hosts.len() * self.ports.len() as u128) as u64
+    }
+
+    /// Check if there is nothing to probe.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the target hosts.
+    pub fn hosts(&self) -> &TargetSet {
+        &self.hosts
+    }
+
+    /// Get the target ports.
+    pub fn ports(&self) -> &[u16] {
+        &self.ports
+    }
+
+    /// Check if the socket address is one of the work items.
+    pub fn contains(&self, socket_addr: &SocketAddr) -> bool {
+        self.port_set.contains(&socket_addr.port()) && self.hosts.contains(&socket_addr.ip())
+    }
+
+    /// Iterate over the target hosts. Each call yields a new random order unless
+    /// the targets are ordered, in which case the hosts come in address order.
+    pub fn host_iter(&self) -> Box<dyn Iterator<Item = IpAddr> + Send> {
+        let hosts = self.hosts.clone();
+        let indices: Box<dyn Iterator<Item = u64> + Send> = if self.randomize {
+            Box::new(CyclicPermutation::new(hosts.len() as u64))
+        } else {
+            Box::new(0..hosts.len() as u64)
+        };
+        Box::new(indices.filter_map(move |index| hosts.get(index as u128)))
+    }
+
+    /// Iterate over the work items. Each call yields a new random order unless
+    /// the targets are ordered, in which case all ports of a host come before the next host.
+    pub fn iter(&self) -> ScanTargetIter {
+        let indices: Box<dyn Iterator<Item = u64> + Send> = if self.randomize {
+            Box::new(CyclicPermutation::new(self.len()))
+        } else {
+            Box::new(0..self.len())
+        };
+        ScanTargetIter {
+            hosts: self.hosts.clone(),
+            ports: self.ports.clone(),
+            indices,
+            remaining: self.len() as usize,
+        }
+    }
+}
+
+/// Iterator over the work items of [`ScanTargets`]
+pub struct ScanTargetIter {
+    hosts: Arc<TargetSet>,
+    ports: Arc<Vec<u16>>,
+    indices: Box<dyn Iterator<Item = u64> + Send>,
+    remaining: usize,
+}
+
+impl Iterator for ScanTargetIter {
+    type Item = SocketAddr;
+
+    fn next(&mut self) -> Option<SocketAddr> {
+        let index = self.indices.next()?;
+        self.remaining -= 1;
+        let port_count = self.ports.len() as u64;
+        let ip = self.hosts.get((index / port_count) as u128)?;
+        Some(SocketAddr::new(ip, self.ports[(index % port_count) as usize]))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl ExactSizeIterator for ScanTargetIter {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn range(start: &str, end: &str) -> AddrRange {
+        AddrRange::new(ip(start), ip(end))
+    }
+
+    fn block(start: &str, end: &str) -> AddrBlock {
+        AddrBlock::Range(ip(start), ip(end))
+    }
+
+    fn pattern(octets: [(u8, u8); 4]) -> AddrBlock {
+        AddrBlock::Pattern(OctetPattern::new(octets))
+    }
+
+    fn v4(s: &str) -> u32 {
+        u32::from(s.parse::<Ipv4Addr>().unwrap())
+    }
+
+    #[test]
+    fn merge_overlapping_and_adjacent() {
+        let merged = merge_ranges(vec![
+            range("10.0.0.20", "10.0.0.30"),
+            range("10.0.0.1", "10.0.0.10"),
+            range("10.0.0.11", "10.0.0.15"),
+            range("10.0.0.25", "10.0.0.40"),
+            range("10.0.0.50", "10.0.0.50"),
+        ]);
+        assert_eq!(
+            merged,
+            vec![range("10.0.0.1", "10.0.0.15"), range("10.0.0.20", "10.0.0.40"), range("10.0.0.50", "10.0.0.50")]
+        );
+    }
+
+    #[test]
+    fn merge_keeps_families_apart() {
+        // ::10 and 0.0.0.11 are adjacent as numbers, but not the same family
+        let merged = merge_ranges(vec![range("::1", "::10"), range("0.0.0.11", "0.0.0.20")]);
+        assert_eq!(merged.len(), 2);
+    }
+
+    #[test]
+    fn subtract() {
+        let ranges = vec![range("10.0.0.1", "10.0.0.100"), range("10.0.1.1", "10.0.1.10")];
+        let excluded = merge_ranges(vec![
+            range("10.0.0.1", "10.0.0.9"),
+            range("10.0.0.50", "10.0.0.60"),
+            range("10.0.0.100", "10.0.1.5"),
+        ]);
+        assert_eq!(
+            subtract_ranges(&ranges, &excluded),
+            vec![range("10.0.0.10", "10.0.0.49"), range("10.0.0.61", "10.0.0.99"), range("10.0.1.6", "10.0.1.10")]
+        );
+        // Fully covered ranges disappear
+        assert!(subtract_ranges(&ranges, &[range("10.0.0.0", "10.0.2.0")]).is_empty());
+        // Other families are untouched
+        assert_eq!(subtract_ranges(&ranges, &[range("::", "::ffff")]), ranges);
+    }
+
+    #[test]
+    fn target_set_lookup() {
+        let set = TargetSet::new(
+            vec![block("10.0.0.1", "10.0.0.10"), block("2001:db8::1", "2001:db8::4")],
+            vec![Host { ip: ip("10.0.0.5"), hostname: Some("dup.example".into()) }, Host::new(ip("192.168.0.1"))],
+        );
+        assert_eq!(set.len(), 15);
+        assert_eq!(set.get(0), Some(ip("10.0.0.1")));
+        assert_eq!(set.get(10), Some(ip("192.168.0.1")));
+        assert_eq!(set.get(14), Some(ip("2001:db8::4")));
+        assert_eq!(set.get(15), None);
+        assert!(set.contains(&ip("10.0.0.10")) && set.contains(&ip("2001:db8::2")));
+        assert!(!set.contains(&ip("10.0.0.11")) && !set.contains(&ip("2001:db8::5")));
+        let hosts: Vec<Host> = set.hosts().collect();
+        assert_eq!(hosts.len(), 15);
+        assert_eq!(hosts[4].hostname.as_deref(), Some("dup.example"));
+    }
+
+    #[test]
+    fn target_set_exclude() {
+        let mut set = TargetSet::new(
+            vec![block("10.0.0.1", "10.0.0.254")],
+            vec![Host { ip: ip("10.0.0.7"), hostname: Some("gone.example".into()) }],
+        );
+        let removed = set.exclude(&[block("10.0.0.0", "10.0.0.9"), block("10.0.0.200", "10.0.1.0")]);
+        assert_eq!(removed, 9 + 55);
+        assert_eq!(set.len(), 190);
+        assert_eq!(set.get(0), Some(ip("10.0.0.10")));
+        assert!(set.hostnames().is_empty());
+        assert_eq!(set.exclude(&[]), 0);
+    }
+
+    #[test]
+    fn scan_targets_cover_every_item_once() {
+        let set = TargetSet::new(vec![block("10.0.0.1", "10.0.0.20")], Vec::new());
+        for randomize in [false, true] {
+            let targets = ScanTargets::new(set.clone(), vec![22, 80, 443], randomize).unwrap();
+            let items: Vec<SocketAddr> = targets.iter().collect();
+            assert_eq!(items.len(), 60);
+            assert_eq!(items.iter().collect::<HashSet<_>>().len(), 60);
+            assert!(items.iter().all(|item| targets.contains(item)));
+            assert_eq!(targets.host_iter().collect::<HashSet<_>>().len(), 20);
+            if !randomize {
+                assert_eq!(items[0], SocketAddr::new(ip("10.0.0.1"), 22));
+                assert_eq!(items[3], SocketAddr::new(ip("10.0.0.2"), 22));
+            }
+        }
+        assert!(!ScanTargets::new(set, vec![80], false).unwrap().contains(&SocketAddr::new(ip("10.0.0.1"), 81)));
+    }
+
+    #[test]
+    fn pattern_lookup() {
+        let p = OctetPattern::new([(10, 10), (0, 1), (0, 255), (1, 2)]);
+        assert_eq!(p.len(), 2 * 256 * 2);
+        assert_eq!(p.get(0), "10.0.0.1".parse::<Ipv4Addr>().unwrap());
+        assert_eq!(p.get(1), "10.0.0.2".parse::<Ipv4Addr>().unwrap());
+        assert_eq!(p.get(2), "10.0.1.1".parse::<Ipv4Addr>().unwrap());
+        assert_eq!(p.get(p.len() - 1), "10.1.255.2".parse::<Ipv4Addr>().unwrap());
+        assert!(p.contains(&"10.1.7.2".parse().unwrap()));
+        assert!(!p.contains(&"10.1.7.3".parse().unwrap()));
+        assert_eq!(p.bounds(), (v4("10.0.0.1"), v4("10.1.255.2")));
+    }
+
+    #[test]
+    fn pattern_subtract_is_disjoint_and_complete() {
+        let p = OctetPattern::new([(1, 4), (0, 3), (0, 3), (0, 3)]);
+        let q = OctetPattern::new([(2, 3), (1, 5), (0, 0), (2, 3)]);
+        let rest = p.subtract(&q);
+        assert!(rest.len() <= 8);
+        let total: u64 = rest.iter().map(|r| r.len()).sum();
+        assert_eq!(total, p.len() - p.intersect(&q).unwrap().len());
+        for (i, a) in rest.iter().enumerate() {
+            assert!(a.intersect(&q).is_none());
+            assert!(rest[i + 1..].iter().all(|b| a.intersect(b).is_none()));
+        }
+        assert_eq!(p.subtract(&OctetPattern::new([(5, 9), (0, 0), (0, 0), (0, 0)])), vec![p]);
+        assert!(p.subtract(&p).is_empty());
+    }
+
+    #[test]
+    fn pattern_from_range() {
+        for (start, end) in [("10.0.0.5", "10.0.3.9"), ("0.0.0.0", "255.255.255.255"), ("10.0.0.1", "10.255.255.254"), ("1.2.3.4", "1.2.3.4")] {
+            let (start, end) = (v4(start), v4(end));
+            let patterns = OctetPattern::from_range(start, end);
+            assert!(patterns.len() <= 7);
+            assert_eq!(patterns.iter().map(|p| p.len()).sum::<u64>(), (end - start) as u64 + 1);
+            assert_eq!(patterns[0].bounds().0, start);
+            assert_eq!(patterns.last().unwrap().bounds().1, end);
+        }
+    }
+
+    #[test]
+    fn target_set_with_patterns() {
+        // "*.*.*.1" stays a single pattern; the overlapping range and host are counted once
+        let set = TargetSet::new(
+            vec![pattern([(0, 255), (0, 255), (0, 255), (1, 1)]), block("10.0.0.0", "10.0.0.3"), pattern([(10, 10), (0, 0), (0, 1), (1, 2)])],
+            vec![Host::new(ip("192.168.0.1"))],
+        );
+        assert_eq!(set.len(), (1 << 24) + 4 - 1 + 2 - 1);
+        assert!(set.contains(&ip("172.16.5.1")) && set.contains(&ip("10.0.1.2")) && set.contains(&ip("10.0.0.3")));
+        assert!(!set.contains(&ip("172.16.5.2")));
+        let small = TargetSet::new(vec![pattern([(10, 10), (0, 1), (0, 1), (1, 1)]), block("10.0.0.1", "10.0.0.1")], Vec::new());
+        let mut hosts: Vec<IpAddr> = small.hosts().map(|h| h.ip).collect();
+        hosts.sort();
+        assert_eq!(hosts, vec![ip("10.0.0.1"), ip("10.0.1.1"), ip("10.1.0.1"), ip("10.1.1.1")]);
+        assert_eq!((0..small.len()).filter_map(|i| small.get(i)).collect::<HashSet<_>>().len(), 4);
+    }
+
+    #[test]
+    fn target_set_exclude_patterns() {
+        // Excluding a pattern punches holes in a range
+        let mut set = TargetSet::new(vec![block("10.0.0.0", "10.0.3.255")], Vec::new());
+        assert_eq!(set.exclude(&[pattern([(0, 255), (0, 255), (0, 255), (0, 0)]), pattern([(0, 255), (0, 255), (0, 255), (255, 255)])]), 8);
+        assert_eq!(set.len(), 1024 - 8);
+        assert!(!set.contains(&ip("10.0.2.255")) && set.contains(&ip("10.0.2.254")));
+        // Excluding a range from a pattern
+        let mut set = TargetSet::new(vec![pattern([(10, 10), (0, 255), (0, 255), (1, 1)])], Vec::new());
+        assert_eq!(set.exclude(&[block("10.1.0.0", "10.1.255.255")]), 256);
+        assert!(!set.contains(&ip("10.1.9.1")) && set.contains(&ip("10.2.9.1")));
+        let items: HashSet<IpAddr> = (0..set.len()).filter_map(|i| set.get(i)).collect();
+        assert_eq!(items.len() as u128, set.len());
+    }
+
+    #[test]
+    fn target_set_blocks_display() {
+        let mut set = TargetSet::new(vec![block("192.0.2.1", "192.0.2.9"), pattern([(10, 10), (0, 255), (0, 3), (1, 1)])], Vec::new());
+        set.exclude(&[block("192.0.2.5", "192.0.2.5")]);
+        let blocks: Vec<String> = set.blocks().map(|b| b.to_string()).collect();
+        assert_eq!(blocks, ["192.0.2.1-192.0.2.4", "192.0.2.6-192.0.2.9", "10.*.0-3.1"]);
+        assert_eq!(block("::1", "::1").to_string(), "::1");
+    }
+}
@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 use regex::{Regex, RegexBuilder};
 use anyhow::{Result, bail};
 use futures::stream::{self, StreamExt};
@@ -6,19 +6,24 @@ use tokio::{io::{AsyncRead, AsyncReadExt}, net::TcpStream, time::{timeout, Insta
 use tokio::sync::mpsc;
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 
-use crate::{endpoint::Endpoint, service::probe::{PortProbe, PortProbeResult, ProbeContext, ServiceProbe}};
+use crate::{endpoint::Endpoint, probe::ProbeSource, proxy::Proxy, scan::scheduler::Scheduler, service::probe::{PortProbe, PortProbeResult, ProbeContext, ServiceProbe}};
 
 pub mod probe;
-mod payload;
+pub mod payload;
 
 /// Configuration for service probing
 #[derive(Clone,Debug)]
 pub struct ServiceProbeConfig {
     pub timeout: Duration,
-    pub max_concurrency: usize,
+    /// Connection scheduler, shared with the port scan
+    pub scheduler: Scheduler,
     pub max_read_size: usize,
     pub sni: bool,
     pub skip_cert_verify: bool,
+    /// Source address of probe connections
+    pub source: ProbeSource,
+    /// Proxy that TCP probe connections are tunneled through
+    pub proxy: Option<Arc<Proxy>>,
 }
 
 /// Result of service detection on multiple endpoints
@@ -71,12 +76,15 @@ impl ServiceDetector {
         });
 
         let ports = endpoint.ports.clone();
-        let prod = stream::iter(ports).for_each_concurrent(config.max_concurrency, move |port| {
+        let prod = stream::iter(ports).for_each_concurrent(config.scheduler.limits().per_host, move |port| {
             let tx = ch_tx.clone();
             let endpoint = endpoint.clone();
             let port_probe_db = port_probe_db.clone();
             let service_probe_db = service_probe_db.clone();
+            let scheduler = config.scheduler.clone();
+            let proxy = config.proxy.clone();
             async move {
+                let _permit = scheduler.acquire(endpoint.ip).await;
                 // Perform service detection for each endpoint
                 let mut results: Vec<Result<PortProbeResult>> = Vec::new();
                 if let Some(probes) = port_probe_db.get(&port) {
@@ -104,6 +112,8 @@ impl ServiceDetector {
                             max_read_size: config.max_read_size,
                             sni: config.sni,
                             skip_cert_verify: config.skip_cert_verify,
+                            source: config.source,
+                            proxy: proxy.clone(),
                         };
 
                         let r = match probe {
@@ -137,6 +147,8 @@ impl ServiceDetector {
                         max_read_size: config.max_read_size,
                         sni: config.sni,
                         skip_cert_verify: config.skip_cert_verify,
+                        source: config.source,
+                        proxy: proxy.clone(),
                     };
                     results.push(probe::null::NullProbe::run(ctx).await);
                 }
@@ -152,32 +164,23 @@ impl ServiceDetector {
         Ok(results)
     }
 
+    /// Detect services on the given endpoints. Endpoints are probed in parallel
+    /// within the host limit of the scheduler.
     pub async fn run_service_detection(&self, targets: Vec<Endpoint>) -> Result<ServiceDetectionResult> {
         let start_time = Instant::now();
-        let mut tasks = vec![];
-        for endpoint in targets {
-            let endpoint = endpoint.clone();
-            let conf = self.config.clone();
-            tasks.push(tokio::spawn(async move {
-                let probe_results = Self::detect_services(
-                        conf,
-                        endpoint
-                    )
-                    .await;
-                probe_results
-            }));      
-        }
+        let hosts = self.config.scheduler.limits().hosts;
+        let mut tasks = stream::iter(targets)
+            .map(|endpoint| Self::detect_services(self.config.clone(), endpoint))
+            .buffer_unordered(hosts);
         let mut results: Vec<PortProbeResult> = Vec::new();
-        for task in tasks {
-            if let Ok(r) = task.await {
-                match r {
-                    Ok(mut result) => {
-                        // Merge results
-                        results.append(&mut result);
-                    },
-                    Err(e) => {
-                        tracing::error!("Service detection failed: {}", e);
-                    }
+        while let Some(r) = tasks.next().await {
+            match r {
+                Ok(mut result) => {
+                    // Merge results
+                    results.append(&mut result);
+                },
+                Err(e) => {
+                    tracing::error!("Service detection failed: {}", e);
                 }
             }
         }
@@ -1,14 +1,13 @@
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use anyhow::Result;
-use crate::{endpoint::ServiceInfo, service::{build_regex, expand_cpe_templates, probe::{PortProbeResult, ProbeContext, ServiceProbe}}};
+use crate::{endpoint::ServiceInfo, probe::ProbeSource, service::{build_regex, expand_cpe_templates, probe::{PortProbeResult, ProbeContext, ServiceProbe}}};
 
 use hickory_proto::{
     op::{Message, MessageType, OpCode, Query},
     rr::{DNSClass, Name, RecordType},
     serialize::binary::{BinEncodable, BinEncoder},
 };
-use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpStream, UdpSocket}};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::UdpSocket};
 
 /// Build a DNS query message for "version.bind" TXT record in CHAOS class.
 fn build_version_bind_query() -> anyhow::Result<Vec<u8>> {
@@ -31,15 +30,10 @@ fn build_version_bind_query() -> anyhow::Result<Vec<u8>> {
 }
 
 /// Perform a DNS version.bind query over UDP.
-async fn run_dns_version_bind_udp(addr: std::net::SocketAddr, idle: std::time::Duration, _total: std::time::Duration, max_bytes: usize)
+async fn run_dns_version_bind_udp(addr: std::net::SocketAddr, source: ProbeSource, idle: std::time::Duration, _total: std::time::Duration, max_bytes: usize)
 -> anyhow::Result<(String, bool)> {
     let q = build_version_bind_query()?;
-    let local = if addr.is_ipv6() {
-        SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
-    } else {
-        SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
-    };
-    let sock = UdpSocket::bind(local).await?;
+    let sock = UdpSocket::bind(source.local_addr(addr.ip())).await?;
     sock.connect(addr).await?;
     sock.send(&q).await?;
 
@@ -73,9 +67,9 @@ async fn run_dns_version_bind_udp(addr: std::net::SocketAddr, idle: std::time::D
 }
 
 /// Perform a DNS version.bind query over TCP.
-async fn run_dns_version_bind_tcp(addr: std::net::SocketAddr, idle: std::time::Duration, total: std::time::Duration, max_bytes: usize)
+async fn run_dns_version_bind_tcp(ctx: &ProbeContext, idle: std::time::Duration, total: std::time::Duration, max_bytes: usize)
 -> anyhow::Result<String> {
-    let mut stream = tokio::time::timeout(total, TcpStream::connect(addr)).await??;
+    let mut stream = tokio::time::timeout(total, ctx.connect_tcp()).await??;
 
     let q = build_version_bind_query()?;
     let mut framed = Vec::with_capacity(q.len() + 2);
@@ -152,10 +146,10 @@ impl DnsProbe {
     /// Run the DNS probe with the given context.
     pub async fn run(ctx: ProbeContext) -> Result<PortProbeResult> {
         let addr = std::net::SocketAddr::new(ctx.ip, ctx.probe.port);
-        // Try UDP first
-        if matches!(ctx.probe.probe_id, ServiceProbe::UdpDNSVersionBindReq | ServiceProbe::TcpDNSVersionBindReq) {
+        // Try UDP first, unless UDP cannot go through the proxy
+        if matches!(ctx.probe.probe_id, ServiceProbe::UdpDNSVersionBindReq | ServiceProbe::TcpDNSVersionBindReq) && ctx.proxy.is_none() {
             tracing::debug!("DNS Version Bind Probe (UDP): {}:{}", ctx.ip, ctx.probe.port);
-            match run_dns_version_bind_udp(addr, ctx.timeout, ctx.timeout, ctx.max_read_size).await {
+            match run_dns_version_bind_udp(addr, ctx.source, ctx.timeout, ctx.timeout, ctx.max_read_size).await {
                 Ok((txt, truncated)) => {
                     let mut svc = ServiceInfo::default();
                     let udp_svc_db = crate::db::service::udp_service_db();
@@ -172,7 +166,7 @@ impl DnsProbe {
                     }
                     // If truncated, try TCP as well
                     if truncated {
-                        if let Ok(txt2) = run_dns_version_bind_tcp(addr, ctx.timeout, ctx.timeout, ctx.max_read_size).await {
+                        if let Ok(txt2) = run_dns_version_bind_tcp(&ctx, ctx.timeout, ctx.timeout, ctx.max_read_size).await {
                             svc.raw = Some(txt2.clone());
                             let hits2 = match_response_signatures(
                                 "tcp:dns_version_bind_req", &txt2
@@ -200,7 +194,7 @@ impl DnsProbe {
                     let tcp_svc_db = crate::db::service::tcp_service_db();
                     svc.name = tcp_svc_db.get_name(ctx.probe.port).map(|s| s.to_string());
                     // If UDP failed, try TCP
-                    if let Ok(txt) = run_dns_version_bind_tcp(addr, ctx.timeout, ctx.timeout, ctx.max_read_size).await {
+                    if let Ok(txt) = run_dns_version_bind_tcp(&ctx, ctx.timeout, ctx.timeout, ctx.max_read_size).await {
                         svc.banner = Some(txt.clone());
                         svc.raw = Some(txt.clone());
                         let hits = match_response_signatures(
@@ -227,7 +221,7 @@ impl DnsProbe {
         // If UDP not selected or failed, and TCP is selected
         if matches!(ctx.probe.probe_id, ServiceProbe::TcpDNSVersionBindReq) {
             tracing::debug!("DNS Version Bind Probe (TCP): {}:{}", ctx.ip, ctx.probe.port);
-            let txt = run_dns_version_bind_tcp(addr, ctx.timeout, ctx.timeout, ctx.max_read_size).await?;
+            let txt = run_dns_version_bind_tcp(&ctx, ctx.timeout, ctx.timeout, ctx.max_read_size).await?;
             let mut svc = ServiceInfo::default();
             let tcp_svc_db = crate::db::service::tcp_service_db();
             svc.name = tcp_svc_db.get_name(ctx.probe.port).map(|s| s.to_string());
@@ -1,6 +1,5 @@
-use std::net::SocketAddr;
 use anyhow::Result;
-use tokio::{io::{AsyncWriteExt}, net::TcpStream, time::timeout};
+use tokio::{io::{AsyncWriteExt}, time::timeout};
 
 use crate::{
     endpoint::ServiceInfo,
@@ -70,8 +69,7 @@ impl GenericProbe {
     /// Run the generic probe with the given context.
     pub async fn run(ctx: ProbeContext) -> Result<PortProbeResult> {
         tracing::debug!("Generic Probe: {}:{} - Connecting", ctx.ip, ctx.probe.port);
-        let addr: SocketAddr = SocketAddr::new(ctx.ip, ctx.probe.port);
-        let mut stream = timeout(ctx.timeout, TcpStream::connect(addr)).await??;
+        let mut stream = timeout(ctx.timeout, ctx.connect_tcp()).await??;
 
         tracing::debug!("Generic Probe: {}:{} - Connected", ctx.ip, ctx.probe.port);
 
@@ -2,7 +2,7 @@ use std::{collections::HashMap, net::SocketAddr};
 
 use anyhow::Result;
 use rustls_pki_types::ServerName;
-use tokio::{io::{AsyncWriteExt}, net::TcpStream, time::timeout};
+use tokio::{io::{AsyncWriteExt}, time::timeout};
 use tokio_rustls::{TlsConnector, rustls::{ClientConfig, RootCertStore}};
 use std::sync::Arc;
 
@@ -113,7 +113,7 @@ impl HttpProbe {
     pub async fn run(ctx: ProbeContext) -> Result<PortProbeResult> {
         let addr: SocketAddr = SocketAddr::new(ctx.ip, ctx.probe.port);
         let hostname = ctx.hostname.clone().unwrap_or_else(|| ctx.ip.to_string());
-        let mut tcp_stream = timeout(ctx.timeout, TcpStream::connect(addr)).await??;
+        let mut tcp_stream = timeout(ctx.timeout, ctx.connect_tcp()).await??;
         let payload_builder = PayloadBuilder::new(ctx.probe.clone());
         let tcp_svc_db = crate::db::service::tcp_service_db();
         match ctx.probe.probe_id {
@@ -5,11 +5,12 @@ pub mod tls;
 pub mod dns;
 pub mod quic;
 
-use std::{collections::BTreeMap, net::IpAddr, time::Duration};
+use std::{collections::BTreeMap, net::{IpAddr, SocketAddr}, sync::Arc, time::Duration};
 
 use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
 
-use crate::{endpoint::{ServiceInfo, TransportProtocol}};
+use crate::{endpoint::{ServiceInfo, TransportProtocol}, probe::ProbeSource, proxy::Proxy};
 
 /// Metadata for the database
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -203,6 +204,21 @@ pub struct ProbeContext {
     pub max_read_size: usize,
     pub sni: bool,
     pub skip_cert_verify: bool,
+    /// Source address of probe connections
+    pub source: ProbeSource,
+    /// Proxy that TCP probe connections are tunneled through
+    pub proxy: Option<Arc<Proxy>>,
+}
+
+impl ProbeContext {
+    /// Open a TCP connection to the probed port, through the proxy if one is set
+    pub async fn connect_tcp(&self) -> std::io::Result<TcpStream> {
+        let addr = SocketAddr::new(self.ip, self.probe.port);
+        match &self.proxy {
+            Some(proxy) => proxy.connect(addr, self.source).await,
+            None => self.source.connect(addr).await,
+        }
+    }
 }
 
 /// Result of running a probe against a target
@@ -1,6 +1,5 @@
-use std::net::SocketAddr;
 use anyhow::{bail, Result};
-use tokio::{io::{AsyncWriteExt}, net::TcpStream, time::timeout};
+use tokio::{io::{AsyncWriteExt}, time::timeout};
 
 use crate::{
     endpoint::ServiceInfo,
@@ -91,8 +90,7 @@ impl NullProbe {
         }
 
         tracing::debug!("Null Probe: {}:{} - Connecting", ctx.ip, ctx.probe.port);
-        let addr: SocketAddr = SocketAddr::new(ctx.ip, ctx.probe.port);
-        let mut stream = timeout(ctx.timeout, TcpStream::connect(addr)).await??;
+        let mut stream = timeout(ctx.timeout, ctx.connect_tcp()).await??;
 
         tracing::debug!("Null Probe: {}:{} - Connected", ctx.ip, ctx.probe.port);
 
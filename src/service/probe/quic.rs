@@ -46,7 +46,7 @@ impl QuicProbe {
             b"hq-29".as_slice(),
         ];
         let client_cfg = quic_client_config(ctx.skip_cert_verify, &alpn)?;
-        let mut endpoint = Endpoint::client((if ctx.ip.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" }).parse().unwrap())?;
+        let mut endpoint = Endpoint::client(ctx.source.local_addr(ctx.ip))?;
         endpoint.set_default_client_config(client_cfg);
 
         // Connect to the server (SNI is hostname or "localhost")
@@ -2,11 +2,10 @@ use anyhow::Result;
 use rustls::client::danger::ServerCertVerifier;
 use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
 use rustls::ClientConnection;
-use tokio::{net::TcpStream, time::timeout};
+use tokio::time::timeout;
 use tokio_rustls::{TlsConnector, rustls::{ClientConfig, RootCertStore}};
 use std::sync::Arc;
 use x509_parser::prelude::{FromDer, ParsedExtension, X509Certificate};
-use std::net::SocketAddr;
 use crate::endpoint::TlsInfo;
 use crate::{endpoint::ServiceInfo};
 use crate::service::probe::{PortProbeResult, ProbeContext};
@@ -136,9 +135,8 @@ pub struct TlsProbe;
 impl TlsProbe {
     /// Run the TLS probe with the given context.
     pub async fn run(ctx: ProbeContext) -> Result<PortProbeResult> {
-        let addr: SocketAddr = SocketAddr::new(ctx.ip, ctx.probe.port);
         let hostname = ctx.hostname.clone().unwrap_or_else(|| ctx.ip.to_string());
-        let tcp_stream = timeout(ctx.timeout, TcpStream::connect(addr)).await??;
+        let tcp_stream = timeout(ctx.timeout, ctx.connect_tcp()).await??;
 
         // rustls config
         let mut roots = RootCertStore::empty();
@@ -9,7 +9,7 @@ use anyhow::Result;
 
 use crate::config::default::{DEFAULT_BASE_TARGET_UDP_PORT, DEFAULT_HOP_LIMIT};
 use crate::endpoint::Host;
-use crate::probe::{ProbeResult, ProbeStatus};
+use crate::probe::{ProbeResult, ProbeSource, ProbeStatus};
 use crate::protocol::Protocol;
 
 /// Settings for traceroute operations.
@@ -26,6 +26,9 @@ pub struct TraceSetting {
     pub send_rate: Duration,
     pub tunnel: bool,
     pub loopback: bool,
+    /// Source address and port of probes
+    #[serde(default)]
+    pub source: ProbeSource,
 }
 
 impl Default for TraceSetting {
@@ -42,6 +45,7 @@ impl Default for TraceSetting {
             send_rate: Duration::from_secs(1),
             tunnel: false,
             loopback: false,
+            source: ProbeSource::default(),
         }
     }
 }
@@ -64,6 +68,7 @@ impl TraceSetting {
             send_rate: Duration::from_secs(1),
             tunnel: use_tun,
             loopback: loopback,
+            source: ProbeSource::default(),
         };
         Ok(setting)
     }
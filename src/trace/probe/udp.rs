@@ -9,9 +9,9 @@ use nex::packet::frame::{Frame, ParseOption};
 use nex::packet::icmp::IcmpType;
 use nex::packet::icmpv6::Icmpv6Type;
 use crate::endpoint::NodeType;
+use crate::nei::cache::NeighborCache;
 use crate::probe::ProbeStatus;
 use crate::{probe::ProbeResult, protocol::Protocol};
-use nex::datalink::async_io::{async_channel, AsyncChannel};
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 
 /// Run a UDP traceroute based on the provided trace settings.
@@ -23,31 +23,14 @@ pub async fn run_udp_trace(setting: &TraceSetting) -> Result<TraceResult> {
         Some(interface) => interface,
         None => return Err(anyhow::anyhow!("Interface not found")),
     };
+    let neighbors = NeighborCache::default();
+    neighbors.resolve(&interface, [setting.dst_ip], setting.receive_timeout).await;
     // Create sender
-    let config = nex::datalink::Config {
-        write_buffer_size: 4096,
-        read_buffer_size: 4096,
-        read_timeout: Some(setting.receive_timeout),
-        write_timeout: None,
-        channel_type: nex::datalink::ChannelType::Layer2,
-        bpf_fd_attempts: 1000,
-        linux_fanout: None,
-        promiscuous: false,
-    };
-
-    let AsyncChannel::Ethernet(mut tx, mut rx) = async_channel(&interface, config)?
-    else {
-        unreachable!();
-    };
+    let (mut tx, mut rx) = crate::capture::channel::open(&interface, setting.receive_timeout)?;
 
     let mut responses: Vec<ProbeResult> = Vec::new();
 
-    let mut parse_option: ParseOption = ParseOption::default();
-    if interface.is_tun() || (cfg!(any(target_os = "macos", target_os = "ios")) && interface.is_loopback()) {
-        let payload_offset = if interface.is_loopback() { 14 } else { 0 };
-        parse_option.from_ip_packet = true;
-        parse_option.offset = payload_offset;
-    }
+    let parse_option: ParseOption = crate::interface::parse_option(&interface);
 
     let header_span = tracing::info_span!("trace");
     header_span.pb_set_style(&crate::output::progress::get_progress_style());
@@ -59,7 +42,7 @@ pub async fn run_udp_trace(setting: &TraceSetting) -> Result<TraceResult> {
     let mut dst_reached: bool = false;
     let start_time = Instant::now();
     for seq_ttl in 1..setting.hop_limit {
-        let udp_packet = crate::packet::udp::build_udp_trace_packet(&interface, &setting, seq_ttl);
+        let udp_packet = crate::packet::udp::build_udp_trace_packet(&interface, &neighbors, &setting, seq_ttl);
         let send_time = Instant::now();
         match poll_fn(|cx| tx.poll_send(cx, &udp_packet)).await {
             Ok(_) => {
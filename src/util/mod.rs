@@ -1,2 +1,5 @@
 pub mod ip;
 pub mod json;
+pub mod permutation;
+pub mod privilege;
+pub mod rlimit;
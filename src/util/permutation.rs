@@ -0,0 +1,160 @@
+use rand::Rng;
+
+/// Miller-Rabin bases that are deterministic for every u64
+const PRIME_TEST_BASES: [u64; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+
+fn mul_mod(a: u64, b: u64, m: u64) -> u64 {
+    ((a as u128 * b as u128) % m as u128) as u64
+}
+
+fn pow_mod(mut base: u64, mut exp: u64, m: u64) -> u64 {
+    let mut result = 1;
+    base %= m;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = mul_mod(result, base, m);
+        }
+        base = mul_mod(base, base, m);
+        exp >>= 1;
+    }
+    result
+}
+
+fn is_prime(n: u64) -> bool {
+    if n < 2 {
+        return false;
+    }
+    for p in PRIME_TEST_BASES {
+        if n.is_multiple_of(p) {
+            return n == p;
+        }
+    }
+    let mut d = n - 1;
+    let mut s = 0;
+    while d.is_multiple_of(2) {
+        d /= 2;
+        s += 1;
+    }
+    'bases: for a in PRIME_TEST_BASES {
+        let mut x = pow_mod(a, d, n);
+        if x == 1 || x == n - 1 {
+            continue;
+        }
+        for _ in 1..s {
+            x = mul_mod(x, x, n);
+            if x == n - 1 {
+                continue 'bases;
+            }
+        }
+        return false;
+    }
+    true
+}
+
+/// Find the smallest safe prime `p = 2q + 1` (q prime) greater than `n`.
+/// `p - 1` then has only the prime factors 2 and q, so generators are cheap to verify.
+fn next_safe_prime(n: u64) -> u64 {
+    let mut q = (n / 2).max(2);
+    loop {
+        let p = 2 * q + 1;
+        if p > n && is_prime(q) && is_prime(p) {
+            return p;
+        }
+        q += 1;
+    }
+}
+
+/// Random permutation of `0..n` in constant memory.
+///
+/// Walks the multiplicative group of integers modulo a prime `p > n` with a
+/// random generator from a random starting point, skipping values `>= n`.
+/// The group is cyclic, so every value is visited exactly once.
+#[derive(Clone, Debug)]
+pub struct CyclicPermutation {
+    n: u64,
+    prime: u64,
+    generator: u64,
+    start: u64,
+    current: u64,
+    done: bool,
+}
+
+impl CyclicPermutation {
+    /// Create a new permutation of `0..n`. `n` must be below 2^62.
+    pub fn new(n: u64) -> Self {
+        let mut rng = rand::thread_rng();
+        let prime = next_safe_prime(n);
+        let q = (prime - 1) / 2;
+        let generator = loop {
+            let g = rng.gen_range(2..prime - 1);
+            if pow_mod(g, 2, prime) != 1 && pow_mod(g, q, prime) != 1 {
+                break g;
+            }
+        };
+        let start = rng.gen_range(1..prime);
+        Self {
+            n,
+            prime,
+            generator,
+            start,
+            current: start,
+            done: n == 0,
+        }
+    }
+}
+
+impl Iterator for CyclicPermutation {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        while !self.done {
+            let value = self.current - 1;
+            self.current = mul_mod(self.current, self.generator, self.prime);
+            self.done = self.current == self.start;
+            if value < self.n {
+                return Some(value);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn primes() {
+        assert!(is_prime(2) && is_prime(3) && is_prime(65_537) && is_prime(4_294_967_311));
+        assert!(!is_prime(0) && !is_prime(1) && !is_prime(9) && !is_prime(3_215_031_751));
+        for n in [0, 1, 10, 1000, 65_536, 1 << 32] {
+            let p = next_safe_prime(n);
+            assert!(p > n && is_prime(p) && is_prime((p - 1) / 2));
+        }
+    }
+
+    #[test]
+    fn visits_every_value_once() {
+        for n in [1u64, 2, 3, 7, 100, 1000, 65_536] {
+            let values: Vec<u64> = CyclicPermutation::new(n).collect();
+            assert_eq!(values.len() as u64, n);
+            let unique: HashSet<u64> = values.iter().copied().collect();
+            assert_eq!(unique.len() as u64, n);
+            assert!(values.iter().all(|v| *v < n));
+        }
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(CyclicPermutation::new(0).next(), None);
+    }
+
+    #[test]
+    fn order_changes() {
+        let first: Vec<u64> = CyclicPermutation::new(1000).collect();
+        let differs = (0..8).any(|_| CyclicPermutation::new(1000).collect::<Vec<u64>>() != first);
+        assert!(differs);
+        assert_ne!(first, (0..1000).collect::<Vec<u64>>());
+    }
+}
@@ -0,0 +1,114 @@
+use std::fmt;
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+
+/// Sockets the process may open, detected once at startup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketAccess {
+    /// Raw Layer2 sockets (CAP_NET_RAW, root or a readable BPF device)
+    pub raw: bool,
+    /// Unprivileged ICMP datagram sockets (`SOCK_DGRAM` with `IPPROTO_ICMP`)
+    pub icmp_datagram: bool,
+}
+
+static SOCKET_ACCESS: OnceLock<SocketAccess> = OnceLock::new();
+
+/// Detect which sockets are available and remember the result. Called once at startup.
+pub fn init_socket_access() {
+    let access = socket_access();
+    tracing::debug!("Raw sockets: {}, ICMP datagram sockets: {}", access.raw, access.icmp_datagram);
+}
+
+/// Get the sockets the process may open.
+pub fn socket_access() -> SocketAccess {
+    *SOCKET_ACCESS.get_or_init(|| SocketAccess {
+        raw: raw_socket_available(),
+        icmp_datagram: icmp_datagram_available(),
+    })
+}
+
+/// Fail with a clear message if raw sockets are not available for `what`, which has no fallback.
+pub fn require_raw(what: &str) -> anyhow::Result<()> {
+    if !socket_access().raw {
+        return Err(raw_required(what));
+    }
+    Ok(())
+}
+
+/// Error for `what`, which needs raw sockets and has no unprivileged fallback.
+pub fn raw_required(what: &str) -> anyhow::Error {
+    anyhow::anyhow!("{} requires raw socket access; run nrev as root or grant it CAP_NET_RAW", what)
+}
+
+/// Technique replaced by an unprivileged fallback because raw sockets are not available
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Downgrade {
+    /// Technique that was requested, e.g. "tcp-syn scan"
+    pub requested: String,
+    /// Technique used instead, e.g. "tcp-connect scan"
+    pub used: String,
+}
+
+impl Downgrade {
+    /// Record a downgrade and tell the user about it.
+    pub fn new(requested: impl Into<String>, used: impl Into<String>) -> Self {
+        let downgrade = Self {
+            requested: requested.into(),
+            used: used.into(),
+        };
+        tracing::warn!("No raw socket access: {}", downgrade);
+        downgrade
+    }
+}
+
+impl fmt::Display for Downgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} downgraded to {}", self.requested, self.used)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn raw_socket_available() -> bool {
+    // Protocol 0 receives nothing, so the probe socket never sees traffic
+    let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+    close_probe_socket(fd)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn raw_socket_available() -> bool {
+    // BPF devices are root-only unless made readable (e.g. by ChmodBPF on macOS)
+    if unsafe { libc::geteuid() } == 0 {
+        return true;
+    }
+    ["/dev/bpf", "/dev/bpf0"]
+        .iter()
+        .any(|path| std::fs::OpenOptions::new().read(true).write(true).open(path).is_ok())
+}
+
+/// Windows uses Npcap, whose access can only be checked by opening a channel
+#[cfg(not(unix))]
+fn raw_socket_available() -> bool {
+    true
+}
+
+#[cfg(unix)]
+fn icmp_datagram_available() -> bool {
+    // Linux allows these sockets to the groups in net.ipv4.ping_group_range
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, libc::IPPROTO_ICMP) };
+    close_probe_socket(fd)
+}
+
+#[cfg(not(unix))]
+fn icmp_datagram_available() -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn close_probe_socket(fd: libc::c_int) -> bool {
+    if fd == -1 {
+        return false;
+    }
+    unsafe { libc::close(fd) };
+    true
+}
@@ -0,0 +1,81 @@
+use std::sync::OnceLock;
+
+/// File descriptors kept free for everything but probe sockets
+/// (standard streams, raw sockets, output files, DNS lookups)
+const RESERVED_FDS: u64 = 64;
+
+/// Soft limit on open file descriptors (RLIMIT_NOFILE) after startup, if known
+static NOFILE_LIMIT: OnceLock<Option<u64>> = OnceLock::new();
+
+/// Raise the soft limit on open file descriptors as far as the hard limit allows
+/// and remember the limit in effect. Called once at startup.
+pub fn init_nofile_limit() {
+    let limit = raise_nofile_limit();
+    if let Some(limit) = limit {
+        tracing::debug!("Open file limit: {}", limit);
+    }
+    let _ = NOFILE_LIMIT.set(limit);
+}
+
+/// Get the soft limit on open file descriptors, or `None` if it is unknown.
+pub fn nofile_limit() -> Option<u64> {
+    NOFILE_LIMIT.get().copied().flatten()
+}
+
+/// Get the number of probe sockets that can be open at once within the file
+/// descriptor limit, or `None` if the limit is unknown.
+pub fn max_open_sockets() -> Option<usize> {
+    nofile_limit().map(sockets_within)
+}
+
+fn sockets_within(limit: u64) -> usize {
+    usize::try_from(limit.saturating_sub(RESERVED_FDS)).unwrap_or(usize::MAX).max(1)
+}
+
+#[cfg(unix)]
+fn raise_nofile_limit() -> Option<u64> {
+    let mut rlim = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) } != 0 {
+        return None;
+    }
+    let soft = rlim.rlim_cur;
+    let mut targets = vec![rlim.rlim_max];
+    // macOS rejects soft limits above OPEN_MAX, even with an unlimited hard limit
+    if cfg!(target_os = "macos") {
+        targets.push(rlim.rlim_max.min(10240));
+    }
+    for target in targets {
+        if target <= soft {
+            break;
+        }
+        let raised = libc::rlimit { rlim_cur: target, rlim_max: rlim.rlim_max };
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+            return Some(rlim_value(target));
+        }
+    }
+    Some(rlim_value(soft))
+}
+
+#[cfg(not(unix))]
+fn raise_nofile_limit() -> Option<u64> {
+    None
+}
+
+/// `rlim_t` is narrower than 64 bits on some 32-bit targets
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)]
+fn rlim_value(value: libc::rlim_t) -> u64 {
+    if value == libc::RLIM_INFINITY { u64::MAX } else { value as u64 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserved_descriptors_are_kept_free() {
+        assert_eq!(sockets_within(1024), 960);
+        assert_eq!(sockets_within(10), 1);
+        assert_eq!(sockets_within(u64::MAX), usize::try_from(u64::MAX - RESERVED_FDS).unwrap_or(usize::MAX));
+    }
+}